@@ -0,0 +1,83 @@
+/* C ABI of Fwumious Wabbit (src/ffi.rs).
+ * Regenerate after changing src/ffi.rs with: cbindgen --config cbindgen.toml --output include/fw.h
+ * Link with libfw.so (target/release/libfw.so after cargo build --release).
+ */
+
+#ifndef FW_H
+#define FW_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#define FW_OK 0
+
+#define FW_ERROR -1
+
+typedef struct FwModel FwModel;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Loads a regressor in immutable (prediction only) mode. Returns NULL on failure.
+ */
+FwModel *fw_load(const char *filename);
+
+/**
+ * New handle for use in another thread, weights are shared with the original. Returns NULL on failure.
+ */
+FwModel *fw_clone(FwModel *model);
+
+/**
+ * Predicts on a single example in vowpal wabbit text format, the trailing newline is optional
+ */
+int fw_predict(FwModel *model, const char *line, float *prediction);
+
+/**
+ * Computes what the parser would for a feature: the namespace index and the hash of the feature name.
+ * Services can compute these once and then use fw_predict_features(), skipping text formatting and parsing.
+ */
+int fw_feature_hash(FwModel *model,
+                    const char *namespace_name,
+                    const char *feature_name,
+                    uint16_t *namespace_index,
+                    uint32_t *hash);
+
+/**
+ * Predicts on pre-parsed features: for each feature its namespace index, hash and value.
+ * Value is the feature weight (1.0 for plain features), or the parsed float for float namespaces.
+ */
+int fw_predict_features(FwModel *model,
+                        const uint16_t *namespace_indexes,
+                        const uint32_t *hashes,
+                        const float *values,
+                        size_t len,
+                        float *prediction);
+
+/**
+ * Replaces weights with the ones from another regressor with the same model instance, like "hogwild_load" command of the daemon.
+ * Fails when the structure or namespaces of the two models differ.
+ * The new weights are loaded into new memory and then swapped in for all clones, other threads can keep predicting
+ * meanwhile: each prediction uses either the old or the new weights.
+ */
+int fw_hogwild_load(FwModel *model, const char *filename);
+
+/**
+ * Frees the handle, weights are freed together with the last handle that uses them
+ */
+void fw_free(FwModel *model);
+
+/**
+ * Message of the last error in this thread, valid until the next failing call. NULL when there was no error.
+ */
+const char *fw_last_error(void);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* FW_H */
@@ -0,0 +1,142 @@
+// Python bindings: training with the same arguments as the fw binary, and in-process prediction
+// on lists of vowpal wabbit lines or dicts of namespace to features, with results as numpy arrays.
+
+use std::error::Error;
+use numpy::{PyArray1, IntoPyArray};
+use pyo3::prelude::*;
+use pyo3::exceptions::PyValueError;
+use pyo3::types::{PyDict, PyList, PyString};
+
+fn to_py_err(e: Box<dyn Error>) -> PyErr {
+    PyValueError::new_err(e.to_string())
+}
+
+// Names are written into the vowpal wabbit line as they are, so they cannot contain its separators,
+// with_value allows the "name:value" syntax
+fn check_name(name: String, what: &str, with_value: bool) -> PyResult<String> {
+    if name.is_empty() || name.contains(|c: char| c.is_whitespace() || c == '|' || (!with_value && c == ':')) {
+        return Err(PyValueError::new_err(format!("Invalid {}: {:?}", what, name)));
+    }
+    Ok(name)
+}
+
+// Turns one example into a vowpal wabbit line. An example is either a string in vowpal wabbit format,
+// or a dict of namespace to features, where features are a string ("a b:0.5"), a list of feature names,
+// or a dict of feature name to feature weight.
+fn example_to_line(example: &Bound<'_, PyAny>, label: Option<f32>) -> PyResult<String> {
+    if let Ok(line) = example.cast::<PyString>() {
+        if label.is_some() {
+            return Err(PyValueError::new_err("Labels can only be given for dict examples, string examples have their own labels"));
+        }
+        // Only the first line would be used, so an example can end with a newline but not contain one
+        let line = line.to_str()?;
+        if line.strip_suffix('\n').unwrap_or(line).contains(|c| c == '\n' || c == '\r') {
+            return Err(PyValueError::new_err(format!("String examples have to be a single line: {:?}", line)));
+        }
+        return Ok(line.to_string());
+    }
+    let namespaces = match example.cast::<PyDict>() {
+        Ok(namespaces) => namespaces,
+        Err(_) => return Err(PyValueError::new_err("Example has to be either a str or a dict of namespace to features")),
+    };
+    let mut line = match label {
+        Some(label) => format!("{} ", label),
+        None => String::new(),
+    };
+    for (namespace, features) in namespaces.iter() {
+        line.push('|');
+        let namespace = check_name(namespace.extract::<String>()?, "namespace name", false)?;
+        line.push_str(&namespace);
+        if let Ok(features) = features.cast::<PyString>() {
+            let features = features.to_str()?;
+            if features.contains(|c| c == '|' || c == '\n' || c == '\r') {
+                return Err(PyValueError::new_err(format!("Invalid features of namespace {}: {:?}", namespace, features)));
+            }
+            line.push(' ');
+            line.push_str(features);
+        } else if let Ok(features) = features.cast::<PyList>() {
+            for feature in features.iter() {
+                line.push(' ');
+                line.push_str(&check_name(feature.extract::<String>()?, "feature", true)?);
+            }
+        } else if let Ok(features) = features.cast::<PyDict>() {
+            for (feature, weight) in features.iter() {
+                let feature = check_name(feature.extract::<String>()?, "feature name", false)?;
+                line.push_str(&format!(" {}:{}", feature, weight.extract::<f32>()?));
+            }
+        } else {
+            return Err(PyValueError::new_err("Features of a namespace have to be a str, a list of str or a dict of str to float"));
+        }
+        line.push(' ');
+    }
+    Ok(line)
+}
+
+// Model is not thread safe (it holds parsing buffers), so python objects stay on the thread that created them
+#[pyclass(unsendable, module = "fwumious")]
+struct Model {
+    model: fw::Model,
+}
+
+#[pymethods]
+impl Model {
+    // Loads the model with optimizer state, so it can keep learning
+    #[staticmethod]
+    fn load(path: &str) -> PyResult<Model> {
+        Ok(Model {model: fw::Model::load(path).map_err(to_py_err)?})
+    }
+
+    // Loads only the weights, which uses less memory, but the model can only predict
+    #[staticmethod]
+    fn load_immutable(path: &str) -> PyResult<Model> {
+        Ok(Model {model: fw::Model::load_immutable(path).map_err(to_py_err)?})
+    }
+
+    fn predict<'py>(&mut self, py: Python<'py>, examples: Vec<Bound<'py, PyAny>>) -> PyResult<Bound<'py, PyArray1<f32>>> {
+        let lines = examples.iter().map(|example| example_to_line(example, None)).collect::<PyResult<Vec<String>>>()?;
+        let lines: Vec<&str> = lines.iter().map(|line| line.as_str()).collect();
+        let predictions = self.model.predict_batch(&lines).map_err(to_py_err)?;
+        Ok(predictions.into_pyarray(py))
+    }
+
+    // Learns from examples in order, returns predictions made before each update (progressive validation)
+    #[pyo3(signature = (examples, labels=None))]
+    fn learn<'py>(&mut self, py: Python<'py>, examples: Vec<Bound<'py, PyAny>>, labels: Option<Vec<f32>>) -> PyResult<Bound<'py, PyArray1<f32>>> {
+        if let Some(labels) = &labels {
+            if labels.len() != examples.len() {
+                return Err(PyValueError::new_err(format!("Got {} examples, but {} labels", examples.len(), labels.len())));
+            }
+        }
+        let mut predictions: Vec<f32> = Vec::with_capacity(examples.len());
+        for (i, example) in examples.iter().enumerate() {
+            let line = example_to_line(example, labels.as_ref().map(|labels| labels[i]))?;
+            predictions.push(self.model.learn(&line).map_err(to_py_err)?);
+        }
+        Ok(predictions.into_pyarray(py))
+    }
+
+    fn save(&self, path: &str) -> PyResult<()> {
+        self.model.save(path).map_err(to_py_err)
+    }
+}
+
+// Runs training exactly like the fw binary with the same arguments, e.g.
+// fwumious.train(["--data", "train.vw", "--keep", "A", "--adaptive"]), and returns the trained model
+#[pyfunction]
+fn train(args: Vec<String>) -> PyResult<Model> {
+    let mut argv = vec!["fw".to_string()];
+    argv.extend(args);
+    let cl = fw::cmdline::parse_from(argv).map_err(|e| PyValueError::new_err(e.message))?;
+    if cl.is_present("daemon") {
+        return Err(PyValueError::new_err("--daemon is not supported from python, use Model.load_immutable() instead"));
+    }
+    let (mi, vw, re) = fw::train::train(&cl).map_err(to_py_err)?;
+    Ok(Model {model: fw::Model::from_regressor(mi, vw, *re)})
+}
+
+#[pymodule]
+fn fwumious(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(train, m)?)?;
+    m.add_class::<Model>()?;
+    Ok(())
+}
@@ -0,0 +1,77 @@
+import os
+import random
+
+import numpy as np
+import pytest
+
+import fwumious
+
+
+@pytest.fixture
+def data_dir(tmp_path):
+    with open(tmp_path / "vw_namespace_map.csv", "w") as f:
+        f.write("A,featureA\nB,featureB\n")
+    rnd = random.Random(1)
+    with open(tmp_path / "train.vw", "w") as f:
+        for _ in range(1000):
+            a, b = rnd.randrange(10), rnd.randrange(10)
+            label = 1 if (a + b) % 3 == 0 else -1
+            f.write(f"{label} |A a{a} |B b{b}\n")
+    return tmp_path
+
+
+def train(data_dir, *extra):
+    return fwumious.train(["--data", str(data_dir / "train.vw"), "--keep", "A", "--keep", "B",
+                           "--interactions", "AB", "--adaptive", "-l", "0.1", *extra])
+
+
+def test_train_and_predict(data_dir):
+    model = train(data_dir)
+    predictions = model.predict(["|A a0 |B b0", "|A a0 |B b1"])
+    assert isinstance(predictions, np.ndarray)
+    assert predictions.dtype == np.float32
+    assert predictions.shape == (2,)
+    assert predictions[0] > 0.5 > predictions[1]
+
+
+def test_dict_examples(data_dir):
+    model = train(data_dir)
+    text = model.predict(["|A a3 |B b3:0.5"])
+    as_str = model.predict([{"A": "a3", "B": "b3:0.5"}])
+    as_dict = model.predict([{"A": ["a3"], "B": {"b3": 0.5}}])
+    assert text[0] == as_str[0] == as_dict[0]
+
+
+def test_learn_save_load(data_dir, tmp_path):
+    model_path = str(tmp_path / "model.fw")
+    model = train(data_dir, "--save_resume", "-f", model_path)
+    before = model.predict([{"A": ["a9"], "B": ["b9"]}])[0]
+    progressive = model.learn([{"A": ["a9"], "B": ["b9"]}] * 5, labels=[1.0] * 5)
+    assert progressive[0] == before
+    assert model.predict(["|A a9 |B b9"])[0] > before
+
+    loaded = fwumious.Model.load(model_path)
+    assert loaded.predict(["|A a9 |B b9"])[0] == before
+    model.save(model_path)
+    assert fwumious.Model.load_immutable(model_path).predict(["|A a9 |B b9"])[0] > before
+
+
+def test_errors(data_dir):
+    model = train(data_dir)
+    with pytest.raises(ValueError):
+        model.predict(["|C c"])
+    with pytest.raises(ValueError):
+        model.predict([42])
+    for example in [{"A b": ["a"]}, {"A:": ["a"]}, {"A": ["a b"]}, {"A": ["a|B"]}, {"A": {"a:1": 1.0}}, {"A": "a\n|B b"}]:
+        with pytest.raises(ValueError):
+            model.predict([example])
+    for example in ["|A a\n|B b", "1 |A a\r|B b"]:
+        with pytest.raises(ValueError):
+            model.predict([example])
+    assert (model.predict(["|A a\n"]) == model.predict(["|A a"])).all()
+    with pytest.raises(ValueError):
+        model.learn(["1 |A a"], labels=[1.0])
+    with pytest.raises(ValueError):
+        fwumious.train(["--no_such_argument"])
+    with pytest.raises(ValueError):
+        fwumious.Model.load(os.path.join(str(data_dir), "missing.fw"))
@@ -14,7 +14,7 @@ use crate::consts;
 use crate::block_helpers;
 use optimizer::OptimizerTrait;
 use regressor::BlockTrait;
-use block_helpers::{Weight, WeightAndOptimizerData};
+use block_helpers::{WeightAndOptimizerData, WeightsStore};
 
 
 const FFM_STACK_BUF_LEN:usize= 32768;
@@ -23,17 +23,17 @@ const FFM_CONTRA_BUF_LEN:usize = 16384;
 
 const SQRT_OF_ONE_HALF:f32 = 0.70710678118;
  
-use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
 
 
 pub struct BlockFFM<L:OptimizerTrait> {
     pub optimizer_ffm: L,
-    pub local_data_ffm_values: Vec<f32>,
     pub ffm_k: u32,
     pub ffm_weights_len: u32, 
     pub field_embedding_len: u32,
-    pub weights: Vec<WeightAndOptimizerData<L>>,
+    pub weights: WeightsStore<WeightAndOptimizerData<L>>,
+    pub l1: f32,
+    pub l2: f32,
 }
 
 
@@ -45,7 +45,8 @@ macro_rules! specialize_1f32 {
               const $output_const:f32 = 1.0; 
               $code_block
           } else {
-              let $output_const:f32 = $input_expr; 
+              #[allow(non_snake_case)]
+              let $output_const:f32 = $input_expr;
               $code_block
           }
       };
@@ -61,12 +62,65 @@ macro_rules! specialize_k {
                 2 => {const $output_const:u32 = 2;   let mut $wsumbuf: [f32;$output_const as usize] = [0.0;$output_const as usize]; $code_block},
                 4 => {const $output_const:u32 = 4;   let mut $wsumbuf: [f32;$output_const as usize] = [0.0;$output_const as usize]; $code_block},
                 8 => {const $output_const:u32 = 8;   let mut $wsumbuf: [f32;$output_const as usize] = [0.0;$output_const as usize]; $code_block},
-                val => {let $output_const:u32 = val; let mut $wsumbuf: [f32;consts::FFM_MAX_K] = [0.0;consts::FFM_MAX_K];      $code_block},
+                val => {#[allow(non_snake_case)] let $output_const:u32 = val; let mut $wsumbuf: [f32;consts::FFM_MAX_K] = [0.0;consts::FFM_MAX_K];      $code_block},
             }
     };
 }
 
 
+impl <L:OptimizerTrait> BlockFFM<L> {
+    // Collapses the embeddings of features of one field into contra_fields (same layout as in forward()),
+    // self-interactions of the features are pre-substracted from wsumbuf
+    #[inline(always)]
+    unsafe fn prepare_contra_field(&self, features: &[feature_buffer::HashAndValueAndSeq], field_index: usize, contra_fields: &mut [f32], wsumbuf: &mut [f32]) {
+        let ffm_k = self.ffm_k as usize;
+        let field_embedding_len = self.field_embedding_len as usize;
+        let offset = field_index * field_embedding_len;
+        for z in 0..field_embedding_len {
+            *contra_fields.get_unchecked_mut(offset + z) = 0.0;
+        }
+        for feature in features {
+            let hash = feature.hash as usize;
+            for z in 0..field_embedding_len {
+                *contra_fields.get_unchecked_mut(offset + z) += self.weights.get_unchecked(hash + z).weight * feature.value;
+            }
+            let vv = SQRT_OF_ONE_HALF * feature.value;
+            for k in 0..ffm_k {
+                let ss = self.weights.get_unchecked(hash + field_index * ffm_k + k).weight * vv;
+                *wsumbuf.get_unchecked_mut(k) -= ss * ss;
+            }
+        }
+    }
+
+    // Adds interaction of fields f1 and f2 (f1 <= f2) to wsumbuf
+    #[inline(always)]
+    unsafe fn interact_contra_fields(&self, f1: usize, f2: usize, contra_fields: &[f32], wsumbuf: &mut [f32]) {
+        let ffm_k = self.ffm_k as usize;
+        let field_embedding_len = self.field_embedding_len as usize;
+        let f1_offset = f1 * field_embedding_len + f2 * ffm_k;
+        let f2_offset = f2 * field_embedding_len + f1 * ffm_k;
+        let half = if f1 == f2 {0.5} else {1.0};
+        for k in 0..ffm_k {
+            *wsumbuf.get_unchecked_mut(k) += contra_fields.get_unchecked(f1_offset + k) * contra_fields.get_unchecked(f2_offset + k) * half;
+        }
+    }
+}
+
+// Returns start and end of features in ffm_buffer for each field, ffm_buffer is ordered by field
+fn ffm_field_ranges(fb: &feature_buffer::FeatureBuffer, ffm_k: u32) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::with_capacity(fb.ffm_fields_count as usize);
+    let mut start = 0;
+    for field_index in 0..fb.ffm_fields_count {
+        let mut end = start;
+        while end < fb.ffm_buffer.len() && fb.ffm_buffer[end].contra_field_index == field_index * ffm_k {
+            end += 1;
+        }
+        ranges.push((start, end));
+        start = end;
+    }
+    ranges
+}
+
 
 impl <L:OptimizerTrait + 'static> BlockTrait for BlockFFM<L>
 
@@ -78,16 +132,21 @@ impl <L:OptimizerTrait + 'static> BlockTrait for BlockFFM<L>
     fn new_without_weights(mi: &model_instance::ModelInstance) -> Result<Box<dyn BlockTrait>, Box<dyn Error>> {
 
         let mut reg_ffm = BlockFFM::<L> {
-            weights: Vec::new(),
+            weights: WeightsStore::new(),
             ffm_weights_len: 0, 
-            local_data_ffm_values: Vec::with_capacity(1024),
             ffm_k: mi.ffm_k, 
             field_embedding_len: mi.ffm_k * mi.ffm_fields.len() as u32,
             optimizer_ffm: L::new(),
+            l1: mi.ffm_l1,
+            l2: mi.ffm_l2,
         };
 
         if mi.ffm_k > 0 {
             reg_ffm.optimizer_ffm.init(mi.ffm_learning_rate, mi.ffm_power_t, mi.ffm_init_acc_gradient);
+            if reg_ffm.optimizer_ffm.init_extra(&optimizer::OptimizerExtraParams::new(mi, mi.ffm_l1, mi.ffm_l2)) {
+                reg_ffm.l1 = 0.0;
+                reg_ffm.l2 = 0.0;
+            }
             // At the end we add "spillover buffer", so we can do modulo only on the base address and add offset
             reg_ffm.ffm_weights_len = (1 << mi.ffm_bit_precision) + (mi.ffm_fields.len() as u32 * reg_ffm.ffm_k);
         }
@@ -103,12 +162,13 @@ impl <L:OptimizerTrait + 'static> BlockTrait for BlockFFM<L>
 
     fn new_forward_only_without_weights(&self) -> Result<Box<dyn BlockTrait>, Box<dyn Error>> {
         let forwards_only = BlockFFM::<optimizer::OptimizerSGD> {
-            weights: Vec::new(),
+            weights: WeightsStore::new(),
             ffm_weights_len: self.ffm_weights_len, 
-            local_data_ffm_values: Vec::new(),
             ffm_k: self.ffm_k, 
             field_embedding_len: self.field_embedding_len,
             optimizer_ffm: optimizer::OptimizerSGD::new(),
+            l1: 0.0,
+            l2: 0.0,
         };
         
         Ok(Box::new(forwards_only))
@@ -117,7 +177,7 @@ impl <L:OptimizerTrait + 'static> BlockTrait for BlockFFM<L>
 
 
     fn allocate_and_init_weights(&mut self, mi: &model_instance::ModelInstance) {
-        self.weights =vec![WeightAndOptimizerData::<L>{weight:0.0, optimizer_data: self.optimizer_ffm.initial_data()}; self.ffm_weights_len as usize];
+        self.weights =vec![WeightAndOptimizerData::<L>{weight:0.0, optimizer_data: self.optimizer_ffm.initial_data()}; self.ffm_weights_len as usize].into();
         if mi.ffm_k > 0 {       
             if mi.ffm_init_width == 0.0 {
                 // Initialization that has showed to work ok for us, like in ffm.pdf, but centered around zero and further divided by 50
@@ -163,10 +223,9 @@ impl <L:OptimizerTrait + 'static> BlockTrait for BlockFFM<L>
                     let mut local_data_ffm_values = $local_data_ffm_values;
                      //   let mut local_data_ffm_values = &mut $local_data_ffm_values;
                             
-                    let ffm_weights = &mut self.weights;
+                    let ffm_weights = &mut self.weights[..];
                     let fc = (fb.ffm_fields_count  * self.ffm_k) as usize;
                     let mut contra_fields: [f32; FFM_CONTRA_BUF_LEN] = MaybeUninit::uninit().assume_init();
-                    let field_embedding_len = self.field_embedding_len;
                     specialize_k!(self.ffm_k, FFMK, wsumbuf, {
                         /* first prepare two things:
                         - transposed contra vectors in contra_fields - 
@@ -179,12 +238,11 @@ impl <L:OptimizerTrait + 'static> BlockTrait for BlockFFM<L>
                         let mut ffm_buffer_index = 0;
                         for field_index in 0..fb.ffm_fields_count {
                             let field_index_ffmk = field_index * FFMK;
-                            let offset = (field_index_ffmk * fb.ffm_fields_count) as usize;
                             // first we handle fields with no features
                             if ffm_buffer_index >= fb.ffm_buffer.len() ||
                                 fb.ffm_buffer.get_unchecked(ffm_buffer_index).contra_field_index > field_index_ffmk {
                                 let mut zfc:usize = field_index_ffmk as usize;
-                                for z in 0..fb.ffm_fields_count {
+                                for _z in 0..fb.ffm_fields_count {
                                     for k in 0..FFMK as usize{
                                         *contra_fields.get_unchecked_mut(zfc + k) = 0.0;
                                     }
@@ -194,14 +252,16 @@ impl <L:OptimizerTrait + 'static> BlockTrait for BlockFFM<L>
                             } 
                             let mut feature_num = 0;
                             while ffm_buffer_index < fb.ffm_buffer.len() && fb.ffm_buffer.get_unchecked(ffm_buffer_index).contra_field_index == field_index_ffmk {
-                                _mm_prefetch(mem::transmute::<&f32, &i8>(&ffm_weights.get_unchecked(fb.ffm_buffer.get_unchecked(ffm_buffer_index+1).hash as usize).weight), _MM_HINT_T0);
+                                if ffm_buffer_index + 1 < fb.ffm_buffer.len() {
+                                    _mm_prefetch(mem::transmute::<&f32, &i8>(&ffm_weights.get_unchecked(fb.ffm_buffer.get_unchecked(ffm_buffer_index+1).hash as usize).weight), _MM_HINT_T0);
+                                }
                                 let left_hash = fb.ffm_buffer.get_unchecked(ffm_buffer_index);
                                 let mut addr = left_hash.hash as usize;
                                 let mut zfc:usize = field_index_ffmk as usize;
                                 
                                 specialize_1f32!(left_hash.value, LEFT_HASH_VALUE, {
                                     if feature_num == 0 {
-                                        for z in 0..fb.ffm_fields_count {
+                                        for _z in 0..fb.ffm_fields_count {
                                             _mm_prefetch(mem::transmute::<&f32, &i8>(&ffm_weights.get_unchecked(addr + FFMK as usize).weight), _MM_HINT_T0);
                                             for k in 0..FFMK as usize{
                                                 *contra_fields.get_unchecked_mut(zfc + k) = ffm_weights.get_unchecked(addr + k).weight * LEFT_HASH_VALUE;
@@ -210,7 +270,7 @@ impl <L:OptimizerTrait + 'static> BlockTrait for BlockFFM<L>
                                             addr += FFMK as usize
                                         }
                                     } else {
-                                        for z in 0..fb.ffm_fields_count {
+                                        for _z in 0..fb.ffm_fields_count {
                                             _mm_prefetch(mem::transmute::<&f32, &i8>(&ffm_weights.get_unchecked(addr + FFMK as usize).weight), _MM_HINT_T0);
                                             for k in 0..FFMK as usize{
                                                 *contra_fields.get_unchecked_mut(zfc + k) += ffm_weights.get_unchecked(addr + k).weight * LEFT_HASH_VALUE;
@@ -226,8 +286,8 @@ impl <L:OptimizerTrait + 'static> BlockTrait for BlockFFM<L>
                         }
                         
                         let mut ffm_values_offset = 0;
-                        for (i, left_hash) in fb.ffm_buffer.iter().enumerate() {
-                            let mut contra_offset = (left_hash.contra_field_index * fb.ffm_fields_count) as usize;
+                        for left_hash in &fb.ffm_buffer {
+                            let contra_offset = (left_hash.contra_field_index * fb.ffm_fields_count) as usize;
                             let mut vv = 0;
                             let left_hash_value = left_hash.value;
                             let left_hash_contra_field_index = left_hash.contra_field_index;
@@ -235,7 +295,7 @@ impl <L:OptimizerTrait + 'static> BlockTrait for BlockFFM<L>
                             //let LEFT_HASH_VALUE = left_hash_value;
                             specialize_1f32!(left_hash_value, LEFT_HASH_VALUE, {
 
-                              for z in 0..fb.ffm_fields_count as usize {
+                              for _z in 0..fb.ffm_fields_count as usize {
                                   if vv == left_hash_contra_field_index as usize {
                                       for k in 0..FFMK as usize {
                                           let ffm_weight = ffm_weights.get_unchecked(left_hash_hash + vv + k).weight;
@@ -271,35 +331,50 @@ impl <L:OptimizerTrait + 'static> BlockTrait for BlockFFM<L>
                     
                     if update {
                         let mut local_index: usize = 0;
-                        for left_hash in &fb.ffm_buffer {
-                            let mut feature_index = left_hash.hash as usize;
-                            for j in 0..fc as usize {
-                                let feature_value = *local_data_ffm_values.get_unchecked(local_index);
-                                let gradient = general_gradient * feature_value;
-                                let update = self.optimizer_ffm.calculate_update(gradient, &mut ffm_weights.get_unchecked_mut(feature_index).optimizer_data);
-                                ffm_weights.get_unchecked_mut(feature_index).weight += update;
-                                local_index += 1;
-                                feature_index += 1;
+                        if self.l1 == 0.0 && self.l2 == 0.0 {
+                            for left_hash in &fb.ffm_buffer {
+                                let mut feature_index = left_hash.hash as usize;
+                                for _ in 0..fc as usize {
+                                    let feature_value = *local_data_ffm_values.get_unchecked(local_index);
+                                    let gradient = general_gradient * feature_value;
+                                    let update = self.optimizer_ffm.calculate_update(gradient, &mut ffm_weights.get_unchecked_mut(feature_index).optimizer_data);
+                                    ffm_weights.get_unchecked_mut(feature_index).weight += update;
+                                    local_index += 1;
+                                    feature_index += 1;
+                                }
+                            }
+                        } else {
+                            for left_hash in &fb.ffm_buffer {
+                                let mut feature_index = left_hash.hash as usize;
+                                for _ in 0..fc as usize {
+                                    let feature_value = *local_data_ffm_values.get_unchecked(local_index);
+                                    let weight = ffm_weights.get_unchecked(feature_index).weight;
+                                    let gradient = block_helpers::regularized_gradient(general_gradient * feature_value, weight, self.l1, self.l2);
+                                    if gradient != 0.0 {
+                                        let update = self.optimizer_ffm.calculate_update(gradient, &mut ffm_weights.get_unchecked_mut(feature_index).optimizer_data);
+                                        ffm_weights.get_unchecked_mut(feature_index).weight = block_helpers::truncated_weight(weight, weight + update, self.l1);
+                                    }
+                                    local_index += 1;
+                                    feature_index += 1;
+                                }
                             }
                         }
                     }
                     // The only exit point
                     return (prediction_probability, general_gradient)
                 }
-            }; // End of macro
+            } // End of macro
             
 
             if local_data_ffm_len < FFM_STACK_BUF_LEN {
                 // Fast-path - using on-stack data structures
-                let mut local_data_ffm_values: [f32; FFM_STACK_BUF_LEN as usize] = MaybeUninit::uninit().assume_init();//[0.0; FFM_STACK_BUF_LEN as usize];
+                let local_data_ffm_values: [f32; FFM_STACK_BUF_LEN as usize] = MaybeUninit::uninit().assume_init();//[0.0; FFM_STACK_BUF_LEN as usize];
                 core_macro!(local_data_ffm_values);
 
             } else {
                 // Slow-path - using heap data structures
-                if local_data_ffm_len > self.local_data_ffm_values.len() {
-                    self.local_data_ffm_values.reserve(local_data_ffm_len - self.local_data_ffm_values.len() + 1024);
-                }
-                let mut local_data_ffm_values = &mut self.local_data_ffm_values;
+                // Allocated per call and not kept in the block, so that concurrent (Hogwild) learning is safe
+                let local_data_ffm_values: Vec<f32> = vec![0.0; local_data_ffm_len];
             
                 core_macro!(local_data_ffm_values);
             }             
@@ -311,7 +386,9 @@ impl <L:OptimizerTrait + 'static> BlockTrait for BlockFFM<L>
         unsafe {
             let ffm_weights = &self.weights;
             if true {
-                _mm_prefetch(mem::transmute::<&f32, &i8>(&ffm_weights.get_unchecked(fb.ffm_buffer.get_unchecked(0).hash as usize).weight), _MM_HINT_T0);
+                if fb.ffm_buffer.len() > 0 {
+                    _mm_prefetch(mem::transmute::<&f32, &i8>(&ffm_weights.get_unchecked(fb.ffm_buffer.get_unchecked(0).hash as usize).weight), _MM_HINT_T0);
+                }
                 let field_embedding_len = self.field_embedding_len as usize;
                 let mut contra_fields: [f32; FFM_STACK_BUF_LEN] = MaybeUninit::uninit().assume_init();
 
@@ -339,7 +416,11 @@ impl <L:OptimizerTrait + 'static> BlockTrait for BlockFFM<L>
                         } 
                         let mut feature_num = 0;
                         while ffm_buffer_index < fb.ffm_buffer.len() && fb.ffm_buffer.get_unchecked(ffm_buffer_index).contra_field_index == field_index_ffmk {
-                            _mm_prefetch(mem::transmute::<&f32, &i8>(&ffm_weights.get_unchecked(fb.ffm_buffer.get_unchecked(ffm_buffer_index+1).hash as usize).weight), _MM_HINT_T0);
+                            // Reading past the end of ffm_buffer is undefined behaviour, which the optimizer used to drop
+                            // the zeroing of empty trailing fields, so predictions picked up values of the previous example
+                            if ffm_buffer_index + 1 < fb.ffm_buffer.len() {
+                                _mm_prefetch(mem::transmute::<&f32, &i8>(&ffm_weights.get_unchecked(fb.ffm_buffer.get_unchecked(ffm_buffer_index+1).hash as usize).weight), _MM_HINT_T0);
+                            }
                             let left_hash = fb.ffm_buffer.get_unchecked(ffm_buffer_index);
                             let left_hash_hash = left_hash.hash as usize;
                             let left_hash_value = left_hash.value;
@@ -376,7 +457,7 @@ impl <L:OptimizerTrait + 'static> BlockTrait for BlockFFM<L>
                             *wsumbuf.get_unchecked_mut(k) += v * v * 0.5;
                         }
 
-                        for f2 in f1+1..fb.ffm_fields_count as usize {
+                        for _f2 in f1+1..fb.ffm_fields_count as usize {
                             f2_offset_ffmk += field_embedding_len as usize;
                             f1_offset_ffmk += FFMK as usize;
                             //assert_eq!(f1_offset_ffmk, f1 * field_embedding_len + f2 * FFMK as usize);
@@ -424,11 +505,143 @@ impl <L:OptimizerTrait + 'static> BlockTrait for BlockFFM<L>
         prediction_probability         
                  
     }
+
+    fn prepare_context(&self, further_blocks: &[Box<dyn BlockTrait>], fb: &feature_buffer::FeatureBuffer, context: &mut regressor::ContextCache) {
+        // Collapsed embeddings of context fields and interactions between them are the same for all the examples
+        let ffm_fields_count = fb.ffm_fields_count as usize;
+        let field_ranges = ffm_field_ranges(fb, self.ffm_k);
+        context.ffm_contra_fields = vec![0.0; ffm_fields_count * self.field_embedding_len as usize];
+        let mut wsumbuf: Vec<f32> = vec![0.0; self.ffm_k as usize];
+        unsafe {
+            for f1 in 0..ffm_fields_count {
+                if context.ffm_context_fields[f1] {
+                    let (start, end) = field_ranges[f1];
+                    self.prepare_contra_field(&fb.ffm_buffer[start..end], f1, &mut context.ffm_contra_fields, &mut wsumbuf);
+                }
+            }
+            for f1 in 0..ffm_fields_count {
+                for f2 in f1..ffm_fields_count {
+                    if context.ffm_context_fields[f1] && context.ffm_context_fields[f2] {
+                        self.interact_contra_fields(f1, f2, &context.ffm_contra_fields, &mut wsumbuf);
+                    }
+                }
+            }
+        }
+        context.ffm_wsum = wsumbuf.iter().sum::<f32>();
+        let (next_regressor, further_blocks) = further_blocks.split_at(1);
+        next_regressor[0].prepare_context(further_blocks, fb, context);
+    }
+
+    fn forward_with_context(&self, further_blocks: &[Box<dyn BlockTrait>], wsum_input: f32, fb: &feature_buffer::FeatureBuffer, context: &mut regressor::ContextCache) -> f32 {
+        // Only fields that are not from the context get collapsed, contra fields of the context stay in place
+        let ffm_fields_count = fb.ffm_fields_count as usize;
+        let ffm_k = self.ffm_k as usize;
+        let mut wsumbuf: [f32; consts::FFM_MAX_K] = [0.0; consts::FFM_MAX_K];
+        let wsumbuf = &mut wsumbuf[0..ffm_k];
+        unsafe {
+            let mut ffm_buffer_index = 0;
+            for f1 in 0..ffm_fields_count {
+                if context.ffm_context_fields[f1] {
+                    continue;
+                }
+                let start = ffm_buffer_index;
+                while ffm_buffer_index < fb.ffm_buffer.len() && fb.ffm_buffer.get_unchecked(ffm_buffer_index).contra_field_index == (f1 * ffm_k) as u32 {
+                    ffm_buffer_index += 1;
+                }
+                self.prepare_contra_field(&fb.ffm_buffer[start..ffm_buffer_index], f1, &mut context.ffm_contra_fields, wsumbuf);
+            }
+            for f1 in 0..ffm_fields_count {
+                for f2 in f1..ffm_fields_count {
+                    if !context.ffm_context_fields[f1] || !context.ffm_context_fields[f2] {
+                        self.interact_contra_fields(f1, f2, &context.ffm_contra_fields, wsumbuf);
+                    }
+                }
+            }
+        }
+        let wsum = context.ffm_wsum + wsumbuf.iter().sum::<f32>();
+        let (next_regressor, further_blocks) = further_blocks.split_at(1);
+        next_regressor[0].forward_with_context(further_blocks, wsum + wsum_input, fb, context)
+    }
+
+    fn forward_batch(&self, further_blocks: &[Box<dyn BlockTrait>], wsums: &mut [f32], fbs: &[feature_buffer::FeatureBuffer], predictions: &mut [f32]) {
+        /* Candidates scored for the same context usually have identical features in most of the fields.
+           Collapsed embeddings of such shared fields and interactions between them are computed only once
+           for the whole batch, so per example we only do the work for the fields that differ.
+        */
+        let ffm_fields_count = fbs[0].ffm_fields_count as usize;
+        let ffm_k = self.ffm_k as usize;
+        let field_ranges: Vec<Vec<(usize, usize)>> = fbs.iter().map(|fb| ffm_field_ranges(fb, self.ffm_k)).collect();
+        let shared: Vec<bool> = (0..ffm_fields_count).map(|field_index| {
+            let (start, end) = field_ranges[0][field_index];
+            let first = &fbs[0].ffm_buffer[start..end];
+            fbs.iter().zip(field_ranges.iter()).all(|(fb, ranges)| {
+                let (start, end) = ranges[field_index];
+                &fb.ffm_buffer[start..end] == first
+            })
+        }).collect();
+
+        let mut contra_fields: Vec<f32> = vec![0.0; ffm_fields_count * self.field_embedding_len as usize];
+        let mut shared_wsumbuf: Vec<f32> = vec![0.0; ffm_k];
+        let mut wsumbuf: Vec<f32> = vec![0.0; ffm_k];
+        unsafe {
+            for f1 in 0..ffm_fields_count {
+                if shared[f1] {
+                    let (start, end) = field_ranges[0][f1];
+                    self.prepare_contra_field(&fbs[0].ffm_buffer[start..end], f1, &mut contra_fields, &mut shared_wsumbuf);
+                }
+            }
+            for f1 in 0..ffm_fields_count {
+                for f2 in f1..ffm_fields_count {
+                    if shared[f1] && shared[f2] {
+                        self.interact_contra_fields(f1, f2, &contra_fields, &mut shared_wsumbuf);
+                    }
+                }
+            }
+
+            for (i, fb) in fbs.iter().enumerate() {
+                // Contra fields of shared fields stay in place, the rest get overwritten for each example
+                wsumbuf.copy_from_slice(&shared_wsumbuf);
+                for f1 in 0..ffm_fields_count {
+                    if !shared[f1] {
+                        let (start, end) = field_ranges[i][f1];
+                        self.prepare_contra_field(&fb.ffm_buffer[start..end], f1, &mut contra_fields, &mut wsumbuf);
+                    }
+                }
+                for f1 in 0..ffm_fields_count {
+                    for f2 in f1..ffm_fields_count {
+                        if !shared[f1] || !shared[f2] {
+                            self.interact_contra_fields(f1, f2, &contra_fields, &mut wsumbuf);
+                        }
+                    }
+                }
+                wsums[i] += wsumbuf.iter().sum::<f32>();
+            }
+        }
+        let (next_regressor, further_blocks) = further_blocks.split_at(1);
+        next_regressor[0].forward_batch(further_blocks, wsums, fbs, predictions);
+    }
     
     fn get_serialized_len(&self) -> usize {
         return self.ffm_weights_len as usize;
     }
 
+    fn get_serialized_bytes(&self) -> usize {
+        self.ffm_weights_len as usize * mem::size_of::<WeightAndOptimizerData<L>>()
+    }
+
+    fn get_block_type(&self) -> u32 {
+        regressor::BLOCK_TYPE_FFM
+    }
+
+    fn get_optimizer_tag(&self) -> u32 {
+        L::get_tag()
+    }
+
+    fn map_weights(&mut self, file: &Arc<block_helpers::MappedFile>, offset: usize) -> Result<(), Box<dyn Error>> {
+        self.weights = WeightsStore::mapped(file, offset, self.ffm_weights_len as usize)?;
+        Ok(())
+    }
+
     fn read_weights_from_buf(&mut self, input_bufreader: &mut dyn io::Read) -> Result<(), Box<dyn Error>> {
         block_helpers::read_weights_from_buf(&mut self.weights, input_bufreader)
     }
@@ -438,12 +651,12 @@ impl <L:OptimizerTrait + 'static> BlockTrait for BlockFFM<L>
     }
 
     fn read_weights_from_buf_into_forward_only(&self, input_bufreader: &mut dyn io::Read, forward: &mut Box<dyn BlockTrait>) -> Result<(), Box<dyn Error>> {
-        let mut forward = forward.as_any().downcast_mut::<BlockFFM<optimizer::OptimizerSGD>>().unwrap();
+        let forward = forward.as_any().downcast_mut::<BlockFFM<optimizer::OptimizerSGD>>().unwrap();
         block_helpers::read_weights_only_from_buf2::<L>(self.ffm_weights_len as usize, &mut forward.weights, input_bufreader)
     }
 
     /// Sets internal state of weights based on some completely object-dependent parameters
-    fn testing_set_weights(&mut self, aa: i32, bb: i32, index: usize, w: &[f32]) -> Result<(), Box<dyn Error>> {
+    fn testing_set_weights(&mut self, _aa: i32, _bb: i32, index: usize, w: &[f32]) -> Result<(), Box<dyn Error>> {
         self.weights[index].weight = w[0];
         self.weights[index].optimizer_data = self.optimizer_ffm.initial_data();
         Ok(())
@@ -453,13 +666,13 @@ impl <L:OptimizerTrait + 'static> BlockTrait for BlockFFM<L>
 
 
 
+#[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
     use crate::block_loss_functions::BlockSigmoid;
     use crate::feature_buffer;
     use crate::feature_buffer::HashAndValueAndSeq;
-    use crate::vwmap;
     use block_helpers::{slearn, spredict};
 
     use crate::assert_epsilon;
@@ -477,7 +690,7 @@ mod tests {
     }
 
     fn ffm_init<T:OptimizerTrait + 'static>(block_ffm: &mut Box<dyn BlockTrait>) -> () {
-        let mut block_ffm = block_ffm.as_any().downcast_mut::<BlockFFM<T>>().unwrap();
+        let block_ffm = block_ffm.as_any().downcast_mut::<BlockFFM<T>>().unwrap();
         
         for i in 0..block_ffm.weights.len() {
             block_ffm.weights[i].weight = 1.0;
@@ -539,6 +752,31 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_ffm_l2() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();        
+        mi.ffm_learning_rate = 0.1;
+        mi.ffm_power_t = 0.0;
+        mi.ffm_k = 1;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_fields = vec![vec![], vec![]]; // This isn't really used
+        mi.ffm_l2 = 0.5;
+        let mut lossf = BlockSigmoid::new_without_weights(&mi).unwrap();
+
+        let mut re = BlockFFM::<optimizer::OptimizerSGD>::new_without_weights(&mi).unwrap();
+        re.allocate_and_init_weights(&mi);
+
+        ffm_init::<optimizer::OptimizerSGD>(&mut re);
+        let fb = ffm_vec(vec![
+                                  HashAndValueAndSeq{hash:1, value: 1.0, contra_field_index: 0},
+                                  HashAndValueAndSeq{hash:100, value: 1.0, contra_field_index: mi.ffm_k}
+                                  ], 2);
+        assert_eq!(slearn(&mut re, &mut lossf, &fb, true), 0.7310586);
+        // regularization adds to the pull towards zero: 1.0 + 0.1 * (-0.7310586 - 0.5 * 1.0) = 0.87689414
+        assert_eq!(spredict(&mut re, &mut lossf, &fb, true), 0.6832922);
+    }
+
+
     #[test]
     fn test_ffm_k4() {
         let mut mi = model_instance::ModelInstance::new_empty().unwrap();        
@@ -594,11 +832,6 @@ mod tests {
 
     #[test]
     fn test_ffm_multivalue() {
-        let vw_map_string = r#"
-A,featureA
-B,featureB
-"#;
-        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
         let mut mi = model_instance::ModelInstance::new_empty().unwrap();
         mi.learning_rate = 0.1;
         mi.power_t = 0.0;
@@ -613,7 +846,6 @@ B,featureB
 
         let mut re = BlockFFM::<optimizer::OptimizerAdagradLUT>::new_without_weights(&mi).unwrap();
         re.allocate_and_init_weights(&mi);
-        let mut p: f32;
 
         ffm_init::<optimizer::OptimizerAdagradLUT>(&mut re);
         let fbuf = &ffm_vec(vec![
@@ -631,11 +863,6 @@ B,featureB
 
     #[test]
     fn test_ffm_multivalue_k4_nonzero_powert() {
-        let vw_map_string = r#"
-A,featureA
-B,featureB
-"#;
-        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
         let mut mi = model_instance::ModelInstance::new_empty().unwrap();
         mi.ffm_k = 4;
         mi.ffm_bit_precision = 18;
@@ -2,19 +2,18 @@ use std::error::Error;
 use crate::optimizer::OptimizerTrait;
 use std::io;
 use std::slice;
-use std::mem::{self, MaybeUninit};
+use std::mem;
 use std::cmp::min;
-use std::ops::Deref;
 use crate::optimizer::OptimizerSGD;
-use std::marker::PhantomData;
+#[cfg(test)]
 use crate::feature_buffer;
+#[cfg(test)]
 use crate::regressor::BlockTrait;
-
-#[derive(Clone, Debug)]
-#[repr(C)]
-pub struct Weight {
-    pub weight: f32, 
-}
+use std::fs::File;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::sync::Arc;
 
 #[derive(Clone, Debug)]
 #[repr(C)]
@@ -23,6 +22,100 @@ pub struct WeightAndOptimizerData<L:OptimizerTrait> {
     pub optimizer_data: L::PerWeightStore,
 }
 
+// A file memory mapped read-only, unmapped when the last user is dropped
+pub struct MappedFile {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// The mapping is never written to
+unsafe impl Send for MappedFile {}
+unsafe impl Sync for MappedFile {}
+
+impl MappedFile {
+    pub fn new(file: &File) -> Result<MappedFile, Box<dyn Error>> {
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            return Err("Cannot memory map an empty file")?;
+        }
+        let ptr = unsafe { libc::mmap(ptr::null_mut(), len, libc::PROT_READ, libc::MAP_SHARED, file.as_raw_fd(), 0) };
+        if ptr == libc::MAP_FAILED {
+            return Err(format!("Cannot memory map file: {}", io::Error::last_os_error()))?;
+        }
+        Ok(MappedFile {ptr: ptr, len: len})
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr, self.len); }
+    }
+}
+
+// Weights of a block, either allocated or read-only in a memory mapped serving model
+pub struct WeightsStore<T> {
+    ptr: *mut T,
+    len: usize,
+    // Allocated weights, ptr points into them
+    #[allow(dead_code)]
+    owned: Vec<T>,
+    mapped: Option<Arc<MappedFile>>,
+}
+
+impl<T> WeightsStore<T> {
+    pub fn new() -> WeightsStore<T> {
+        WeightsStore::from(Vec::new())
+    }
+
+    // Only weights without optimizer data have the layout of f32 serving models
+    pub fn mapped(file: &Arc<MappedFile>, offset: usize, len: usize) -> Result<WeightsStore<T>, Box<dyn Error>> {
+        if mem::size_of::<T>() != mem::size_of::<f32>() {
+            return Err("Only weights without optimizer data can be memory mapped")?;
+        }
+        if offset % mem::align_of::<T>() != 0 || offset + len * mem::size_of::<T>() > file.len {
+            return Err(format!("Weights at offset {} can't be memory mapped", offset))?;
+        }
+        Ok(WeightsStore {
+            ptr: unsafe { (file.ptr as *mut u8).add(offset) as *mut T },
+            len: len,
+            owned: Vec::new(),
+            mapped: Some(file.clone()),
+        })
+    }
+
+    #[cfg(test)]
+    pub fn is_mapped(&self) -> bool {
+        self.mapped.is_some()
+    }
+}
+
+impl<T> From<Vec<T>> for WeightsStore<T> {
+    fn from(mut weights: Vec<T>) -> WeightsStore<T> {
+        // Moving the vector doesn't move its buffer
+        WeightsStore {ptr: weights.as_mut_ptr(), len: weights.len(), owned: weights, mapped: None}
+    }
+}
+
+impl<T> Deref for WeightsStore<T> {
+    type Target = [T];
+    fn deref(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl<T> DerefMut for WeightsStore<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        if self.mapped.is_some() {
+            panic!("Memory mapped weights are read-only");
+        }
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
 #[macro_export]
 macro_rules! assert_epsilon {
     ($x:expr, $y:expr) => {
@@ -34,7 +127,7 @@ macro_rules! assert_epsilon {
 
 
 // It's OK! I am a limo driver!
-pub fn read_weights_from_buf<L:OptimizerTrait>(weights: &mut Vec<WeightAndOptimizerData<L>>, input_bufreader: &mut dyn io::Read) -> Result<(), Box<dyn Error>> {
+pub fn read_weights_from_buf<L:OptimizerTrait>(weights: &mut [WeightAndOptimizerData<L>], input_bufreader: &mut dyn io::Read) -> Result<(), Box<dyn Error>> {
     if weights.len() == 0 {
         return Err(format!("Loading weights to unallocated weighs buffer"))?;
     }
@@ -47,7 +140,7 @@ pub fn read_weights_from_buf<L:OptimizerTrait>(weights: &mut Vec<WeightAndOptimi
 }
 
 
-pub fn write_weights_to_buf<L:OptimizerTrait>(weights: &Vec<WeightAndOptimizerData<L>>, output_bufwriter: &mut dyn io::Write) -> Result<(), Box<dyn Error>> {
+pub fn write_weights_to_buf<L:OptimizerTrait>(weights: &[WeightAndOptimizerData<L>], output_bufwriter: &mut dyn io::Write) -> Result<(), Box<dyn Error>> {
     if weights.len() == 0 {
         return Err(format!("Writing weights of unallocated weights buffer"))?;
     }
@@ -60,7 +153,7 @@ pub fn write_weights_to_buf<L:OptimizerTrait>(weights: &Vec<WeightAndOptimizerDa
 }
 
 
-pub fn read_weights_only_from_buf2<L:OptimizerTrait>(weights_len: usize, out_weights: &mut Vec<WeightAndOptimizerData<OptimizerSGD>>, input_bufreader: &mut dyn io::Read) -> Result<(), Box<dyn Error>> {
+pub fn read_weights_only_from_buf2<L:OptimizerTrait>(weights_len: usize, out_weights: &mut [WeightAndOptimizerData<OptimizerSGD>], input_bufreader: &mut dyn io::Read) -> Result<(), Box<dyn Error>> {
     const BUF_LEN:usize = 1024 * 1024;
     let mut in_weights: Vec<WeightAndOptimizerData::<L>> = Vec::with_capacity(BUF_LEN as usize);
     let mut remaining_weights = weights_len;
@@ -88,7 +181,297 @@ pub fn read_weights_only_from_buf2<L:OptimizerTrait>(weights_len: usize, out_wei
 }
 
 
+// CRC-32 (IEEE) table, for checksums of blocks of weights in regressor files
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xEDB88320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+}
+
+pub fn crc32_update(crc: u32, buf: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for b in buf {
+        crc = CRC32_TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+// Counts the bytes written through it and computes their checksum
+pub struct ChecksumWriter<'a> {
+    inner: &'a mut dyn io::Write,
+    pub crc: u32,
+    pub len: u64,
+}
+
+impl<'a> ChecksumWriter<'a> {
+    pub fn new(inner: &'a mut dyn io::Write) -> ChecksumWriter<'a> {
+        ChecksumWriter {inner: inner, crc: 0, len: 0}
+    }
+}
+
+impl<'a> io::Write for ChecksumWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.crc = crc32_update(self.crc, &buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Counts the bytes read through it and computes their checksum
+pub struct ChecksumReader<'a> {
+    inner: &'a mut dyn io::Read,
+    pub crc: u32,
+    pub len: u64,
+}
+
+impl<'a> ChecksumReader<'a> {
+    pub fn new(inner: &'a mut dyn io::Read) -> ChecksumReader<'a> {
+        ChecksumReader {inner: inner, crc: 0, len: 0}
+    }
+}
+
+impl<'a> io::Read for ChecksumReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.crc = crc32_update(self.crc, &buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+}
+
+
+// Half precision floats of serving models, rounded to nearest even
+pub fn f32_to_f16(value: f32) -> u16 {
+    let x = value.to_bits();
+    let sign = ((x >> 16) & 0x8000) as u16;
+    let exponent = ((x >> 23) & 0xff) as i32;
+    let mantissa = x & 0x7fffff;
+    if exponent == 0xff { // infinity and NaN
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+    let e = exponent - 127 + 15;
+    if e >= 0x1f {
+        return sign | 0x7c00;
+    }
+    if e <= 0 { // subnormal, the leading 1 of the mantissa becomes explicit
+        if e < -10 {
+            return sign;
+        }
+        let m = mantissa | 0x800000;
+        let shift = (14 - e) as u32;
+        let rounded = (m + (1 << (shift - 1)) - 1 + ((m >> shift) & 1)) >> shift;
+        return sign | rounded as u16;
+    }
+    // Rounding can carry into the exponent, up to infinity
+    let rounded = (mantissa + 0xfff + ((mantissa >> 13) & 1)) >> 13;
+    let bits = ((e as u32) << 10) + rounded;
+    sign | min(bits, 0x7c00) as u16
+}
+
+pub fn f16_to_f32(value: u16) -> f32 {
+    let sign = ((value & 0x8000) as u32) << 16;
+    let exponent = ((value >> 10) & 0x1f) as u32;
+    let mantissa = (value & 0x3ff) as u32;
+    if exponent == 0 {
+        let v = mantissa as f32 / (1 << 24) as f32;
+        return if sign != 0 { -v } else { v };
+    }
+    if exponent == 0x1f {
+        return f32::from_bits(sign | 0x7f800000 | (mantissa << 13));
+    }
+    f32::from_bits(sign | ((exponent + 127 - 15) << 23) | (mantissa << 13))
+}
+
+// Encodings of weights-only blocks in serving models
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WeightsEncoding {
+    F16,
+    Int8(f32), // weight is the i8 times the scale
+}
+
+// Takes weights as f32 bytes, the way blocks write them, and writes them encoded. An f32 can be split between two writes.
+pub struct WeightsEncoder<'a> {
+    inner: &'a mut dyn io::Write,
+    encoding: WeightsEncoding,
+    pending: Vec<u8>,
+    out: Vec<u8>,
+}
+
+impl<'a> WeightsEncoder<'a> {
+    pub fn new(inner: &'a mut dyn io::Write, encoding: WeightsEncoding) -> WeightsEncoder<'a> {
+        WeightsEncoder {inner: inner, encoding: encoding, pending: Vec::with_capacity(4), out: Vec::new()}
+    }
+
+    fn encode(&mut self, bytes: &[u8]) {
+        let weight = f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        match self.encoding {
+            WeightsEncoding::F16 => self.out.extend_from_slice(&f32_to_f16(weight).to_le_bytes()),
+            WeightsEncoding::Int8(scale) => {
+                let q = if scale > 0.0 { (weight / scale).round().max(-127.0).min(127.0) as i8 } else { 0 };
+                self.out.push(q as u8);
+            },
+        }
+    }
+}
+
+impl<'a> io::Write for WeightsEncoder<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rest = buf;
+        if self.pending.len() > 0 {
+            let n = min(4 - self.pending.len(), rest.len());
+            self.pending.extend_from_slice(&rest[..n]);
+            rest = &rest[n..];
+            if self.pending.len() < 4 {
+                return Ok(buf.len());
+            }
+            let pending = mem::take(&mut self.pending);
+            self.encode(&pending);
+        }
+        for chunk in rest.chunks(1024 * 1024) {
+            let whole = chunk.len() / 4 * 4;
+            for bytes in chunk[..whole].chunks_exact(4) {
+                self.encode(bytes);
+            }
+            self.pending.extend_from_slice(&chunk[whole..]);
+            self.inner.write_all(&self.out)?;
+            self.out.truncate(0);
+        }
+        if self.out.len() > 0 {
+            self.inner.write_all(&self.out)?;
+            self.out.truncate(0);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Largest absolute value of the weights written to it, for the scale of int8 encoding
+#[derive(Default)]
+pub struct MaxAbsWriter {
+    pub max_abs: f32,
+    pending: Vec<u8>,
+}
+
+impl io::Write for MaxAbsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut bytes = mem::take(&mut self.pending);
+        let mut rest = buf;
+        if bytes.len() > 0 {
+            let n = min(4 - bytes.len(), rest.len());
+            bytes.extend_from_slice(&rest[..n]);
+            rest = &rest[n..];
+            if bytes.len() < 4 {
+                self.pending = bytes;
+                return Ok(buf.len());
+            }
+            self.max_abs = self.max_abs.max(f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]).abs());
+        }
+        let whole = rest.len() / 4 * 4;
+        for b in rest[..whole].chunks_exact(4) {
+            self.max_abs = self.max_abs.max(f32::from_le_bytes([b[0], b[1], b[2], b[3]]).abs());
+        }
+        self.pending = rest[whole..].to_vec();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Reads encoded weights and returns them as f32 bytes, reads have to be for whole f32s
+pub struct WeightsDecoder<'a> {
+    inner: &'a mut dyn io::Read,
+    encoding: WeightsEncoding,
+    buf: Vec<u8>,
+}
+
+impl<'a> WeightsDecoder<'a> {
+    pub fn new(inner: &'a mut dyn io::Read, encoding: WeightsEncoding) -> WeightsDecoder<'a> {
+        WeightsDecoder {inner: inner, encoding: encoding, buf: Vec::new()}
+    }
+}
+
+impl<'a> io::Read for WeightsDecoder<'a> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if out.len() == 0 {
+            return Ok(0);
+        }
+        let n = min(out.len() / 4, 1024 * 1024);
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Encoded weights can only be read as whole f32s"));
+        }
+        let width = match self.encoding {
+            WeightsEncoding::F16 => 2,
+            WeightsEncoding::Int8(_) => 1,
+        };
+        self.buf.resize(n * width, 0);
+        self.inner.read_exact(&mut self.buf)?;
+        for (i, weight_out) in out[..n * 4].chunks_exact_mut(4).enumerate() {
+            let weight = match self.encoding {
+                WeightsEncoding::F16 => f16_to_f32(u16::from_le_bytes([self.buf[2 * i], self.buf[2 * i + 1]])),
+                WeightsEncoding::Int8(scale) => self.buf[i] as i8 as f32 * scale,
+            };
+            weight_out.copy_from_slice(&weight.to_le_bytes());
+        }
+        Ok(n * 4)
+    }
+}
+
+
+/// Adds L2 and L1 regularization to the (negative) gradient of a single weight.
+/// Regularization is applied lazily: only weights of features present in the example get regularized,
+/// so sparse update loops do not need to touch the whole weight array.
+/// The regularization a weight misses while its feature is absent is not caught up later (vowpal does that),
+/// so rare features are regularized less than frequent ones, and --vwcompat does not allow regularization.
+/// For a weight that is exactly zero, L1 keeps it at zero unless the gradient is stronger than L1.
+#[inline(always)]
+pub fn regularized_gradient(gradient: f32, weight: f32, l1: f32, l2: f32) -> f32 {
+    let gradient = gradient - l2 * weight;
+    if weight > 0.0 {
+        gradient - l1
+    } else if weight < 0.0 {
+        gradient + l1
+    } else if gradient.abs() <= l1 {
+        0.0
+    } else {
+        gradient - l1 * gradient.signum()
+    }
+}
+
+/// Truncated gradient: when L1 is used, update is not allowed to push the weight across zero
+#[inline(always)]
+pub fn truncated_weight(old_weight: f32, new_weight: f32, l1: f32) -> f32 {
+    if l1 > 0.0 && old_weight * new_weight < 0.0 {
+        0.0
+    } else {
+        new_weight
+    }
+}
+
+
 /// This function is used only in tests to run a single block with given loss function
+#[cfg(test)]
 pub fn slearn<'a>(block_run: &mut Box<dyn BlockTrait>, 
                     block_loss_function: &mut Box<dyn BlockTrait>,
                     fb: &feature_buffer::FeatureBuffer, 
@@ -98,7 +481,7 @@ pub fn slearn<'a>(block_run: &mut Box<dyn BlockTrait>,
         let block_loss_function: Box<dyn BlockTrait> = mem::transmute(& *block_loss_function.deref().deref());
         let mut further_blocks_v: Vec<Box<dyn BlockTrait>> = vec![block_loss_function];
         let further_blocks = &mut further_blocks_v[..];
-        let (prediction_probability, general_gradient) = block_run.forward_backward(further_blocks, 0.0, fb, update);
+        let (prediction_probability, _general_gradient) = block_run.forward_backward(further_blocks, 0.0, fb, update);
         // black magic here: forget about further blocks that we got through transmute:
         further_blocks_v.set_len(0);
         return prediction_probability
@@ -106,10 +489,11 @@ pub fn slearn<'a>(block_run: &mut Box<dyn BlockTrait>,
 }
 
 /// This function is used only in tests to run a single block with given loss function
+#[cfg(test)]
 pub fn spredict<'a>(block_run: &mut Box<dyn BlockTrait>, 
                     block_loss_function: &mut Box<dyn BlockTrait>,
                     fb: &feature_buffer::FeatureBuffer, 
-                    update: bool) -> f32 {
+                    _update: bool) -> f32 {
 
     unsafe {
         let block_loss_function: Box<dyn BlockTrait> = mem::transmute(& *block_loss_function.deref().deref());
@@ -5,6 +5,7 @@ use std::io;
 use crate::regressor;
 use crate::feature_buffer;
 use crate::model_instance;
+use model_instance::{LossFunction, Link};
 use regressor::BlockTrait;
 
 
@@ -29,19 +30,108 @@ pub fn logistic(t: f32) -> f32 {
     return (1.0+(-t).exp()).recip();
 }
 
+#[inline(always)]
+pub fn apply_link(link: Link, wsum: f32) -> f32 {
+    match link {
+        Link::Logistic => logistic(wsum),
+        Link::Identity => wsum,
+    }
+}
+
+/// Loss of a single example, given the prediction as returned by the regressor (after --link)
+/// Used for reporting only, blocks below compute gradients directly
+pub fn example_loss(mi: &model_instance::ModelInstance, prediction: f32, label: f32) -> f32 {
+    match mi.loss_function {
+        LossFunction::Logistic => {
+            let p = match mi.link {
+                Link::Logistic => prediction,
+                Link::Identity => logistic(prediction),
+            };
+            // Clamp, so we never take log of zero
+            let p = p.max(f32::EPSILON).min(1.0 - f32::EPSILON);
+            if label > 0.0 { -p.ln() } else { -(1.0 - p).ln() }
+        },
+        LossFunction::Squared => (prediction - label) * (prediction - label),
+        LossFunction::Hinge => {
+            let y: f32 = if label > 0.0 {1.0} else {-1.0};
+            (1.0 - y * prediction).max(0.0)
+        },
+        LossFunction::Quantile => {
+            let residual = label - prediction;
+            if residual > 0.0 { mi.quantile_tau * residual } else { (mi.quantile_tau - 1.0) * residual }
+        },
+    }
+}
+
+/// Creates the terminal block of the chain, based on --loss_function
+pub fn new_loss_function_block(mi: &model_instance::ModelInstance) -> Result<Box<dyn BlockTrait>, Box<dyn Error>> {
+    match mi.loss_function {
+        LossFunction::Logistic => BlockSigmoid::new_without_weights(mi),
+        LossFunction::Squared => BlockSquared::new_without_weights(mi),
+        LossFunction::Hinge => BlockHinge::new_without_weights(mi),
+        LossFunction::Quantile => BlockQuantile::new_without_weights(mi),
+    }
+}
 
+// Loss functions have no weights, so all of them share the same (empty) implementation of weight handling
+macro_rules! loss_function_without_weights {
+    ($block_type:expr) => {
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+
+        fn get_block_type(&self) -> u32 {
+            $block_type
+        }
+
+        fn get_serialized_bytes(&self) -> usize {
+            0
+        }
+
+        fn allocate_and_init_weights(&mut self, _mi: &model_instance::ModelInstance) {
+            // empty
+        }
+
+        fn get_serialized_len(&self) -> usize {
+            return 0
+        }
 
+        fn read_weights_from_buf(&mut self, _input_bufreader: &mut dyn io::Read) -> Result<(), Box<dyn Error>> {
+            Ok(())
+        }
+
+        fn write_weights_to_buf(&self, _output_bufwriter: &mut dyn io::Write) -> Result<(), Box<dyn Error>> {
+            Ok(())
+        }
+
+        fn read_weights_from_buf_into_forward_only(&self, _input_bufreader: &mut dyn io::Read, _forward: &mut Box<dyn BlockTrait>) -> Result<(), Box<dyn Error>> {
+            Ok(())
+        }
+
+        fn new_forward_only_without_weights(&self) -> Result<Box<dyn BlockTrait>, Box<dyn Error>> {
+            Ok(Box::new(self.clone()))
+        }
+
+        /// Sets internal state of weights based on some completely object-dependent parameters
+        fn testing_set_weights(&mut self, _aa: i32, _bb: i32, _index: usize, _w: &[f32]) -> Result<(), Box<dyn Error>> {
+            Ok(())
+        }
+    };
+}
+
+
+
+/// Logistic loss. Labels above 0.0 are positive, the rest (0.0 and vowpal's -1.0) are negative
+#[derive(Clone)]
 pub struct BlockSigmoid {
+    link: Link,
 }
 
 impl BlockTrait for BlockSigmoid {
-
-    fn as_any(&mut self) -> &mut dyn Any {
-        self
-    }
+    loss_function_without_weights!(regressor::BLOCK_TYPE_SIGMOID);
 
     fn new_without_weights(mi: &model_instance::ModelInstance) -> Result<Box<dyn BlockTrait>, Box<dyn Error>> {
-        Ok(Box::new(BlockSigmoid {}))
+        Ok(Box::new(BlockSigmoid {link: mi.link}))
     }
 
     #[inline(always)]
@@ -49,7 +139,7 @@ impl BlockTrait for BlockSigmoid {
                     further_regressors: &mut [Box<dyn BlockTrait>], 
                     wsum: f32, 
                     fb: &feature_buffer::FeatureBuffer, 
-                    update:bool) -> (f32, f32) {
+                    _update:bool) -> (f32, f32) {
         if further_regressors.len() != 0 {
             panic!("RegSigmoid can only be at the end of the chain!");
         }
@@ -57,17 +147,21 @@ impl BlockTrait for BlockSigmoid {
         // vowpal compatibility
         if wsum.is_nan() {
             eprintln!("NAN prediction in example {}, forcing 0.0", fb.example_number);
-            return (logistic(0.0), 0.0);
+            return (apply_link(self.link, 0.0), 0.0);
         } else if wsum < -50.0 {
-            return (logistic(-50.0), 0.0);
+            return (apply_link(self.link, -50.0), 0.0);
         } else if wsum > 50.0 {
-            return (logistic(50.0), 0.0);
+            return (apply_link(self.link, 50.0), 0.0);
         }        
 
         let prediction_probability = logistic(wsum);
-        let general_gradient = (fb.label - prediction_probability) * fb.example_importance;
+        let label: f32 = if fb.label > 0.0 {1.0} else {0.0};
+        let general_gradient = (label - prediction_probability) * fb.example_importance;
         //println!("General gradient: {}", general_gradient);
-        (prediction_probability, general_gradient)
+        match self.link {
+            Link::Logistic => (prediction_probability, general_gradient),
+            Link::Identity => (wsum, general_gradient),
+        }
     }
 
     fn forward(&self, 
@@ -82,45 +176,263 @@ impl BlockTrait for BlockSigmoid {
         // vowpal compatibility
         if wsum.is_nan() {
             eprintln!("NAN prediction in example {}, forcing 0.0", fb.example_number);
-            return logistic(0.0);
+            return apply_link(self.link, 0.0);
         } else if wsum < -50.0 {
-            return logistic(-50.0);
+            return apply_link(self.link, -50.0);
         } else if wsum > 50.0 {
-            return logistic(50.0);
+            return apply_link(self.link, 50.0);
         }        
 
-        let prediction_probability = logistic(wsum);
-        prediction_probability
+        apply_link(self.link, wsum)
     }
+}
+
+
+/// Squared loss: (label - wsum)^2, as reported by example_loss (and vowpal), the gradient (label - wsum) is of half of it
+#[derive(Clone)]
+pub struct BlockSquared {
+    link: Link,
+}
+
+impl BlockTrait for BlockSquared {
+    loss_function_without_weights!(regressor::BLOCK_TYPE_SQUARED);
 
-    
-    
-    fn allocate_and_init_weights(&mut self, mi: &model_instance::ModelInstance) {
-        // empty
+    fn new_without_weights(mi: &model_instance::ModelInstance) -> Result<Box<dyn BlockTrait>, Box<dyn Error>> {
+        Ok(Box::new(BlockSquared {link: mi.link}))
     }
-    fn get_serialized_len(&self) -> usize {
-        return 0
+
+    #[inline(always)]
+    fn forward_backward(&mut self, 
+                    further_regressors: &mut [Box<dyn BlockTrait>], 
+                    wsum: f32, 
+                    fb: &feature_buffer::FeatureBuffer, 
+                    _update:bool) -> (f32, f32) {
+        if further_regressors.len() != 0 {
+            panic!("BlockSquared can only be at the end of the chain!");
+        }
+        if wsum.is_nan() {
+            eprintln!("NAN prediction in example {}, forcing 0.0", fb.example_number);
+            return (apply_link(self.link, 0.0), 0.0);
+        }
+        let general_gradient = (fb.label - wsum) * fb.example_importance;
+        (apply_link(self.link, wsum), general_gradient)
     }
 
-    fn read_weights_from_buf(&mut self, input_bufreader: &mut dyn io::Read) -> Result<(), Box<dyn Error>> {
-        Ok(())
+    fn forward(&self, 
+                     further_blocks: &[Box<dyn BlockTrait>], 
+                     wsum: f32, 
+                     fb: &feature_buffer::FeatureBuffer) -> f32 {
+        if further_blocks.len() != 0 {
+            panic!("BlockSquared can only be at the end of the chain!");
+        }
+        if wsum.is_nan() {
+            eprintln!("NAN prediction in example {}, forcing 0.0", fb.example_number);
+            return apply_link(self.link, 0.0);
+        }
+        apply_link(self.link, wsum)
     }
+}
+
+
+/// Hinge loss: max(0, 1 - y * wsum), where y is -1.0 for labels <= 0.0 and 1.0 otherwise
+#[derive(Clone)]
+pub struct BlockHinge {
+    link: Link,
+}
 
-    fn write_weights_to_buf(&self, output_bufwriter: &mut dyn io::Write) -> Result<(), Box<dyn Error>> {
-        Ok(())
+impl BlockTrait for BlockHinge {
+    loss_function_without_weights!(regressor::BLOCK_TYPE_HINGE);
+
+    fn new_without_weights(mi: &model_instance::ModelInstance) -> Result<Box<dyn BlockTrait>, Box<dyn Error>> {
+        Ok(Box::new(BlockHinge {link: mi.link}))
     }
 
-    fn read_weights_from_buf_into_forward_only(&self, input_bufreader: &mut dyn io::Read, forward: &mut Box<dyn BlockTrait>) -> Result<(), Box<dyn Error>> {
-        Ok(())        
+    #[inline(always)]
+    fn forward_backward(&mut self, 
+                    further_regressors: &mut [Box<dyn BlockTrait>], 
+                    wsum: f32, 
+                    fb: &feature_buffer::FeatureBuffer, 
+                    _update:bool) -> (f32, f32) {
+        if further_regressors.len() != 0 {
+            panic!("BlockHinge can only be at the end of the chain!");
+        }
+        if wsum.is_nan() {
+            eprintln!("NAN prediction in example {}, forcing 0.0", fb.example_number);
+            return (apply_link(self.link, 0.0), 0.0);
+        }
+        let y: f32 = if fb.label > 0.0 {1.0} else {-1.0};
+        let general_gradient = if y * wsum < 1.0 {
+            y * fb.example_importance
+        } else {
+            0.0
+        };
+        (apply_link(self.link, wsum), general_gradient)
+    }
+
+    fn forward(&self, 
+                     further_blocks: &[Box<dyn BlockTrait>], 
+                     wsum: f32, 
+                     fb: &feature_buffer::FeatureBuffer) -> f32 {
+        if further_blocks.len() != 0 {
+            panic!("BlockHinge can only be at the end of the chain!");
+        }
+        if wsum.is_nan() {
+            eprintln!("NAN prediction in example {}, forcing 0.0", fb.example_number);
+            return apply_link(self.link, 0.0);
+        }
+        apply_link(self.link, wsum)
     }
+}
 
-    fn new_forward_only_without_weights(&self) -> Result<Box<dyn BlockTrait>, Box<dyn Error>> {
-        Ok(Box::new(BlockSigmoid{}))
+
+/// Quantile (pinball) loss: tau * (label - wsum) when label > wsum, (1 - tau) * (wsum - label) otherwise
+#[derive(Clone)]
+pub struct BlockQuantile {
+    link: Link,
+    tau: f32,
+}
+
+impl BlockTrait for BlockQuantile {
+    loss_function_without_weights!(regressor::BLOCK_TYPE_QUANTILE);
+
+    fn new_without_weights(mi: &model_instance::ModelInstance) -> Result<Box<dyn BlockTrait>, Box<dyn Error>> {
+        Ok(Box::new(BlockQuantile {link: mi.link, tau: mi.quantile_tau}))
     }
-    /// Sets internal state of weights based on some completely object-dependent parameters
-    fn testing_set_weights(&mut self, aa: i32, bb: i32, index: usize, w: &[f32]) -> Result<(), Box<dyn Error>> {
-        Ok(())
+
+    #[inline(always)]
+    fn forward_backward(&mut self, 
+                    further_regressors: &mut [Box<dyn BlockTrait>], 
+                    wsum: f32, 
+                    fb: &feature_buffer::FeatureBuffer, 
+                    _update:bool) -> (f32, f32) {
+        if further_regressors.len() != 0 {
+            panic!("BlockQuantile can only be at the end of the chain!");
+        }
+        if wsum.is_nan() {
+            eprintln!("NAN prediction in example {}, forcing 0.0", fb.example_number);
+            return (apply_link(self.link, 0.0), 0.0);
+        }
+        let general_gradient = if fb.label > wsum {
+            self.tau * fb.example_importance
+        } else {
+            (self.tau - 1.0) * fb.example_importance
+        };
+        (apply_link(self.link, wsum), general_gradient)
     }
 
+    fn forward(&self, 
+                     further_blocks: &[Box<dyn BlockTrait>], 
+                     wsum: f32, 
+                     fb: &feature_buffer::FeatureBuffer) -> f32 {
+        if further_blocks.len() != 0 {
+            panic!("BlockQuantile can only be at the end of the chain!");
+        }
+        if wsum.is_nan() {
+            eprintln!("NAN prediction in example {}, forcing 0.0", fb.example_number);
+            return apply_link(self.link, 0.0);
+        }
+        apply_link(self.link, wsum)
+    }
 }
 
+
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    fn label_vec(label: f32) -> feature_buffer::FeatureBuffer {
+        feature_buffer::FeatureBuffer {
+                    label: label,
+                    example_importance: 1.0,
+                    example_number: 0,
+                    lr_buffer: Vec::new(),
+                    ffm_buffer: Vec::new(),
+                    ffm_fields_count: 0,
+        }
+    }
+
+    #[test]
+    fn test_sigmoid_link() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        let mut lossf = new_loss_function_block(&mi).unwrap();
+        assert_eq!(lossf.forward_backward(&mut [], 0.0, &label_vec(1.0), true), (0.5, 0.5));
+        assert_eq!(lossf.forward_backward(&mut [], 0.0, &label_vec(0.0), true), (0.5, -0.5));
+        assert_eq!(lossf.forward_backward(&mut [], 0.0, &label_vec(-1.0), true), (0.5, -0.5));
+        assert_eq!(lossf.forward(&[], 0.0, &label_vec(1.0)), 0.5);
+
+        mi.link = Link::Identity;
+        let mut lossf = new_loss_function_block(&mi).unwrap();
+        assert_eq!(lossf.forward_backward(&mut [], 2.0, &label_vec(1.0), true), (2.0, 1.0 - logistic(2.0)));
+        assert_eq!(lossf.forward(&[], 2.0, &label_vec(1.0)), 2.0);
+        // outside of [-50, 50] we stop learning, but still report the clamped value
+        assert_eq!(lossf.forward_backward(&mut [], 60.0, &label_vec(1.0), true), (50.0, 0.0));
+    }
+
+    #[test]
+    fn test_squared() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.loss_function = LossFunction::Squared;
+        mi.link = Link::Identity;
+        let mut lossf = new_loss_function_block(&mi).unwrap();
+        assert_eq!(lossf.forward_backward(&mut [], 0.5, &label_vec(2.0), true), (0.5, 1.5));
+        assert_eq!(lossf.forward_backward(&mut [], 3.0, &label_vec(2.0), true), (3.0, -1.0));
+        assert_eq!(lossf.forward(&[], 3.0, &label_vec(2.0)), 3.0);
+
+        mi.link = Link::Logistic;
+        let mut lossf = new_loss_function_block(&mi).unwrap();
+        assert_eq!(lossf.forward_backward(&mut [], 0.0, &label_vec(2.0), true), (0.5, 2.0));
+        assert_eq!(lossf.forward(&[], 0.0, &label_vec(2.0)), 0.5);
+    }
+
+    #[test]
+    fn test_hinge() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.loss_function = LossFunction::Hinge;
+        mi.link = Link::Identity;
+        let mut lossf = new_loss_function_block(&mi).unwrap();
+        assert_eq!(lossf.forward_backward(&mut [], 0.5, &label_vec(1.0), true), (0.5, 1.0));
+        assert_eq!(lossf.forward_backward(&mut [], 0.5, &label_vec(0.0), true), (0.5, -1.0));
+        assert_eq!(lossf.forward_backward(&mut [], 0.5, &label_vec(-1.0), true), (0.5, -1.0));
+        // margin is satisfied, nothing to learn
+        assert_eq!(lossf.forward_backward(&mut [], 1.5, &label_vec(1.0), true), (1.5, 0.0));
+        assert_eq!(lossf.forward_backward(&mut [], -1.5, &label_vec(-1.0), true), (-1.5, 0.0));
+    }
+
+    #[test]
+    fn test_quantile() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.loss_function = LossFunction::Quantile;
+        mi.link = Link::Identity;
+        mi.quantile_tau = 0.25;
+        let mut lossf = new_loss_function_block(&mi).unwrap();
+        assert_eq!(lossf.forward_backward(&mut [], 1.0, &label_vec(2.0), true), (1.0, 0.25));
+        assert_eq!(lossf.forward_backward(&mut [], 3.0, &label_vec(2.0), true), (3.0, -0.75));
+        assert_eq!(lossf.forward(&[], 3.0, &label_vec(2.0)), 3.0);
+    }
+
+    #[test]
+    fn test_example_loss() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        assert_eq!(example_loss(&mi, 0.5, 1.0), 0.6931472);
+        assert_eq!(example_loss(&mi, 0.5, -1.0), 0.6931472);
+        assert_eq!(example_loss(&mi, 1.0, 0.0), 15.942385);
+        mi.link = Link::Identity;
+        assert_eq!(example_loss(&mi, 0.0, 1.0), 0.6931472);
+
+        mi.loss_function = LossFunction::Squared;
+        assert_eq!(example_loss(&mi, 1.5, 2.0), 0.25);
+
+        mi.loss_function = LossFunction::Hinge;
+        assert_eq!(example_loss(&mi, 0.5, 1.0), 0.5);
+        assert_eq!(example_loss(&mi, 0.5, 0.0), 1.5);
+        assert_eq!(example_loss(&mi, 2.0, 1.0), 0.0);
+
+        mi.loss_function = LossFunction::Quantile;
+        mi.quantile_tau = 0.9;
+        assert_eq!(example_loss(&mi, 1.0, 2.0), 0.9);
+        assert_eq!(example_loss(&mi, 2.0, 1.0), 0.100000024);
+    }
+
+}
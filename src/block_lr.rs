@@ -5,22 +5,24 @@ use crate::regressor;
 use crate::model_instance;
 use crate::feature_buffer;
 use std::io;
-use core::arch::x86_64::*;
 use std::error::Error;
+use std::sync::Arc;
 
 
 
-use std::mem::{self, MaybeUninit};
+use std::mem;
 use optimizer::OptimizerTrait;
 use regressor::BlockTrait;
 use crate::block_helpers;
-use block_helpers::{Weight, WeightAndOptimizerData};
+use block_helpers::{WeightAndOptimizerData, WeightsStore};
 
 
 pub struct BlockLR<L:OptimizerTrait> {
-    pub weights: Vec<WeightAndOptimizerData<L>>,
+    pub weights: WeightsStore<WeightAndOptimizerData<L>>,
     pub weights_len: u32,
     pub optimizer_lr: L,
+    pub l1: f32,
+    pub l2: f32,
 }
 
 impl <L:OptimizerTrait + 'static> BlockTrait for BlockLR<L> 
@@ -31,11 +33,17 @@ impl <L:OptimizerTrait + 'static> BlockTrait for BlockLR<L>
 
     fn new_without_weights(mi: &model_instance::ModelInstance) -> Result<Box<dyn BlockTrait>, Box<dyn Error>>  where Self: Sized {
         let mut reg_lr = BlockLR::<L> {
-            weights: Vec::new(),
+            weights: WeightsStore::new(),
             weights_len: 0, 
             optimizer_lr: L::new(),
+            l1: mi.l1,
+            l2: mi.l2,
         };
         reg_lr.optimizer_lr.init(mi.learning_rate, mi.power_t, mi.init_acc_gradient);
+        if reg_lr.optimizer_lr.init_extra(&optimizer::OptimizerExtraParams::new(mi, mi.l1, mi.l2)) {
+            reg_lr.l1 = 0.0;
+            reg_lr.l2 = 0.0;
+        }
         reg_lr.weights_len = 1 << mi.bit_precision;
         Ok(Box::new(reg_lr))
     }
@@ -43,8 +51,10 @@ impl <L:OptimizerTrait + 'static> BlockTrait for BlockLR<L>
     fn new_forward_only_without_weights(&self) -> Result<Box<dyn BlockTrait>, Box<dyn Error>> {
         let forwards_only = BlockLR::<optimizer::OptimizerSGD> {
             weights_len: self.weights_len,
-            weights: Vec::new(),
+            weights: WeightsStore::new(),
             optimizer_lr:optimizer::OptimizerSGD::new(),
+            l1: 0.0,
+            l2: 0.0,
         };
         
         Ok(Box::new(forwards_only))
@@ -52,8 +62,8 @@ impl <L:OptimizerTrait + 'static> BlockTrait for BlockLR<L>
 
 
 
-    fn allocate_and_init_weights(&mut self, mi: &model_instance::ModelInstance) {
-        self.weights = vec![WeightAndOptimizerData::<L>{weight:0.0, optimizer_data: self.optimizer_lr.initial_data()}; self.weights_len as usize];
+    fn allocate_and_init_weights(&mut self, _mi: &model_instance::ModelInstance) {
+        self.weights = vec![WeightAndOptimizerData::<L>{weight:0.0, optimizer_data: self.optimizer_lr.initial_data()}; self.weights_len as usize].into();
         
     }
 
@@ -79,12 +89,25 @@ impl <L:OptimizerTrait + 'static> BlockTrait for BlockLR<L>
             let (prediction_probability, general_gradient) = next_regressor[0].forward_backward(further_regressors, wsum_input + wsum, fb, update);
 
             if update {
-                for hashvalue in fb.lr_buffer.iter() {
-                    let feature_index     = hashvalue.hash as usize;
-                    let feature_value:f32 = hashvalue.value;                        
-                    let gradient = general_gradient * feature_value;
-                    let update = self.optimizer_lr.calculate_update(gradient, &mut self.weights.get_unchecked_mut(feature_index).optimizer_data);
-                    self.weights.get_unchecked_mut(feature_index).weight += update;
+                if self.l1 == 0.0 && self.l2 == 0.0 {
+                    for hashvalue in fb.lr_buffer.iter() {
+                        let feature_index     = hashvalue.hash as usize;
+                        let feature_value:f32 = hashvalue.value;                        
+                        let gradient = general_gradient * feature_value;
+                        let update = self.optimizer_lr.calculate_update(gradient, &mut self.weights.get_unchecked_mut(feature_index).optimizer_data);
+                        self.weights.get_unchecked_mut(feature_index).weight += update;
+                    }
+                } else {
+                    for hashvalue in fb.lr_buffer.iter() {
+                        let feature_index     = hashvalue.hash as usize;
+                        let feature_value:f32 = hashvalue.value;                        
+                        let weight = self.weights.get_unchecked(feature_index).weight;
+                        let gradient = block_helpers::regularized_gradient(general_gradient * feature_value, weight, self.l1, self.l2);
+                        if gradient != 0.0 {
+                            let update = self.optimizer_lr.calculate_update(gradient, &mut self.weights.get_unchecked_mut(feature_index).optimizer_data);
+                            self.weights.get_unchecked_mut(feature_index).weight = block_helpers::truncated_weight(weight, weight + update, self.l1);
+                        }
+                    }
                 }
             }
             (prediction_probability, general_gradient)
@@ -109,12 +132,77 @@ impl <L:OptimizerTrait + 'static> BlockTrait for BlockLR<L>
         let prediction_probability = next_regressor[0].forward(further_blocks, wsum + wsum_input, fb);
         prediction_probability         
     }
+
+    fn prepare_context(&self,
+             further_blocks: &[Box<dyn BlockTrait>],
+             fb: &feature_buffer::FeatureBuffer,
+             context: &mut regressor::ContextCache) {
+        let mut wsum:f32 = 0.0;
+        unsafe {
+            for val in &fb.lr_buffer {
+                wsum += self.weights.get_unchecked(val.hash as usize).weight * val.value;
+            }
+        }
+        context.lr_wsum = wsum;
+        let (next_regressor, further_blocks) = further_blocks.split_at(1);
+        next_regressor[0].prepare_context(further_blocks, fb, context);
+    }
+
+    fn forward_with_context(&self,
+             further_blocks: &[Box<dyn BlockTrait>],
+             wsum_input: f32,
+             fb: &feature_buffer::FeatureBuffer,
+             context: &mut regressor::ContextCache) -> f32 {
+        let mut wsum:f32 = context.lr_wsum;
+        unsafe {
+            for val in &fb.lr_buffer {
+                wsum += self.weights.get_unchecked(val.hash as usize).weight * val.value;
+            }
+        }
+        let (next_regressor, further_blocks) = further_blocks.split_at(1);
+        next_regressor[0].forward_with_context(further_blocks, wsum + wsum_input, fb, context)
+    }
+
+    fn forward_batch(&self,
+             further_blocks: &[Box<dyn BlockTrait>],
+             wsums: &mut [f32],
+             fbs: &[feature_buffer::FeatureBuffer],
+             predictions: &mut [f32]) {
+        unsafe {
+            for (i, fb) in fbs.iter().enumerate() {
+                let mut wsum:f32 = 0.0;
+                for val in &fb.lr_buffer {
+                    wsum += self.weights.get_unchecked(val.hash as usize).weight * val.value;
+                }
+                wsums[i] += wsum;
+            }
+        }
+        let (next_regressor, further_blocks) = further_blocks.split_at(1);
+        next_regressor[0].forward_batch(further_blocks, wsums, fbs, predictions);
+    }
     
     
     fn get_serialized_len(&self) -> usize {
         return self.weights_len as usize;
     }
 
+    fn get_serialized_bytes(&self) -> usize {
+        self.weights_len as usize * mem::size_of::<WeightAndOptimizerData<L>>()
+    }
+
+    fn get_block_type(&self) -> u32 {
+        regressor::BLOCK_TYPE_LR
+    }
+
+    fn get_optimizer_tag(&self) -> u32 {
+        L::get_tag()
+    }
+
+    fn map_weights(&mut self, file: &Arc<block_helpers::MappedFile>, offset: usize) -> Result<(), Box<dyn Error>> {
+        self.weights = WeightsStore::mapped(file, offset, self.weights_len as usize)?;
+        Ok(())
+    }
+
     fn read_weights_from_buf(&mut self, input_bufreader: &mut dyn io::Read) -> Result<(), Box<dyn Error>> {
         block_helpers::read_weights_from_buf(&mut self.weights, input_bufreader)
     }
@@ -124,11 +212,11 @@ impl <L:OptimizerTrait + 'static> BlockTrait for BlockLR<L>
     }
 
     fn read_weights_from_buf_into_forward_only(&self, input_bufreader: &mut dyn io::Read, forward: &mut Box<dyn BlockTrait>) -> Result<(), Box<dyn Error>> {
-        let mut forward = forward.as_any().downcast_mut::<BlockLR<optimizer::OptimizerSGD>>().unwrap();
+        let forward = forward.as_any().downcast_mut::<BlockLR<optimizer::OptimizerSGD>>().unwrap();
         block_helpers::read_weights_only_from_buf2::<L>(self.weights_len as usize, &mut forward.weights, input_bufreader)
     }
     /// Sets internal state of weights based on some completely object-dependent parameters
-    fn testing_set_weights(&mut self, aa: i32, bb: i32, index: usize, w: &[f32]) -> Result<(), Box<dyn Error>> {
+    fn testing_set_weights(&mut self, _aa: i32, _bb: i32, index: usize, w: &[f32]) -> Result<(), Box<dyn Error>> {
         self.weights[index].weight = w[0];
         self.weights[index].optimizer_data = self.optimizer_lr.initial_data();
         Ok(())
@@ -16,9 +16,10 @@ use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use crate::vwmap;
 
 const CACHE_HEADER_MAGIC_STRING: &[u8; 4] = b"FWCA";    // Fwumious Wabbit CAche
-const CACHE_HEADER_VERSION:u32 = 11; 
+const CACHE_HEADER_VERSION:u32 = 12; 
 /*
 Version incompatibilites:
+11->12: labels are stored as f32
 10->11: float namespaces cannot have a weight attached
 9->10: enable binning
 8->9: enabled multi-byte feature names in vw files
@@ -52,7 +53,7 @@ impl<W: io::Write> Write for Wrapper<W> {
 impl<W: Write> Drop for Wrapper<W> {
     fn drop(&mut self) {
         match self.s.take() {
-            Some(s) => {let a = s.finish();}
+            Some(s) => {let _ = s.finish();}
             None => {}
         }
     }
@@ -63,6 +64,7 @@ pub struct RecordCache {
     input_bufreader: Box<dyn io::Read>,
     temporary_filename: String,
     final_filename: String,
+    gz: bool,
     pub writing: bool,
     pub reading: bool,
 //    pub output_buffer: Vec<u32>,
@@ -91,6 +93,7 @@ impl RecordCache {
             input_bufreader: Box::new(io::empty()),
             temporary_filename: temporary_filename.to_string(),
             final_filename: final_filename.to_string(),
+            gz: gz,
             writing: false,
             reading: false,
             byte_buffer: Vec::new(),
@@ -161,10 +164,33 @@ impl RecordCache {
         if self.writing {
             self.output_bufwriter.flush()?;
             fs::rename(&self.temporary_filename, &self.final_filename)?;
+            self.writing = false;
         }
         Ok(())
     }
 
+    // Start reading the (finished) cache file from the beginning, used for additional passes over the data
+    pub fn rewind(&mut self, vw_map: &vwmap::VwNamespaceMap) -> Result<(), Box<dyn Error>> {
+        if self.writing {
+            return Err("rewind() called on cache that is still being written")?;
+        }
+        if !path::Path::new(&self.final_filename).exists() {
+            return Err(format!("Cannot rewind, cache file {} does not exist", self.final_filename))?;
+        }
+        if !self.gz {
+            self.input_bufreader = Box::new(fs::File::open(&self.final_filename)?);
+        } else {
+            self.input_bufreader = Box::new(lz4::Decoder::new(fs::File::open(&self.final_filename)?)?);
+        }
+        self.verify_header(vw_map)?;
+        self.reading = true;
+        self.byte_buffer.resize(READBUF_LEN, 0);
+        self.start_pointer = 0;
+        self.end_pointer = 0;
+        self.total_read = 0;
+        Ok(())
+    }
+
     pub fn write_header(&mut self, vw_map: &vwmap::VwNamespaceMap) -> Result<(), Box<dyn Error>> {
         self.output_bufwriter.write_all(CACHE_HEADER_MAGIC_STRING)?;
         self.output_bufwriter.write_u32::<LittleEndian>(CACHE_HEADER_VERSION)?;
@@ -231,4 +257,36 @@ impl RecordCache {
             }            
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_rewind() {
+        let vw_map_string = r#"
+A,featureA
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let dir = tempdir().unwrap();
+        let input_filename = dir.path().join("input.vw");
+        let input_filename = input_filename.to_str().unwrap();
+
+        let mut cache = RecordCache::new(input_filename, true, &vw);
+        assert!(cache.writing);
+        assert!(cache.rewind(&vw).is_err());
+        cache.push_record(&[4, 1, 2, 3]).unwrap();
+        cache.push_record(&[3, 4, 5]).unwrap();
+        cache.write_finish().unwrap();
+        assert!(!cache.writing);
+
+        for _pass in 0..2 {
+            cache.rewind(&vw).unwrap();
+            assert_eq!(cache.get_next_record().unwrap(), &[4, 1, 2, 3]);
+            assert_eq!(cache.get_next_record().unwrap(), &[3, 4, 5]);
+            assert_eq!(cache.get_next_record().unwrap(), &[] as &[u32]);
+        }
+    }
+}
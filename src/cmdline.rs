@@ -2,7 +2,17 @@ use clap::{App, Arg,  AppSettings};
 use crate::version;
 
 pub fn parse<'a>() -> clap::ArgMatches<'a> {
-  let matches = App::new("fwumious wabbit")
+  create_app().get_matches()
+}
+
+// Parsing of arguments given by library users (e.g. python bindings): errors are returned instead of exiting the process
+pub fn parse_from<'a, I, T>(args: I) -> Result<clap::ArgMatches<'a>, clap::Error>
+    where I: IntoIterator<Item = T>, T: Into<std::ffi::OsString> + Clone {
+  create_app().get_matches_from_safe(args)
+}
+
+fn create_app<'a, 'b>() -> App<'a, 'b> {
+  App::new("fwumious wabbit")
                     .version(version::LATEST)
                     .author("Andraz Tori <atori@outbrain.com>")
                     .about("Superfast Logistic Regression & Field Aware Factorization Machines")
@@ -76,10 +86,25 @@ pub fn parse<'a>() -> clap::ArgMatches<'a> {
                      .value_name("0.5")
                      .help("How to apply Adagrad (0.5 = sqrt)")
                      .takes_value(true))
+                    .arg(Arg::with_name("l1")
+                     .long("l1")
+                     .value_name("0.0")
+                     .help("L1 regularization (truncated gradient) of LR weights, applied only when the feature is present")
+                     .takes_value(true))
                     .arg(Arg::with_name("l2")
                      .long("l2")
                      .value_name("0.0")
-                     .help("Regularization is not supported (only 0.0 will work)")
+                     .help("L2 regularization of LR weights, applied only when the feature is present")
+                     .takes_value(true))
+                    .arg(Arg::with_name("ffm_l1")
+                     .long("ffm_l1")
+                     .value_name("0.0")
+                     .help("L1 regularization (truncated gradient) of FFM weights, applied only when the feature is present")
+                     .takes_value(true))
+                    .arg(Arg::with_name("ffm_l2")
+                     .long("ffm_l2")
+                     .value_name("0.0")
+                     .help("L2 regularization of FFM weights, applied only when the feature is present")
                      .takes_value(true))
 
                     .arg(Arg::with_name("sgd")
@@ -92,6 +117,47 @@ pub fn parse<'a>() -> clap::ArgMatches<'a> {
                      .value_name("")
                      .help("Use Adagrad")
                      .takes_value(false))
+                    .arg(Arg::with_name("ftrl")
+                     .long("ftrl")
+                     .value_name("")
+                     .help("Use FTRL-Proximal optimizer, with --l1/--l2 (--ffm_l1/--ffm_l2) applied in closed form")
+                     .takes_value(false))
+                    .arg(Arg::with_name("optimizer")
+                     .long("optimizer")
+                     .value_name("adagrad")
+                     .help("Optimizer for LR weights (and FFM weights unless --ffm_optimizer is set): sgd, adagrad, ftrl, adam or rmsprop")
+                     .conflicts_with_all(&["sgd", "adaptive", "ftrl"])
+                     .takes_value(true))
+                    .arg(Arg::with_name("ffm_optimizer")
+                     .long("ffm_optimizer")
+                     .value_name("adagrad")
+                     .help("Optimizer for FFM weights: sgd, adagrad, ftrl, adam or rmsprop")
+                     .takes_value(true))
+                    .arg(Arg::with_name("adam_beta1")
+                     .long("adam_beta1")
+                     .value_name("0.9")
+                     .help("Decay rate of Adam's first moment estimate")
+                     .takes_value(true))
+                    .arg(Arg::with_name("adam_beta2")
+                     .long("adam_beta2")
+                     .value_name("0.999")
+                     .help("Decay rate of Adam's second moment estimate")
+                     .takes_value(true))
+                    .arg(Arg::with_name("rmsprop_rho")
+                     .long("rmsprop_rho")
+                     .value_name("0.9")
+                     .help("Decay rate of RMSProp's average of squared gradients")
+                     .takes_value(true))
+                    .arg(Arg::with_name("ftrl_alpha")
+                     .long("ftrl_alpha")
+                     .value_name("0.005")
+                     .help("Learning rate alpha of FTRL-Proximal")
+                     .takes_value(true))
+                    .arg(Arg::with_name("ftrl_beta")
+                     .long("ftrl_beta")
+                     .value_name("0.1")
+                     .help("Learning rate beta of FTRL-Proximal")
+                     .takes_value(true))
                     .arg(Arg::with_name("noconstant")
                      .long("noconstant")
                      .value_name("")
@@ -100,12 +166,17 @@ pub fn parse<'a>() -> clap::ArgMatches<'a> {
                     .arg(Arg::with_name("link")
                      .long("link")
                      .value_name("logistic")
-                     .help("What link function to use")
+                     .help("What link function to use: logistic or identity (default: logistic for logistic loss, identity otherwise)")
                      .takes_value(true))
                     .arg(Arg::with_name("loss_function")
                      .long("loss_function")
                      .value_name("logistic")
-                     .help("What loss function to use")
+                     .help("What loss function to use: logistic, squared, hinge or quantile")
+                     .takes_value(true))
+                    .arg(Arg::with_name("quantile_tau")
+                     .long("quantile_tau")
+                     .value_name("0.5")
+                     .help("Parameter tau of the quantile loss")
                      .takes_value(true))
                     .arg(Arg::with_name("bit_precision")
                      .short("b")
@@ -126,6 +197,26 @@ pub fn parse<'a>() -> clap::ArgMatches<'a> {
                      .value_name("arg")
                      .help("Final regressor to save (arg is filename)")
                      .takes_value(true))
+                    .arg(Arg::with_name("export_serving_model")
+                     .long("export_serving_model")
+                     .value_name("filename")
+                     .help("Also save the final model without optimizer state, it can only be used for predictions (-t and daemon)")
+                     .takes_value(true))
+                    .arg(Arg::with_name("serving_model_precision")
+                     .long("serving_model_precision")
+                     .value_name("f32")
+                     .help("Precision of weights in --export_serving_model: f32, f16 or int8 (with one scale per block)")
+                     .takes_value(true))
+                    .arg(Arg::with_name("checkpoint_every")
+                     .long("checkpoint_every")
+                     .value_name("examples")
+                     .help("Save a checkpoint of the model to --checkpoint_dir every this many examples, resume with -i checkpoint_dir/checkpoint.fw")
+                     .takes_value(true))
+                    .arg(Arg::with_name("checkpoint_dir")
+                     .long("checkpoint_dir")
+                     .value_name("directory")
+                     .help("Directory for --checkpoint_every snapshots")
+                     .takes_value(true))
                     .arg(Arg::with_name("initial_regressor")
                      .short("i")
                      .long("initial_regressor")
@@ -212,11 +303,57 @@ pub fn parse<'a>() -> clap::ArgMatches<'a> {
                      .long("daemon")
                      .help("read data from port 26542")
                      .takes_value(false))
+                    .arg(Arg::with_name("mmap")
+                     .long("mmap")
+                     .help("In daemon and test mode, use weights of f32 serving models (--export_serving_model) from the memory mapped file, processes serving the same file share the memory")
+                     .takes_value(false))
+                    .arg(Arg::with_name("allow_model_loading")
+                     .long("allow_model_loading")
+                     .help("In daemon mode, let clients load any model file on the server: the load_model command and HTTP POST /reload")
+                     .takes_value(false))
                     .arg(Arg::with_name("port")
                      .long("port")
                      .value_name("arg")
                      .help("port to listen on")
                      .takes_value(true))
+                    .arg(Arg::with_name("bind")
+                     .long("bind")
+                     .value_name("address")
+                     .help("in daemon mode, IPv4 or IPv6 address to listen on (default 127.0.0.1)")
+                     .takes_value(true))
+                    .arg(Arg::with_name("unix_socket")
+                     .long("unix_socket")
+                     .value_name("path")
+                     .help("in daemon mode, serve the line protocol on this unix domain socket instead of the TCP port")
+                     .takes_value(true))
+                    .arg(Arg::with_name("http_port")
+                     .long("http_port")
+                     .takes_value(true)
+                     .help("in daemon mode, also serve HTTP/JSON on this port (POST /predict, POST /reload, GET /health)"))
+                    .arg(Arg::with_name("metrics_port")
+                     .long("metrics_port")
+                     .takes_value(true)
+                     .help("in daemon mode, serve Prometheus metrics on this port (GET /metrics)"))
+                    .arg(Arg::with_name("read_timeout")
+                     .long("read_timeout")
+                     .value_name("seconds")
+                     .help("in daemon mode, close connections that send nothing for this long (default: no timeout)")
+                     .takes_value(true))
+                    .arg(Arg::with_name("write_timeout")
+                     .long("write_timeout")
+                     .value_name("seconds")
+                     .help("in daemon mode, close connections that don't read the responses for this long (default: no timeout)")
+                     .takes_value(true))
+                    .arg(Arg::with_name("max_pending_connections")
+                     .long("max_pending_connections")
+                     .value_name("arg")
+                     .help("in daemon mode, reject new connections with an error when this many are waiting for a free thread (default: no limit)")
+                     .takes_value(true))
+                    .arg(Arg::with_name("shutdown_timeout")
+                     .long("shutdown_timeout")
+                     .value_name("seconds (=10)")
+                     .help("in daemon mode, how long to wait for open connections to finish after SIGTERM or SIGINT")
+                     .takes_value(true))
                     .arg(Arg::with_name("num_children")
                      .long("num_children")
                      .value_name("arg (=10")
@@ -244,7 +381,49 @@ pub fn parse<'a>() -> clap::ArgMatches<'a> {
                      .value_name("examples")
                      .help("After how many examples stop updating weights")
                      .takes_value(true))
-                    .get_matches();
+                    .arg(Arg::with_name("passes")
+                     .long("passes")
+                     .value_name("1")
+                     .help("Number of passes over the data, more than one requires --cache")
+                     .takes_value(true))
+                    .arg(Arg::with_name("shuffle_buffer")
+                     .long("shuffle_buffer")
+                     .value_name("records (0)")
+                     .help("Shuffle records within a window of this size when replaying the cache on passes after the first one")
+                     .takes_value(true))
+                    .arg(Arg::with_name("threads")
+                     .long("threads")
+                     .value_name("1")
+                     .help("Number of Hogwild training threads sharing the same weights. More than 1 makes training non-deterministic")
+                     .takes_value(true))
+                    .arg(Arg::with_name("parse_threads")
+                     .long("parse_threads")
+                     .value_name("0")
+                     .help("Number of threads parsing text input, in parallel to learning. 0 parses in the learning thread")
+                     .takes_value(true))
+                    .arg(Arg::with_name("invert_hash")
+                     .long("invert_hash")
+                     .value_name("filename")
+                     .help("Write a TSV of feature names, namespaces, hashes and weights of all features in --data. Use with -i and -t to inspect an existing model")
+                     .takes_value(true))
+                    .arg(Arg::with_name("holdout_off")
+                     .long("holdout_off")
+                     .help("With multiple passes, do not hold out every 10th example for reporting holdout loss")
+                     .takes_value(false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-matches
-}
\ No newline at end of file
+    #[test]
+    fn test_parse_from() {
+        assert!(parse_from(vec!["fw", "--optimizer", "adam", "--ffm_optimizer", "sgd"]).is_ok());
+        // Argument errors are returned, not exiting the process
+        assert!(parse_from(vec!["fw", "--no_such_argument"]).is_err());
+        // --optimizer conflicts with the older optimizer flags
+        assert!(parse_from(vec!["fw", "--ftrl", "--optimizer", "adam"]).is_err());
+        assert!(parse_from(vec!["fw", "--optimizer", "adam", "--sgd"]).is_err());
+        assert!(parse_from(vec!["fw", "--adaptive", "--optimizer", "adam"]).is_err());
+    }
+}
@@ -1,12 +1,11 @@
 use crate::model_instance;
 use crate::parser;
 use crate::feature_transform_executor;
-use crate::feature_transform_parser;
-use crate::vwmap::{NamespaceType, NamespaceFormat};
+use crate::vwmap::{NamespaceType, NamespaceFormat, NamespaceDescriptor};
 
-const VOWPAL_FNV_PRIME:u32 = 16777619;	// vowpal magic number
+pub const VOWPAL_FNV_PRIME:u32 = 16777619;	// vowpal magic number
 //const CONSTANT_NAMESPACE:usize = 128;
-const CONSTANT_HASH:u32 = 11650396;
+pub const CONSTANT_HASH:u32 = 11650396;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct HashAndValue {
@@ -45,6 +44,26 @@ pub struct FeatureBufferTranslator {
     pub lr_hash_mask: u32,
     pub ffm_hash_mask: u32,
     pub transform_executors: feature_transform_executor::TransformExecutors,
+    // Set by translate_context(): feature combos and ffm fields that consist of context namespaces only
+    lr_context_combos: Vec<bool>,
+    pub ffm_context_fields: Vec<bool>,
+}
+
+// Which features translate_() puts into the feature buffer
+#[derive(Clone, Copy, PartialEq)]
+enum TranslatePart {
+    All,
+    Context,
+    WithoutContext,
+}
+
+#[inline(always)]
+fn is_translated(part: TranslatePart, from_context: bool) -> bool {
+    match part {
+        TranslatePart::All => true,
+        TranslatePart::Context => from_context,
+        TranslatePart::WithoutContext => !from_context,
+    }
 }
 
 // A macro that takes care of decoding the individual feature - which can have two different encodings
@@ -80,8 +99,8 @@ macro_rules! feature_reader {
                 let $hash_value: f32 = 1.0;
                 $bl
             } else {
-                let start = ((first_token >> 16) & 0x3fff) as usize; 
-                let end = (first_token & 0xffff) as usize;
+                let start = ((first_token >> 16) & parser::FEATURES_START_MASK) as usize; 
+                let end = (first_token & parser::FEATURES_END_MASK) as usize;
                 if $namespace_descriptor.namespace_format != NamespaceFormat::F32 {
                     for hash_offset in (start..end).step_by(2) {
                         let $hash_index = unsafe {*$record_buffer.get_unchecked(hash_offset)};
@@ -111,8 +130,8 @@ macro_rules! feature_reader_float_namespace {
         let namespace_index = $namespace_descriptor.namespace_index as usize;
         let first_token = unsafe {*$record_buffer.get_unchecked(namespace_index + parser::HEADER_LEN as usize)};
         if $namespace_descriptor.namespace_format == NamespaceFormat::F32 {
-            let start = ((first_token >> 16) & 0x3fff) as usize; 
-            let end = (first_token & 0xffff) as usize;
+            let start = ((first_token >> 16) & parser::FEATURES_START_MASK) as usize; 
+            let end = (first_token & parser::FEATURES_END_MASK) as usize;
             for hash_offset in (start..end).step_by(2) {
                 let $hash_index = unsafe {*$record_buffer.get_unchecked(hash_offset)};
                 let $hash_value:f32 = 1.0;
@@ -143,7 +162,7 @@ impl FeatureBufferTranslator {
         let ffm_hash_mask = ((1 << mi.ffm_bit_precision) -1) ^ dimensions_mask;
 
 
-        let mut fb = FeatureBuffer {
+        let fb = FeatureBuffer {
             label: 0.0,
             example_importance: 1.0,
             example_number: 0,
@@ -162,26 +181,52 @@ impl FeatureBufferTranslator {
                             lr_hash_mask: lr_hash_mask,
                             ffm_hash_mask: ffm_hash_mask, 
                             transform_executors: feature_transform_executor::TransformExecutors::from_namespace_transforms(&mi.transform_namespaces),
+                            lr_context_combos: vec![false; mi.feature_combo_descs.len()],
+                            ffm_context_fields: vec![false; mi.ffm_fields.len()],
         };
         fbt
     }
     
-    pub fn print(&self) -> () {
-        println!("item out {:?}", self.feature_buffer.lr_buffer);
-    }
-    
     
     pub fn translate(&mut self, record_buffer: &[u32], example_number: u64) -> () {
+        self.translate_(record_buffer, example_number, TranslatePart::All);
+    }
+
+    // Translates only the features that come from the context, which is shared by all the examples that follow.
+    // Only transformed namespaces are never treated as context, since they can depend on any other namespace.
+    pub fn translate_context(&mut self, record_buffer: &[u32]) -> () {
+        let from_context = |namespace_descriptor: &NamespaceDescriptor| {
+            namespace_descriptor.namespace_type == NamespaceType::Primitive &&
+            record_buffer[namespace_descriptor.namespace_index as usize + parser::HEADER_LEN as usize] != parser::NO_FEATURES
+        };
+        self.lr_context_combos = self.model_instance.feature_combo_descs.iter()
+                                    .map(|feature_combo_desc| feature_combo_desc.namespace_descriptors.iter().all(from_context))
+                                    .collect();
+        self.ffm_context_fields = self.model_instance.ffm_fields.iter()
+                                    .map(|ffm_field| ffm_field.iter().all(from_context))
+                                    .collect();
+        self.translate_(record_buffer, 0, TranslatePart::Context);
+    }
+
+    // Translates an example that already has the context merged in (VowpalParser::next_vowpal_with_context()),
+    // leaving out the features that translate_context() already translated
+    pub fn translate_with_context(&mut self, record_buffer: &[u32], example_number: u64) -> () {
+        self.translate_(record_buffer, example_number, TranslatePart::WithoutContext);
+    }
+
+    fn translate_(&mut self, record_buffer: &[u32], example_number: u64, part: TranslatePart) -> () {
         {
             let lr_buffer = &mut self.feature_buffer.lr_buffer;
             lr_buffer.truncate(0);
-            self.feature_buffer.label = record_buffer[parser::LABEL_OFFSET] as f32;  // copy label
+            self.feature_buffer.label = f32::from_bits(record_buffer[parser::LABEL_OFFSET]);  // copy label (NAN if there is no label)
             self.feature_buffer.example_importance = f32::from_bits(record_buffer[parser::EXAMPLE_IMPORTANCE_OFFSET]);    
             self.feature_buffer.example_number = example_number;
-            let mut output_len:usize = 0;
             let mut hashes_vec_in : &mut Vec<HashAndValue> = &mut self.hashes_vec_in;
             let mut hashes_vec_out : &mut Vec<HashAndValue> = &mut self.hashes_vec_out;
-            for feature_combo_desc in &self.model_instance.feature_combo_descs {
+            for (combo_index, feature_combo_desc) in self.model_instance.feature_combo_descs.iter().enumerate() {
+                if !is_translated(part, self.lr_context_combos[combo_index]) {
+                    continue;
+                }
                 let feature_combo_weight = feature_combo_desc.weight;
                 // we unroll first iteration of the loop and optimize
                 let num_namespaces:usize = feature_combo_desc.namespace_descriptors.len() ;
@@ -215,7 +260,7 @@ impl FeatureBufferTranslator {
                 }
             }
             // add the constant
-            if self.model_instance.add_constant_feature {
+            if self.model_instance.add_constant_feature && part != TranslatePart::Context {
                     lr_buffer.push(HashAndValue{hash: CONSTANT_HASH & self.lr_hash_mask,
                                                 value: 1.0});
             }
@@ -230,6 +275,9 @@ impl FeatureBufferTranslator {
                 self.feature_buffer.ffm_fields_count = self.model_instance.ffm_fields.len() as u32;    
                 //let feature_len = self.feature_buffer.ffm_fields_count * self.model_instance.ffm_k;
                 for (contra_field_index, ffm_field) in self.model_instance.ffm_fields.iter().enumerate() {
+                    if !is_translated(part, self.ffm_context_fields[contra_field_index]) {
+                        continue;
+                    }
                     for namespace_descriptor in ffm_field {
                         feature_reader!(record_buffer, self.transform_executors, *namespace_descriptor, hash_index, hash_value, {
                                 ffm_buffer.push(HashAndValueAndSeq {hash: hash_index & self.ffm_hash_mask,
@@ -246,6 +294,7 @@ impl FeatureBufferTranslator {
 }
     
 
+#[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
@@ -253,7 +302,7 @@ mod tests {
     use crate::vwmap::{NamespaceType, NamespaceDescriptor, NamespaceFormat};
 
     fn add_header(v2: Vec<u32>) -> Vec<u32> {
-        let mut rr: Vec<u32> = vec![100, 1, 1.0f32.to_bits()];
+        let mut rr: Vec<u32> = vec![100, 1.0f32.to_bits(), 1.0f32.to_bits()];
         rr.extend(v2);
         rr
     }
@@ -455,6 +504,35 @@ mod tests {
         // one more which we dont test
     }
 
+    #[test]
+    fn test_translate_context() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.add_constant_feature = true;
+        mi.feature_combo_descs.push(model_instance::FeatureComboDesc {namespace_descriptors: vec![ns_desc(0)], weight: 1.0});
+        mi.feature_combo_descs.push(model_instance::FeatureComboDesc {namespace_descriptors: vec![ns_desc(1)], weight: 1.0});
+        mi.feature_combo_descs.push(model_instance::FeatureComboDesc {namespace_descriptors: vec![ns_desc(0), ns_desc(1)], weight: 1.0});
+        mi.ffm_fields.push(vec![ns_desc(0)]);
+        mi.ffm_fields.push(vec![ns_desc(0), ns_desc(1)]);
+        mi.ffm_fields.push(vec![ns_desc(1)]);
+        mi.ffm_k = 1;
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        let rb_context = add_header(vec![0xfea, parser::NO_FEATURES]);
+        let rb = add_header(vec![0xfea, 0xfeb]);
+        fbt.translate(&rb, 0);
+        let full = fbt.feature_buffer.clone();
+
+        // Only the combo and the ffm field of namespace 0 are context
+        fbt.translate_context(&rb_context);
+        assert_eq!(fbt.ffm_context_fields, vec![true, false, false]);
+        assert_eq!(fbt.feature_buffer.lr_buffer, vec![HashAndValue {hash: 0xfea, value: 1.0}]);
+        assert_eq!(fbt.feature_buffer.ffm_buffer, vec![HashAndValueAndSeq{hash: 0xfea, value: 1.0, contra_field_index: 0}]);
+
+        fbt.translate_with_context(&rb, 0);
+        assert_eq!(fbt.feature_buffer.lr_buffer, full.lr_buffer[1..].to_vec());
+        assert_eq!(fbt.feature_buffer.ffm_buffer, full.ffm_buffer[1..].to_vec());
+        assert_eq!(fbt.feature_buffer.ffm_fields_count, 3);
+    }
+
     #[test]
     fn test_example_importance() {
         let mut mi = model_instance::ModelInstance::new_empty().unwrap();
@@ -469,6 +547,19 @@ mod tests {
         assert_eq!(fbt.feature_buffer.example_importance, 1.0); // Did example importance get parsed correctly
     }
 
+    #[test]
+    fn test_label() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.add_constant_feature = false;
+        let mut fbt = FeatureBufferTranslator::new(&mi);
+        let rb: Vec<u32> = vec![4, (-0.37f32).to_bits(), 1.0f32.to_bits(), parser::NO_FEATURES];
+        fbt.translate(&rb, 0);
+        assert_eq!(fbt.feature_buffer.label, -0.37);
+        let rb: Vec<u32> = vec![4, parser::NO_LABEL, 1.0f32.to_bits(), parser::NO_FEATURES];
+        fbt.translate(&rb, 0);
+        assert!(fbt.feature_buffer.label.is_nan());
+    }
+
     #[test]
     fn test_single_namespace_float() {
         let mut mi = model_instance::ModelInstance::new_empty().unwrap();
@@ -1,4 +1,3 @@
-use crate::model_instance;
 use crate::parser;
 use crate::vwmap;
 use std::error::Error;
@@ -8,11 +7,9 @@ use std::io::ErrorKind;
 use std::cell::RefCell;
 
 use fasthash::murmur3;
-use serde::{Serialize,Deserialize};
 use dyn_clone::{clone_trait_object, DynClone};
 
 use crate::feature_transform_parser;
-use crate::feature_transform_parser::NamespaceTransforms;
 use crate::feature_transform_implementations::{TransformerBinner, TransformerLogRatioBinner, TransformerCombine, TransformerWeight};
 
 
@@ -35,7 +32,6 @@ pub enum SeedNumber {
     One = 1,
     Two = 2,
     Three = 3,
-    Four = 4,
 }
 
 
@@ -157,7 +153,6 @@ pub struct TransformExecutors {
 impl TransformExecutors {
     pub fn from_namespace_transforms(namespace_transforms: &feature_transform_parser::NamespaceTransforms) -> TransformExecutors{
         let mut executors:Vec<TransformExecutor> = Vec::new();
-        let mut namespaces_to: Vec<ExecutorToNamespace> = Vec::new();
         for transformed_namespace in &namespace_transforms.v {
             let transformed_namespace_executor = TransformExecutor::from_namespace_transform(&transformed_namespace).unwrap();
             executors.push(transformed_namespace_executor);
@@ -194,10 +189,10 @@ clone_trait_object!(FunctionExecutorTrait);
 
 
 
+#[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
-    use crate::parser::{IS_NOT_SINGLE_MASK, MASK31};
     use crate::feature_transform_executor::default_seeds;
 
     fn ns_desc(i: u16) -> vwmap::NamespaceDescriptor {
@@ -15,14 +15,15 @@ use crate::vwmap::{NamespaceType, NamespaceFormat, NamespaceDescriptor};
 
 
 // Basic example of a "full blown" simple FunctionExecutorTrait
+#[allow(dead_code)]
 #[derive(Clone)]
 struct FunctionExampleSqrt {
     from_namespace: ExecutorFromNamespace,
 }
 
 impl FunctionExecutorTrait for FunctionExampleSqrt {
-    fn execute_function(&self, record_buffer: &[u32], to_namespace: &mut ExecutorToNamespace, transform_executors: &TransformExecutors) {
-        feature_reader_float_namespace!(record_buffer, self.from_namespace.namespace_descriptor, hash_index, hash_value, float_value, {
+    fn execute_function(&self, record_buffer: &[u32], to_namespace: &mut ExecutorToNamespace, _transform_executors: &TransformExecutors) {
+        feature_reader_float_namespace!(record_buffer, self.from_namespace.namespace_descriptor, _hash_index, hash_value, float_value, {
             let transformed_float = float_value.sqrt();
             let transformed_int = transformed_float as i32;
             to_namespace.emit_i32::<{SeedNumber::Default as usize}>(transformed_int, hash_value);
@@ -30,8 +31,9 @@ impl FunctionExecutorTrait for FunctionExampleSqrt {
     }
 }
 
+#[allow(dead_code)]
 impl FunctionExampleSqrt {
-    fn create_function(function_name: &str, from_namespaces: &Vec<feature_transform_parser::Namespace>, function_params: &Vec<f32>) -> Result<Box<dyn FunctionExecutorTrait>, Box<dyn Error>> {
+    fn create_function(_function_name: &str, from_namespaces: &Vec<feature_transform_parser::Namespace>, function_params: &Vec<f32>) -> Result<Box<dyn FunctionExecutorTrait>, Box<dyn Error>> {
         // For simplicity of example, we just assert instead of full error reporting
         assert!(function_params.len() == 0);
         assert!(from_namespaces.len() == 1);
@@ -74,8 +76,8 @@ pub struct TransformerBinner {
 }
 
 impl FunctionExecutorTrait for TransformerBinner {
-    fn execute_function(&self, record_buffer: &[u32], to_namespace: &mut ExecutorToNamespace, transform_executors: &TransformExecutors) {
-        feature_reader_float_namespace!(record_buffer, self.from_namespace.namespace_descriptor, hash_index, hash_value, float_value, {
+    fn execute_function(&self, record_buffer: &[u32], to_namespace: &mut ExecutorToNamespace, _transform_executors: &TransformExecutors) {
+        feature_reader_float_namespace!(record_buffer, self.from_namespace.namespace_descriptor, _hash_index, hash_value, float_value, {
             if float_value < self.greater_than {
                 to_namespace.emit_i32::<{SeedNumber::Default as usize}>(float_value as i32, hash_value);
             } else {
@@ -151,9 +153,9 @@ pub struct TransformerLogRatioBinner {
 }
 
 impl FunctionExecutorTrait for TransformerLogRatioBinner {
-    fn execute_function(&self, record_buffer: &[u32], to_namespace: &mut ExecutorToNamespace, transform_executors: &TransformExecutors) {
-        feature_reader_float_namespace!(record_buffer, self.from_namespace1.namespace_descriptor, hash_index1, hash_value1, float_value1, {
-            feature_reader_float_namespace!(record_buffer, self.from_namespace2.namespace_descriptor, hash_index2, hash_value2, float_value2, {
+    fn execute_function(&self, record_buffer: &[u32], to_namespace: &mut ExecutorToNamespace, _transform_executors: &TransformExecutors) {
+        feature_reader_float_namespace!(record_buffer, self.from_namespace1.namespace_descriptor, _hash_index1, hash_value1, float_value1, {
+            feature_reader_float_namespace!(record_buffer, self.from_namespace2.namespace_descriptor, _hash_index2, hash_value2, float_value2, {
 
                 let joint_value = hash_value1 * hash_value2;
                 let val1 = float_value1;
@@ -362,18 +364,13 @@ impl TransformerCombine {
 
 
 
+#[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
     use crate::parser::{IS_NOT_SINGLE_MASK, MASK31};
     use crate::feature_transform_executor::default_seeds;
 
-    fn add_header(v2: Vec<u32>) -> Vec<u32> {
-        let mut rr: Vec<u32> = vec![100, 1, 1.0f32.to_bits()];
-        rr.extend(v2);
-        rr
-    }
-    
     fn nd(start: u32, end: u32) -> u32 {
         return (start << 16) + end;
     }
@@ -402,12 +399,6 @@ mod tests {
             namespace_descriptor: ns_desc(0),
         };
         
-        let to_namespace_empty = ExecutorToNamespace {
-            namespace_descriptor: ns_desc(1),
-            namespace_seeds: default_seeds(1),	// These are precomputed namespace seeds
-            tmp_data: Vec::new(),
-        };
-        
         let result = TransformerBinner::create_function(&(|x, y| x.sqrt() * y), "Blah", &vec![from_namespace], &vec![40., 1.4], false);
         assert!(result.is_err());
 
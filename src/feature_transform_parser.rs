@@ -1,23 +1,17 @@
 //#[macro_use]
 //extern crate nom;
 
-use crate::model_instance;
-use crate::parser;
 use crate::vwmap;
 use std::error::Error;
 use std::io::Error as IOError;
 use std::io::ErrorKind;
 use std::collections::HashMap;
-use std::mem::replace;
 use std::cell::Cell;
-use fasthash::murmur3;
 use serde::{Serialize,Deserialize};
 
 
 use crate::feature_transform_executor;
 
-pub const TRANSFORM_NAMESPACE_MARK: u32 = 1<< 31;
-
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Namespace {
@@ -39,7 +33,6 @@ pub struct NamespaceTransforms {
 }
 
 struct NSStage1Parse {
-    name: String,
     definition: String,
     from_namespaces: Vec<std::string::String>,
     processing: Cell<bool>, 
@@ -67,7 +60,7 @@ impl NamespaceTransformsParser {
         if rr.is_err() {
             return Err(Box::new(IOError::new(ErrorKind::Other, format!("Error parsing {}\n{:?}", s, rr))));            
         }
-        let (_, (to_namespace_verbose, function_name, from_namespaces_verbose, function_parameters)) = rr.unwrap();
+        let (_, (to_namespace_verbose, _function_name, from_namespaces_verbose, _function_parameters)) = rr.unwrap();
 
         // Here we just check for clashes with namespaces from input file
         let namespace_descriptor = vw.map_verbose_to_namespace_descriptor.get(&to_namespace_verbose);
@@ -76,7 +69,6 @@ impl NamespaceTransformsParser {
         }
         
         self.denormalized.insert(to_namespace_verbose.to_owned(), NSStage1Parse {
-                                                                    name: to_namespace_verbose.to_owned(),
                                                                     definition: s.to_string(),
                                                                     from_namespaces: from_namespaces_verbose,
                                                                     processing: Cell::new(false),
@@ -206,10 +198,10 @@ impl NamespaceTransforms {
 
 }
 
-pub fn get_namespace_descriptor(transform_namespaces: &NamespaceTransforms, vw: &vwmap::VwNamespaceMap, namespace_char: char) 
+pub fn get_namespace_descriptor(_transform_namespaces: &NamespaceTransforms, vw: &vwmap::VwNamespaceMap, namespace_char: char) 
     -> Result<vwmap::NamespaceDescriptor, Box<dyn Error>> {
    // Does not support transformed names
-   let namespace_descriptor = match vw.map_vwname_to_namespace_descriptor.get(&vec![namespace_char as u8]) {
+   match vw.map_vwname_to_namespace_descriptor.get(&vec![namespace_char as u8]) {
        Some(namespace_descriptor) => return Ok(*namespace_descriptor),
        None => return Err(Box::new(IOError::new(ErrorKind::Other, format!("Unknown namespace char in command line: {}", namespace_char))))
    };
@@ -217,7 +209,7 @@ pub fn get_namespace_descriptor(transform_namespaces: &NamespaceTransforms, vw:
 
 pub fn get_namespace_descriptor_verbose(transform_namespaces: &NamespaceTransforms, vw: &vwmap::VwNamespaceMap, namespace_verbose: &str) 
     -> Result<vwmap::NamespaceDescriptor, Box<dyn Error>> {
-   let namespace_descriptor = match vw.map_verbose_to_namespace_descriptor.get(namespace_verbose) {
+   match vw.map_verbose_to_namespace_descriptor.get(namespace_verbose) {
        Some(namespace_descriptor) => return Ok(*namespace_descriptor),
        None => {
            // Yes, we do linear search, we only call this couple of times. It's fast enough
@@ -233,17 +225,13 @@ pub fn get_namespace_descriptor_verbose(transform_namespaces: &NamespaceTransfor
 
 
 use nom::IResult;
-use nom::number::complete::be_u16;
 use nom::character::complete;
 use nom::bytes::complete::take_while;
 use nom::AsChar;
 use nom::sequence::tuple;
-use nom::branch;
 use nom::number;
 use nom::character;
 use nom;
-use nom::multi;
-use nom::combinator::complete;
 
 
 pub fn name_char(c:char) -> bool {
@@ -310,10 +298,10 @@ pub fn parse_namespace_statement(input: &str) -> IResult<&str, (String, String,
 
 
 
+#[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
-    use crate::parser::{NO_FEATURES, IS_NOT_SINGLE_MASK, MASK31};
     use crate::vwmap::{NamespaceType, NamespaceFormat, NamespaceDescriptor, VwNamespaceMap};
 
     fn ns_desc(i: u16) -> NamespaceDescriptor {
@@ -369,14 +357,14 @@ C,featureC,f32
         {
             let mut nstp = NamespaceTransformsParser::new();
             let result = nstp.add_transform_namespace(&vw, "featureA=Combine(featureA,featureB)()"); // unknown function
-            let nst = nstp.resolve(&vw).unwrap();
+            nstp.resolve(&vw).unwrap();
             assert!(result.is_err());
             assert_eq!(format!("{:?}", result), "Err(Custom { kind: Other, error: \"To namespace of featureA=Combine(featureA,featureB)() already exists as primitive namespace: \\\"featureA\\\"\" })");
         }
 
         {
             let mut nstp = NamespaceTransformsParser::new();
-            let result = nstp.add_transform_namespace(&vw, "new=Combine(featureA,featureA)()"); // unknown function
+            nstp.add_transform_namespace(&vw, "new=Combine(featureA,featureA)()").unwrap(); // unknown function
             let result = nstp.resolve(&vw);
             assert!(result.is_err());
             assert_eq!(format!("{:?}", result), "Err(Custom { kind: Other, error: \"Using the same from namespace in multiple arguments to a function is not supported: \\\"featureA\\\"\" })");
@@ -511,21 +499,21 @@ C,featureC,f32
         assert_eq!(r.unwrap().1, fv);
 
         let r = parse_namespace_statement("a=sqrt(B)(3,1,2.0)");
-        let (o, rw) = r.unwrap();
+        let (_o, rw) = r.unwrap();
         assert_eq!(rw.0, "a");
         assert_eq!(rw.1, "sqrt");
         assert_eq!(rw.2, vec!["B"]);
         assert_eq!(rw.3, vec![3f32, 1f32, 2.0]);
         
         let r = parse_namespace_statement("abc=sqrt(BDE,CG)(3,1,2.0)");
-        let (o, rw) = r.unwrap();
+        let (_o, rw) = r.unwrap();
         assert_eq!(rw.0, "abc");
         assert_eq!(rw.1, "sqrt");
         assert_eq!(rw.2, vec!["BDE", "CG"]);
         assert_eq!(rw.3, vec![3f32, 1f32, 2.0]);
 
         let r = parse_namespace_statement("a_bcw=s_qrt(_BD_E_,C_G)(3,1,2.0)");
-        let (o, rw) = r.unwrap();
+        let (_o, rw) = r.unwrap();
         assert_eq!(rw.0, "a_bcw");
         assert_eq!(rw.1, "s_qrt");
         assert_eq!(rw.2, vec!["_BD_E_", "C_G"]);
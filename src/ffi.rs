@@ -0,0 +1,428 @@
+// C ABI for embedding prediction into non-Rust services, see include/fw.h
+//
+// Functions return FW_OK (0) on success and FW_ERROR (-1) on failure, in which case
+// fw_last_error() describes what went wrong. Panics are caught and reported as errors,
+// they never unwind into the caller.
+//
+// A model handle is not thread safe: it holds parsing buffers. Use fw_clone() to get a handle
+// for each thread, clones share the weights, so they cost little memory. fw_hogwild_load() loads
+// new weights into new memory and swaps them in for all the clones, like the daemon does.
+
+use std::cell::RefCell;
+use std::error::Error;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::panic;
+use std::ptr;
+use std::slice;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use fasthash::murmur3;
+
+use crate::model_instance;
+use crate::vwmap;
+use crate::parser;
+use crate::feature_buffer;
+use crate::persistence;
+use crate::regressor;
+
+pub const FW_OK: c_int = 0;
+pub const FW_ERROR: c_int = -1;
+
+// Weights are never written to, fw_hogwild_load() replaces the whole model
+struct LoadedModel {
+    mi: model_instance::ModelInstance,
+    vw: vwmap::VwNamespaceMap,
+    re: regressor::Regressor,
+}
+
+// Shared by a handle and all its clones, the generation is bumped when a new model is swapped in
+struct SharedModel {
+    model: Mutex<Arc<LoadedModel>>,
+    generation: AtomicU64,
+}
+
+pub struct FwModel {
+    shared: Arc<SharedModel>,
+    // The model this handle predicts with, until it sees a new generation
+    model: Arc<LoadedModel>,
+    generation: u64,
+    pa: parser::VowpalParser,
+    fbt: feature_buffer::FeatureBufferTranslator,
+    line: Vec<u8>,
+    record: Vec<u32>,
+    namespace_features: Vec<Vec<(u32, f32)>>,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(message));
+}
+
+// Runs f, turning both errors and panics into FW_ERROR
+fn ffi_call<F: FnOnce() -> Result<(), Box<dyn Error>> + panic::UnwindSafe>(f: F) -> c_int {
+    match panic::catch_unwind(f) {
+        Ok(Ok(())) => FW_OK,
+        Ok(Err(e)) => { set_last_error(e.to_string()); FW_ERROR },
+        Err(_) => { set_last_error("Panic inside fw".to_string()); FW_ERROR },
+    }
+}
+
+unsafe fn str_from_c<'a>(s: *const c_char) -> Result<&'a str, Box<dyn Error>> {
+    if s.is_null() {
+        return Err("Null string passed")?;
+    }
+    Ok(CStr::from_ptr(s).to_str()?)
+}
+
+unsafe fn model_from_c<'a>(model: *mut FwModel) -> Result<&'a mut FwModel, Box<dyn Error>> {
+    match model.as_mut() {
+        Some(model) => Ok(model),
+        None => Err("Null model passed")?,
+    }
+}
+
+unsafe fn slice_from_c<'a, T>(p: *const T, len: usize) -> Result<&'a [T], Box<dyn Error>> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+    if p.is_null() {
+        return Err("Null array passed")?;
+    }
+    Ok(slice::from_raw_parts(p, len))
+}
+
+impl FwModel {
+    fn new(shared: Arc<SharedModel>) -> FwModel {
+        let (model, generation) = {
+            let model = shared.model.lock().unwrap();
+            (model.clone(), shared.generation.load(Ordering::SeqCst))
+        };
+        let pa = parser::VowpalParser::new(&model.vw);
+        let fbt = feature_buffer::FeatureBufferTranslator::new(&model.mi);
+        let namespace_features = vec![Vec::new(); model.vw.num_namespaces];
+        FwModel {
+            shared: shared,
+            model: model,
+            generation: generation,
+            pa: pa,
+            fbt: fbt,
+            line: Vec::new(),
+            record: Vec::new(),
+            namespace_features: namespace_features,
+        }
+    }
+
+    // Picks up the model swapped in by fw_hogwild_load(), it has the same structure and namespaces,
+    // so the parser and the translator stay valid. The old model is freed when no handle uses it.
+    fn refresh_model(&mut self) {
+        if self.shared.generation.load(Ordering::SeqCst) != self.generation {
+            let model = self.shared.model.lock().unwrap();
+            self.model = model.clone();
+            self.generation = self.shared.generation.load(Ordering::SeqCst);
+        }
+    }
+
+    fn predict_line(&mut self, line: &[u8]) -> Result<f32, Box<dyn Error>> {
+        self.refresh_model();
+        let buffer = self.pa.parse_example(&mut self.line, line)?;
+        self.fbt.translate(buffer, 0);
+        Ok(self.model.re.predict(&self.fbt.feature_buffer))
+    }
+
+    // Builds the same record as the parser would, but from already hashed features.
+    // All namespaces use the out-of-place encoding: pairs of (hash, value) after the namespace descriptors.
+    fn predict_features(&mut self, namespace_indexes: &[u16], hashes: &[u32], values: &[f32]) -> Result<f32, Box<dyn Error>> {
+        self.refresh_model();
+        for features in self.namespace_features.iter_mut() {
+            features.truncate(0);
+        }
+        for i in 0..namespace_indexes.len() {
+            let namespace_index = namespace_indexes[i] as usize;
+            if namespace_index >= self.namespace_features.len() {
+                return Err(format!("Namespace index out of range: {}", namespace_index))?;
+            }
+            self.namespace_features[namespace_index].push((hashes[i] & parser::MASK31, values[i]));
+        }
+
+        let header_len = (self.model.vw.num_namespaces as u32 * parser::NAMESPACE_DESC_LEN + parser::HEADER_LEN) as usize;
+        self.record.truncate(0);
+        self.record.resize(header_len, parser::NO_FEATURES);
+        self.record[parser::LABEL_OFFSET] = parser::NO_LABEL;
+        self.record[parser::EXAMPLE_IMPORTANCE_OFFSET] = parser::FLOAT32_ONE;
+        for (namespace_index, features) in self.namespace_features.iter().enumerate() {
+            if features.is_empty() {
+                continue;
+            }
+            let start = self.record.len();
+            for (hash, value) in features {
+                self.record.push(*hash);
+                self.record.push(value.to_bits());
+            }
+            let end = self.record.len();
+            if start > parser::FEATURES_START_MASK as usize || end > parser::FEATURES_END_MASK as usize {
+                return Err("Too many features in the example")?;
+            }
+            self.record[namespace_index * parser::NAMESPACE_DESC_LEN as usize + parser::HEADER_LEN as usize] =
+                parser::IS_NOT_SINGLE_MASK | ((start << 16) + end) as u32;
+        }
+        self.record[0] = self.record.len() as u32;
+        self.fbt.translate(&self.record, 0);
+        Ok(self.model.re.predict(&self.fbt.feature_buffer))
+    }
+}
+
+
+/// Loads a regressor in immutable (prediction only) mode. Returns NULL on failure.
+#[no_mangle]
+pub unsafe extern "C" fn fw_load(filename: *const c_char) -> *mut FwModel {
+    let mut model: *mut FwModel = ptr::null_mut();
+    let result = ffi_call(panic::AssertUnwindSafe(|| {
+        let filename = str_from_c(filename)?;
+        let (mi, vw, re) = persistence::new_regressor_from_filename(filename, true)?;
+        let shared = Arc::new(SharedModel {
+            model: Mutex::new(Arc::new(LoadedModel {mi: mi, vw: vw, re: re})),
+            generation: AtomicU64::new(0),
+        });
+        model = Box::into_raw(Box::new(FwModel::new(shared)));
+        Ok(())
+    }));
+    if result != FW_OK {
+        return ptr::null_mut();
+    }
+    model
+}
+
+/// New handle for use in another thread, weights are shared with the original. Returns NULL on failure.
+#[no_mangle]
+pub unsafe extern "C" fn fw_clone(model: *mut FwModel) -> *mut FwModel {
+    let mut clone: *mut FwModel = ptr::null_mut();
+    let result = ffi_call(panic::AssertUnwindSafe(|| {
+        let model = model_from_c(model)?;
+        clone = Box::into_raw(Box::new(FwModel::new(model.shared.clone())));
+        Ok(())
+    }));
+    if result != FW_OK {
+        return ptr::null_mut();
+    }
+    clone
+}
+
+/// Predicts on a single example in vowpal wabbit text format, the trailing newline is optional
+#[no_mangle]
+pub unsafe extern "C" fn fw_predict(model: *mut FwModel, line: *const c_char, prediction: *mut f32) -> c_int {
+    ffi_call(panic::AssertUnwindSafe(|| {
+        let model = model_from_c(model)?;
+        let line = str_from_c(line)?;
+        if prediction.is_null() {
+            return Err("Null prediction pointer passed")?;
+        }
+        *prediction = model.predict_line(line.as_bytes())?;
+        Ok(())
+    }))
+}
+
+/// Computes what the parser would for a feature: the namespace index and the hash of the feature name.
+/// Services can compute these once and then use fw_predict_features(), skipping text formatting and parsing.
+#[no_mangle]
+pub unsafe extern "C" fn fw_feature_hash(model: *mut FwModel,
+                                         namespace_name: *const c_char,
+                                         feature_name: *const c_char,
+                                         namespace_index: *mut u16,
+                                         hash: *mut u32) -> c_int {
+    ffi_call(panic::AssertUnwindSafe(|| {
+        let model = model_from_c(model)?;
+        let namespace = str_from_c(namespace_name)?;
+        let feature = str_from_c(feature_name)?;
+        if namespace_index.is_null() || hash.is_null() {
+            return Err("Null output pointer passed")?;
+        }
+        let namespace_descriptor = match model.model.vw.map_vwname_to_namespace_descriptor.get(namespace.as_bytes()) {
+            Some(namespace_descriptor) => namespace_descriptor,
+            None => return Err(format!("Namespace was not declared in vw_namespace_map.csv: {}", namespace))?,
+        };
+        *namespace_index = namespace_descriptor.namespace_index;
+        *hash = murmur3::hash32_with_seed(feature.as_bytes(), murmur3::hash32(namespace)) & parser::MASK31;
+        Ok(())
+    }))
+}
+
+/// Predicts on pre-parsed features: for each feature its namespace index, hash and value.
+/// Value is the feature weight (1.0 for plain features), or the parsed float for float namespaces.
+#[no_mangle]
+pub unsafe extern "C" fn fw_predict_features(model: *mut FwModel,
+                                             namespace_indexes: *const u16,
+                                             hashes: *const u32,
+                                             values: *const f32,
+                                             len: usize,
+                                             prediction: *mut f32) -> c_int {
+    ffi_call(panic::AssertUnwindSafe(|| {
+        let model = model_from_c(model)?;
+        let namespace_indexes = slice_from_c(namespace_indexes, len)?;
+        let hashes = slice_from_c(hashes, len)?;
+        let values = slice_from_c(values, len)?;
+        if prediction.is_null() {
+            return Err("Null prediction pointer passed")?;
+        }
+        *prediction = model.predict_features(namespace_indexes, hashes, values)?;
+        Ok(())
+    }))
+}
+
+/// Replaces weights with the ones from another regressor with the same model instance, like "hogwild_load" command of the daemon.
+/// Fails when the structure or namespaces of the two models differ.
+/// The new weights are loaded into new memory and then swapped in for all clones, other threads can keep predicting
+/// meanwhile: each prediction uses either the old or the new weights.
+#[no_mangle]
+pub unsafe extern "C" fn fw_hogwild_load(model: *mut FwModel, filename: *const c_char) -> c_int {
+    ffi_call(panic::AssertUnwindSafe(|| {
+        let model = model_from_c(model)?;
+        let filename = str_from_c(filename)?;
+        let current = model.shared.model.lock().unwrap().clone();
+        let (mi, vw, re) = persistence::hogwild_load(&current.mi, &current.vw, filename, true)?;
+        let mut shared_model = model.shared.model.lock().unwrap();
+        *shared_model = Arc::new(LoadedModel {mi: mi, vw: vw, re: re});
+        model.shared.generation.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }))
+}
+
+/// Frees the handle, weights are freed together with the last handle that uses them
+#[no_mangle]
+pub unsafe extern "C" fn fw_free(model: *mut FwModel) {
+    if !model.is_null() {
+        drop(Box::from_raw(model));
+    }
+}
+
+/// Message of the last error in this thread, valid until the next failing call. NULL when there was no error.
+#[no_mangle]
+pub extern "C" fn fw_last_error() -> *const c_char {
+    LAST_ERROR.with(|last_error| match last_error.borrow().as_ref() {
+        Some(message) => message.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::regressor;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_ffi() {
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.optimizer = model_instance::Optimizer::SGD;
+        mi.feature_combo_descs.push(model_instance::FeatureComboDesc {
+                                        namespace_descriptors: vec![vw.map_vwname_to_namespace_descriptor[&b"A".to_vec()]],
+                                        weight: 1.0});
+        mi.feature_combo_descs.push(model_instance::FeatureComboDesc {
+                                        namespace_descriptors: vec![vw.map_vwname_to_namespace_descriptor[&b"B".to_vec()]],
+                                        weight: 1.0});
+        let mut re = regressor::get_regressor_with_weights(&mi);
+        let mut pa = parser::VowpalParser::new(&vw);
+        let mut fbt = feature_buffer::FeatureBufferTranslator::new(&mi);
+        let example = b"1 |A a:2 |B b1 b2\n";
+        fbt.translate(pa.next_vowpal(&mut &example[..]).unwrap(), 0);
+        re.learn(&fbt.feature_buffer, true);
+        re.learn(&fbt.feature_buffer, true);
+        let expected = re.predict(&fbt.feature_buffer);
+        assert!(expected > 0.5);
+
+        let dir = tempdir().unwrap();
+        let path_1 = CString::new(dir.path().join("model_1.fw").to_str().unwrap()).unwrap();
+        let path_2 = CString::new(dir.path().join("model_2.fw").to_str().unwrap()).unwrap();
+        let fresh_re = regressor::get_regressor_with_weights(&mi);
+        persistence::save_regressor_to_filename(path_1.to_str().unwrap(), &mi, &vw, &fresh_re).unwrap();
+        persistence::save_regressor_to_filename(path_2.to_str().unwrap(), &mi, &vw, &re).unwrap();
+
+        unsafe {
+            let missing = CString::new(dir.path().join("missing.fw").to_str().unwrap()).unwrap();
+            assert!(fw_load(missing.as_ptr()).is_null());
+            assert!(CStr::from_ptr(fw_last_error()).to_str().unwrap().starts_with("Cannot open regressor"));
+
+            let model = fw_load(path_1.as_ptr());
+            assert!(!model.is_null());
+            let mut prediction: f32 = 0.0;
+            let line = CString::new("|A a:2 |B b1 b2").unwrap();
+            assert_eq!(fw_predict(model, line.as_ptr(), &mut prediction), FW_OK);
+            assert_eq!(prediction, 0.5);
+
+            // Weights are swapped for all the clones
+            let clone = fw_clone(model);
+            assert_eq!(fw_hogwild_load(model, path_2.as_ptr()), FW_OK);
+            assert_eq!(fw_predict(clone, line.as_ptr(), &mut prediction), FW_OK);
+            assert_eq!(prediction, expected);
+
+            // Pre-parsed features give the same prediction as text
+            let mut namespace_indexes = [0u16; 3];
+            let mut hashes = [0u32; 3];
+            let features = [("A", "a"), ("B", "b1"), ("B", "b2")];
+            for (i, (namespace, feature)) in features.iter().enumerate() {
+                let namespace = CString::new(*namespace).unwrap();
+                let feature = CString::new(*feature).unwrap();
+                assert_eq!(fw_feature_hash(model, namespace.as_ptr(), feature.as_ptr(), &mut namespace_indexes[i], &mut hashes[i]), FW_OK);
+            }
+            let values = [2.0f32, 1.0, 1.0];
+            prediction = 0.0;
+            assert_eq!(fw_predict_features(model, namespace_indexes.as_ptr(), hashes.as_ptr(), values.as_ptr(), 3, &mut prediction), FW_OK);
+            assert_eq!(prediction, expected);
+
+            // Positions of features have to fit in the namespace descriptor
+            for (a_features, b_features) in [(9000, 1), (33000, 0)] {
+                let indexes: Vec<u16> = [namespace_indexes[0]].repeat(a_features).into_iter().chain([namespace_indexes[1]].repeat(b_features)).collect();
+                let hashes = vec![hashes[0]; indexes.len()];
+                let values = vec![1.0f32; indexes.len()];
+                assert_eq!(fw_predict_features(model, indexes.as_ptr(), hashes.as_ptr(), values.as_ptr(), indexes.len(), &mut prediction), FW_ERROR);
+                assert_eq!(CStr::from_ptr(fw_last_error()).to_str().unwrap(), "Too many features in the example");
+            }
+
+            let bad_line = CString::new("|C c").unwrap();
+            assert_eq!(fw_predict(model, bad_line.as_ptr(), &mut prediction), FW_ERROR);
+            assert!(CStr::from_ptr(fw_last_error()).to_str().unwrap().starts_with("Feature name was not predeclared"));
+            let namespace = CString::new("C").unwrap();
+            assert_eq!(fw_feature_hash(model, namespace.as_ptr(), namespace.as_ptr(), &mut namespace_indexes[0], &mut hashes[0]), FW_ERROR);
+            assert_eq!(fw_predict(ptr::null_mut(), line.as_ptr(), &mut prediction), FW_ERROR);
+
+            // Another thread keeps predicting while the weights are reloaded, each prediction is made with one of the models
+            let predicting = fw_clone(model) as usize;
+            let predicting_line = line.clone();
+            let predictions = std::thread::spawn(move || {
+                let mut predictions = Vec::new();
+                for _ in 0..2000 {
+                    let mut prediction: f32 = 0.0;
+                    assert_eq!(fw_predict(predicting as *mut FwModel, predicting_line.as_ptr(), &mut prediction), FW_OK);
+                    predictions.push(prediction);
+                }
+                fw_free(predicting as *mut FwModel);
+                predictions
+            });
+            for i in 0..20 {
+                let path = if i % 2 == 0 {&path_1} else {&path_2};
+                assert_eq!(fw_hogwild_load(model, path.as_ptr()), FW_OK);
+            }
+            for prediction in predictions.join().unwrap() {
+                assert!(prediction == 0.5 || prediction == expected, "{}", prediction);
+            }
+            assert_eq!(fw_predict(clone, line.as_ptr(), &mut prediction), FW_OK);
+            assert_eq!(prediction, expected);
+
+            fw_free(clone);
+            fw_free(model);
+            fw_free(ptr::null_mut());
+        }
+    }
+}
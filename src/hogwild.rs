@@ -0,0 +1,158 @@
+use std::error::Error;
+use std::thread;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use crate::feature_buffer;
+use crate::model_instance;
+use crate::block_loss_functions;
+use crate::metrics;
+use crate::multithread_helpers::{BoxedRegressorTrait};
+
+// Records are sent to workers in batches, so channel synchronization is amortized
+const HOGWILD_BATCH_SIZE: usize = 256;
+
+type RecordBatch = Vec<(u64, Vec<u32>)>;
+
+// Hogwild training: records from a single reader are spread across worker threads that
+// all call learn() on the same regressor, without any locking of the weights.
+// One trainer handles one pass over the data.
+pub struct HogwildTrainer {
+    sender: mpsc::SyncSender<RecordBatch>,
+    workers: Vec<thread::JoinHandle<(metrics::ProgressiveValidation, metrics::HoldoutMetrics)>>,
+    batch: RecordBatch,
+}
+
+struct HogwildWorker {
+    re: BoxedRegressorTrait,
+    fbt: feature_buffer::FeatureBufferTranslator,
+    mi: model_instance::ModelInstance,
+    holdout_selection: metrics::HoldoutSelection,
+    testonly: bool,
+    progressive_validation: metrics::ProgressiveValidation,
+    holdout_metrics: metrics::HoldoutMetrics,
+}
+
+impl HogwildWorker {
+    fn start(mut self, receiver: Arc<Mutex<mpsc::Receiver<RecordBatch>>>) -> (metrics::ProgressiveValidation, metrics::HoldoutMetrics) {
+        loop {
+            // Lock is held only while receiving, not while learning
+            let batch = match receiver.lock().unwrap().recv() {
+                Ok(batch) => batch,
+                Err(_) => break, // sender is gone, pass is over
+            };
+            for (example_num, record) in batch.iter() {
+                self.fbt.translate(record, *example_num);
+                let holdout = self.holdout_selection.is_holdout(*example_num);
+                let prediction = self.re.learn(&self.fbt.feature_buffer, !self.testonly && !holdout);
+                let label = self.fbt.feature_buffer.label;
+                if !label.is_nan() {
+                    let loss = block_loss_functions::example_loss(&self.mi, prediction, label);
+                    if holdout {
+                        self.holdout_metrics.add(loss, prediction, label);
+                    } else {
+                        self.progressive_validation.add(loss, label, prediction);
+                    }
+                }
+            }
+        }
+        (self.progressive_validation, self.holdout_metrics)
+    }
+}
+
+impl HogwildTrainer {
+    pub fn new(threads: u32,
+               re: &BoxedRegressorTrait,
+               fbt: &feature_buffer::FeatureBufferTranslator,
+               mi: &model_instance::ModelInstance,
+               holdout_selection: metrics::HoldoutSelection,
+               testonly: bool) -> Result<HogwildTrainer, Box<dyn Error>> {
+        let (sender, receiver) = mpsc::sync_channel::<RecordBatch>(threads as usize * 4);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let mut workers = Vec::new();
+        for _i in 0..threads {
+            let worker = HogwildWorker {
+                re: re.clone(),
+                fbt: fbt.clone(),
+                mi: mi.clone(),
+                holdout_selection: holdout_selection,
+                testonly: testonly,
+                progressive_validation: metrics::ProgressiveValidation::new(),
+                holdout_metrics: metrics::HoldoutMetrics::new(),
+            };
+            let receiver = Arc::clone(&receiver);
+            workers.push(thread::spawn(move || worker.start(receiver)));
+        }
+        Ok(HogwildTrainer {
+            sender: sender,
+            workers: workers,
+            batch: Vec::with_capacity(HOGWILD_BATCH_SIZE),
+        })
+    }
+
+    pub fn push_record(&mut self, example_num: u64, record: &[u32]) -> Result<(), Box<dyn Error>> {
+        self.batch.push((example_num, record.to_vec()));
+        if self.batch.len() >= HOGWILD_BATCH_SIZE {
+            let batch = std::mem::replace(&mut self.batch, Vec::with_capacity(HOGWILD_BATCH_SIZE));
+            self.sender.send(batch)?;
+        }
+        Ok(())
+    }
+
+    // Sends out the remaining records, waits for workers to finish and returns their combined metrics
+    pub fn finish(self) -> Result<(metrics::ProgressiveValidation, metrics::HoldoutMetrics), Box<dyn Error>> {
+        if !self.batch.is_empty() {
+            self.sender.send(self.batch)?;
+        }
+        drop(self.sender);
+        let mut progressive_validation = metrics::ProgressiveValidation::new();
+        let mut holdout_metrics = metrics::HoldoutMetrics::new();
+        for worker in self.workers {
+            let (pv, hm) = match worker.join() {
+                Ok(result) => result,
+                Err(_) => return Err("Hogwild worker thread panicked")?,
+            };
+            progressive_validation.merge(&pv);
+            holdout_metrics.merge(hm);
+        }
+        Ok((progressive_validation, holdout_metrics))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::regressor;
+    use crate::parser;
+
+    #[test]
+    fn test_hogwild_trainer() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.optimizer = model_instance::Optimizer::SGD;
+        mi.add_constant_feature = true;
+        let re = regressor::get_regressor_with_weights(&mi);
+        let re = BoxedRegressorTrait::new(Box::new(re));
+        let fbt = feature_buffer::FeatureBufferTranslator::new(&mi);
+        let holdout_selection = metrics::HoldoutSelection {holdout_after: Some(901), holdout_period: 0};
+
+        // Only the constant feature: [len, label, importance]
+        let positive = [parser::HEADER_LEN as u32, 1.0f32.to_bits(), parser::FLOAT32_ONE];
+        let mut trainer = HogwildTrainer::new(4, &re, &fbt, &mi, holdout_selection, false).unwrap();
+        for example_num in 1..1001 {
+            trainer.push_record(example_num, &positive).unwrap();
+        }
+        let (pv, hm) = trainer.finish().unwrap();
+        assert_eq!(pv.count(), 900);
+        assert_eq!(hm.count, 100);
+
+        // All threads learned into the same regressor
+        let re = re.try_into_inner().ok().unwrap();
+        let mut fbt = fbt.clone();
+        fbt.translate(&positive, 0);
+        assert!(re.predict(&fbt.feature_buffer) > 0.95);
+    }
+}
@@ -0,0 +1,304 @@
+use std::error::Error;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufWriter, Write};
+use fasthash::murmur3;
+use flate2::read::MultiGzDecoder;
+
+use crate::model_instance;
+use crate::feature_buffer;
+use crate::parser;
+use crate::regressor;
+use crate::vwmap;
+use crate::optimizer;
+use crate::block_lr::BlockLR;
+use crate::block_ffm::BlockFFM;
+use crate::feature_transform_executor;
+use crate::feature_reader;
+use crate::vwmap::{NamespaceType, NamespaceFormat};
+
+// Inverse of hashing, for model inspection (like vowpal's --invert_hash).
+// We go over the text input and remember which feature strings end up in which LR weight and FFM slot.
+// Since hashing is one-way, features from transformed namespaces can only be reported by their hash.
+pub struct InvertHash {
+    mi: model_instance::ModelInstance,
+    transform_executors: feature_transform_executor::TransformExecutors,
+    lr_hash_mask: u32,
+    ffm_hash_mask: u32,
+    namespace_hash_seeds: HashMap<Vec<u8>, (u16, u32)>,    // vwname -> (namespace index, hash seed)
+    namespace_names: HashMap<u16, String>,
+    feature_names: HashMap<(u16, u32), String>,             // (namespace index, feature hash) -> "namespace^feature"
+    // weight index -> set of (feature name, namespace name)
+    lr_names: BTreeMap<u32, BTreeSet<(String, String)>>,
+    ffm_names: BTreeMap<u32, BTreeSet<(String, String)>>,
+}
+
+impl InvertHash {
+    pub fn new(mi: &model_instance::ModelInstance, vw: &vwmap::VwNamespaceMap) -> InvertHash {
+        // Masks have to be exactly the same as the ones used in translation to the feature buffer
+        let fbt = feature_buffer::FeatureBufferTranslator::new(mi);
+        let mut ih = InvertHash {
+            mi: mi.clone(),
+            transform_executors: fbt.transform_executors,
+            lr_hash_mask: fbt.lr_hash_mask,
+            ffm_hash_mask: fbt.ffm_hash_mask,
+            namespace_hash_seeds: HashMap::new(),
+            namespace_names: HashMap::new(),
+            feature_names: HashMap::new(),
+            lr_names: BTreeMap::new(),
+            ffm_names: BTreeMap::new(),
+        };
+        for (vwname, namespace_descriptor) in &vw.map_vwname_to_namespace_descriptor {
+            let vwname_str = String::from_utf8_lossy(vwname).to_string();
+            ih.namespace_hash_seeds.insert(vwname.clone(), (namespace_descriptor.namespace_index, murmur3::hash32(&vwname_str)));
+            ih.namespace_names.insert(namespace_descriptor.namespace_index, vwname_str);
+        }
+        ih
+    }
+
+    fn namespace_name(&self, namespace_descriptor: &vwmap::NamespaceDescriptor) -> String {
+        if namespace_descriptor.namespace_type == NamespaceType::Transformed {
+            self.mi.transform_namespaces.v[namespace_descriptor.namespace_index as usize].to_namespace.namespace_verbose.clone()
+        } else {
+            self.namespace_names.get(&namespace_descriptor.namespace_index).cloned().unwrap_or_default()
+        }
+    }
+
+    fn feature_name(&self, namespace_descriptor: &vwmap::NamespaceDescriptor, hash: u32) -> String {
+        if namespace_descriptor.namespace_type == NamespaceType::Primitive {
+            if let Some(name) = self.feature_names.get(&(namespace_descriptor.namespace_index, hash)) {
+                return name.clone();
+            }
+        }
+        format!("{}^#{}", self.namespace_name(namespace_descriptor), hash)
+    }
+
+    // Remembers feature names of a single line of text input: same tokenization and hashing as in the parser
+    fn collect_feature_names(&mut self, line: &[u8]) {
+        let mut current_namespace: Option<(u16, u32)> = None;
+        let mut current_namespace_name = String::new();
+        for token in line.split(|c| *c == 0x20 || *c == 0x0a).filter(|t| !t.is_empty()) {
+            let name_len = token.iter().position(|c| *c == 0x3a).unwrap_or(token.len()); // 0x3a = ":"
+            if token[0] == 0x7c { // "|"
+                current_namespace = self.namespace_hash_seeds.get(&token[1..name_len]).copied();
+                current_namespace_name = String::from_utf8_lossy(&token[1..name_len]).to_string();
+            } else if let Some((namespace_index, hash_seed)) = current_namespace {
+                let h = murmur3::hash32_with_seed(&token[0..name_len], hash_seed) & parser::MASK31;
+                self.feature_names.entry((namespace_index, h)).or_insert_with(||
+                    format!("{}^{}", current_namespace_name, String::from_utf8_lossy(&token[0..name_len])));
+            }
+        }
+    }
+
+    // Parses the line and records names of all LR and FFM features it produces
+    pub fn add_line(&mut self, pa: &mut parser::VowpalParser, line: &[u8]) -> Result<(), Box<dyn Error>> {
+        let mut input: &[u8] = line;
+        let record_buffer = match pa.next_vowpal(&mut input)? {
+            [] => return Ok(()),
+            record => record,
+        };
+        self.collect_feature_names(line);
+
+        for feature_combo_desc in &self.mi.feature_combo_descs {
+            // This follows FeatureBufferTranslator::translate(), but carries names along with hashes
+            let namespace_descriptor = feature_combo_desc.namespace_descriptors[0];
+            let mut hashes_in: Vec<(u32, String)> = Vec::new();
+            feature_reader!(record_buffer, self.transform_executors, namespace_descriptor, hash_index, _hash_value, {
+                hashes_in.push((hash_index, self.feature_name(&namespace_descriptor, hash_index)));
+            });
+            for namespace_descriptor in &feature_combo_desc.namespace_descriptors[1..] {
+                let mut hashes_out: Vec<(u32, String)> = Vec::new();
+                for (hash, name) in &hashes_in {
+                    let half_hash = hash.overflowing_mul(feature_buffer::VOWPAL_FNV_PRIME).0;
+                    feature_reader!(record_buffer, self.transform_executors, *namespace_descriptor, hash_index, _hash_value, {
+                        hashes_out.push((hash_index ^ half_hash, format!("{}*{}", name, self.feature_name(namespace_descriptor, hash_index))));
+                    });
+                }
+                hashes_in = hashes_out;
+            }
+            let namespace_name = feature_combo_desc.namespace_descriptors.iter()
+                                    .map(|nd| self.namespace_name(nd))
+                                    .collect::<Vec<String>>()
+                                    .join("*");
+            for (hash, name) in hashes_in {
+                self.lr_names.entry(hash & self.lr_hash_mask).or_default().insert((name, namespace_name.clone()));
+            }
+        }
+        if self.mi.add_constant_feature {
+            self.lr_names.entry(feature_buffer::CONSTANT_HASH & self.lr_hash_mask).or_default()
+                            .insert(("Constant".to_string(), "Constant".to_string()));
+        }
+
+        if self.mi.ffm_k > 0 {
+            for ffm_field in &self.mi.ffm_fields {
+                for namespace_descriptor in ffm_field {
+                    feature_reader!(record_buffer, self.transform_executors, *namespace_descriptor, hash_index, _hash_value, {
+                        let name = (self.feature_name(namespace_descriptor, hash_index), self.namespace_name(namespace_descriptor));
+                        self.ffm_names.entry(hash_index & self.ffm_hash_mask).or_default().insert(name);
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Number of LR weights and FFM feature slots that more than one feature name hashes into
+    pub fn collisions(&self) -> (usize, usize) {
+        (self.lr_names.values().filter(|names| names.len() > 1).count(),
+         self.ffm_names.values().filter(|names| names.len() > 1).count())
+    }
+
+    // TSV with columns name, namespace, hash (index of the weight) and weight.
+    // Each FFM feature has an embedding of ffm_k weights for every field, these are named feature[field,k].
+    pub fn write_tsv(&self, re: &mut regressor::Regressor, output: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        // Forward-only regressor has plain weights, regardless of the optimizer used for training
+        let mut re = re.immutable_regressor(&self.mi)?;
+        write!(output, "name\tnamespace\thash\tweight\n")?;
+
+        let block_lr = forward_only_block::<BlockLR<optimizer::OptimizerSGD>>(&mut re, regressor::BLOCK_TYPE_LR)?;
+        for (hash, names) in &self.lr_names {
+            for (name, namespace) in names {
+                write!(output, "{}\t{}\t{}\t{}\n", name, namespace, hash, block_lr.weights[*hash as usize].weight)?;
+            }
+        }
+
+        if self.mi.ffm_k > 0 {
+            let block_ffm = forward_only_block::<BlockFFM<optimizer::OptimizerSGD>>(&mut re, regressor::BLOCK_TYPE_FFM)?;
+            let ffm_k = self.mi.ffm_k;
+            for (hash, names) in &self.ffm_names {
+                for (name, namespace) in names {
+                    for field in 0..self.mi.ffm_fields.len() as u32 {
+                        for k in 0..ffm_k {
+                            let index = hash + field * ffm_k + k;
+                            write!(output, "{}[{},{}]\t{}\t{}\t{}\n", name, field, k, namespace, index, block_ffm.weights[index as usize].weight)?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+
+// Blocks of forward-only regressors always use OptimizerSGD
+fn forward_only_block<B: 'static>(re: &mut regressor::Regressor, block_type: u32) -> Result<&mut B, Box<dyn Error>> {
+    let block = match re.blocks_boxes.iter_mut().find(|block| block.get_block_type() == block_type) {
+        Some(block) => block,
+        None => return Err(format!("Regressor has no {} block", regressor::block_type_name(block_type)))?,
+    };
+    match block.as_any().downcast_mut::<B>() {
+        Some(block) => Ok(block),
+        None => Err(format!("{} block of the regressor is not forward-only", regressor::block_type_name(block_type)))?,
+    }
+}
+
+// Goes over the input file and writes the TSV with names and weights of all the features seen
+pub fn write_invert_hash_to_filename(filename: &str,
+                                     input_filename: &str,
+                                     mi: &model_instance::ModelInstance,
+                                     vw: &vwmap::VwNamespaceMap,
+                                     re: &mut regressor::Regressor) -> Result<(), Box<dyn Error>> {
+    let input = File::open(input_filename)?;
+    let mut bufferred_input: Box<dyn BufRead> = match input_filename.ends_with(".gz") {
+        true =>  Box::new(io::BufReader::new(MultiGzDecoder::new(input))),
+        false => Box::new(io::BufReader::new(input))
+    };
+    let mut pa = parser::VowpalParser::new(vw);
+    let mut ih = InvertHash::new(mi, vw);
+    let mut line: Vec<u8> = Vec::new();
+    loop {
+        line.truncate(0);
+        if bufferred_input.read_until(0x0a, &mut line)? == 0 {
+            break;
+        }
+        ih.add_line(&mut pa, &line)?;
+    }
+
+    let mut output = BufWriter::new(File::create(filename)?);
+    ih.write_tsv(re, &mut output)?;
+    output.flush()?;
+    let (lr_collisions, ffm_collisions) = ih.collisions();
+    println!("invert_hash = {}, hash collisions: {} LR weights, {} FFM features", filename, lr_collisions, ffm_collisions);
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ns_desc(i: u16) -> vwmap::NamespaceDescriptor {
+        vwmap::NamespaceDescriptor {namespace_index: i,
+                                    namespace_type: NamespaceType::Primitive,
+                                    namespace_format: NamespaceFormat::Categorical}
+    }
+
+    #[test]
+    fn test_invert_hash() {
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.add_constant_feature = true;
+        mi.bit_precision = 18;
+        mi.feature_combo_descs.push(model_instance::FeatureComboDesc {namespace_descriptors: vec![ns_desc(0)], weight: 1.0});
+        mi.feature_combo_descs.push(model_instance::FeatureComboDesc {namespace_descriptors: vec![ns_desc(0), ns_desc(1)], weight: 1.0});
+        mi.ffm_k = 2;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_fields.push(vec![ns_desc(0)]);
+        mi.ffm_fields.push(vec![ns_desc(1)]);
+
+        let mut pa = parser::VowpalParser::new(&vw);
+        let mut ih = InvertHash::new(&mi, &vw);
+        let line = b"1 |A a1 a2:0.5 |B b1\n";
+        ih.add_line(&mut pa, line).unwrap();
+        ih.add_line(&mut pa, line).unwrap(); // seeing features again does not duplicate them
+
+        // Names must land on exactly the indexes that the feature buffer translator produces
+        let mut fbt = feature_buffer::FeatureBufferTranslator::new(&mi);
+        let mut input: &[u8] = line;
+        fbt.translate(pa.next_vowpal(&mut input).unwrap(), 0);
+        let lr_hashes: Vec<u32> = fbt.feature_buffer.lr_buffer.iter().map(|hv| hv.hash).collect();
+        let lr_names: Vec<&String> = lr_hashes.iter().map(|h| &ih.lr_names[h].iter().next().unwrap().0).collect();
+        assert_eq!(lr_names, vec!["A^a1", "A^a2", "A^a1*B^b1", "A^a2*B^b1", "Constant"]);
+        assert_eq!(ih.lr_names[&lr_hashes[2]].iter().next().unwrap().1, "A*B");
+        assert_eq!(ih.lr_names.len(), 5);
+        let ffm_names: Vec<&String> = fbt.feature_buffer.ffm_buffer.iter().map(|hv| &ih.ffm_names[&hv.hash].iter().next().unwrap().0).collect();
+        assert_eq!(ffm_names, vec!["A^a1", "A^a2", "B^b1"]);
+        assert_eq!(ih.collisions(), (0, 0));
+
+        let mut re = regressor::get_regressor_with_weights(&mi);
+        fbt.feature_buffer.label = 1.0;
+        re.learn(&fbt.feature_buffer, true);
+        let mut output: Vec<u8> = Vec::new();
+        ih.write_tsv(&mut re, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], "name\tnamespace\thash\tweight");
+        // 5 LR weights, 3 FFM features with 2 fields of 2 dimensions each
+        assert_eq!(lines.len(), 1 + 5 + 3 * 2 * 2);
+        let constant_line = lines.iter().find(|l| l.starts_with("Constant\t")).unwrap();
+        let constant_weight = get_lr_weight(&mut re, &mi, lr_hashes[4]);
+        assert!(constant_weight > 0.0);
+        assert_eq!(*constant_line, format!("Constant\tConstant\t{}\t{}", lr_hashes[4], constant_weight));
+        assert!(lines.iter().any(|l| l.starts_with("B^b1[1,0]\tB\t")));
+        // A regressor that learns with Adagrad is not forward-only, and no regressor has two loss functions
+        let mut mi_adagrad = mi.clone();
+        mi_adagrad.optimizer = model_instance::Optimizer::Adagrad;
+        let mut re_adagrad = regressor::get_regressor_with_weights(&mi_adagrad);
+        assert_eq!(forward_only_block::<BlockLR<optimizer::OptimizerSGD>>(&mut re_adagrad, regressor::BLOCK_TYPE_LR).err().unwrap().to_string(),
+                   "LR block of the regressor is not forward-only");
+        assert_eq!(forward_only_block::<BlockLR<optimizer::OptimizerSGD>>(&mut re, regressor::BLOCK_TYPE_HINGE).err().unwrap().to_string(),
+                   "Regressor has no hinge loss block");
+    }
+
+    fn get_lr_weight(re: &mut regressor::Regressor, mi: &model_instance::ModelInstance, index: u32) -> f32 {
+        let mut re = re.immutable_regressor(mi).unwrap();
+        let block_lr = forward_only_block::<BlockLR<optimizer::OptimizerSGD>>(&mut re, regressor::BLOCK_TYPE_LR).unwrap();
+        block_lr.weights[index as usize].weight
+    }
+}
@@ -0,0 +1,38 @@
+// Fwumious Wabbit as a library.
+// model::Model is the stable API for embedding: load a regressor, predict, learn and save, all in-process.
+// ffi is a C ABI for loading regressors and predicting, built as a cdylib.
+// cmdline, train, serving and persistence are public for the fw binary and the python bindings, but they are
+// internals and can change between releases. The other modules are private to the crate.
+
+pub(crate) mod vwmap;
+pub(crate) mod parser;
+pub(crate) mod model_instance;
+pub(crate) mod feature_buffer;
+pub(crate) mod regressor;
+pub(crate) mod cache;
+pub mod persistence;
+pub mod serving;
+pub(crate) mod serving_http;
+pub(crate) mod serving_metrics;
+pub(crate) mod optimizer;
+pub(crate) mod version;
+pub(crate) mod consts;
+pub(crate) mod block_ffm;
+pub(crate) mod block_lr;
+pub(crate) mod block_loss_functions;
+pub(crate) mod block_helpers;
+pub(crate) mod multithread_helpers;
+pub(crate) mod feature_transform_parser;
+pub(crate) mod feature_transform_executor;
+pub(crate) mod feature_transform_implementations;
+pub(crate) mod shuffle_buffer;
+pub(crate) mod metrics;
+pub(crate) mod hogwild;
+pub(crate) mod parse_pipeline;
+pub(crate) mod invert_hash;
+pub mod model;
+pub mod cmdline;
+pub mod train;
+mod ffi;
+
+pub use model::Model;
@@ -0,0 +1,221 @@
+// Metrics reported during training. All of them are computed from predictions that
+// Regressor::learn() already returns, so they cost no additional forward passes.
+
+
+// Progressive validation: every example is predicted before it is learned from,
+// so average loss over the stream is an honest estimate of generalization.
+// Like vowpal, we report at doubling intervals.
+pub struct ProgressiveValidation {
+    loss_sum: f64,
+    count: u64,
+    since_last_loss_sum: f64,
+    since_last_count: u64,
+    next_report: u64,
+}
+
+impl ProgressiveValidation {
+    pub fn new() -> ProgressiveValidation {
+        ProgressiveValidation {
+            loss_sum: 0.0,
+            count: 0,
+            since_last_loss_sum: 0.0,
+            since_last_count: 0,
+            next_report: 1,
+        }
+    }
+
+    pub fn header() -> String {
+        format!("{:<12} {:<12} {:>12} {:>12} {:>12}", "average", "since", "example", "current", "current")
+        + "\n" + &format!("{:<12} {:<12} {:>12} {:>12} {:>12}", "loss", "last", "counter", "label", "predict")
+    }
+
+    // Returns a line to be printed when we reach the next reporting point
+    pub fn add(&mut self, loss: f32, label: f32, prediction: f32) -> Option<String> {
+        self.loss_sum += loss as f64;
+        self.count += 1;
+        self.since_last_loss_sum += loss as f64;
+        self.since_last_count += 1;
+        if self.count < self.next_report {
+            return None;
+        }
+        let line = format!("{:<12.6} {:<12.6} {:>12} {:>12.4} {:>12.4}",
+                            self.average_loss(),
+                            self.since_last_loss_sum / self.since_last_count as f64,
+                            self.count,
+                            label,
+                            prediction);
+        self.since_last_loss_sum = 0.0;
+        self.since_last_count = 0;
+        self.next_report *= 2;
+        Some(line)
+    }
+
+    pub fn average_loss(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.loss_sum / self.count as f64
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    // Used to combine results of multiple training threads, reporting state is not merged
+    pub fn merge(&mut self, other: &ProgressiveValidation) {
+        self.loss_sum += other.loss_sum;
+        self.count += other.count;
+    }
+}
+
+
+// Exact ROC AUC: keeps all (prediction, label) pairs, so only use it on holdout sets
+pub struct AUC {
+    examples: Vec<(f32, bool)>,
+}
+
+impl AUC {
+    pub fn new() -> AUC {
+        AUC {examples: Vec::new()}
+    }
+
+    // Labels greater than 0 are positive, same as for logistic loss
+    pub fn add(&mut self, prediction: f32, label: f32) {
+        self.examples.push((prediction, label > 0.0));
+    }
+
+    // Mann-Whitney U statistic with tied predictions getting an average rank
+    // None when there are no positive or no negative examples
+    pub fn auc(&mut self) -> Option<f64> {
+        self.examples.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        let mut positives: u64 = 0;
+        let mut positive_rank_sum: f64 = 0.0;
+        let mut i = 0;
+        while i < self.examples.len() {
+            let mut j = i;
+            while j < self.examples.len() && self.examples[j].0 == self.examples[i].0 {
+                j += 1;
+            }
+            // ranks are 1-based, examples i..j share the average of ranks i+1..=j
+            let average_rank = (i + 1 + j) as f64 / 2.0;
+            for example in &self.examples[i..j] {
+                if example.1 {
+                    positives += 1;
+                    positive_rank_sum += average_rank;
+                }
+            }
+            i = j;
+        }
+        let negatives = self.examples.len() as u64 - positives;
+        if positives == 0 || negatives == 0 {
+            return None;
+        }
+        let p = positives as f64;
+        Some((positive_rank_sum - p * (p + 1.0) / 2.0) / (p * negatives as f64))
+    }
+}
+
+
+// Which examples are held out: either all after --holdout_after, or every n-th one (with multiple passes)
+#[derive(Clone, Copy)]
+pub struct HoldoutSelection {
+    pub holdout_after: Option<u64>,
+    pub holdout_period: u64,
+}
+
+impl HoldoutSelection {
+    pub fn is_holdout(&self, example_num: u64) -> bool {
+        match self.holdout_after {
+            Some(holdout_after) => example_num >= holdout_after,
+            None => self.holdout_period > 0 && example_num % self.holdout_period == 0
+        }
+    }
+}
+
+
+// Loss and AUC over held out examples
+pub struct HoldoutMetrics {
+    loss_sum: f64,
+    pub count: u64,
+    pub auc: AUC,
+}
+
+impl HoldoutMetrics {
+    pub fn new() -> HoldoutMetrics {
+        HoldoutMetrics {
+            loss_sum: 0.0,
+            count: 0,
+            auc: AUC::new(),
+        }
+    }
+
+    pub fn add(&mut self, loss: f32, prediction: f32, label: f32) {
+        self.loss_sum += loss as f64;
+        self.count += 1;
+        self.auc.add(prediction, label);
+    }
+
+    pub fn average_loss(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.loss_sum / self.count as f64
+    }
+
+    pub fn merge(&mut self, other: HoldoutMetrics) {
+        self.loss_sum += other.loss_sum;
+        self.count += other.count;
+        self.auc.examples.extend(other.auc.examples);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progressive_validation() {
+        let mut pv = ProgressiveValidation::new();
+        assert!(pv.add(1.0, 1.0, 0.5).is_some()); // 1
+        assert!(pv.add(3.0, 1.0, 0.5).is_some()); // 2
+        assert!(pv.add(1.0, 1.0, 0.5).is_none()); // 3
+        let line = pv.add(3.0, 1.0, 0.5).unwrap(); // 4
+        assert!(line.starts_with("2.000000     2.000000                4"));
+        assert_eq!(pv.average_loss(), 2.0);
+        assert_eq!(pv.count(), 4);
+        for _ in 4..7 {
+            assert!(pv.add(0.0, 0.0, 0.0).is_none());
+        }
+        assert!(pv.add(0.0, 0.0, 0.0).is_some()); // 8
+    }
+
+    #[test]
+    fn test_auc() {
+        let mut auc = AUC::new();
+        assert_eq!(auc.auc(), None);
+        auc.add(0.9, 1.0);
+        assert_eq!(auc.auc(), None);
+        auc.add(0.1, -1.0);
+        assert_eq!(auc.auc(), Some(1.0));
+        auc.add(0.8, 0.0);
+        auc.add(0.2, 1.0);
+        // positive pairs ordered correctly: (0.9 > 0.1), (0.9 > 0.8), (0.2 > 0.1), not (0.2 > 0.8)
+        assert_eq!(auc.auc(), Some(0.75));
+
+        let mut holdout_1 = HoldoutMetrics::new();
+        holdout_1.add(1.0, 0.9, 1.0);
+        let mut holdout_2 = HoldoutMetrics::new();
+        holdout_2.add(2.0, 0.1, 0.0);
+        holdout_1.merge(holdout_2);
+        assert_eq!(holdout_1.count, 2);
+        assert_eq!(holdout_1.average_loss(), 1.5);
+        assert_eq!(holdout_1.auc.auc(), Some(1.0));
+
+        // ties count as half
+        let mut auc = AUC::new();
+        auc.add(0.5, 1.0);
+        auc.add(0.5, 0.0);
+        assert_eq!(auc.auc(), Some(0.5));
+    }
+}
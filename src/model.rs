@@ -0,0 +1,151 @@
+use std::error::Error;
+
+use crate::model_instance;
+use crate::vwmap;
+use crate::regressor;
+use crate::parser;
+use crate::feature_buffer;
+use crate::persistence;
+
+// A trained model, usable directly from Rust code, without the TCP daemon.
+// Examples are lines in vowpal wabbit input format, the same as in --data files.
+pub struct Model {
+    mi: model_instance::ModelInstance,
+    vw: vwmap::VwNamespaceMap,
+    re: regressor::Regressor,
+    pa: parser::VowpalParser,
+    fbt: feature_buffer::FeatureBufferTranslator,
+    example_num: u64,
+    line: Vec<u8>,
+}
+
+impl Model {
+    // Loads the model with optimizer state, so it can keep learning
+    pub fn load(path: &str) -> Result<Model, Box<dyn Error>> {
+        Model::load_from_filename(path, false)
+    }
+
+    // Loads only the weights, which uses less memory, but the model can only predict
+    pub fn load_immutable(path: &str) -> Result<Model, Box<dyn Error>> {
+        Model::load_from_filename(path, true)
+    }
+
+    fn load_from_filename(path: &str, immutable: bool) -> Result<Model, Box<dyn Error>> {
+        let (mi, vw, re) = persistence::new_regressor_from_filename(path, immutable)?;
+        Ok(Model::from_regressor(mi, vw, re))
+    }
+
+    // Wraps a model that is already in memory, for example the one returned by train::train()
+    pub fn from_regressor(mi: model_instance::ModelInstance, vw: vwmap::VwNamespaceMap, re: regressor::Regressor) -> Model {
+        let pa = parser::VowpalParser::new(&vw);
+        let fbt = feature_buffer::FeatureBufferTranslator::new(&mi);
+        Model {
+            mi: mi,
+            vw: vw,
+            re: re,
+            pa: pa,
+            fbt: fbt,
+            example_num: 0,
+            line: Vec::new(),
+        }
+    }
+
+    fn translate(&mut self, example: &str) -> Result<(), Box<dyn Error>> {
+        let buffer = self.pa.parse_example(&mut self.line, example.as_bytes())?;
+        self.example_num += 1;
+        self.fbt.translate(buffer, self.example_num);
+        Ok(())
+    }
+
+    pub fn predict(&mut self, example: &str) -> Result<f32, Box<dyn Error>> {
+        self.translate(example)?;
+        Ok(self.re.predict(&self.fbt.feature_buffer))
+    }
+
+    // Predicts many examples at once, see Regressor::predict_batch()
+    pub fn predict_batch(&mut self, examples: &[&str]) -> Result<Vec<f32>, Box<dyn Error>> {
+        let mut fbs: Vec<feature_buffer::FeatureBuffer> = Vec::with_capacity(examples.len());
+        for example in examples {
+            self.translate(example)?;
+            fbs.push(self.fbt.feature_buffer.clone());
+        }
+        Ok(self.re.predict_batch(&fbs))
+    }
+
+    // Returns the prediction made before the update, examples without a label are only predicted
+    pub fn learn(&mut self, example: &str) -> Result<f32, Box<dyn Error>> {
+        if self.re.immutable {
+            return Err("Model was loaded with load_immutable() and cannot learn")?;
+        }
+        self.translate(example)?;
+        Ok(self.re.learn(&self.fbt.feature_buffer, true))
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        if self.re.immutable {
+            return Err("Model was loaded with load_immutable() and has no optimizer state to save")?;
+        }
+        persistence::save_regressor_to_filename(path, &self.mi, &self.vw, &self.re)
+    }
+
+    pub fn model_instance(&self) -> &model_instance::ModelInstance {
+        &self.mi
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_model_api() {
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.optimizer = model_instance::Optimizer::SGD;
+        mi.feature_combo_descs.push(model_instance::FeatureComboDesc {
+                                        namespace_descriptors: vec![vw.map_vwname_to_namespace_descriptor[&b"A".to_vec()]],
+                                        weight: 1.0});
+        let re = regressor::get_regressor_with_weights(&mi);
+        let dir = tempdir().unwrap();
+        let path_1 = dir.path().join("model_1.fw");
+        let path_1 = path_1.to_str().unwrap();
+        persistence::save_regressor_to_filename(path_1, &mi, &vw, &re).unwrap();
+
+        let mut model = Model::load(path_1).unwrap();
+        assert_eq!(model.predict("|A a").unwrap(), 0.5);
+        assert_eq!(model.learn("1 |A a").unwrap(), 0.5);
+        assert_eq!(model.learn("1 |A a\n").unwrap(), 0.5249792);
+        let p = model.predict("|A a").unwrap();
+        assert!(p > 0.5249792);
+        // Unlabeled examples don't change the model
+        model.learn("|A a").unwrap();
+        assert_eq!(model.predict("|A a").unwrap(), p);
+        assert!(model.predict("|C c").is_err());
+        assert!(model.predict("").is_err());
+        let p_b = model.predict("|A b").unwrap();
+        assert_eq!(model.predict_batch(&["|A a", "|A b"]).unwrap(), vec![p, p_b]);
+        assert!(model.predict_batch(&["|A a", "|C c"]).is_err());
+
+        let path_2 = dir.path().join("model_2.fw");
+        let path_2 = path_2.to_str().unwrap();
+        model.save(path_2).unwrap();
+        let mut model_2 = Model::load(path_2).unwrap();
+        assert_eq!(model_2.predict("|A a").unwrap(), p);
+        assert_eq!(model_2.learn("1 |A a").unwrap(), model.learn("1 |A a").unwrap());
+
+        let mut model_3 = Model::load_immutable(path_2).unwrap();
+        assert_eq!(model_3.predict("|A a").unwrap(), p);
+        assert!(model_3.learn("1 |A a").is_err());
+        assert!(model_3.save(path_1).is_err());
+
+        assert!(Model::load(dir.path().join("missing.fw").to_str().unwrap()).is_err());
+    }
+}
@@ -2,15 +2,12 @@ use std::error::Error;
 use std::io::Error as IOError;
 use std::io::ErrorKind;
 
-use std::io::Read;
-use std::fs::File;
 use serde::{Serialize,Deserialize};//, Deserialize};
-use serde_json::{Value};
+use serde_json::{Value, json};
 
 use crate::vwmap;
 use crate::consts;
 use crate::feature_transform_parser;
-use crate::vwmap::{NamespaceDescriptor, NamespaceType};
 
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -24,6 +21,23 @@ pub struct FeatureComboDesc {
 pub enum Optimizer {
     SGD = 1,
     Adagrad = 2,
+    FTRL = 3,
+    Adam = 4,
+    RMSProp = 5,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Copy)]
+pub enum LossFunction {
+    Logistic = 1,
+    Squared = 2,
+    Hinge = 3,
+    Quantile = 4,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Copy)]
+pub enum Link {
+    Logistic = 1,
+    Identity = 2,
 }
 
 pub type FieldDesc = Vec<vwmap::NamespaceDescriptor>;
@@ -67,17 +81,59 @@ pub struct ModelInstance {
     #[serde(default = "default_f32_zero")]
     pub ffm_power_t: f32,
 
+    #[serde(default = "default_f32_zero")]
+    pub l1: f32,
+    #[serde(default = "default_f32_zero")]
+    pub l2: f32,
+    #[serde(default = "default_f32_zero")]
+    pub ffm_l1: f32,
+    #[serde(default = "default_f32_zero")]
+    pub ffm_l2: f32,
+
     #[serde(default = "default_optimizer_adagrad")]
     pub optimizer: Optimizer,
+    // When not set, FFM block uses the same optimizer as LR block
+    #[serde(default = "default_optimizer_none")]
+    pub ffm_optimizer: Option<Optimizer>,
+    #[serde(default = "default_f32_ftrl_alpha")]
+    pub ftrl_alpha: f32,
+    #[serde(default = "default_f32_ftrl_beta")]
+    pub ftrl_beta: f32,
+    #[serde(default = "default_f32_adam_beta1")]
+    pub adam_beta1: f32,
+    #[serde(default = "default_f32_adam_beta2")]
+    pub adam_beta2: f32,
+    #[serde(default = "default_f32_rmsprop_rho")]
+    pub rmsprop_rho: f32,
+
+    #[serde(default = "default_loss_function_logistic")]
+    pub loss_function: LossFunction,
+    #[serde(default = "default_link_logistic")]
+    pub link: Link,
+    #[serde(default = "default_f32_half")]
+    pub quantile_tau: f32,
+    // Examples consumed when this checkpoint was written (--checkpoint_every), 0 for other models
+    #[serde(default = "default_u64_zero")]
+    pub checkpoint_examples: u64,
     
     pub transform_namespaces: feature_transform_parser::NamespaceTransforms,
     
 }
 
 fn default_u32_zero() -> u32{0}
+fn default_u64_zero() -> u64{0}
 fn default_f32_zero() -> f32{0.0}
 fn default_bool_false() -> bool{false}
+fn default_f32_half() -> f32{0.5}
 fn default_optimizer_adagrad() -> Optimizer{Optimizer::Adagrad}
+fn default_f32_ftrl_alpha() -> f32{0.005}
+fn default_f32_ftrl_beta() -> f32{0.1}
+fn default_optimizer_none() -> Option<Optimizer>{None}
+fn default_f32_adam_beta1() -> f32{0.9}
+fn default_f32_adam_beta2() -> f32{0.999}
+fn default_f32_rmsprop_rho() -> f32{0.9}
+fn default_loss_function_logistic() -> LossFunction{LossFunction::Logistic}
+fn default_link_logistic() -> Link{Link::Logistic}
 
 
 
@@ -104,13 +160,114 @@ impl ModelInstance {
             ffm_init_zero_band: 0.0,
             ffm_init_acc_gradient: 0.0,
             init_acc_gradient: 1.0,
+            l1: 0.0,
+            l2: 0.0,
+            ffm_l1: 0.0,
+            ffm_l2: 0.0,
             optimizer: Optimizer::SGD,
+            ftrl_alpha: 0.005,  // vw default
+            ffm_optimizer: None,
+            ftrl_beta: 0.1,     // vw default
+            adam_beta1: 0.9,
+            adam_beta2: 0.999,
+            rmsprop_rho: 0.9,
+            loss_function: LossFunction::Logistic,
+            link: Link::Logistic,
+            quantile_tau: 0.5,
+            checkpoint_examples: 0,
             transform_namespaces: feature_transform_parser::NamespaceTransforms::new(),
         };
         Ok(mi)
     }
 
-    
+    // Fields that decide the layout of the weights, how examples turn into features and how predictions are made.
+    // Models that differ only in the other fields (learning rates, regularization, ...) can take each other's weights.
+    pub fn check_same_structure(&self, other: &ModelInstance) -> Result<(), Box<dyn Error>> {
+        let structure = |mi: &ModelInstance| -> Vec<(&'static str, Value)> {
+            vec![("bit_precision", json!(mi.bit_precision)),
+                 ("add_constant_feature", json!(mi.add_constant_feature)),
+                 ("feature_combo_descs", json!(mi.feature_combo_descs)),
+                 ("ffm_fields", json!(mi.ffm_fields)),
+                 ("ffm_k", json!(mi.ffm_k)),
+                 ("ffm_bit_precision", json!(mi.ffm_bit_precision)),
+                 ("optimizer", json!(mi.optimizer)),
+                 ("ffm_optimizer", json!(mi.ffm_optimizer)),
+                 ("loss_function", json!(mi.loss_function)),
+                 ("link", json!(mi.link)),
+                 ("transform_namespaces", json!(mi.transform_namespaces))]
+        };
+        for ((name, value), (_, other_value)) in structure(self).iter().zip(structure(other).iter()) {
+            if value != other_value {
+                return Err(format!("Models differ in {}: {} vs {}", name, value, other_value))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Continuing from a saved model (--initial_regressor): learning rates, power_t and --noconstant on the command line
+    // replace the saved ones. Structure arguments are optional, but when given they have to describe the saved model.
+    pub fn override_from_cmdline<'a>(&mut self, cl: &clap::ArgMatches<'a>, vw: &vwmap::VwNamespaceMap) -> Result<(), Box<dyn Error>> {
+        let structure_arguments = ["bit_precision", "keep", "interactions", "linear", "ffm_field", "ffm_field_verbose", "ffm_k",
+                                   "ffm_bit_precision", "sgd", "adaptive", "ftrl", "optimizer", "ffm_optimizer", "loss_function",
+                                   "link", "transform"];
+        if structure_arguments.iter().any(|argument| cl.is_present(argument)) {
+            let mut cmdline_mi = ModelInstance::new_from_cmdline(cl, vw)?;
+            // --noconstant is overridden below
+            cmdline_mi.add_constant_feature = self.add_constant_feature;
+            if let Err(e) = self.check_same_structure(&cmdline_mi) {
+                return Err(format!("Command line arguments don't match the structure of --initial_regressor: {}", e))?;
+            }
+        }
+        // These are kept in the model and cannot be changed, repeating the saved value is fine
+        let fixed_arguments = [("l1", self.l1), ("l2", self.l2), ("ffm_l1", self.ffm_l1), ("ffm_l2", self.ffm_l2),
+                               ("ftrl_alpha", self.ftrl_alpha), ("ftrl_beta", self.ftrl_beta), ("adam_beta1", self.adam_beta1),
+                               ("adam_beta2", self.adam_beta2), ("rmsprop_rho", self.rmsprop_rho)];
+        for (argument, saved) in fixed_arguments.iter() {
+            if let Some(val) = cl.value_of(argument) {
+                let val: f32 = val.parse()?;
+                if val != *saved {
+                    return Err(format!("--{} cannot be changed when continuing from --initial_regressor, the model has {}, got {}", argument, saved, val))?;
+                }
+            }
+        }
+        if cl.is_present("noconstant") {
+            self.add_constant_feature = false;
+        }
+
+        if let Some(val) = cl.value_of("learning_rate") {
+            self.learning_rate = val.parse()?;
+        }
+        if let Some(val) = cl.value_of("ffm_learning_rate") {
+            self.ffm_learning_rate = val.parse()?;
+        } else if cl.is_present("learning_rate") {
+            self.ffm_learning_rate = self.learning_rate;
+        }
+        if let Some(val) = cl.value_of("minimum_learning_rate") {
+            self.minimum_learning_rate = val.parse()?;
+        }
+        if let Some(val) = cl.value_of("power_t") {
+            self.power_t = val.parse()?;
+        }
+        if let Some(val) = cl.value_of("ffm_power_t") {
+            self.ffm_power_t = val.parse()?;
+        } else if cl.is_present("power_t") {
+            self.ffm_power_t = self.power_t;
+        }
+        self.check_learning_parameters()
+    }
+
+    fn check_learning_parameters(&self) -> Result<(), Box<dyn Error>> {
+        let parameters = [("learning_rate", self.learning_rate), ("ffm_learning_rate", self.ffm_learning_rate),
+                          ("minimum_learning_rate", self.minimum_learning_rate), ("power_t", self.power_t),
+                          ("ffm_power_t", self.ffm_power_t)];
+        for (name, value) in parameters.iter() {
+            if !(value.is_finite() && *value >= 0.0) {
+                return Err(Box::new(IOError::new(ErrorKind::Other, format!("--{} has to be a non-negative number, got {}", name, value))))
+            }
+        }
+        Ok(())
+    }
+
     pub fn create_feature_combo_desc(&self, vw: &vwmap::VwNamespaceMap, s: &str) -> Result<FeatureComboDesc, Box<dyn Error>> {
 
         let vsplit: Vec<&str> = s.split(":").collect(); // We use : as a delimiter for weight
@@ -143,7 +300,7 @@ impl ModelInstance {
             let weight_str = vsplit[1];
             combo_weight = match weight_str.parse() {
                Ok(x) => x,  
-               Err(y) => return Err(Box::new(IOError::new(ErrorKind::Other, format!("Could not parse the value of a feature combination: {}", weight_str))))
+               Err(_y) => return Err(Box::new(IOError::new(ErrorKind::Other, format!("Could not parse the value of a feature combination: {}", weight_str))))
             }
         } else if vsplit.len() > 2 {
             return Err(Box::new(IOError::new(ErrorKind::Other, format!("Verbose features cannot have \":\" as part of their names: \"{:?}\"", s))))
@@ -320,21 +477,54 @@ impl ModelInstance {
             mi.ffm_power_t = mi.power_t;
         }
         
+        if let Some(val) = cl.value_of("loss_function") {
+            mi.loss_function = match val {
+                "logistic" => LossFunction::Logistic,
+                "squared" => LossFunction::Squared,
+                "hinge" => LossFunction::Hinge,
+                "quantile" => LossFunction::Quantile,
+                _ => return Err(Box::new(IOError::new(ErrorKind::Other, format!("--loss_function only supports 'logistic', 'squared', 'hinge' and 'quantile'"))))
+            };
+        }
+
+        // Logistic loss outputs probabilities by default, other loss functions output raw predictions (like vowpal)
         if let Some(val) = cl.value_of("link") {
-            if val != "logistic" {
-                return Err(Box::new(IOError::new(ErrorKind::Other, format!("--link only supports 'logistic'"))))
-            }            
+            mi.link = match val {
+                "logistic" => Link::Logistic,
+                "identity" => Link::Identity,
+                _ => return Err(Box::new(IOError::new(ErrorKind::Other, format!("--link only supports 'logistic' and 'identity'"))))
+            };
+        } else if mi.loss_function != LossFunction::Logistic {
+            mi.link = Link::Identity;
+        }
+
+        if let Some(val) = cl.value_of("quantile_tau") {
+            if mi.loss_function != LossFunction::Quantile {
+                return Err(Box::new(IOError::new(ErrorKind::Other, format!("--quantile_tau can only be used with --loss_function quantile"))))
+            }
+            mi.quantile_tau = val.parse()?;
+            if mi.quantile_tau <= 0.0 || mi.quantile_tau >= 1.0 {
+                return Err(Box::new(IOError::new(ErrorKind::Other, format!("--quantile_tau has to be between 0.0 and 1.0, passed: {}", mi.quantile_tau))))
+            }
         }
-        if let Some(val) = cl.value_of("loss_function") {
-            if val != "logistic" {
-                return Err(Box::new(IOError::new(ErrorKind::Other, format!("--loss_function only supports 'logistic'"))))
-            }            
+
+        if let Some(val) = cl.value_of("l1") {
+            mi.l1 = val.parse()?;
         }
         if let Some(val) = cl.value_of("l2") {
-            let v2:f32 = val.parse()?;
-            if v2.abs() > 0.00000001 {
-                return Err(Box::new(IOError::new(ErrorKind::Other, format!("--l2 can only be 0.0"))))
-            }
+            mi.l2 = val.parse()?;
+        }
+        if let Some(val) = cl.value_of("ffm_l1") {
+            mi.ffm_l1 = val.parse()?;
+        }
+        if let Some(val) = cl.value_of("ffm_l2") {
+            mi.ffm_l2 = val.parse()?;
+        }
+        if mi.l1 < 0.0 || mi.l2 < 0.0 || mi.ffm_l1 < 0.0 || mi.ffm_l2 < 0.0 {
+            return Err(Box::new(IOError::new(ErrorKind::Other, format!("Regularization strengths (--l1, --l2, --ffm_l1, --ffm_l2) cannot be negative"))))
+        }
+        if vwcompat && (mi.l1 != 0.0 || mi.l2 != 0.0 || mi.ffm_l1 != 0.0 || mi.ffm_l2 != 0.0) {
+            return Err(Box::new(IOError::new(ErrorKind::Other, "--l1, --l2, --ffm_l1 and --ffm_l2 are not supported in --vwcompat mode, regularization is implemented differently than in vowpal")))
         }
 
         if cl.is_present("noconstant") {
@@ -350,6 +540,54 @@ impl ModelInstance {
             mi.optimizer = Optimizer::Adagrad;
         }
 
+        if cl.is_present("ftrl") {
+            if vwcompat {
+                return Err(Box::new(IOError::new(ErrorKind::Other, "--ftrl is not supported in --vwcompat mode")))
+            }
+            mi.optimizer = Optimizer::FTRL;
+        }
+
+        if let Some(val) = cl.value_of("optimizer") {
+            mi.optimizer = parse_optimizer(val)?;
+        }
+
+        if let Some(val) = cl.value_of("ffm_optimizer") {
+            mi.ffm_optimizer = Some(parse_optimizer(val)?);
+        }
+
+        if vwcompat && (mi.optimizer != Optimizer::SGD && mi.optimizer != Optimizer::Adagrad || mi.ffm_optimizer.is_some()) {
+            return Err(Box::new(IOError::new(ErrorKind::Other, "Only sgd and adagrad optimizers are supported in --vwcompat mode")))
+        }
+
+        if let Some(val) = cl.value_of("ftrl_alpha") {
+            mi.ftrl_alpha = val.parse()?;
+            if mi.ftrl_alpha <= 0.0 {
+                return Err(Box::new(IOError::new(ErrorKind::Other, format!("--ftrl_alpha has to be positive, got {}", mi.ftrl_alpha))))
+            }
+        }
+
+        if let Some(val) = cl.value_of("ftrl_beta") {
+            mi.ftrl_beta = val.parse()?;
+            if mi.ftrl_beta < 0.0 {
+                return Err(Box::new(IOError::new(ErrorKind::Other, format!("--ftrl_beta cannot be negative, got {}", mi.ftrl_beta))))
+            }
+        }
+
+        if let Some(val) = cl.value_of("adam_beta1") {
+            mi.adam_beta1 = val.parse()?;
+        }
+        if let Some(val) = cl.value_of("adam_beta2") {
+            mi.adam_beta2 = val.parse()?;
+        }
+        if let Some(val) = cl.value_of("rmsprop_rho") {
+            mi.rmsprop_rho = val.parse()?;
+        }
+        for (name, val) in [("--adam_beta1", mi.adam_beta1), ("--adam_beta2", mi.adam_beta2), ("--rmsprop_rho", mi.rmsprop_rho)].iter() {
+            if *val < 0.0 || *val >= 1.0 {
+                return Err(Box::new(IOError::new(ErrorKind::Other, format!("{} has to be in [0, 1), got {}", name, val))))
+            }
+        }
+
         
         
         Ok(mi)
@@ -392,12 +630,24 @@ impl ModelInstance {
     */
 }
 
+fn parse_optimizer(s: &str) -> Result<Optimizer, Box<dyn Error>> {
+    match s {
+        "sgd" => Ok(Optimizer::SGD),
+        "adagrad" => Ok(Optimizer::Adagrad),
+        "ftrl" => Ok(Optimizer::FTRL),
+        "adam" => Ok(Optimizer::Adam),
+        "rmsprop" => Ok(Optimizer::RMSProp),
+        _ => Err(Box::new(IOError::new(ErrorKind::Other, format!("Unknown optimizer: {} (supported: sgd, adagrad, ftrl, adam, rmsprop)", s))))
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
-    
+    use crate::vwmap::NamespaceDescriptor;
+
     fn ns_desc(i: u16) -> NamespaceDescriptor {
         NamespaceDescriptor {namespace_index: i, 
                              namespace_type: vwmap::NamespaceType::Primitive,
@@ -497,6 +747,86 @@ C,featureC
         
     }
 
+    #[test]
+    fn test_check_same_structure() {
+        let mi = ModelInstance::new_empty().unwrap();
+        let mut mi_2 = mi.clone();
+        mi_2.learning_rate = 0.1;
+        mi_2.l2 = 1.0;
+        assert!(mi.check_same_structure(&mi_2).is_ok());
+        mi_2.ffm_k = 4;
+        assert_eq!(mi.check_same_structure(&mi_2).unwrap_err().to_string(), "Models differ in ffm_k: 0 vs 4");
+        let mut mi_3 = mi.clone();
+        mi_3.feature_combo_descs.push(FeatureComboDesc {namespace_descriptors: vec![ns_desc(0)], weight: 1.0});
+        assert!(mi.check_same_structure(&mi_3).is_err());
+    }
+
+    #[test]
+    fn test_vwcompat_regularization() {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\nB,featureB\n").unwrap();
+        let vwcompat = vec!["fw", "--vwcompat", "--keep", "A", "--hash", "all", "--sgd"];
+        assert!(ModelInstance::new_from_cmdline(&crate::cmdline::parse_from(vwcompat.clone()).unwrap(), &vw).is_ok());
+        for regularization in ["--l1", "--l2", "--ffm_l1", "--ffm_l2"] {
+            let mut args = vwcompat.clone();
+            args.extend(vec![regularization, "0.1"]);
+            assert_eq!(ModelInstance::new_from_cmdline(&crate::cmdline::parse_from(args).unwrap(), &vw).unwrap_err().to_string(),
+                       "--l1, --l2, --ffm_l1 and --ffm_l2 are not supported in --vwcompat mode, regularization is implemented differently than in vowpal");
+        }
+    }
+
+    #[test]
+    fn test_override_from_cmdline() {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\nB,featureB\n").unwrap();
+        let structure = vec!["--keep", "A", "--interactions", "AB", "--ffm_field", "A", "--ffm_field", "B", "--ffm_k", "2", "--adaptive"];
+        let args = |extra: Vec<&'static str>| {
+            let mut args = vec!["fw"];
+            args.extend(extra);
+            crate::cmdline::parse_from(args).unwrap()
+        };
+        let mut learning = structure.clone();
+        learning.extend(vec!["-l", "0.1", "--power_t", "0.5"]);
+        let saved = ModelInstance::new_from_cmdline(&args(learning), &vw).unwrap();
+
+        // Only learning parameters, the other ones (and the ffm ones not given) follow them like in new_from_cmdline()
+        let mut mi = saved.clone();
+        mi.override_from_cmdline(&args(vec!["-l", "0.05", "--minimum_learning_rate", "0.001"]), &vw).unwrap();
+        assert_eq!((mi.learning_rate, mi.ffm_learning_rate, mi.minimum_learning_rate), (0.05, 0.05, 0.001));
+        assert_eq!((mi.power_t, mi.ffm_power_t), (0.5, 0.5));
+        assert!(mi.add_constant_feature);
+        // Learning parameters that cannot change, and invalid values
+        assert_eq!(saved.clone().override_from_cmdline(&args(vec!["-l", "0.05", "--l2", "1.0"]), &vw).unwrap_err().to_string(),
+                   "--l2 cannot be changed when continuing from --initial_regressor, the model has 0, got 1");
+        assert!(saved.clone().override_from_cmdline(&args(vec!["--l2", "0.0", "--ftrl_alpha", "0.005"]), &vw).is_ok());
+        assert_eq!(saved.clone().override_from_cmdline(&args(vec!["--power_t=-0.5"]), &vw).unwrap_err().to_string(),
+                   "--power_t has to be a non-negative number, got -0.5");
+        assert!(saved.clone().override_from_cmdline(&args(vec!["--ffm_learning_rate", "NaN"]), &vw).is_err());
+
+        // The same structure arguments as the saved model
+        let mut mi = saved.clone();
+        let mut same = structure.clone();
+        same.extend(vec!["--ffm_learning_rate", "0.01", "--power_t", "0.25"]);
+        mi.override_from_cmdline(&args(same), &vw).unwrap();
+        assert_eq!((mi.learning_rate, mi.ffm_learning_rate), (0.1, 0.01));
+        assert_eq!((mi.power_t, mi.ffm_power_t), (0.25, 0.25));
+        assert!(saved.check_same_structure(&mi).is_ok());
+
+        // Different or partial structure arguments
+        let mut different = structure.clone();
+        different[9] = "4";
+        assert_eq!(saved.clone().override_from_cmdline(&args(different), &vw).unwrap_err().to_string(),
+                   "Command line arguments don't match the structure of --initial_regressor: Models differ in ffm_k: 2 vs 4");
+        assert!(saved.clone().override_from_cmdline(&args(vec!["--keep", "A", "-l", "0.05"]), &vw).is_err());
+        // The constant feature can be turned off, with or without the structure arguments
+        let mut mi = saved.clone();
+        mi.override_from_cmdline(&args(vec!["--noconstant"]), &vw).unwrap();
+        assert!(!mi.add_constant_feature);
+        let mut mi = saved.clone();
+        let mut noconstant = structure.clone();
+        noconstant.push("--noconstant");
+        mi.override_from_cmdline(&args(noconstant), &vw).unwrap();
+        assert!(!mi.add_constant_feature);
+    }
+
 
 
 
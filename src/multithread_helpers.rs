@@ -45,11 +45,8 @@ impl <T:Sized>Drop for UnsafelySharableTrait<T> {
             // we are called before reference is removed, so we need to decide if to drop it or not
             let count = Arc::<Mutex<PhantomData<u32>>>::strong_count(&self.reference_count) - 1;
             if count == 0 {
-                let box_to_be_dropped = ManuallyDrop::take(&mut self.content);
                 // Now this means that the content will be dropped
-                println!("Dropping BoxedRegressorTrait!");
-            } else {
-                println!("Not dropping BoxedRegressorTrait as there are still {} references!", count);
+                let _box_to_be_dropped = ManuallyDrop::take(&mut self.content);
             }
         }
         
@@ -65,6 +62,21 @@ impl <T:Sized + 'static> UnsafelySharableTrait<T> {
             reference_count: Arc::new(Mutex::new(std::marker::PhantomData{})),
         }
     }
+
+    // Gives back the content, but only when this is the last remaining reference
+    pub fn try_into_inner(mut self) -> Result<T, UnsafelySharableTrait<T>> {
+        if Arc::<Mutex<PhantomData<u32>>>::strong_count(&self.reference_count) != 1 {
+            return Err(self);
+        }
+        unsafe {
+            let content = ManuallyDrop::take(&mut self.content);
+            // We can't move out of a type that implements Drop, so we read the reference count out and forget self
+            let reference_count = std::ptr::read(&self.reference_count);
+            mem::forget(self);
+            drop(reference_count);
+            Ok(content)
+        }
+    }
 }
 
 // Non-generalized implementation
@@ -77,12 +89,10 @@ impl BoxedRegressorTrait {
             // Double deref here sounds weird, but you got to know that dyn Trait and Box<dyn Trait> are the same thing, just box owns it.
             // And you can get dyn Trait content, but you can't get box content (directly)
             let r2: Box<Regressor> = mem::transmute(& *self.content.deref().deref());
-            let ret = BoxedRegressorTrait{
+            BoxedRegressorTrait{
                 content: ManuallyDrop::new(r2),
                 reference_count: self.reference_count.clone()        
-            };
-            println!("References to object: {}", Arc::<Mutex<PhantomData<u32>>>::strong_count(&ret.reference_count));
-            ret
+            }
         }
     }
 }
@@ -1,14 +1,63 @@
 
 use std::marker::PhantomData;
 
+use crate::model_instance;
+
+
+// Hyperparameters that only some of the optimizers use
+#[derive(Clone, Copy, Debug)]
+pub struct OptimizerExtraParams {
+    pub ftrl_alpha: f32,
+    pub ftrl_beta: f32,
+    pub adam_beta1: f32,
+    pub adam_beta2: f32,
+    pub rmsprop_rho: f32,
+    pub l1: f32,
+    pub l2: f32,
+}
+
+impl OptimizerExtraParams {
+    // l1 and l2 are passed separately, since LR and FFM blocks have their own
+    pub fn new(mi: &model_instance::ModelInstance, l1: f32, l2: f32) -> OptimizerExtraParams {
+        OptimizerExtraParams {
+            ftrl_alpha: mi.ftrl_alpha,
+            ftrl_beta: mi.ftrl_beta,
+            adam_beta1: mi.adam_beta1,
+            adam_beta2: mi.adam_beta2,
+            rmsprop_rho: mi.rmsprop_rho,
+            l1: l1,
+            l2: l2,
+        }
+    }
+}
 
 pub trait OptimizerTrait : std::clone::Clone {
     type PerWeightStore: std::clone::Clone;
     fn new() -> Self;
     fn init(&mut self, learning_rate: f32, power_t: f32, initial_acc_gradient: f32);
+    // Returns true when optimizer takes over L1/L2 regularization, so the block must not regularize the gradient
+    fn init_extra(&mut self, _params: &OptimizerExtraParams) -> bool {
+        false
+    }
     unsafe fn calculate_update(&self, gradient: f32, data: &mut Self::PerWeightStore) -> f32;
     fn initial_data(&self) -> Self::PerWeightStore;
     fn get_name() -> &'static str;
+    // Identifies the layout of the per-weight data in regressor files, so values must never change
+    fn get_tag() -> u32;
+}
+
+// Name of the optimizer with the given tag, for error messages about regressor files
+pub fn name_from_tag(tag: u32) -> String {
+    let names = [(OptimizerSGD::get_tag(), "SGD"),
+                 (OptimizerAdagradFlex::get_tag(), "Adagrad"),
+                 (OptimizerFTRL::get_tag(), "FTRL"),
+                 (OptimizerAdam::get_tag(), "Adam"),
+                 (OptimizerRMSProp::get_tag(), "RMSProp")];
+    match names.iter().find(|(t, _)| *t == tag) {
+        Some((_, name)) => name.to_string(),
+        None if tag == 0 => "none".to_string(),
+        None => format!("unknown ({})", tag),
+    }
 }
 
 /******************* SGD **************************/
@@ -24,6 +73,10 @@ impl OptimizerTrait for OptimizerSGD {
     fn get_name() -> &'static str {
         "SGD"
     }
+
+    fn get_tag() -> u32 {
+        1
+    }
     
     fn new() -> Self {
         OptimizerSGD{learning_rate: 0.0}
@@ -60,6 +113,10 @@ impl OptimizerTrait for OptimizerAdagradFlex {
     fn get_name() -> &'static str {
         "AdagradFlex"
     }
+
+    fn get_tag() -> u32 {
+        2
+    }
     type PerWeightStore = f32;
 
     fn new() -> Self {
@@ -107,6 +164,11 @@ impl OptimizerTrait for OptimizerAdagradLUT {
     fn get_name() -> &'static str {
         "AdagradLUT"
     }
+
+    // Same per-weight data as AdagradFlex, so models can switch between them
+    fn get_tag() -> u32 {
+        OptimizerAdagradFlex::get_tag()
+    }
     type PerWeightStore = f32;
 
     fn new() -> Self {
@@ -155,8 +217,196 @@ impl OptimizerTrait for OptimizerAdagradLUT {
 
 
 
+/***************** FTRL-Proximal ******************/
+// McMahan et al., "Ad Click Prediction: a View from the Trenches", Algorithm 1
+// Per-coordinate learning rate is alpha / (beta + sqrt(n)) and L1/L2 are applied in closed form,
+// which gives exact zeros for weights whose accumulated gradient is not stronger than L1.
+// Weight is a function of (z, n), so we return the difference between new and old FTRL weight.
+// That way the update is correct also for blocks that start from randomly initialized weights (FFM).
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct FTRLPerWeightStore {
+    pub z: f32,
+    pub n: f32,
+}
+
+#[derive(Clone)]
+pub struct OptimizerFTRL {
+    alpha: f32,
+    beta: f32,
+    l1: f32,
+    l2: f32,
+}
+
+impl OptimizerFTRL {
+    #[inline(always)]
+    fn weight(&self, data: &FTRLPerWeightStore) -> f32 {
+        if data.z.abs() <= self.l1 {
+            0.0
+        } else {
+            - (data.z - data.z.signum() * self.l1) / ((self.beta + data.n.sqrt()) / self.alpha + self.l2)
+        }
+    }
+}
+
+impl OptimizerTrait for OptimizerFTRL {
+    fn get_name() -> &'static str {
+        "FTRL"
+    }
+
+    fn get_tag() -> u32 {
+        4
+    }
+    type PerWeightStore = FTRLPerWeightStore;
+
+    fn new() -> Self {
+        OptimizerFTRL{alpha: 0.0, beta: 0.0, l1: 0.0, l2: 0.0}
+    } 
+
+    fn init(&mut self, _learning_rate: f32, _power_t: f32, _initial_acc_gradient: f32) {
+    }
+
+    fn init_extra(&mut self, params: &OptimizerExtraParams) -> bool {
+        self.alpha = params.ftrl_alpha;
+        self.beta = params.ftrl_beta;
+        self.l1 = params.l1;
+        self.l2 = params.l2;
+        true
+    }
+
+    #[inline(always)]
+    unsafe fn calculate_update(&self, gradient: f32, data: &mut Self::PerWeightStore) -> f32 {
+        // Our gradients point in the direction of the update, FTRL is formulated with the gradient of the loss
+        let g = -gradient;
+        let old_weight = self.weight(data);
+        let new_n = data.n + g * g;
+        let sigma = (new_n.sqrt() - data.n.sqrt()) / self.alpha;
+        data.z += g - sigma * old_weight;
+        data.n = new_n;
+        return self.weight(data) - old_weight;
+    }
+
+    fn initial_data(&self) -> Self::PerWeightStore {
+        FTRLPerWeightStore{z: 0.0, n: 0.0}
+    }
+
+}
+
+
+
+/***************** Adam ******************/
+// Kingma & Ba, "Adam: A Method for Stochastic Optimization"
+// Since updates are sparse, each weight keeps its own step counter for bias correction.
+// Unlike Adagrad, effective learning rate does not decay over time, which suits non-stationary streams.
+
+pub const ADAM_EPSILON:f32 = 1e-8;
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct AdamPerWeightStore {
+    pub m: f32,
+    pub v: f32,
+    pub t: u32,
+}
+
+#[derive(Clone)]
+pub struct OptimizerAdam {
+    learning_rate: f32,
+    beta1: f32,
+    beta2: f32,
+}
+
+impl OptimizerTrait for OptimizerAdam {
+    fn get_name() -> &'static str {
+        "Adam"
+    }
+
+    fn get_tag() -> u32 {
+        5
+    }
+    type PerWeightStore = AdamPerWeightStore;
+
+    fn new() -> Self {
+        OptimizerAdam{learning_rate: 0.0, beta1: 0.0, beta2: 0.0}
+    } 
+
+    fn init(&mut self, learning_rate: f32, _power_t: f32, _initial_acc_gradient: f32) {
+        self.learning_rate = learning_rate;
+    }
+
+    fn init_extra(&mut self, params: &OptimizerExtraParams) -> bool {
+        self.beta1 = params.adam_beta1;
+        self.beta2 = params.adam_beta2;
+        false
+    }
+
+    #[inline(always)]
+    unsafe fn calculate_update(&self, gradient: f32, data: &mut Self::PerWeightStore) -> f32 {
+        data.m = self.beta1 * data.m + (1.0 - self.beta1) * gradient;
+        data.v = self.beta2 * data.v + (1.0 - self.beta2) * gradient * gradient;
+        data.t = data.t.saturating_add(1);
+        let t = data.t.min(i32::MAX as u32) as i32;
+        let m_hat = data.m / (1.0 - self.beta1.powi(t));
+        let v_hat = data.v / (1.0 - self.beta2.powi(t));
+        return self.learning_rate * m_hat / (v_hat.sqrt() + ADAM_EPSILON);
+    }
+
+    fn initial_data(&self) -> Self::PerWeightStore {
+        AdamPerWeightStore{m: 0.0, v: 0.0, t: 0}
+    }
+
+}
+
+
+/***************** RMSProp ******************/
+// Exponentially decaying average of squared gradients instead of Adagrad's ever growing sum
+
+#[derive(Clone)]
+pub struct OptimizerRMSProp {
+    learning_rate: f32,
+    rho: f32,
+}
+
+impl OptimizerTrait for OptimizerRMSProp {
+    fn get_name() -> &'static str {
+        "RMSProp"
+    }
+
+    fn get_tag() -> u32 {
+        6
+    }
+    type PerWeightStore = f32;
+
+    fn new() -> Self {
+        OptimizerRMSProp{learning_rate: 0.0, rho: 0.0}
+    } 
 
+    fn init(&mut self, learning_rate: f32, _power_t: f32, _initial_acc_gradient: f32) {
+        self.learning_rate = learning_rate;
+    }
+
+    fn init_extra(&mut self, params: &OptimizerExtraParams) -> bool {
+        self.rho = params.rmsprop_rho;
+        false
+    }
+
+    #[inline(always)]
+    unsafe fn calculate_update(&self, gradient: f32, data: &mut Self::PerWeightStore) -> f32 {
+        *data = self.rho * *data + (1.0 - self.rho) * gradient * gradient;
+        return self.learning_rate * gradient / ((*data).sqrt() + ADAM_EPSILON);
+    }
 
+    fn initial_data(&self) -> Self::PerWeightStore {
+        0.0
+    }
+
+}
+
+
+
+
+#[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
@@ -256,6 +506,75 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_ftrl() {
+        let mut l = OptimizerFTRL::new();
+        l.init(0.0, 0.0, 0.0);
+        let mut params = OptimizerExtraParams::new(&model_instance::ModelInstance::new_empty().unwrap(), 0.0, 0.0);
+        params.ftrl_alpha = 0.1;
+        params.ftrl_beta = 1.0;
+        assert_eq!(l.init_extra(&params), true);
+        unsafe {
+            let mut acc = l.initial_data();
+            // first step: z = -0.5, n = 0.25, w = 0.5 / ((1.0 + 0.5) / 0.1) 
+            let p = l.calculate_update(0.5, &mut acc);
+            assert_eq!(p, 0.033333335);
+            assert_eq!(acc.n, 0.25);
+            assert_eq!(acc.z, -0.5);
+
+            // zero gradient does not move the weight
+            let p = l.calculate_update(0.0, &mut acc);
+            assert_eq!(p, 0.0);
+        }
+
+        // With L1, weight stays at exactly zero until accumulated gradient is stronger than L1
+        params.l1 = 1.0;
+        l.init_extra(&params);
+        unsafe {
+            let mut acc = l.initial_data();
+            let p = l.calculate_update(0.5, &mut acc);
+            assert_eq!(p, 0.0);
+            let p = l.calculate_update(0.5, &mut acc);
+            assert_eq!(p, 0.0);
+            let p = l.calculate_update(0.5, &mut acc);
+            assert!(p > 0.0);
+        }
+    }
+
+    #[test]
+    fn test_adam() {
+        let mut l = OptimizerAdam::new();
+        l.init(0.01, 0.0, 0.0);
+        let params = OptimizerExtraParams::new(&model_instance::ModelInstance::new_empty().unwrap(), 0.0, 0.0);
+        assert_eq!(l.init_extra(&params), false);
+        unsafe {
+            let mut acc = l.initial_data();
+            // With bias correction first step is learning_rate * sign(gradient)
+            let p = l.calculate_update(0.5, &mut acc);
+            assert_eq!(p, 0.01);
+            assert_eq!(acc.t, 1);
+            let p = l.calculate_update(-0.5, &mut acc);
+            assert_eq!(p, -0.00052631414);
+            assert_eq!(acc.t, 2);
+        }
+    }
+
+    #[test]
+    fn test_rmsprop() {
+        let mut l = OptimizerRMSProp::new();
+        l.init(0.01, 0.0, 0.0);
+        let params = OptimizerExtraParams::new(&model_instance::ModelInstance::new_empty().unwrap(), 0.0, 0.0);
+        assert_eq!(l.init_extra(&params), false);
+        unsafe {
+            let mut acc = l.initial_data();
+            let p = l.calculate_update(0.5, &mut acc);
+            assert_eq!(acc, 0.025000006);
+            assert_eq!(p, 0.03162277);
+            let p = l.calculate_update(0.0, &mut acc);
+            assert_eq!(p, 0.0);
+        }
+    }
+
 
 }
 
@@ -0,0 +1,208 @@
+use std::error::Error;
+use std::io::BufRead;
+use std::thread;
+use std::sync::mpsc;
+
+use crate::parser;
+
+// Number of input lines that travel together through the pipeline
+const PARSE_BATCH_LINES: usize = 1024;
+// How many batches can be in flight per parser thread, this bounds memory use
+const PARSE_BATCHES_IN_FLIGHT: usize = 4;
+
+// Raw lines, or an error from reading the input
+type LinesBatch = Result<Vec<u8>, String>;
+
+// Parsed records of a batch are concatenated, each record starts with its length, just like in the cache.
+// If parsing fails, records before the failing line are still delivered, followed by the error.
+struct ParsedBatch {
+    records: Vec<u32>,
+    error: Option<String>,
+}
+
+// Text parsing pipeline: a reader thread reads (and decompresses) raw lines and hands out batches
+// round-robin to parser threads. We collect parsed batches in the same round-robin order,
+// so the example order is exactly the same as when parsing inline.
+pub struct ParsePipeline {
+    receivers: Vec<mpsc::Receiver<ParsedBatch>>,
+    next_receiver: usize,
+    batch: Vec<u32>,
+    position: usize,
+    pending_error: Option<String>,
+    eof: bool,
+}
+
+impl ParsePipeline {
+    pub fn new(mut input: Box<dyn BufRead + Send>, pa: &parser::VowpalParser, parse_threads: u32) -> ParsePipeline {
+        let mut senders: Vec<mpsc::SyncSender<LinesBatch>> = Vec::new();
+        let mut receivers: Vec<mpsc::Receiver<ParsedBatch>> = Vec::new();
+        for _i in 0..parse_threads {
+            let (lines_sender, lines_receiver) = mpsc::sync_channel::<LinesBatch>(PARSE_BATCHES_IN_FLIGHT);
+            let (parsed_sender, parsed_receiver) = mpsc::sync_channel::<ParsedBatch>(PARSE_BATCHES_IN_FLIGHT);
+            let mut pa = pa.clone();
+            thread::spawn(move || {
+                for lines in lines_receiver.iter() {
+                    let parsed = match lines {
+                        Ok(lines) => parse_batch(&mut pa, &lines),
+                        Err(e) => ParsedBatch {records: Vec::new(), error: Some(e)},
+                    };
+                    let failed = parsed.error.is_some();
+                    if parsed_sender.send(parsed).is_err() || failed {
+                        break;
+                    }
+                }
+            });
+            senders.push(lines_sender);
+            receivers.push(parsed_receiver);
+        }
+
+        thread::spawn(move || {
+            let mut next_sender = 0;
+            loop {
+                let mut lines: Vec<u8> = Vec::new();
+                let mut num_lines = 0;
+                let mut read_error = None;
+                while num_lines < PARSE_BATCH_LINES {
+                    match input.read_until(0x0a, &mut lines) {
+                        Ok(0) => break,
+                        Ok(_) => num_lines += 1,
+                        Err(e) => {
+                            read_error = Some(format!("Error reading input: {}", e));
+                            break;
+                        }
+                    }
+                }
+                let done = num_lines < PARSE_BATCH_LINES;
+                if !lines.is_empty() {
+                    if senders[next_sender].send(Ok(lines)).is_err() {
+                        break;
+                    }
+                    next_sender = (next_sender + 1) % senders.len();
+                }
+                if let Some(e) = read_error {
+                    // Goes through the next parser in line, so it reaches the consumer after all the records read so far
+                    let _ = senders[next_sender].send(Err(e));
+                    break;
+                }
+                if done {
+                    break; // dropping the senders tells parsers there is no more input
+                }
+            }
+        });
+
+        ParsePipeline {
+            receivers: receivers,
+            next_receiver: 0,
+            batch: Vec::new(),
+            position: 0,
+            pending_error: None,
+            eof: false,
+        }
+    }
+
+    // Same contract as VowpalParser::next_vowpal(): empty slice means end of input
+    pub fn next_record(&mut self) -> Result<&[u32], Box<dyn Error>> {
+        while self.position >= self.batch.len() {
+            if let Some(e) = self.pending_error.take() {
+                self.eof = true;
+                return Err(e)?;
+            }
+            if self.eof {
+                return Ok(&[]);
+            }
+            match self.receivers[self.next_receiver].recv() {
+                Ok(parsed) => {
+                    self.batch = parsed.records;
+                    self.pending_error = parsed.error;
+                    self.position = 0;
+                    self.next_receiver = (self.next_receiver + 1) % self.receivers.len();
+                },
+                Err(_) => { // all batches were consumed
+                    self.eof = true;
+                }
+            }
+        }
+        let record_len = self.batch[self.position] as usize;
+        let record = &self.batch[self.position..self.position + record_len];
+        self.position += record_len;
+        Ok(record)
+    }
+}
+
+fn parse_batch(pa: &mut parser::VowpalParser, lines: &Vec<u8>) -> ParsedBatch {
+    let mut input: &[u8] = &lines[..];
+    let mut parsed = ParsedBatch {records: Vec::new(), error: None};
+    loop {
+        match pa.next_vowpal(&mut input) {
+            Ok([]) => break,
+            Ok(record) => parsed.records.extend_from_slice(record),
+            Err(e) => {
+                parsed.error = Some(e.to_string());
+                break;
+            }
+        }
+    }
+    parsed
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vwmap;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_parse_pipeline_preserves_order() {
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut input_text = String::new();
+        for i in 0..5000 {
+            input_text.push_str(&format!("{} |A a{} |B b\n", i, i));
+        }
+
+        // Reference: inline parsing
+        let mut pa = parser::VowpalParser::new(&vw);
+        let mut reference: Vec<Vec<u32>> = Vec::new();
+        let mut input = Cursor::new(input_text.clone().into_bytes());
+        loop {
+            match pa.next_vowpal(&mut input).unwrap() {
+                [] => break,
+                record => reference.push(record.to_vec()),
+            }
+        }
+        assert_eq!(reference.len(), 5000);
+
+        let mut pp = ParsePipeline::new(Box::new(Cursor::new(input_text.into_bytes())), &pa, 3);
+        let mut i = 0;
+        loop {
+            match pp.next_record().unwrap() {
+                [] => break,
+                record => assert_eq!(record, &reference[i][..]),
+            }
+            i += 1;
+        }
+        assert_eq!(i, 5000);
+        // End of input is sticky
+        assert_eq!(pp.next_record().unwrap(), &[] as &[u32]);
+    }
+
+    #[test]
+    fn test_parse_pipeline_error() {
+        let vw_map_string = r#"
+A,featureA
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let pa = parser::VowpalParser::new(&vw);
+        let input_text = "1 |A a\n1x |A a\n";
+        let mut pp = ParsePipeline::new(Box::new(Cursor::new(input_text.as_bytes().to_vec())), &pa, 2);
+        // Records before the failing one are still delivered
+        assert_eq!(pp.next_record().unwrap().len(), 4);
+        let result = pp.next_record();
+        assert!(result.is_err());
+        assert_eq!(format!("{}", result.err().unwrap()), "Failed parsing label: 1x");
+    }
+}
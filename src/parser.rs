@@ -16,8 +16,11 @@ pub const EXAMPLE_IMPORTANCE_OFFSET:usize = 2;
 pub const IS_NOT_SINGLE_MASK : u32 = 1u32 << 31;
 pub const MASK31: u32 = !IS_NOT_SINGLE_MASK;
 pub const NO_FEATURES: u32= IS_NOT_SINGLE_MASK; // null is just an exact IS_NOT_SINGLE_MASK
-pub const NO_LABEL: u32 = 0xff;
+pub const NO_LABEL: u32 = 0x7fc00000;    // f32::NAN.to_bits()
 pub const FLOAT32_ONE: u32 = 1065353216;  // 1.0f32.to_bits()
+// Namespaces with more than one feature point to them in the record with (start << 16) + end
+pub const FEATURES_START_MASK: u32 = 0x3fff;
+pub const FEATURES_END_MASK: u32 = 0xffff;
 
 
 
@@ -36,6 +39,16 @@ pub struct FlushCommand;  // Parser returns FlushCommand to signal flush message
 pub struct HogwildLoadCommand { // Parser returns Hogwild Load as a command  
     pub filename: String,
 }
+#[derive(Debug)]
+pub struct LoadModelCommand { // Like HogwildLoadCommand, but the model may have a different structure
+    pub filename: String,
+}
+#[derive(Debug)]
+pub struct StatsCommand;  // Parser returns StatsCommand when the client asks for serving metrics
+#[derive(Debug)]
+pub struct ContextCommand { // Parser returns "context" command with the rest of the line, which is parsed as an example
+    pub line: Vec<u8>,
+}
 
 
 impl Error for FlushCommand {}
@@ -52,11 +65,32 @@ impl fmt::Display for HogwildLoadCommand {
     }
 }
 
+impl Error for LoadModelCommand {}
+impl fmt::Display for LoadModelCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Not really an error: a \"load_model\" command from client to load: {}", self.filename)
+    }
+}
+
+impl Error for StatsCommand {}
+impl fmt::Display for StatsCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Not really an error: a \"stats\" command from client")
+    }
+}
+
+impl Error for ContextCommand {}
+impl fmt::Display for ContextCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Not really an error: a \"context\" command from client: {}", String::from_utf8_lossy(&self.line))
+    }
+}
+
 
 /* 
 organization of records buffer 
 (u32) length of the output record
-(u32) label
+(f32) label (NAN when there is no label)
 (f32) Example importance (default: 1.0)
 (union_u u32)[number of features], where:
     -- if the most significant bit is zero
@@ -88,10 +122,6 @@ impl VowpalParser {
         rr
     }
     
-    pub fn print(&self) -> () {
-        println!("item out {:?}", self.output_buffer);
-    }
-
     #[inline(always)]
     pub fn parse_float_or_error(&self, i_start: usize, i_end :usize, error_str: &str) -> Result<f32, Box<dyn Error>> {
         unsafe {
@@ -114,6 +144,9 @@ impl VowpalParser {
     // This is a very very slow implementation, but it's ok, this is called extremely infrequently to decode a command
     pub fn parse_cmd(&self, i_start: usize, rowlen :usize) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
         let mut o: Vec<Vec<u8>> = Vec::new();
+        // Newline is not a part of the last argument
+        let mut rowlen = rowlen;
+        while rowlen > i_start && (self.tmp_read_buf[rowlen - 1] == 0x0a || self.tmp_read_buf[rowlen - 1] == 0x20) {rowlen -= 1;}
         let mut i_end = i_start;
         while i_end < rowlen {
             let mut out_vec : Vec<u8> = Vec::new();
@@ -149,13 +182,39 @@ impl VowpalParser {
 
                 // first token is a label or "flush" command
                 match *p.add(0) {
-                    0x31 => self.output_buffer[LABEL_OFFSET] = 1,    // 1
-                    0x2d => self.output_buffer[LABEL_OFFSET] = 0,    // -1
                     0x7c => self.output_buffer[LABEL_OFFSET] = NO_LABEL, // when first character is |, this means there is no label
+                    0x2b | 0x2d | 0x2e | 0x30..=0x39 => {   // "+", "-", "." or a digit: a real-valued label
+                        while i_end < rowlen1 && *p.add(i_end) != 0x20 && *p.add(i_end) != 0x0a {i_end += 1;}
+                        let label = self.parse_float_or_error(0, i_end, "Failed parsing label")?;
+                        // NaN is reserved for examples without a label
+                        if label.is_nan() {
+                            return Err(Box::new(IOError::new(ErrorKind::Other, format!("Label cannot be NaN: {}", String::from_utf8_lossy(&self.tmp_read_buf[0..i_end])))));
+                        }
+                        self.output_buffer[LABEL_OFFSET] = label.to_bits();
+                    },
                     _ => {
                         // "flush" ascii 66, 6C, 75, 73, 68
                         if rowlen1 >= 5 && *p.add(0) == 0x66  && *p.add(1) == 0x6C && *p.add(2) == 0x75 && *p.add(3) == 0x73 && *p.add(4) == 0x68 {
                             return Err(Box::new(FlushCommand))
+                        } else if self.tmp_read_buf.starts_with(b"stats") && self.tmp_read_buf[5..rowlen1].iter().all(|c| *c == 0x20 || *c == 0x0a) {
+                            return Err(Box::new(StatsCommand))
+                        } else if self.tmp_read_buf.starts_with(b"context") && (rowlen1 == 7 || self.tmp_read_buf[7] == 0x20 || self.tmp_read_buf[7] == 0x0a) {
+                            // Features of the context are shared by all examples that follow, they are parsed separately by the caller
+                            let mut i_start = 7;
+                            while i_start < rowlen1 && self.tmp_read_buf[i_start] == 0x20 {i_start += 1;}
+                            let mut line: Vec<u8> = self.tmp_read_buf[i_start..].to_vec();
+                            if line.last() != Some(&0x0a) {
+                                line.push(0x0a);
+                            }
+                            return Err(Box::new(ContextCommand{line: line}));
+                        } else if self.tmp_read_buf.starts_with(b"load_model ") {
+                            let vecs = self.parse_cmd(0, rowlen1)?;
+                            if vecs.len() == 2 {
+                                let filename = String::from_utf8_lossy(&vecs[1]);
+                                return Err(Box::new(LoadModelCommand{filename: filename.to_string()}));
+                            } else {
+                                return Err(Box::new(IOError::new(ErrorKind::Other, format!("Cannot parse an example"))))
+                            }
                         } else if rowlen1 >= "hogwild_load ".len() {
                             // THIS IS SLOW, BUT IT IS CALLED VERY RARELY
                             // IF WE WILL AVE COMMANDS CALLED MORE FREQUENTLY, WE WILL NEED A FASTER IMPLEMENTATION
@@ -297,6 +356,54 @@ impl VowpalParser {
             Ok(&self.output_buffer)
         }
 
+    // Raw text of the last line that was read
+    pub fn last_line(&self) -> &[u8] {
+        &self.tmp_read_buf
+    }
+
+    // Parses a single example given in memory (Model API, C ABI), the trailing newline is optional.
+    // line is a buffer for the example with the newline added, kept by the caller to avoid allocations.
+    pub fn parse_example(&mut self, line: &mut Vec<u8>, example: &[u8]) -> Result<&[u32], Box<dyn Error>> {
+        line.truncate(0);
+        line.extend_from_slice(example);
+        if line.last() != Some(&0x0a) {
+            line.push(0x0a);
+        }
+        match self.next_vowpal(&mut &line[..])? {
+            [] => Err("Empty example")?,
+            buffer => Ok(buffer),
+        }
+    }
+
+    // Parses the next example and adds the namespaces of the context record to it (see ContextCommand).
+    // Examples cannot have features in namespaces that are already in the context.
+    pub fn next_vowpal_with_context(&mut self, input_bufread: &mut impl BufRead, context_buffer: &[u32]) -> Result<&[u32], Box<dyn Error>> {
+        if self.next_vowpal(input_bufread)?.is_empty() {
+            return Ok(&[]);
+        }
+        for namespace_index in 0..self.vw_map.num_namespaces {
+            let offset = namespace_index * NAMESPACE_DESC_LEN as usize + HEADER_LEN as usize;
+            let context_token = context_buffer[offset];
+            if context_token == NO_FEATURES {
+                continue;
+            }
+            if self.output_buffer[offset] != NO_FEATURES {
+                return Err(Box::new(IOError::new(ErrorKind::Other, format!("Namespace is already in the context: {}", self.vw_map.vw_source.entries[namespace_index].namespace_vwname))));
+            }
+            if (context_token & IS_NOT_SINGLE_MASK) == 0 {
+                self.output_buffer[offset] = context_token;
+            } else {
+                let start = ((context_token >> 16) & FEATURES_START_MASK) as usize;
+                let end = (context_token & FEATURES_END_MASK) as usize;
+                let bufpos_namespace_start = self.output_buffer.len();
+                self.output_buffer.extend_from_slice(&context_buffer[start..end]);
+                self.output_buffer[offset] = IS_NOT_SINGLE_MASK | (((bufpos_namespace_start<<16) + self.output_buffer.len()) as u32);
+            }
+        }
+        self.output_buffer[0] = self.output_buffer.len() as u32;
+        Ok(&self.output_buffer)
+    }
+
 }
 
 
@@ -307,6 +414,8 @@ mod tests {
     use vwmap;
     use super::*;
     use std::io::Cursor;
+
+    const FLOAT32_MINUS_ONE: u32 = 3212836864;  // (-1.0f32).to_bits()
         
     fn nd(start: u32, end: u32) -> u32 {
         return (start << 16) + end;
@@ -330,14 +439,14 @@ C,featureC
         let mut rr = VowpalParser::new(&vw);
         // we test a single record, single namespace
         let mut buf = str_to_cursor("1 |A a\n");
-        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [6,  1, FLOAT32_ONE,  
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [6,  FLOAT32_ONE, FLOAT32_ONE,  
                                                         2988156968 & MASK31, 
                                                         NO_FEATURES, 
                                                         NO_FEATURES]);
  
         // we test a single record, single namespace, space at the end
         let mut buf = str_to_cursor("1 |A a \n");
-        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [6,  1, FLOAT32_ONE,  
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [6,  FLOAT32_ONE, FLOAT32_ONE,  
                                                         2988156968 & MASK31, 
                                                         NO_FEATURES, 
                                                         NO_FEATURES]);
@@ -345,14 +454,14 @@ C,featureC
 
         // we test a single record, single namespace, space after label
         let mut buf = str_to_cursor("1  |A a\n");
-        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [6,  1, FLOAT32_ONE,  
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [6,  FLOAT32_ONE, FLOAT32_ONE,  
                                                         2988156968 & MASK31, 
                                                         NO_FEATURES, 
                                                         NO_FEATURES]);
                                                         
         // we test a single record, single namespace, space between namespace and label
         let mut buf = str_to_cursor("1 |A  a\n");
-        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [6,  1, FLOAT32_ONE,  
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [6,  FLOAT32_ONE, FLOAT32_ONE,  
                                                         2988156968 & MASK31, 
                                                         NO_FEATURES, 
                                                         NO_FEATURES]);
@@ -362,13 +471,13 @@ C,featureC
                                                          
                                                         
         let mut buf = str_to_cursor("-1 |B b\n");
-        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [6, 0, FLOAT32_ONE,
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [6, FLOAT32_MINUS_ONE, FLOAT32_ONE,
                                                         NO_FEATURES, 
                                                         2422381320 & MASK31, 
                                                         NO_FEATURES]);
         // single namespace with two features
         let mut buf = str_to_cursor("1 |A a b\n");
-        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [10, 1, FLOAT32_ONE,  
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [10, FLOAT32_ONE, FLOAT32_ONE,  
                                                         nd(6,10) | IS_NOT_SINGLE_MASK, 	// |A
                                                         NO_FEATURES, 				// |B 
                                                         NO_FEATURES, 				// |C
@@ -376,14 +485,14 @@ C,featureC
                                                         3529656005 & MASK31, FLOAT32_ONE]); // |A b
         // two namespaces
         let mut buf = str_to_cursor("-1 |A a |B b\n");
-        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [6, 0, FLOAT32_ONE,
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [6, FLOAT32_MINUS_ONE, FLOAT32_ONE,
                                                         2988156968 & MASK31, 
                                                         2422381320 & MASK31, 
                                                         NO_FEATURES]);
 
         // two namespaces, double space
         let mut buf = str_to_cursor("-1 |A a  |B b\n");
-        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [6, 0, FLOAT32_ONE,
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [6, FLOAT32_MINUS_ONE, FLOAT32_ONE,
                                                         2988156968 & MASK31, 
                                                         2422381320 & MASK31, 
                                                         NO_FEATURES]);
@@ -395,7 +504,7 @@ C,featureC
  
         // namespace weight test
         let mut buf = str_to_cursor("1 |A:1.0 a\n");
-        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [6, 1, FLOAT32_ONE,
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [6, FLOAT32_ONE, FLOAT32_ONE,
                                                         2988156968 & MASK31, 
                                                         NO_FEATURES, 
                                                         NO_FEATURES]);
@@ -413,14 +522,14 @@ C,featureC
 
         // namespace weight test
         let mut buf = str_to_cursor("1 |A:2.0 a\n");
-        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [8, 1, FLOAT32_ONE, 
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [8, FLOAT32_ONE, FLOAT32_ONE, 
                                                         nd(6, 8) | IS_NOT_SINGLE_MASK, 
                                                         NO_FEATURES, 
                                                         NO_FEATURES, 
                                                         2988156968 & MASK31, 2.0f32.to_bits()]);
        // feature weight
         let mut buf = str_to_cursor("1 |A a:2.0\n");
-        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [8, 1, FLOAT32_ONE, 
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [8, FLOAT32_ONE, FLOAT32_ONE, 
                                                         nd(6, 8) | IS_NOT_SINGLE_MASK, 
                                                         NO_FEATURES, 
                                                         NO_FEATURES, 
@@ -428,7 +537,7 @@ C,featureC
 
        // two feature weights
         let mut buf = str_to_cursor("1 |A a:2.0 b:3.0\n");
-        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [10, 1, FLOAT32_ONE, 
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [10, FLOAT32_ONE, FLOAT32_ONE, 
                                                         nd(6, 10) | IS_NOT_SINGLE_MASK, 
                                                         NO_FEATURES, 
                                                         NO_FEATURES, 
@@ -438,7 +547,7 @@ C,featureC
 
        // feature weight + namespace weight
         let mut buf = str_to_cursor("1 |A:3 a:2.0\n");
-        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [8, 1, FLOAT32_ONE, 
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [8, FLOAT32_ONE, FLOAT32_ONE, 
                                                         nd(6, 8) | IS_NOT_SINGLE_MASK, 
                                                         NO_FEATURES, 
                                                         NO_FEATURES, 
@@ -453,7 +562,7 @@ C,featureC
 
        // first no weight, then two weighted features
         let mut buf = str_to_cursor("1 |A a b:2.0 c:3.0\n");
-        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [12, 1, FLOAT32_ONE, 
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [12, FLOAT32_ONE, FLOAT32_ONE, 
                                                         nd(6, 12) | IS_NOT_SINGLE_MASK, 
                                                         NO_FEATURES, 
                                                         NO_FEATURES, 
@@ -473,6 +582,44 @@ C,featureC
                                                         NO_FEATURES, 
                                                         NO_FEATURES]);
 
+        // real-valued labels
+        let mut buf = str_to_cursor("0.37 |A a\n");
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [6, 0.37f32.to_bits(), FLOAT32_ONE,
+                                                        2988156968 & MASK31, 
+                                                        NO_FEATURES, 
+                                                        NO_FEATURES]);
+
+        let mut buf = str_to_cursor("-1.5 |A a\n");
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [6, (-1.5f32).to_bits(), FLOAT32_ONE,
+                                                        2988156968 & MASK31, 
+                                                        NO_FEATURES, 
+                                                        NO_FEATURES]);
+
+        let mut buf = str_to_cursor("0 2.0 |A a\n");
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [6, 0.0f32.to_bits(), 2.0f32.to_bits(),
+                                                        2988156968 & MASK31, 
+                                                        NO_FEATURES, 
+                                                        NO_FEATURES]);
+
+        let mut buf = str_to_cursor(".5e1 |A a\n");
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [6, 5.0f32.to_bits(), FLOAT32_ONE,
+                                                        2988156968 & MASK31, 
+                                                        NO_FEATURES, 
+                                                        NO_FEATURES]);
+
+        // label that is not a number
+        let mut buf = str_to_cursor("1x |A a\n");
+        let result = rr.next_vowpal(&mut buf);
+        assert!(result.is_err());
+        assert_eq!(format!("{:?}", result), "Err(Custom { kind: Other, error: \"Failed parsing label: 1x\" })");
+
+        // NaN label would be mistaken for a missing label
+        for label in ["+nan", "-nan", "+NaN"] {
+            let mut buf = str_to_cursor(&format!("{} |A a\n", label));
+            let result = rr.next_vowpal(&mut buf);
+            assert_eq!(format!("{:?}", result), format!("Err(Custom {{ kind: Other, error: \"Label cannot be NaN: {}\" }})", label));
+        }
+
         /* Should we support this ? 
         let mut buf = str_to_cursor(" |A a\n");
         assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [6, NO_LABEL, FLOAT32_ONE,
@@ -510,14 +657,14 @@ C,featureC
         
         // Example importance
         let mut buf = str_to_cursor("1 0.1 |A a\n");
-        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [6, 1, 0.1f32.to_bits(),
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [6, FLOAT32_ONE, 0.1f32.to_bits(),
                                                         2988156968 & MASK31, 
                                                         NO_FEATURES, 
                                                         NO_FEATURES]);
 
         // Example importance with bunch of spaces
         let mut buf = str_to_cursor("1  0.1  |A  a \n");
-        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [6, 1, 0.1f32.to_bits(),
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [6, FLOAT32_ONE, 0.1f32.to_bits(),
                                                         2988156968 & MASK31, 
                                                         NO_FEATURES, 
                                                         NO_FEATURES]);
@@ -543,6 +690,9 @@ C,featureC
         assert_eq!(hogwild_command.filename, "/path/to/filename");
  
 
+        let result = rr.next_vowpal(&mut str_to_cursor("hogwild_load /path/to/filename \n")).err().unwrap();
+        assert_eq!(result.downcast_ref::<HogwildLoadCommand>().unwrap().filename, "/path/to/filename");
+
         // flush should return FlushCommand
         let mut buf = str_to_cursor("hogwild_load   /path/to/filename  ");
         let result = rr.next_vowpal(&mut buf).err().unwrap();
@@ -550,6 +700,10 @@ C,featureC
         let hogwild_command = result.downcast_ref::<HogwildLoadCommand>().unwrap();
         assert_eq!(hogwild_command.filename, "/path/to/filename");
 
+        let result = rr.next_vowpal(&mut str_to_cursor("load_model  /path/to/filename\n")).err().unwrap();
+        assert_eq!(result.downcast_ref::<LoadModelCommand>().unwrap().filename, "/path/to/filename");
+        assert!(rr.next_vowpal(&mut str_to_cursor("load_model \n")).err().unwrap().downcast_ref::<LoadModelCommand>().is_none());
+
         // Check for two pathological cases - command without space, and command with a space but no file
         let mut buf = str_to_cursor("hogwild_load");
         let result = rr.next_vowpal(&mut buf);
@@ -560,7 +714,64 @@ C,featureC
         let result = rr.next_vowpal(&mut buf);
         assert!(result.is_err());
         assert_eq!(format!("{:?}", result), "Err(Custom { kind: Other, error: \"Cannot parse an example\" })");
- 
+
+        // context returns the rest of the line, to be parsed as an example
+        let mut buf = str_to_cursor("context  |A a |B b\n|C c\n");
+        let result = rr.next_vowpal(&mut buf).err().unwrap();
+        assert_eq!(result.downcast_ref::<ContextCommand>().unwrap().line, b"|A a |B b\n");
+        let mut buf = str_to_cursor("context");
+        let result = rr.next_vowpal(&mut buf).err().unwrap();
+        assert_eq!(result.downcast_ref::<ContextCommand>().unwrap().line, b"\n");
+        assert!(rr.next_vowpal(&mut str_to_cursor("stats\n")).err().unwrap().is::<StatsCommand>());
+        assert!(rr.next_vowpal(&mut str_to_cursor("stats  ")).err().unwrap().is::<StatsCommand>());
+        assert!(!rr.next_vowpal(&mut str_to_cursor("statsd\n")).err().unwrap().is::<StatsCommand>());
+        let mut buf = str_to_cursor("contextual |A a\n");
+        assert!(rr.next_vowpal(&mut buf).err().unwrap().downcast_ref::<ContextCommand>().is_none());
+    }
+
+    #[test]
+    fn test_next_vowpal_with_context() {
+        fn str_to_cursor(s: &str) -> Cursor<Vec<u8>> {
+          Cursor::new(s.as_bytes().to_vec())
+        }
+
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+C,featureC
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut rr = VowpalParser::new(&vw);
+        let context = rr.next_vowpal(&mut str_to_cursor("|A a |B b1 b2:2\n")).unwrap().to_vec();
+
+        let mut buf = str_to_cursor("1 |C c\n");
+        let merged = rr.next_vowpal_with_context(&mut buf, &context).unwrap().to_vec();
+        let mut buf = str_to_cursor("1 |A a |B b1 b2:2 |C c\n");
+        let expected = rr.next_vowpal(&mut buf).unwrap().to_vec();
+        assert_eq!(merged[1..6], expected[1..6]);
+        // Multi-value namespace is copied to the end of the buffer
+        assert_eq!(merged[4], IS_NOT_SINGLE_MASK | (6 << 16) + 10);
+        assert_eq!(merged[6..], expected[6..]);
+        assert_eq!(merged[0], 10);
+
+        let mut buf = str_to_cursor("1 |A a2\n");
+        let result = rr.next_vowpal_with_context(&mut buf, &context);
+        assert_eq!(format!("{:?}", result), "Err(Custom { kind: Other, error: \"Namespace is already in the context: A\" })");
+
+        // Commands and end of stream are passed through
+        assert!(rr.next_vowpal_with_context(&mut str_to_cursor("flush"), &context).err().unwrap().is::<FlushCommand>());
+        assert_eq!(rr.next_vowpal_with_context(&mut str_to_cursor(""), &context).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_parse_example() {
+        let vw = vwmap::VwNamespaceMap::new("A,featureA\nB,featureB\n").unwrap();
+        let mut rr = VowpalParser::new(&vw);
+        let mut line = Vec::new();
+        let expected = rr.next_vowpal(&mut Cursor::new(b"1 |A a |B b:2\n".to_vec())).unwrap().to_vec();
+        assert_eq!(rr.parse_example(&mut line, b"1 |A a |B b:2").unwrap(), &expected[..]);
+        assert_eq!(rr.parse_example(&mut line, b"1 |A a |B b:2\n").unwrap(), &expected[..]);
+        assert!(rr.parse_example(&mut line, b"").is_err());
     }
 
 
@@ -579,7 +790,7 @@ C,featureC
         let mut rr = VowpalParser::new(&vw);
         // we test a single record, single namespace, with string value "3"
         let mut buf = str_to_cursor("-1 |B 3\n");
-        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [6, 0, FLOAT32_ONE,
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [6, FLOAT32_MINUS_ONE, FLOAT32_ONE,
                                                         NO_FEATURES, 
                                                         1775699190 & MASK31, 
                                                         NO_FEATURES]);
@@ -594,14 +805,14 @@ C,featureC
         let mut rr = VowpalParser::new(&vw);
         // we test a single record, single namespace, with string value "3"
         let mut buf = str_to_cursor("-1 |B 3\n");
-        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [8, 0, FLOAT32_ONE,
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [8, FLOAT32_MINUS_ONE, FLOAT32_ONE,
                                                         NO_FEATURES, 
                                                         nd(6, 8) | IS_NOT_SINGLE_MASK, 
                                                         NO_FEATURES, 
                                                         1775699190 & MASK31, 3.0f32.to_bits()]);
 
         let mut buf = str_to_cursor("-1 |B 3 4\n");
-        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [10, 0, FLOAT32_ONE,
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [10, FLOAT32_MINUS_ONE, FLOAT32_ONE,
                                                         NO_FEATURES, 
                                                         nd(6, 10) | IS_NOT_SINGLE_MASK, 
                                                         NO_FEATURES, 
@@ -616,7 +827,7 @@ C,featureC
 
 
         let mut buf = str_to_cursor("-1 |B 3 4\n");
-        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [10, 0, FLOAT32_ONE,
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [10, FLOAT32_MINUS_ONE, FLOAT32_ONE,
                                                         NO_FEATURES, 
                                                         nd(6, 10) | IS_NOT_SINGLE_MASK, 
                                                         NO_FEATURES, 
@@ -636,7 +847,6 @@ C,featureC
 
 
 
-        let mut buf = str_to_cursor("-1 |B NONE\n");
         // Now test with skip_prefix = 1 
         let vw_map_string = r#"
 A,featureA
@@ -649,7 +859,7 @@ _namespace_skip_prefix,1
         let mut rr = VowpalParser::new(&vw);
         // we test a single record, single namespace, with string value "3"
         let mut buf = str_to_cursor("-1 |B B3\n");
-        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [8, 0, FLOAT32_ONE,
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [8, FLOAT32_MINUS_ONE, FLOAT32_ONE,
                                                         NO_FEATURES, 
                                                         nd(6, 8) | IS_NOT_SINGLE_MASK, 
                                                         NO_FEATURES, 
@@ -657,14 +867,14 @@ _namespace_skip_prefix,1
 
         // Because we skip one char, the float value of B is the float value of "" which is NAN
         let mut buf = str_to_cursor("-1 |B B\n");
-        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [8, 0, FLOAT32_ONE,
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [8, FLOAT32_MINUS_ONE, FLOAT32_ONE,
                                                         NO_FEATURES, 
                                                         nd(6, 8) | IS_NOT_SINGLE_MASK, 
                                                         NO_FEATURES, 
                                                         25602353 & MASK31, f32::NAN.to_bits()]);
 
         let mut buf = str_to_cursor("-1 |B BNONE\n");
-        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [8, 0, FLOAT32_ONE,
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [8, FLOAT32_MINUS_ONE, FLOAT32_ONE,
                                                         NO_FEATURES, 
                                                         nd(6, 8) | IS_NOT_SINGLE_MASK, 
                                                         NO_FEATURES, 
@@ -697,14 +907,14 @@ CC,featureC
         let mut rr = VowpalParser::new(&vw);
         // we test a single record, single namespace
         let mut buf = str_to_cursor("1 |AA a\n");
-        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [6,  1, FLOAT32_ONE,  
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [6,  FLOAT32_ONE, FLOAT32_ONE,  
                                                         292540976 & MASK31, 
                                                         NO_FEATURES, 
                                                         NO_FEATURES]);
  
         // feature weight + namespace weight
         let mut buf = str_to_cursor("1 |AA:3 a:2.0\n");
-        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [8, 1, FLOAT32_ONE, 
+        assert_eq!(rr.next_vowpal(&mut buf).unwrap(), [8, FLOAT32_ONE, FLOAT32_ONE, 
                                                         nd(6, 8) | IS_NOT_SINGLE_MASK, 
                                                         NO_FEATURES, 
                                                         NO_FEATURES, 
@@ -1,21 +1,22 @@
 use std::str;
 use std::error::Error;
 
-use std::io::{Read};
+use std::io::{Read, Write};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use std::fs::File;
 use std::io;
 use std::fs;
 
 use crate::model_instance;
 use crate::regressor;
 use crate::vwmap;
-use crate::optimizer;
-use optimizer::OptimizerTrait;
-use regressor::Regressor;
+use crate::block_helpers;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::path::Path;
 
 const REGRESSOR_HEADER_MAGIC_STRING: &[u8; 4] = b"FWRE";    // Fwumious Wabbit REgressor
-const REGRESSOR_HEADER_VERSION:u32 = 5; // Change to 5: introduce namespace descriptors which changes regressor
+const REGRESSOR_HEADER_VERSION:u32 = 6; // Change to 6: each block of weights has its type, optimizer, length and checksum
+const REGRESSOR_HEADER_VERSION_5:u32 = 5; // Change to 5: introduce namespace descriptors which changes regressor, still readable
 
 
 
@@ -55,34 +56,141 @@ pub fn save_regressor_to_filename(
                         filename: &str, 
                         mi: &model_instance::ModelInstance,
                         vwmap: &vwmap::VwNamespaceMap,
-                        re: regressor::Regressor,
+                        re: &regressor::Regressor,
                         ) -> Result<(), Box<dyn Error>> {
-        let output_bufwriter = &mut io::BufWriter::new(fs::File::create(filename).expect(format!("Cannot open {} to save regressor to", filename).as_str()));
-        write_regressor_header(output_bufwriter)?;
-        vwmap.save_to_buf(output_bufwriter)?;
-        mi.save_to_buf(output_bufwriter)?;
-        re.write_weights_to_buf(output_bufwriter)?;
+        write_regressor_to_file(create_file(filename, "regressor")?, mi, vwmap, re)?;
         Ok(())
     }
 
-fn write_regressor_header(output_bufwriter: &mut dyn io::Write) -> Result<(), Box<dyn Error>> {
+// Writes a temporary file and renames it, so filename is never left with a partially written model
+pub fn save_regressor_to_filename_atomic(
+                        filename: &str,
+                        mi: &model_instance::ModelInstance,
+                        vwmap: &vwmap::VwNamespaceMap,
+                        re: &regressor::Regressor,
+                        ) -> Result<(), Box<dyn Error>> {
+    let temporary_filename = temporary_filename(filename);
+    let result = write_regressor_to_file(create_file(&temporary_filename, "regressor")?, mi, vwmap, re)
+                    .and_then(|output_file| Ok(output_file.sync_all()?));
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temporary_filename);
+        return Err(e);
+    }
+    replace_file(&temporary_filename, filename)
+}
+
+fn write_regressor_to_file(output_file: fs::File,
+                           mi: &model_instance::ModelInstance,
+                           vwmap: &vwmap::VwNamespaceMap,
+                           re: &regressor::Regressor,
+                           ) -> Result<fs::File, Box<dyn Error>> {
+    let mut output_bufwriter = io::BufWriter::new(output_file);
+    write_regressor_header(&mut output_bufwriter, REGRESSOR_HEADER_VERSION)?;
+    vwmap.save_to_buf(&mut output_bufwriter)?;
+    mi.save_to_buf(&mut output_bufwriter)?;
+    re.write_blocks_to_buf(&mut output_bufwriter)?;
+    match output_bufwriter.into_inner() {
+        Ok(output_file) => Ok(output_file),
+        Err(e) => Err(e.into_error())?,
+    }
+}
+
+fn create_file(filename: &str, what: &str) -> Result<fs::File, Box<dyn Error>> {
+    match fs::File::create(filename) {
+        Ok(output_file) => Ok(output_file),
+        Err(e) => Err(format!("Cannot open {} to save {} to: {}", filename, what, e))?
+    }
+}
+
+// Temporary files are unique, so that two saves of the same file can't write into each other's
+static TEMPORARY_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+fn temporary_filename(filename: &str) -> String {
+    format!("{}.tmp.{}.{}", filename, std::process::id(), TEMPORARY_FILE_COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+// Renames the synced temporary file over filename, and syncs the directory so that the rename survives a crash too
+fn replace_file(temporary_filename: &str, filename: &str) -> Result<(), Box<dyn Error>> {
+    fs::rename(temporary_filename, filename)?;
+    let directory = match Path::new(filename).parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    };
+    fs::File::open(directory)?.sync_all()?;
+    Ok(())
+}
+
+// Model with only the weights, optionally in lower precision, that can only be loaded for predictions
+pub fn save_serving_model_to_filename(
+                        filename: &str,
+                        mi: &model_instance::ModelInstance,
+                        vwmap: &vwmap::VwNamespaceMap,
+                        re: &mut regressor::Regressor,
+                        precision: regressor::ServingPrecision,
+                        ) -> Result<(), Box<dyn Error>> {
+        let forward_re;
+        let re = if re.immutable {
+            re
+        } else {
+            forward_re = re.immutable_regressor(mi)?;
+            &forward_re
+        };
+        // A daemon can be serving the file from memory (--mmap), so it is replaced, not overwritten
+        let temporary_filename = temporary_filename(filename);
+        let output_file = create_file(&temporary_filename, "serving model")?;
+        let output_bufwriter = &mut io::BufWriter::new(output_file);
+        // Blocks need to know where in the file they are
+        let mut header: Vec<u8> = Vec::new();
+        write_regressor_header(&mut header, REGRESSOR_HEADER_VERSION)?;
+        vwmap.save_to_buf(&mut header)?;
+        mi.save_to_buf(&mut header)?;
+        output_bufwriter.write_all(&header)?;
+        re.write_serving_blocks_to_buf(output_bufwriter, precision, header.len() as u64)?;
+        output_bufwriter.flush()?;
+        output_bufwriter.get_ref().sync_all()?;
+        replace_file(&temporary_filename, filename)
+    }
+
+fn write_regressor_header(output_bufwriter: &mut dyn io::Write, version: u32) -> Result<(), Box<dyn Error>> {
     // we will write magic string FWFW
     // And then 32 bit unsigned version of the regressor
     output_bufwriter.write_all(REGRESSOR_HEADER_MAGIC_STRING)?;
-    output_bufwriter.write_u32::<LittleEndian>(REGRESSOR_HEADER_VERSION)?;
+    output_bufwriter.write_u32::<LittleEndian>(version)?;
     Ok(())
 }
 
-fn load_regressor_without_weights(input_bufreader: &mut io::BufReader::<File>) 
-                        -> Result<(model_instance::ModelInstance,
+// The model instance can be changed by override_mi before the regressor is created from it
+fn load_regressor_without_weights_with_overrides<F>(input_bufreader: &mut dyn io::Read, override_mi: F)
+                        -> Result<(u32,
+                                   model_instance::ModelInstance,
                                    vwmap::VwNamespaceMap,
                                    regressor::Regressor,
-                                 ), Box<dyn Error>> {
-    verify_header(input_bufreader).expect("Regressor header error");    
-    let vw = vwmap::VwNamespaceMap::new_from_buf(input_bufreader).expect("Loading vwmap from regressor failed");
-    let mi = model_instance::ModelInstance::new_from_buf(input_bufreader).expect("Loading model instance from regressor failed");
+                                 ), Box<dyn Error>>
+    where F: Fn(&mut model_instance::ModelInstance, &vwmap::VwNamespaceMap) -> Result<(), Box<dyn Error>> {
+    // Errors are returned, not panicked on, since the library can load models in long running services
+    let version = verify_header(input_bufreader)?;
+    let vw = vwmap::VwNamespaceMap::new_from_buf(input_bufreader)?;
+    let mut mi = model_instance::ModelInstance::new_from_buf(input_bufreader)?;
+    override_mi(&mut mi, &vw)?;
     let re = regressor::get_regressor_without_weights(&mi);
-    Ok((mi, vw, re))
+    Ok((version, mi, vw, re))
+}
+
+fn overwrite_weights(version: u32, re: &mut regressor::Regressor, input_bufreader: &mut dyn io::Read) -> Result<(), Box<dyn Error>> {
+    if version == REGRESSOR_HEADER_VERSION_5 {
+        re.overwrite_weights_from_buf(input_bufreader)
+    } else {
+        re.overwrite_blocks_from_buf(input_bufreader)
+    }
+}
+
+// re is the regressor read from the file, its weights are loaded into the immutable regressor
+fn load_weights_into_immutable(version: u32, re: &mut regressor::Regressor, immutable_re: &mut regressor::Regressor, input_bufreader: &mut dyn io::Read) -> Result<(), Box<dyn Error>> {
+    if version == REGRESSOR_HEADER_VERSION_5 {
+        re.into_immutable_regressor_from_buf(immutable_re, input_bufreader)
+    } else {
+        re.into_immutable_regressor_from_blocks_buf(immutable_re, input_bufreader)
+    }
 }
 
 
@@ -91,35 +199,97 @@ pub fn new_regressor_from_filename(filename: &str, immutable: bool)
                                    vwmap::VwNamespaceMap,
                                    regressor::Regressor), 
                                   Box<dyn Error>> {
-    let mut input_bufreader = io::BufReader::new(fs::File::open(filename).unwrap());
-    let (mi, vw, mut re) = load_regressor_without_weights(&mut input_bufreader)?;
+    new_regressor_from_filename_with_overrides(filename, immutable, |_, _| Ok(()))
+}
+
+// Continuing from a saved model with some of its parameters changed, see ModelInstance::override_from_cmdline()
+pub fn new_regressor_from_filename_with_overrides<F>(filename: &str, immutable: bool, override_mi: F)
+                        -> Result<(model_instance::ModelInstance,
+                                   vwmap::VwNamespaceMap,
+                                   regressor::Regressor),
+                                  Box<dyn Error>>
+    where F: Fn(&mut model_instance::ModelInstance, &vwmap::VwNamespaceMap) -> Result<(), Box<dyn Error>> {
+    let input_file = match fs::File::open(filename) {
+        Ok(input_file) => input_file,
+        Err(e) => return Err(format!("Cannot open regressor {}: {}", filename, e))?
+    };
+    let mut input_bufreader = io::BufReader::new(input_file);
+    let (version, mi, vw, mut re) = load_regressor_without_weights_with_overrides(&mut input_bufreader, override_mi)?;
     if !immutable {
         re.allocate_and_init_weights(&mi);
-        re.overwrite_weights_from_buf(&mut input_bufreader)?;
+        overwrite_weights(version, &mut re, &mut input_bufreader)?;
         Ok((mi, vw, re))
     } else {
         let mut immutable_re = re.immutable_regressor_without_weights(&mi)?;
         immutable_re.allocate_and_init_weights(&mi);
-        re.into_immutable_regressor_from_buf(&mut immutable_re, &mut input_bufreader)?;
+        load_weights_into_immutable(version, &mut re, &mut immutable_re, &mut input_bufreader)?;
         Ok((mi, vw, immutable_re))
     }
 }
 
 
-pub fn hogwild_load(re: &mut regressor::Regressor, filename: &str) -> Result<(), Box<dyn Error>> {
+// Weights of f32 serving models (--export_serving_model) are used in place from the memory mapped file, so processes
+// serving the same file share the memory. Weights of other models are read into memory.
+pub fn new_regressor_from_filename_mmap(filename: &str)
+                        -> Result<(model_instance::ModelInstance,
+                                   vwmap::VwNamespaceMap,
+                                   regressor::Regressor),
+                                  Box<dyn Error>> {
+    new_regressor_from_filename_mmap_with_overrides(filename, |_, _| Ok(()))
+}
 
-    let mut input_bufreader = io::BufReader::new(fs::File::open(filename)?);
-    let (mi_hw, vw_hw, mut re_hw) = load_regressor_without_weights(&mut input_bufreader)?;
-    // TODO: Here we should do safety comparison that the regressor is really the same;
-    if !re.immutable {
-        re.overwrite_weights_from_buf(&mut input_bufreader)?;
-    } else {
-        re_hw.into_immutable_regressor_from_buf(re, &mut input_bufreader)?;
+pub fn new_regressor_from_filename_mmap_with_overrides<F>(filename: &str, override_mi: F)
+                        -> Result<(model_instance::ModelInstance,
+                                   vwmap::VwNamespaceMap,
+                                   regressor::Regressor),
+                                  Box<dyn Error>>
+    where F: Fn(&mut model_instance::ModelInstance, &vwmap::VwNamespaceMap) -> Result<(), Box<dyn Error>> {
+    let input_file = match fs::File::open(filename) {
+        Ok(input_file) => input_file,
+        Err(e) => return Err(format!("Cannot open regressor {}: {}", filename, e))?
+    };
+    let mapped_file = Arc::new(block_helpers::MappedFile::new(&input_file)?);
+    let mut input = io::Cursor::new(mapped_file.as_slice());
+    let (version, mi, vw, mut re) = load_regressor_without_weights_with_overrides(&mut input, &override_mi)?;
+    if version != REGRESSOR_HEADER_VERSION_5 {
+        let mut mapped_re = re.immutable_regressor_without_weights(&mi)?;
+        if mapped_re.map_blocks_from_file(&mapped_file, input.position() as usize)? {
+            return Ok((mi, vw, mapped_re));
+        }
     }
-    Ok(())
+    println!("{} is not an f32 serving model, reading its weights into memory", filename);
+    new_regressor_from_filename_with_overrides(filename, true, override_mi)
+}
+
+
+// Weights of a model can only be replaced by the weights of a model with the same structure and namespaces
+pub fn check_compatible(mi: &model_instance::ModelInstance,
+                        vw: &vwmap::VwNamespaceMap,
+                        mi_new: &model_instance::ModelInstance,
+                        vw_new: &vwmap::VwNamespaceMap) -> Result<(), Box<dyn Error>> {
+    if vw.vw_source != vw_new.vw_source {
+        return Err("Models have different namespace maps")?;
+    }
+    mi.check_same_structure(mi_new)
+}
+
+// Loads a model with the same structure and namespaces as mi and vw into new memory. Weights are never overwritten
+// in place, callers swap the new regressor in, so whoever is still predicting with the old one is not disturbed.
+pub fn hogwild_load(mi: &model_instance::ModelInstance,
+                    vw: &vwmap::VwNamespaceMap,
+                    filename: &str,
+                    immutable: bool)
+                        -> Result<(model_instance::ModelInstance,
+                                   vwmap::VwNamespaceMap,
+                                   regressor::Regressor),
+                                  Box<dyn Error>> {
+    let (mi_hw, vw_hw, re_hw) = new_regressor_from_filename(filename, immutable)?;
+    check_compatible(mi, vw, &mi_hw, &vw_hw)?;
+    Ok((mi_hw, vw_hw, re_hw))
 }
 
-fn verify_header(input_bufreader: &mut dyn io::Read) -> Result<(), Box<dyn Error>> {
+// Returns the version of the regressor file
+fn verify_header(input_bufreader: &mut dyn io::Read) -> Result<u32, Box<dyn Error>> {
     let mut magic_string: [u8; 4] = [0;4];
     input_bufreader.read(&mut magic_string)?;
     if &magic_string != REGRESSOR_HEADER_MAGIC_STRING {
@@ -127,10 +297,10 @@ fn verify_header(input_bufreader: &mut dyn io::Read) -> Result<(), Box<dyn Error
     }
     
     let version = input_bufreader.read_u32::<LittleEndian>()?;
-    if REGRESSOR_HEADER_VERSION != version {
-        return Err(format!("Cache file version of this binary: {}, version of the cache file: {}", REGRESSOR_HEADER_VERSION, version))?;
+    if version != REGRESSOR_HEADER_VERSION && version != REGRESSOR_HEADER_VERSION_5 {
+        return Err(format!("Regressor file versions readable by this binary: {} and {}, version of the regressor file: {}", REGRESSOR_HEADER_VERSION_5, REGRESSOR_HEADER_VERSION, version))?;
     }
-    Ok(())
+    Ok(version)
 }        
 
 
@@ -140,6 +310,7 @@ fn verify_header(input_bufreader: &mut dyn io::Read) -> Result<(), Box<dyn Error
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
+    use crate::optimizer;
     use crate::feature_buffer;
     use crate::feature_buffer::HashAndValue;
     use crate::feature_buffer::HashAndValueAndSeq;
@@ -166,7 +337,7 @@ B,featureB
         let rr = regressor::get_regressor_with_weights(&mi);
         let dir = tempfile::tempdir().unwrap();
         let regressor_filepath = dir.path().join("test_regressor.fw");
-        save_regressor_to_filename(regressor_filepath.to_str().unwrap(), &mi, &vw, rr).unwrap();
+        save_regressor_to_filename(regressor_filepath.to_str().unwrap(), &mi, &vw, &rr).unwrap();
     }    
 
     fn lr_vec(v:Vec<feature_buffer::HashAndValue>) -> feature_buffer::FeatureBuffer {
@@ -201,39 +372,80 @@ B,featureB
         assert_eq!(re.learn(fbuf, true), 0.45016602);
         assert_eq!(re.learn(fbuf, false), 0.41731137);
 
-        let CONST_RESULT = 0.41731137;
-        assert_eq!(re.learn(fbuf, false), CONST_RESULT);
+        let const_result = 0.41731137;
+        assert_eq!(re.learn(fbuf, false), const_result);
 
         // Now we test conversion to fixed regressor 
         {
             let re_fixed = re.immutable_regressor(&mi).unwrap();
             // predict with the same feature vector
-            assert_eq!(re_fixed.predict(&fbuf), CONST_RESULT);
+            assert_eq!(re_fixed.predict(&fbuf), const_result);
         }
         // Now we test saving and loading a) regular regressor, b) fixed regressor
         {
             let dir = tempdir().unwrap();
             let regressor_filepath = dir.path().join("test_regressor2.fw");
-            save_regressor_to_filename(regressor_filepath.to_str().unwrap(), &mi, &vw, re).unwrap();
+            save_regressor_to_filename(regressor_filepath.to_str().unwrap(), &mi, &vw, &re).unwrap();
 
             // a) load as regular regressor
             let (_mi2, _vw2, mut re2) = new_regressor_from_filename(regressor_filepath.to_str().unwrap(), false).unwrap();
-            assert_eq!(re2.learn(fbuf, false), CONST_RESULT);
-            assert_eq!(re2.predict(fbuf), CONST_RESULT);
+            assert_eq!(re2.learn(fbuf, false), const_result);
+            assert_eq!(re2.predict(fbuf), const_result);
 
             // a) load as regular regressor, immutable
             let (_mi2, _vw2, mut re2) = new_regressor_from_filename(regressor_filepath.to_str().unwrap(), true).unwrap();
-            assert_eq!(re2.learn(fbuf, false), CONST_RESULT);
-            assert_eq!(re2.predict(fbuf), CONST_RESULT);
+            assert_eq!(re2.learn(fbuf, false), const_result);
+            assert_eq!(re2.predict(fbuf), const_result);
 
         }
 
     }    
 
+    #[test]
+    fn save_load_separate_ffm_optimizer() {
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.ffm_learning_rate = 0.01;
+        mi.ffm_k = 2;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_fields = vec![vec![],vec![]]; 
+        mi.optimizer = model_instance::Optimizer::Adagrad;
+        mi.ffm_optimizer = Some(model_instance::Optimizer::Adam);
+        mi.fastmath = false;
+        let mut re = regressor::get_regressor_with_weights(&mi);
+
+        let fbuf = &lr_and_ffm_vec(
+                                vec![HashAndValue{hash: 1, value: 1.0}],
+                                vec![
+                                  HashAndValueAndSeq{hash:1, value: 1.0, contra_field_index: 0},
+                                  HashAndValueAndSeq{hash:100, value: 1.0, contra_field_index: 2}
+                                  ], 2);
+        re.learn(fbuf, true);
+        re.learn(fbuf, true);
+
+        let dir = tempdir().unwrap();
+        let regressor_filepath = dir.path().join("test_regressor.fw");
+        save_regressor_to_filename(regressor_filepath.to_str().unwrap(), &mi, &vw, &re).unwrap();
+        let (_mi2, _vw2, mut re2) = new_regressor_from_filename(regressor_filepath.to_str().unwrap(), false).unwrap();
+        assert_eq!(re2.get_name(), "Regressor with optimizers \"AdagradFlex\" (LR) and \"Adam\" (FFM)");
+
+        // Optimizer state (Adam's moments) has to survive the round trip, so further learning continues identically
+        let mut re = regressor::get_regressor_with_weights(&mi);
+        re.learn(fbuf, true);
+        re.learn(fbuf, true);
+        assert_eq!(re2.learn(fbuf, true), re.learn(fbuf, true));
+        assert_eq!(re2.predict(fbuf), re.predict(fbuf));
+    }
+
     fn ffm_fixed_init(rg: &mut Regressor) -> () {
         // This is a bit of black magic - we "know" that FFM is at index 1 and we downcast...
         let block_ffm = &mut rg.blocks_boxes[1];
-        let mut block_ffm = block_ffm.as_any().downcast_mut::<BlockFFM<optimizer::OptimizerAdagradFlex>>().unwrap();
+        let block_ffm = block_ffm.as_any().downcast_mut::<BlockFFM<optimizer::OptimizerAdagradFlex>>().unwrap();
 
         // TODO: this is not future compatible
         for i in 0..block_ffm.get_serialized_len() {// it only happens that this matches number of weights
@@ -282,35 +494,35 @@ B,featureB
                                   ], 2);
         p = re.learn(fbuf, true);
         assert_eq!(p, 0.9933072); 
-        let CONST_RESULT = 0.9395168;
+        let const_result = 0.9395168;
         p = re.learn(fbuf, false);
-        assert_epsilon!(p, CONST_RESULT);
+        assert_epsilon!(p, const_result);
         p = re.predict(fbuf);
-        assert_epsilon!(p, CONST_RESULT);
+        assert_epsilon!(p, const_result);
 
         // Now we test conversion to fixed regressor 
         {
             let re_fixed = re.immutable_regressor(&mi).unwrap();
             // predict with the same feature vector
-            assert_epsilon!(re_fixed.predict(&fbuf), CONST_RESULT);
+            assert_epsilon!(re_fixed.predict(&fbuf), const_result);
         }
         // Now we test saving and loading a) regular regressor, b) fixed regressor
         {
             let dir = tempdir().unwrap();
             let regressor_filepath = dir.path().join("test_regressor2.fw");
-            save_regressor_to_filename(regressor_filepath.to_str().unwrap(), &mi, &vw, re).unwrap();
+            save_regressor_to_filename(regressor_filepath.to_str().unwrap(), &mi, &vw, &re).unwrap();
 
             // a) load as regular regressor
             let (_mi2, _vw2, mut re2) = new_regressor_from_filename(regressor_filepath.to_str().unwrap(), false).unwrap();
             assert_eq!(re2.get_name(), "Regressor with optimizer \"AdagradFlex\"");
-            assert_epsilon!(re2.learn(fbuf, false), CONST_RESULT);
-            assert_epsilon!(re2.predict(fbuf), CONST_RESULT);
+            assert_epsilon!(re2.learn(fbuf, false), const_result);
+            assert_epsilon!(re2.predict(fbuf), const_result);
 
             // b) load as regular regressor, immutable
             let (_mi2, _vw2, mut re2) = new_regressor_from_filename(regressor_filepath.to_str().unwrap(), true).unwrap();
             assert_eq!(re2.get_name(), "Regressor with optimizer \"SGD\"");
-            assert_epsilon!(re2.learn(fbuf, false), CONST_RESULT);
-            assert_epsilon!(re2.predict(fbuf), CONST_RESULT);
+            assert_epsilon!(re2.learn(fbuf, false), const_result);
+            assert_epsilon!(re2.predict(fbuf), const_result);
 
         }
     }    
@@ -369,35 +581,35 @@ B,featureB
 
         p = re_1.learn(fbuf_1, true);
         assert_eq!(p, 0.97068775); 
-        let CONST_RESULT_1_ON_1 = 0.8922257;
+        let const_result_1_on_1 = 0.8922257;
         p = re_1.learn(fbuf_1, false);
-        assert_eq!(p, CONST_RESULT_1_ON_1);
+        assert_eq!(p, const_result_1_on_1);
         p = re_1.predict(fbuf_1);
-        assert_eq!(p, CONST_RESULT_1_ON_1);
+        assert_eq!(p, const_result_1_on_1);
 
         p = re_2.learn(fbuf_2, true);
         assert_eq!(p, 0.9933072); 
-        let CONST_RESULT_2_ON_2 = 0.92719215;
+        let const_result_2_on_2 = 0.92719215;
         p = re_2.learn(fbuf_2, false);
-        assert_eq!(p, CONST_RESULT_2_ON_2);
+        assert_eq!(p, const_result_2_on_2);
         p = re_2.predict(fbuf_2);
-        assert_eq!(p, CONST_RESULT_2_ON_2);
+        assert_eq!(p, const_result_2_on_2);
 
         p = re_2.learn(fbuf_1, false);
         assert_eq!(p, 0.93763095); 
-        let CONST_RESULT_1_ON_2 = 0.93763095;
+        let const_result_1_on_2 = 0.93763095;
         p = re_2.learn(fbuf_1, false);
-        assert_eq!(p, CONST_RESULT_1_ON_2);
+        assert_eq!(p, const_result_1_on_2);
         p = re_2.predict(fbuf_1);
-        assert_eq!(p, CONST_RESULT_1_ON_2);
+        assert_eq!(p, const_result_1_on_2);
 
         p = re_1.learn(fbuf_2, false);
         assert_eq!(p, 0.98559695); 
-        let CONST_RESULT_2_ON_1 = 0.98559695;
+        let const_result_2_on_1 = 0.98559695;
         p = re_1.learn(fbuf_2, false);
-        assert_eq!(p, CONST_RESULT_2_ON_1);
+        assert_eq!(p, const_result_2_on_1);
         p = re_1.predict(fbuf_2);
-        assert_eq!(p, CONST_RESULT_2_ON_1);
+        assert_eq!(p, const_result_2_on_1);
 
 
 
@@ -407,48 +619,265 @@ B,featureB
         {
             let dir = tempdir().unwrap();
             let regressor_filepath_1 = dir.path().join("test_regressor1.fw").to_str().unwrap().to_owned();
-            save_regressor_to_filename(&regressor_filepath_1, &mi, &vw, re_1).unwrap();
+            save_regressor_to_filename(&regressor_filepath_1, &mi, &vw, &re_1).unwrap();
             let regressor_filepath_2 = dir.path().join("test_regressor2.fw").to_str().unwrap().to_owned();
-            save_regressor_to_filename(&regressor_filepath_2, &mi, &vw, re_2).unwrap();
+            save_regressor_to_filename(&regressor_filepath_2, &mi, &vw, &re_2).unwrap();
 
             // The mutable path
             let (_mi1, _vw1, mut new_re_1) = new_regressor_from_filename(&regressor_filepath_1, false).unwrap();
             assert_eq!(new_re_1.get_name(), "Regressor with optimizer \"AdagradFlex\"");
-            assert_eq!(new_re_1.learn(fbuf_1, false), CONST_RESULT_1_ON_1);
-            assert_eq!(new_re_1.predict(fbuf_1), CONST_RESULT_1_ON_1);
-            assert_eq!(new_re_1.learn(fbuf_2, false), CONST_RESULT_2_ON_1);
-            assert_eq!(new_re_1.predict(fbuf_2), CONST_RESULT_2_ON_1);
-            hogwild_load(&mut new_re_1, &regressor_filepath_2).unwrap();
-            assert_eq!(new_re_1.learn(fbuf_2, false), CONST_RESULT_2_ON_2);
-            assert_eq!(new_re_1.predict(fbuf_2), CONST_RESULT_2_ON_2);
-            hogwild_load(&mut new_re_1, &regressor_filepath_1).unwrap();
-            assert_eq!(new_re_1.learn(fbuf_1, false), CONST_RESULT_1_ON_1);
-            assert_eq!(new_re_1.predict(fbuf_1), CONST_RESULT_1_ON_1);
-            assert_eq!(new_re_1.learn(fbuf_2, false), CONST_RESULT_2_ON_1);
-            assert_eq!(new_re_1.predict(fbuf_2), CONST_RESULT_2_ON_1);
+            assert_eq!(new_re_1.learn(fbuf_1, false), const_result_1_on_1);
+            assert_eq!(new_re_1.predict(fbuf_1), const_result_1_on_1);
+            assert_eq!(new_re_1.learn(fbuf_2, false), const_result_2_on_1);
+            assert_eq!(new_re_1.predict(fbuf_2), const_result_2_on_1);
+            new_re_1 = hogwild_load(&mi, &vw, &regressor_filepath_2, false).unwrap().2;
+            assert_eq!(new_re_1.learn(fbuf_2, false), const_result_2_on_2);
+            assert_eq!(new_re_1.predict(fbuf_2), const_result_2_on_2);
+            new_re_1 = hogwild_load(&mi, &vw, &regressor_filepath_1, false).unwrap().2;
+            assert_eq!(new_re_1.learn(fbuf_1, false), const_result_1_on_1);
+            assert_eq!(new_re_1.predict(fbuf_1), const_result_1_on_1);
+            assert_eq!(new_re_1.learn(fbuf_2, false), const_result_2_on_1);
+            assert_eq!(new_re_1.predict(fbuf_2), const_result_2_on_1);
 
             // The immutable path
             let (_mi1, _vw1, mut new_re_1) = new_regressor_from_filename(&regressor_filepath_1, true).unwrap();
             assert_eq!(new_re_1.get_name(), "Regressor with optimizer \"SGD\"");
-            assert_eq!(new_re_1.learn(fbuf_1, false), CONST_RESULT_1_ON_1);
-            assert_eq!(new_re_1.predict(fbuf_1), CONST_RESULT_1_ON_1);
-            assert_eq!(new_re_1.learn(fbuf_2, false), CONST_RESULT_2_ON_1);
-            assert_eq!(new_re_1.predict(fbuf_2), CONST_RESULT_2_ON_1);
-            hogwild_load(&mut new_re_1, &regressor_filepath_2).unwrap();
-            assert_eq!(new_re_1.learn(fbuf_2, false), CONST_RESULT_2_ON_2);
-            assert_eq!(new_re_1.predict(fbuf_2), CONST_RESULT_2_ON_2);
-            hogwild_load(&mut new_re_1, &regressor_filepath_1).unwrap();
-            assert_eq!(new_re_1.learn(fbuf_1, false), CONST_RESULT_1_ON_1);
-            assert_eq!(new_re_1.predict(fbuf_1), CONST_RESULT_1_ON_1);
-            assert_eq!(new_re_1.learn(fbuf_2, false), CONST_RESULT_2_ON_1);
-            assert_eq!(new_re_1.predict(fbuf_2), CONST_RESULT_2_ON_1);
+            assert_eq!(new_re_1.learn(fbuf_1, false), const_result_1_on_1);
+            assert_eq!(new_re_1.predict(fbuf_1), const_result_1_on_1);
+            assert_eq!(new_re_1.learn(fbuf_2, false), const_result_2_on_1);
+            assert_eq!(new_re_1.predict(fbuf_2), const_result_2_on_1);
+            new_re_1 = hogwild_load(&mi, &vw, &regressor_filepath_2, true).unwrap().2;
+            assert_eq!(new_re_1.learn(fbuf_2, false), const_result_2_on_2);
+            assert_eq!(new_re_1.predict(fbuf_2), const_result_2_on_2);
+            new_re_1 = hogwild_load(&mi, &vw, &regressor_filepath_1, true).unwrap().2;
+            assert_eq!(new_re_1.learn(fbuf_1, false), const_result_1_on_1);
+            assert_eq!(new_re_1.predict(fbuf_1), const_result_1_on_1);
+            assert_eq!(new_re_1.learn(fbuf_2, false), const_result_2_on_1);
+            assert_eq!(new_re_1.predict(fbuf_2), const_result_2_on_1);
+
+            // Weights of a model with a different structure are refused
+            let mut mi_3 = mi.clone();
+            mi_3.add_constant_feature = !mi.add_constant_feature;
+            let regressor_filepath_3 = dir.path().join("test_regressor3.fw").to_str().unwrap().to_owned();
+            save_regressor_to_filename(&regressor_filepath_3, &mi_3, &vw, &re_2).unwrap();
+            assert_eq!(hogwild_load(&mi, &vw, &regressor_filepath_3, true).err().unwrap().to_string(),
+                       format!("Models differ in add_constant_feature: {} vs {}", mi.add_constant_feature, mi_3.add_constant_feature));
+            assert_eq!(new_re_1.predict(fbuf_2), const_result_2_on_1);
+        }
+    }    
 
+    fn block_records_model() -> (model_instance::ModelInstance, vwmap::VwNamespaceMap, Regressor, feature_buffer::FeatureBuffer) {
+        model_with_power_t(0.5)
+    }
 
+    // With power_t 0.5 the two learned examples bring the prediction of this model back to exactly 0.5,
+    // serving models need one that predicts something else to show that the weights were loaded
+    fn serving_test_model() -> (model_instance::ModelInstance, vwmap::VwNamespaceMap, Regressor, feature_buffer::FeatureBuffer) {
+        model_with_power_t(0.0)
+    }
 
+    fn model_with_power_t(power_t: f32) -> (model_instance::ModelInstance, vwmap::VwNamespaceMap, Regressor, feature_buffer::FeatureBuffer) {
+        let vw = vwmap::VwNamespaceMap::new("\nA,featureA\nB,featureB\n").unwrap();
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.power_t = power_t;
+        mi.bit_precision = 10;
+        mi.ffm_k = 1;
+        mi.ffm_bit_precision = 10;
+        mi.ffm_power_t = power_t;
+        mi.ffm_learning_rate = 0.1;
+        mi.ffm_fields = vec![vec![], vec![]];
+        mi.optimizer = model_instance::Optimizer::Adagrad;
+        mi.fastmath = false;
+        let mut re = regressor::get_regressor_with_weights(&mi);
+        let fbuf = lr_and_ffm_vec(vec![HashAndValue{hash: 5, value: 1.0}],
+                                  vec![HashAndValueAndSeq{hash: 1, value: 1.0, contra_field_index: 0},
+                                       HashAndValueAndSeq{hash: 100, value: 1.0, contra_field_index: 1}], 2);
+        re.learn(&fbuf, true);
+        re.learn(&fbuf, true);
+        (mi, vw, re, fbuf)
+    }
 
+    // Writes a regressor file with the given version and weights
+    fn write_regressor_file(filename: &str, version: u32, mi: &model_instance::ModelInstance, vw: &vwmap::VwNamespaceMap, weights: &[u8]) {
+        let mut output = Vec::new();
+        write_regressor_header(&mut output, version).unwrap();
+        vw.save_to_buf(&mut output).unwrap();
+        mi.save_to_buf(&mut output).unwrap();
+        output.extend_from_slice(weights);
+        fs::write(filename, output).unwrap();
+    }
 
+    fn load_error(filename: &str, immutable: bool) -> String {
+        match new_regressor_from_filename(filename, immutable) {
+            Ok(_) => panic!("Loading {} should fail", filename),
+            Err(e) => e.to_string(),
         }
-    }    
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crate::block_helpers::crc32_update(0, b"123456789"), 0xCBF43926);
+        let crc = crate::block_helpers::crc32_update(0, b"12345");
+        assert_eq!(crate::block_helpers::crc32_update(crc, b"6789"), 0xCBF43926);
+    }
+
+    #[test]
+    fn test_load_version_5() {
+        let (mi, vw, re, fbuf) = block_records_model();
+        let dir = tempdir().unwrap();
+        let filename = dir.path().join("v5.fw").to_str().unwrap().to_owned();
+        let mut weights = Vec::new();
+        re.write_weights_to_buf(&mut weights).unwrap();
+        write_regressor_file(&filename, 5, &mi, &vw, &weights);
+
+        let (_mi2, _vw2, re2) = new_regressor_from_filename(&filename, false).unwrap();
+        assert_eq!(re2.predict(&fbuf), re.predict(&fbuf));
+        let (_mi2, _vw2, re2) = new_regressor_from_filename(&filename, true).unwrap();
+        assert_eq!(re2.predict(&fbuf), re.predict(&fbuf));
+        let (_mi3, _vw3, re3) = hogwild_load(&mi, &vw, &filename, false).unwrap();
+        assert_eq!(re3.predict(&fbuf), re.predict(&fbuf));
+
+        write_regressor_file(&filename, 4, &mi, &vw, &weights);
+        assert_eq!(load_error(&filename, false),
+                   "Regressor file versions readable by this binary: 5 and 6, version of the regressor file: 4");
+    }
+
+    #[test]
+    fn test_f16() {
+        use crate::block_helpers::{f32_to_f16, f16_to_f32};
+        for v in [0.0f32, 1.0, -2.5, 0.1, 65504.0, 6.1035156e-5, 5.9604645e-8, -3.0e-6] {
+            // relative precision of 11 bits, absolute below the smallest normal
+            assert!((f16_to_f32(f32_to_f16(v)) - v).abs() <= (v.abs() / 2048.0).max(2.9802322e-8), "{}", v);
+        }
+        assert_eq!(f32_to_f16(1.0), 0x3c00);
+        assert_eq!(f32_to_f16(1.0 + 1.0 / 2048.0), 0x3c00); // half way rounds to even
+        assert_eq!(f32_to_f16(1.0 + 3.0 / 2048.0), 0x3c02);
+        assert_eq!(f32_to_f16(65520.0), 0x7c00);
+        assert_eq!(f32_to_f16(1.0e-8), 0);
+        assert_eq!(f16_to_f32(0x0001), 5.9604645e-8);
+        assert_eq!(f16_to_f32(0xfc00), f32::NEG_INFINITY);
+        assert!(f16_to_f32(f32_to_f16(f32::NAN)).is_nan());
+    }
+
+    #[test]
+    fn test_serving_model() {
+        let (mi, vw, mut re, fbuf) = serving_test_model();
+        let dir = tempdir().unwrap();
+        let filename = dir.path().join("serving.fw").to_str().unwrap().to_owned();
+        let full_filename = dir.path().join("full.fw").to_str().unwrap().to_owned();
+        save_regressor_to_filename(&full_filename, &mi, &vw, &re).unwrap();
+        let full_len = fs::metadata(&full_filename).unwrap().len();
+        let prediction = re.predict(&fbuf);
+        for (precision, epsilon) in [(regressor::ServingPrecision::F32, 0.0),
+                                     (regressor::ServingPrecision::F16, 0.001),
+                                     (regressor::ServingPrecision::Int8, 0.01)] {
+            save_serving_model_to_filename(&filename, &mi, &vw, &mut re, precision).unwrap();
+            assert!(fs::metadata(&filename).unwrap().len() < full_len);
+            let (_mi2, _vw2, re2) = new_regressor_from_filename(&filename, true).unwrap();
+            assert!((re2.predict(&fbuf) - prediction).abs() <= epsilon, "{:?}", precision);
+            // hogwild_load of a serving model
+            let (_mi3, _vw3, re3) = hogwild_load(&mi, &vw, &filename, true).unwrap();
+            assert_eq!(re3.predict(&fbuf), re2.predict(&fbuf));
+            // Exporting a forward-only regressor writes the same
+            let mut forward_re = re.immutable_regressor(&mi).unwrap();
+            let forward_filename = dir.path().join("forward.fw").to_str().unwrap().to_owned();
+            save_serving_model_to_filename(&forward_filename, &mi, &vw, &mut forward_re, precision).unwrap();
+            assert_eq!(fs::read(&forward_filename).unwrap(), fs::read(&filename).unwrap());
+
+            assert_eq!(load_error(&filename, false),
+                       "Regressor file is a serving model with only the weights of block LR, it can only be loaded for predictions");
+        }
+    }
+
+    #[test]
+    fn test_mmap() {
+        use crate::block_lr::BlockLR;
+        let (mi, vw, mut re, fbuf) = serving_test_model();
+        let dir = tempdir().unwrap();
+        let filename = dir.path().join("serving.fw").to_str().unwrap().to_owned();
+        save_serving_model_to_filename(&filename, &mi, &vw, &mut re, regressor::ServingPrecision::F32).unwrap();
+        let (_mi2, _vw2, re_read) = new_regressor_from_filename(&filename, true).unwrap();
+        let (_mi2, _vw2, mut re_mapped) = new_regressor_from_filename_mmap(&filename).unwrap();
+        assert!(re_mapped.mapped);
+        assert_eq!(re_mapped.predict(&fbuf), re.predict(&fbuf));
+        assert_eq!(re_mapped.predict(&fbuf), re_read.predict(&fbuf));
+        let block_lr = re_mapped.blocks_boxes[0].as_any().downcast_mut::<BlockLR<optimizer::OptimizerSGD>>().unwrap();
+        assert!(block_lr.weights.is_mapped());
+        assert_eq!(block_lr.weights.as_ptr() as usize % 4096, 0);
+
+        // Other models are read into memory
+        for precision in [regressor::ServingPrecision::F16, regressor::ServingPrecision::Int8] {
+            save_serving_model_to_filename(&filename, &mi, &vw, &mut re, precision).unwrap();
+            let (_mi2, _vw2, re_read) = new_regressor_from_filename(&filename, true).unwrap();
+            let (_mi2, _vw2, re_mapped) = new_regressor_from_filename_mmap(&filename).unwrap();
+            assert!(!re_mapped.mapped);
+            assert_eq!(re_mapped.predict(&fbuf), re_read.predict(&fbuf));
+        }
+        // Exporting replaced the file, the memory mapped model keeps using the old one
+        assert_eq!(re_mapped.predict(&fbuf), re.predict(&fbuf));
+        // No temporary files are left behind
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        save_regressor_to_filename(&filename, &mi, &vw, &re).unwrap();
+        let (_mi2, _vw2, re_mapped) = new_regressor_from_filename_mmap(&filename).unwrap();
+        assert!(!re_mapped.mapped);
+        assert_eq!(re_mapped.predict(&fbuf), re.predict(&fbuf));
+    }
+
+    #[test]
+    fn test_block_records() {
+        let (mi, vw, re, fbuf) = block_records_model();
+        let dir = tempdir().unwrap();
+        let filename = dir.path().join("v6.fw").to_str().unwrap().to_owned();
+        let mut weights = Vec::new();
+        re.write_blocks_to_buf(&mut weights).unwrap();
+        let block_bytes = re.blocks_boxes[0].get_serialized_bytes();
+        assert_eq!(block_bytes, (1 << mi.bit_precision) * 8);
+
+        // Records of blocks that the regressor doesn't have are skipped
+        let mut with_unknown = Vec::new();
+        with_unknown.write_u32::<LittleEndian>(re.blocks_boxes.len() as u32 + 1).unwrap();
+        with_unknown.write_u32::<LittleEndian>(99).unwrap();
+        with_unknown.write_u32::<LittleEndian>(0).unwrap();
+        with_unknown.write_u64::<LittleEndian>(3).unwrap();
+        with_unknown.extend_from_slice(b"abc");
+        with_unknown.write_u32::<LittleEndian>(crate::block_helpers::crc32_update(0, b"abc")).unwrap();
+        with_unknown.extend_from_slice(&weights[4..]);
+        write_regressor_file(&filename, 6, &mi, &vw, &with_unknown);
+        let (_mi2, _vw2, re2) = new_regressor_from_filename(&filename, false).unwrap();
+        assert_eq!(re2.predict(&fbuf), re.predict(&fbuf));
+        let (_mi2, _vw2, re2) = new_regressor_from_filename(&filename, true).unwrap();
+        assert_eq!(re2.predict(&fbuf), re.predict(&fbuf));
+
+        // A corrupted weight is detected by the checksum of its block
+        let mut corrupted = weights.clone();
+        corrupted[4 + 16 + 8 * 5] ^= 1;
+        write_regressor_file(&filename, 6, &mi, &vw, &corrupted);
+        assert_eq!(load_error(&filename, false),
+                   "Checksum of weights of block LR in regressor file does not match, the file is corrupted");
+
+        // Truncated file
+        write_regressor_file(&filename, 6, &mi, &vw, &weights[..100]);
+        assert_eq!(load_error(&filename, true),
+                   "failed to fill whole buffer");
+
+        // Weights written with a different optimizer than the model says
+        let re_sgd = Regressor::new::<optimizer::OptimizerSGD>(&mi);
+        let mut sgd_weights = Vec::new();
+        re_sgd.write_blocks_to_buf(&mut sgd_weights).unwrap();
+        write_regressor_file(&filename, 6, &mi, &vw, &sgd_weights);
+        assert_eq!(load_error(&filename, false),
+                   "Block LR in regressor file has weights of optimizer SGD, expected Adagrad");
+
+        // Blocks with weights have to be in the file
+        let mut mi_lr = mi.clone();
+        mi_lr.ffm_k = 0;
+        let mut lr_weights = Vec::new();
+        regressor::get_regressor_with_weights(&mi_lr).write_blocks_to_buf(&mut lr_weights).unwrap();
+        write_regressor_file(&filename, 6, &mi, &vw, &lr_weights);
+        assert_eq!(load_error(&filename, false),
+                   "Regressor file has no weights for block FFM");
+    }
     
     
 
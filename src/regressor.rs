@@ -1,23 +1,18 @@
 //use std::mem::{self};
 use std::any::Any;
-use std::mem;
-use std::slice;
 use std::sync::Arc;
-use core::arch::x86_64::*;
-use merand48::*;
 use std::io;
 use std::io::Cursor;
 use std::error::Error;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use std::cmp::min;
 
 use crate::model_instance;
 use crate::feature_buffer;
 use crate::optimizer;
-use optimizer::OptimizerTrait;
 use crate::block_ffm::BlockFFM;
 use crate::block_lr::BlockLR;
-use crate::block_loss_functions::BlockSigmoid;
+use crate::block_loss_functions;
+use crate::block_helpers;
 
 
 
@@ -34,8 +29,50 @@ pub trait BlockTrait {
                          wsum: f32, 
                          fb: &feature_buffer::FeatureBuffer) -> f32;
 
+    // Batch version of forward(): wsums hold the sums of previous blocks for each of the feature buffers,
+    // predictions are written into predictions. By default feature buffers are simply forwarded one by one.
+    fn forward_batch(&self,
+                         further_blocks: &[Box<dyn BlockTrait>],
+                         wsums: &mut [f32],
+                         fbs: &[feature_buffer::FeatureBuffer],
+                         predictions: &mut [f32]) {
+        for (i, fb) in fbs.iter().enumerate() {
+            predictions[i] = self.forward(further_blocks, wsums[i], fb);
+        }
+    }
+
+    // Scoring many examples that share the context: prepare_context() is called once with the context part of
+    // the examples, then forward_with_context() for each of the examples with the rest of their features.
+    // Blocks that have nothing to precompute keep the defaults, which are only correct at the end of the chain.
+    fn prepare_context(&self,
+                         further_blocks: &[Box<dyn BlockTrait>],
+                         fb: &feature_buffer::FeatureBuffer,
+                         context: &mut ContextCache) {
+        if let Some((next_regressor, further_blocks)) = further_blocks.split_first() {
+            next_regressor.prepare_context(further_blocks, fb, context);
+        }
+    }
+
+    fn forward_with_context(&self,
+                         further_blocks: &[Box<dyn BlockTrait>],
+                         wsum: f32,
+                         fb: &feature_buffer::FeatureBuffer,
+                         _context: &mut ContextCache) -> f32 {
+        self.forward(further_blocks, wsum, fb)
+    }
+
     fn allocate_and_init_weights(&mut self, mi: &model_instance::ModelInstance);
     fn get_serialized_len(&self) -> usize;
+    // Type of the block, the optimizer of its weights (0 for none) and their length in bytes, see Regressor::write_blocks_to_buf()
+    fn get_block_type(&self) -> u32;
+    fn get_optimizer_tag(&self) -> u32 {
+        0
+    }
+    fn get_serialized_bytes(&self) -> usize;
+    // Forward-only blocks can use the weights of an f32 serving model in place, see Regressor::map_blocks_from_file()
+    fn map_weights(&mut self, _file: &Arc<block_helpers::MappedFile>, _offset: usize) -> Result<(), Box<dyn Error>> {
+        Err("Block has no weights to memory map")?
+    }
     fn write_weights_to_buf(&self, output_bufwriter: &mut dyn io::Write) -> Result<(), Box<dyn Error>>;
     fn read_weights_from_buf(&mut self, input_bufreader: &mut dyn io::Read) -> Result<(), Box<dyn Error>>;
     fn new_forward_only_without_weights(&self) -> Result<Box<dyn BlockTrait>, Box<dyn Error>>;
@@ -47,23 +84,101 @@ pub trait BlockTrait {
 }
 
 
+// What blocks precompute for the context shared by many examples, see Regressor::prepare_context()
+#[derive(Clone, Default)]
+pub struct ContextCache {
+    pub ffm_context_fields: Vec<bool>,
+    pub lr_wsum: f32,
+    pub ffm_wsum: f32,
+    pub ffm_contra_fields: Vec<f32>,
+}
+
+// Block types in regressor files, so values must never change
+pub const BLOCK_TYPE_LR: u32 = 1;
+pub const BLOCK_TYPE_FFM: u32 = 2;
+pub const BLOCK_TYPE_SIGMOID: u32 = 3;
+pub const BLOCK_TYPE_SQUARED: u32 = 4;
+pub const BLOCK_TYPE_HINGE: u32 = 5;
+pub const BLOCK_TYPE_QUANTILE: u32 = 6;
+// Zeros in front of blocks of f32 serving models, so that their weights start at a page and can be memory mapped
+pub const BLOCK_TYPE_PADDING: u32 = 100;
+const MAPPED_WEIGHTS_ALIGNMENT: u64 = 4096;
+
+// Blocks of serving models have only the weights, their layout is written in place of the optimizer
+pub const WEIGHTS_TAG_F32: u32 = 100;
+pub const WEIGHTS_TAG_F16: u32 = 101;
+pub const WEIGHTS_TAG_INT8: u32 = 102;
+
+// Precision of the weights of serving models (--export_serving_model)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ServingPrecision {
+    F32,
+    F16,
+    Int8,
+}
+
+fn layout_name(tag: u32) -> String {
+    match tag {
+        WEIGHTS_TAG_F32 => "f32 weights".to_string(),
+        WEIGHTS_TAG_F16 => "f16 weights".to_string(),
+        WEIGHTS_TAG_INT8 => "int8 weights".to_string(),
+        _ => optimizer::name_from_tag(tag),
+    }
+}
+
+// Length in bytes of num_weights weights without optimizer data, None if the layout has optimizer data
+fn weights_only_len(tag: u32, num_weights: u64) -> Option<u64> {
+    match tag {
+        WEIGHTS_TAG_F32 => Some(num_weights * 4),
+        WEIGHTS_TAG_F16 => Some(num_weights * 2),
+        WEIGHTS_TAG_INT8 => Some(4 + num_weights),
+        _ => None,
+    }
+}
+
+pub fn block_type_name(block_type: u32) -> String {
+    match block_type {
+        BLOCK_TYPE_LR => "LR".to_string(),
+        BLOCK_TYPE_FFM => "FFM".to_string(),
+        BLOCK_TYPE_SIGMOID => "logistic loss".to_string(),
+        BLOCK_TYPE_SQUARED => "squared loss".to_string(),
+        BLOCK_TYPE_HINGE => "hinge loss".to_string(),
+        BLOCK_TYPE_QUANTILE => "quantile loss".to_string(),
+        BLOCK_TYPE_PADDING => "padding".to_string(),
+        _ => format!("unknown ({})", block_type),
+    }
+}
+
 pub struct Regressor {
     pub regressor_name: String,
     pub blocks_boxes: Vec<Box<dyn BlockTrait>>,
     pub immutable: bool,
+    // Weights are read-only in a memory mapped file
+    pub mapped: bool,
 }
 
 
 pub fn get_regressor_without_weights(mi: &model_instance::ModelInstance) -> Regressor {
-    if mi.optimizer == model_instance::Optimizer::Adagrad {
-        if mi.fastmath {
-            Regressor::new_without_weights::<optimizer::OptimizerAdagradLUT>(&mi)
-        } else {
-            Regressor::new_without_weights::<optimizer::OptimizerAdagradFlex>(&mi)
-        }
-    } else {
-        Regressor::new_without_weights::<optimizer::OptimizerSGD>(&mi)
-    }    
+    match mi.optimizer {
+        model_instance::Optimizer::Adagrad if mi.fastmath => with_ffm_optimizer::<optimizer::OptimizerAdagradLUT>(mi),
+        model_instance::Optimizer::Adagrad => with_ffm_optimizer::<optimizer::OptimizerAdagradFlex>(mi),
+        model_instance::Optimizer::FTRL => with_ffm_optimizer::<optimizer::OptimizerFTRL>(mi),
+        model_instance::Optimizer::Adam => with_ffm_optimizer::<optimizer::OptimizerAdam>(mi),
+        model_instance::Optimizer::RMSProp => with_ffm_optimizer::<optimizer::OptimizerRMSProp>(mi),
+        model_instance::Optimizer::SGD => with_ffm_optimizer::<optimizer::OptimizerSGD>(mi),
+    }
+}
+
+// Second level of dispatch: LR optimizer is already chosen, now choose the FFM one
+fn with_ffm_optimizer<L: optimizer::OptimizerTrait + 'static>(mi: &model_instance::ModelInstance) -> Regressor {
+    match mi.ffm_optimizer.unwrap_or(mi.optimizer) {
+        model_instance::Optimizer::Adagrad if mi.fastmath => Regressor::new_without_weights_with_optimizers::<L, optimizer::OptimizerAdagradLUT>(mi),
+        model_instance::Optimizer::Adagrad => Regressor::new_without_weights_with_optimizers::<L, optimizer::OptimizerAdagradFlex>(mi),
+        model_instance::Optimizer::FTRL => Regressor::new_without_weights_with_optimizers::<L, optimizer::OptimizerFTRL>(mi),
+        model_instance::Optimizer::Adam => Regressor::new_without_weights_with_optimizers::<L, optimizer::OptimizerAdam>(mi),
+        model_instance::Optimizer::RMSProp => Regressor::new_without_weights_with_optimizers::<L, optimizer::OptimizerRMSProp>(mi),
+        model_instance::Optimizer::SGD => Regressor::new_without_weights_with_optimizers::<L, optimizer::OptimizerSGD>(mi),
+    }
 }
 
 pub fn get_regressor_with_weights(mi: &model_instance::ModelInstance) -> Regressor {
@@ -74,24 +189,36 @@ pub fn get_regressor_with_weights(mi: &model_instance::ModelInstance) -> Regress
 
 impl Regressor  {
     pub fn new_without_weights<L: optimizer::OptimizerTrait + 'static>(mi: &model_instance::ModelInstance) -> Regressor {
+        Regressor::new_without_weights_with_optimizers::<L, L>(mi)
+    }
+
+    // L is the optimizer of the LR block, F of the FFM block
+    pub fn new_without_weights_with_optimizers<L: optimizer::OptimizerTrait + 'static, F: optimizer::OptimizerTrait + 'static>(mi: &model_instance::ModelInstance) -> Regressor {
+
+        let regressor_name = if mi.ffm_k == 0 || L::get_name() == F::get_name() {
+            format!("Regressor with optimizer {:?}", L::get_name())
+        } else {
+            format!("Regressor with optimizers {:?} (LR) and {:?} (FFM)", L::get_name(), F::get_name())
+        };
 
         let mut rg = Regressor{
             blocks_boxes: Vec::new(),
-            regressor_name: format!("Regressor with optimizer {:?}", L::get_name()),
+            regressor_name: regressor_name,
             immutable: false,
+            mapped: false,
         };
 
         // A bit more elaborate than necessary. Let's really make it clear what's happening
-        let mut reg_lr = BlockLR::<L>::new_without_weights(mi).unwrap();
+        let reg_lr = BlockLR::<L>::new_without_weights(mi).unwrap();
         rg.blocks_boxes.push(reg_lr);
 
         if mi.ffm_k > 0 {
-            let mut reg_ffm = BlockFFM::<L>::new_without_weights(mi).unwrap();
+            let reg_ffm = BlockFFM::<F>::new_without_weights(mi).unwrap();
             rg.blocks_boxes.push(reg_ffm);
         }
                     
-        let mut reg_sigmoid = BlockSigmoid::new_without_weights(mi).unwrap();
-        rg.blocks_boxes.push(reg_sigmoid);
+        let reg_loss_function = block_loss_functions::new_loss_function_block(mi).unwrap();
+        rg.blocks_boxes.push(reg_loss_function);
 
         rg
     }
@@ -123,14 +250,15 @@ impl Regressor  {
             // Important to know: learn() functions in blocks aren't guaranteed to be thread-safe
             panic!("This regressor is immutable, you cannot call learn() with update = true");
         }
-        let update:bool = update && (fb.example_importance != 0.0);
+        // Examples without a label (NAN) can only be used for prediction
+        let update:bool = update && (fb.example_importance != 0.0) && !fb.label.is_nan();
         if !update { // Fast-path for no-update case
             return self.predict(fb);
         }
 
         let blocks_list = &mut self.blocks_boxes[..];
         let (current, further_blocks) = &mut blocks_list.split_at_mut(1);
-        let (prediction_probability, general_gradient) = current[0].forward_backward(further_blocks, 0.0, fb, update);
+        let (prediction_probability, _general_gradient) = current[0].forward_backward(further_blocks, 0.0, fb, update);
     
         return prediction_probability
     }
@@ -142,8 +270,163 @@ impl Regressor  {
         let prediction_probability = current[0].forward(further_blocks, 0.0, fb);
         return prediction_probability
     }
+
+    // fb holds only the context part of the examples (FeatureBufferTranslator::translate_context()),
+    // ffm_context_fields tells which ffm fields come from the context
+    pub fn prepare_context(&self, fb: &feature_buffer::FeatureBuffer, ffm_context_fields: &[bool]) -> ContextCache {
+        let mut context = ContextCache {
+            ffm_context_fields: ffm_context_fields.to_vec(),
+            ..Default::default()
+        };
+        let blocks_list = &self.blocks_boxes[..];
+        let (current, further_blocks) = blocks_list.split_at(1);
+        current[0].prepare_context(further_blocks, fb, &mut context);
+        context
+    }
+
+    // fb holds the example without the context part (FeatureBufferTranslator::translate_with_context())
+    pub fn predict_with_context(&self, fb: &feature_buffer::FeatureBuffer, context: &mut ContextCache) -> f32 {
+        let blocks_list = &self.blocks_boxes[..];
+        let (current, further_blocks) = blocks_list.split_at(1);
+        current[0].forward_with_context(further_blocks, 0.0, fb, context)
+    }
+
+    // Predicts many examples at once. This is faster than predict() one by one when examples share
+    // features, for example when scoring many candidates for the same context.
+    pub fn predict_batch(&self, fbs: &[feature_buffer::FeatureBuffer]) -> Vec<f32> {
+        let mut wsums = vec![0.0; fbs.len()];
+        let mut predictions = vec![0.0; fbs.len()];
+        if fbs.len() > 0 {
+            let blocks_list = &self.blocks_boxes[..];
+            let (current, further_blocks) = blocks_list.split_at(1);
+            current[0].forward_batch(further_blocks, &mut wsums, fbs, &mut predictions);
+        }
+        predictions
+    }
     
-    // Yeah, this is weird. I just didn't want to break the format compatibility at this point
+    // Version 6 of regressor files: number of blocks, then for each block its type, the optimizer of its weights,
+    // the length of the weights in bytes, the weights and their CRC-32
+    pub fn write_blocks_to_buf(&self, output_bufwriter: &mut dyn io::Write) -> Result<(), Box<dyn Error>> {
+        output_bufwriter.write_u32::<LittleEndian>(self.blocks_boxes.len() as u32)?;
+        for block in &self.blocks_boxes {
+            write_block_record(output_bufwriter, block.get_block_type(), block.get_optimizer_tag(), block.get_serialized_bytes() as u64,
+                               &mut |writer| block.write_weights_to_buf(writer))?;
+        }
+        Ok(())
+    }
+
+    // Serving models are version 6 files with only the weights of blocks, optionally in lower precision.
+    // Only forward-only regressors (see immutable_regressor()) can be written as serving models.
+    // offset is the position in the file, f32 weights are padded to start at a page.
+    pub fn write_serving_blocks_to_buf(&self, output_bufwriter: &mut dyn io::Write, precision: ServingPrecision, offset: u64) -> Result<(), Box<dyn Error>> {
+        if !self.immutable {
+            return Err("Only immutable regressors can be written as serving models")?;
+        }
+        let blocks_with_weights = self.blocks_boxes.iter().filter(|block| block.get_serialized_len() > 0).count();
+        let num_records = match precision {
+            ServingPrecision::F32 => self.blocks_boxes.len() + blocks_with_weights,
+            _ => self.blocks_boxes.len(),
+        };
+        output_bufwriter.write_u32::<LittleEndian>(num_records as u32)?;
+        let mut position = offset + 4;
+        for block in &self.blocks_boxes {
+            let num_weights = block.get_serialized_len() as u64;
+            if num_weights == 0 {
+                write_block_record(output_bufwriter, block.get_block_type(), block.get_optimizer_tag(), 0, &mut |_| Ok(()))?;
+                position += 20;
+            } else if precision == ServingPrecision::F32 {
+                // Padding record and the header of the block record come before the weights
+                let padding = (MAPPED_WEIGHTS_ALIGNMENT - (position + 36) % MAPPED_WEIGHTS_ALIGNMENT) % MAPPED_WEIGHTS_ALIGNMENT;
+                write_block_record(output_bufwriter, BLOCK_TYPE_PADDING, 0, padding,
+                                   &mut |writer| Ok(writer.write_all(&vec![0; padding as usize])?))?;
+                // Forward-only blocks have no optimizer data, so their weights are already f32 only
+                write_block_record(output_bufwriter, block.get_block_type(), WEIGHTS_TAG_F32, num_weights * 4,
+                                   &mut |writer| block.write_weights_to_buf(writer))?;
+                position += 20 + padding + 20 + num_weights * 4;
+            } else if precision == ServingPrecision::F16 {
+                write_block_record(output_bufwriter, block.get_block_type(), WEIGHTS_TAG_F16, num_weights * 2, &mut |writer| {
+                    block.write_weights_to_buf(&mut block_helpers::WeightsEncoder::new(writer, block_helpers::WeightsEncoding::F16))
+                })?;
+            } else {
+                // One scale for the whole block, so that the largest weight is 127
+                let mut max_abs = block_helpers::MaxAbsWriter::default();
+                block.write_weights_to_buf(&mut max_abs)?;
+                let scale = max_abs.max_abs / 127.0;
+                write_block_record(output_bufwriter, block.get_block_type(), WEIGHTS_TAG_INT8, 4 + num_weights, &mut |writer| {
+                    writer.write_f32::<LittleEndian>(scale)?;
+                    block.write_weights_to_buf(&mut block_helpers::WeightsEncoder::new(writer, block_helpers::WeightsEncoding::Int8(scale)))
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn overwrite_blocks_from_buf(&mut self, input_bufreader: &mut dyn io::Read) -> Result<(), Box<dyn Error>> {
+        let descriptions = self.block_descriptions();
+        let blocks = &mut self.blocks_boxes;
+        read_blocks_from_buf(&descriptions, input_bufreader, false, &mut |i, _, reader| blocks[i].read_weights_from_buf(reader))
+    }
+
+    pub fn into_immutable_regressor_from_blocks_buf(&mut self, rg: &mut Regressor, input_bufreader: &mut dyn io::Read) -> Result<(), Box<dyn Error>> {
+        let descriptions = self.block_descriptions();
+        let blocks = &self.blocks_boxes;
+        read_blocks_from_buf(&descriptions, input_bufreader, true, &mut |i, weights_only, reader| {
+            if weights_only {
+                rg.blocks_boxes[i].read_weights_from_buf(reader)
+            } else {
+                blocks[i].read_weights_from_buf_into_forward_only(reader, &mut rg.blocks_boxes[i])
+            }
+        })
+    }
+
+    // Forward-only regressor uses the weights of an f32 serving model in place, offset is where the blocks start.
+    // Returns false when the file has weights that can't be used in place, with optimizer data, lower precision or unaligned.
+    pub fn map_blocks_from_file(&mut self, file: &Arc<block_helpers::MappedFile>, offset: usize) -> Result<bool, Box<dyn Error>> {
+        let descriptions = self.block_descriptions();
+        let mut input = Cursor::new(&file.as_slice()[offset..]);
+        let num_records = input.read_u32::<LittleEndian>()?;
+        let mut loaded = vec![false; descriptions.len()];
+        let mut next_block = 0;
+        for _ in 0..num_records {
+            let block_type = input.read_u32::<LittleEndian>()?;
+            let tag = input.read_u32::<LittleEndian>()?;
+            let len = input.read_u64::<LittleEndian>()?;
+            let data_offset = offset as u64 + input.position();
+            if input.position() + len + 4 > input.get_ref().len() as u64 {
+                return Err(format!("Regressor file ends in the weights of block {}", block_type_name(block_type)))?;
+            }
+            if let Some(i) = find_block(&descriptions, next_block, block_type) {
+                let num_weights = descriptions[i].3;
+                if num_weights > 0 {
+                    if tag != WEIGHTS_TAG_F32 || data_offset % MAPPED_WEIGHTS_ALIGNMENT != 0 {
+                        return Ok(false);
+                    }
+                    if len != num_weights * 4 {
+                        return Err(format!("Lengths of weights of block {} in regressor file differ: got {}, expected {}",
+                                           block_type_name(block_type), len, num_weights * 4))?;
+                    }
+                    self.blocks_boxes[i].map_weights(file, data_offset as usize)?;
+                }
+                loaded[i] = true;
+                next_block = i + 1;
+            }
+            // Checksums are not verified, that would read all the weights
+            input.set_position(input.position() + len + 4);
+        }
+        check_all_blocks_loaded(&descriptions, &loaded)?;
+        self.mapped = true;
+        Ok(true)
+    }
+
+    // Type, optimizer, length in bytes and number of weights of each block
+    fn block_descriptions(&self) -> Vec<(u32, u32, u64, u64)> {
+        self.blocks_boxes.iter().map(|block| (block.get_block_type(),
+                                              block.get_optimizer_tag(),
+                                              block.get_serialized_bytes() as u64,
+                                              block.get_serialized_len() as u64)).collect()
+    }
+
+    // Version 5 of regressor files: total number of weights of all blocks, then the weights of blocks one after another
     pub fn write_weights_to_buf(&self, output_bufwriter: &mut dyn io::Write) -> Result<(), Box<dyn Error>> {
         let length = self.blocks_boxes.iter().map(|block| block.get_serialized_len()).sum::<usize>() as u64;
         output_bufwriter.write_u64::<LittleEndian>(length as u64)?;
@@ -156,9 +439,7 @@ impl Regressor  {
     
 
     pub fn overwrite_weights_from_buf(&mut self, input_bufreader: &mut dyn io::Read) -> Result<(), Box<dyn Error>> {
-        // This is a bit weird format
-        // You would expect each block to have its own sig
-        // We'll break compatibility in next release or something similar
+        // Version 5 files: blocks carry no type, so the block chain and optimizers have to match the model exactly
         let len = input_bufreader.read_u64::<LittleEndian>()?;
         let expected_length = self.blocks_boxes.iter().map(|block| block.get_serialized_len()).sum::<usize>() as u64;
         if len != expected_length {
@@ -196,7 +477,7 @@ impl Regressor  {
 
     // Create immutable regressor from current regressor
     pub fn immutable_regressor(&mut self, mi: &model_instance::ModelInstance) -> Result<Regressor, Box<dyn Error>> {
-        // Only to be used by unit tests 
+        // Used by unit tests and for model inspection (--invert_hash)
         let mut rg = self.immutable_regressor_without_weights(&mi)?;
         rg.allocate_and_init_weights(&mi);
 
@@ -214,6 +495,100 @@ impl Regressor  {
 }
 
 
+fn write_block_record(output_bufwriter: &mut dyn io::Write,
+                      block_type: u32,
+                      tag: u32,
+                      len: u64,
+                      write_weights: &mut dyn FnMut(&mut dyn io::Write) -> Result<(), Box<dyn Error>>) -> Result<(), Box<dyn Error>> {
+    output_bufwriter.write_u32::<LittleEndian>(block_type)?;
+    output_bufwriter.write_u32::<LittleEndian>(tag)?;
+    output_bufwriter.write_u64::<LittleEndian>(len)?;
+    let mut writer = block_helpers::ChecksumWriter::new(output_bufwriter);
+    write_weights(&mut writer)?;
+    if writer.len != len {
+        return Err(format!("Block {} wrote {} bytes of weights, expected {}", block_type_name(block_type), writer.len, len))?;
+    }
+    let crc = writer.crc;
+    output_bufwriter.write_u32::<LittleEndian>(crc)?;
+    Ok(())
+}
+
+// Reads version 6 block records. Records are matched to the blocks of the regressor by type, in order, and records of
+// blocks that the regressor doesn't have are skipped. Blocks with weights must all be found in the file.
+// Weights-only records of serving models can only be read into forward-only regressors, read_block is told which it gets.
+fn read_blocks_from_buf(blocks: &[(u32, u32, u64, u64)],
+                        input_bufreader: &mut dyn io::Read,
+                        forward_only: bool,
+                        read_block: &mut dyn FnMut(usize, bool, &mut dyn io::Read) -> Result<(), Box<dyn Error>>) -> Result<(), Box<dyn Error>> {
+    let num_records = input_bufreader.read_u32::<LittleEndian>()?;
+    let mut loaded = vec![false; blocks.len()];
+    let mut next_block = 0;
+    for _ in 0..num_records {
+        let block_type = input_bufreader.read_u32::<LittleEndian>()?;
+        let tag = input_bufreader.read_u32::<LittleEndian>()?;
+        let len = input_bufreader.read_u64::<LittleEndian>()?;
+        let name = block_type_name(block_type);
+        let mut block_reader = io::Read::take(&mut *input_bufreader, len);
+        let mut reader = block_helpers::ChecksumReader::new(&mut block_reader);
+        match find_block(blocks, next_block, block_type) {
+            Some(i) => {
+                let (_, expected_tag, mut expected_len, num_weights) = blocks[i];
+                let weights_only = tag != expected_tag && weights_only_len(tag, num_weights).is_some();
+                if tag != expected_tag && !weights_only {
+                    return Err(format!("Block {} in regressor file has weights of optimizer {}, expected {}", name,
+                                        layout_name(tag), layout_name(expected_tag)))?;
+                }
+                if weights_only {
+                    if !forward_only {
+                        return Err(format!("Regressor file is a serving model with only the weights of block {}, it can only be loaded for predictions", name))?;
+                    }
+                    expected_len = weights_only_len(tag, num_weights).unwrap();
+                }
+                if len != expected_len {
+                    return Err(format!("Lengths of weights of block {} in regressor file differ: got {}, expected {}", name, len, expected_len))?;
+                }
+                match tag {
+                    WEIGHTS_TAG_F16 => read_block(i, true, &mut block_helpers::WeightsDecoder::new(&mut reader, block_helpers::WeightsEncoding::F16))?,
+                    WEIGHTS_TAG_INT8 => {
+                        let scale = reader.read_f32::<LittleEndian>()?;
+                        read_block(i, true, &mut block_helpers::WeightsDecoder::new(&mut reader, block_helpers::WeightsEncoding::Int8(scale)))?
+                    },
+                    _ => read_block(i, weights_only, &mut reader)?,
+                }
+                loaded[i] = true;
+                next_block = i + 1;
+            },
+            None => {
+                io::copy(&mut reader, &mut io::sink())?;
+            },
+        }
+        if reader.len != len {
+            return Err(format!("Regressor file ends in the weights of block {}", name))?;
+        }
+        let crc = reader.crc;
+        if input_bufreader.read_u32::<LittleEndian>()? != crc {
+            return Err(format!("Checksum of weights of block {} in regressor file does not match, the file is corrupted", name))?;
+        }
+    }
+    check_all_blocks_loaded(blocks, &loaded)
+}
+
+// Records are matched to the blocks of the regressor by type, in order
+fn find_block(blocks: &[(u32, u32, u64, u64)], next_block: usize, block_type: u32) -> Option<usize> {
+    (next_block..blocks.len()).find(|i| blocks[*i].0 == block_type)
+}
+
+fn check_all_blocks_loaded(blocks: &[(u32, u32, u64, u64)], loaded: &[bool]) -> Result<(), Box<dyn Error>> {
+    for (i, (block_type, _, len, _)) in blocks.iter().enumerate() {
+        if !loaded[i] && *len > 0 {
+            return Err(format!("Regressor file has no weights for block {}", block_type_name(*block_type)))?;
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
@@ -369,5 +744,152 @@ mod tests {
         assert_eq!(re.learn(&fb_instance, true), 0.4875807);
     }
 
-}
+    #[test]
+    fn test_squared_loss() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();        
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.loss_function = model_instance::LossFunction::Squared;
+        mi.link = model_instance::Link::Identity;
+        
+        let mut re = Regressor::new::<optimizer::OptimizerSGD>(&mi);
+        let mut fb_instance = lr_vec(vec![HashAndValue{hash: 1, value: 1.0}]);
+        fb_instance.label = 2.0;
+        assert_eq!(re.learn(&fb_instance, true), 0.0);
+        assert_eq!(re.learn(&fb_instance, true), 0.2);
+        assert_eq!(re.learn(&fb_instance, true), 0.38);
+        assert_eq!(re.predict(&fb_instance), 0.542);
+    }
+
+    #[test]
+    fn test_ftrl() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();        
+        mi.optimizer = model_instance::Optimizer::FTRL;
+        mi.ftrl_alpha = 0.1;
+        mi.ftrl_beta = 1.0;
+        
+        let mut re = get_regressor_with_weights(&mi);
+        assert_eq!(re.get_name(), "Regressor with optimizer \"FTRL\"");
+        let mut fb_instance = lr_vec(vec![HashAndValue{hash: 1, value: 1.0}]);
+        fb_instance.label = 1.0;
+        assert_eq!(re.learn(&fb_instance, true), 0.5);
+        assert_eq!(re.learn(&fb_instance, true), 0.5083326);
+        assert_eq!(re.learn(&fb_instance, true), 0.5155534);
 
+        // Strong L1 keeps the weight at exactly zero
+        mi.l1 = 1.0;
+        let mut re = get_regressor_with_weights(&mi);
+        assert_eq!(re.learn(&fb_instance, true), 0.5);
+        assert_eq!(re.learn(&fb_instance, true), 0.5);
+        assert_eq!(re.learn(&fb_instance, true), 0.5);
+    }
+
+    #[test]
+    fn test_predict_batch() {
+        use crate::feature_buffer::HashAndValueAndSeq;
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.ffm_k = 4;
+        mi.ffm_bit_precision = 18;
+        mi.ffm_fields = vec![vec![], vec![], vec![]];
+        let re = get_regressor_with_weights(&mi);
+
+        // Fields 0 and 1 are the context, field 2 is the candidate
+        let candidate = |lr_hash: u32, ffm_hash: u32, value: f32| {
+            let mut fb = lr_vec(vec![HashAndValue{hash: 1, value: 1.0}, HashAndValue{hash: lr_hash, value: 1.0}]);
+            fb.ffm_fields_count = 3;
+            fb.ffm_buffer = vec![HashAndValueAndSeq{hash: 100, value: 1.0, contra_field_index: 0},
+                                 HashAndValueAndSeq{hash: 200, value: 1.0, contra_field_index: 0},
+                                 HashAndValueAndSeq{hash: 300, value: 0.5, contra_field_index: mi.ffm_k * 1},
+                                 HashAndValueAndSeq{hash: ffm_hash, value: value, contra_field_index: mi.ffm_k * 2}];
+            fb
+        };
+        let mut fbs = vec![candidate(2, 1000, 1.0), candidate(3, 2000, 1.0), candidate(4, 3000, 2.0)];
+        let check = |fbs: &Vec<feature_buffer::FeatureBuffer>| {
+            let predictions = re.predict_batch(fbs);
+            assert_eq!(predictions.len(), fbs.len());
+            for (fb, p) in fbs.iter().zip(predictions.iter()) {
+                assert!((re.predict(fb) - p).abs() < 1e-6);
+            }
+        };
+        check(&fbs);
+        // Context differs in one of the candidates, and another has a missing field
+        fbs[1].ffm_buffer[2].hash = 400;
+        fbs[2].ffm_buffer.truncate(3);
+        check(&fbs);
+        assert_eq!(re.predict_batch(&[]).len(), 0);
+    }
+
+    #[test]
+    fn test_separate_ffm_optimizer() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();        
+        mi.optimizer = model_instance::Optimizer::Adagrad;
+        mi.ffm_optimizer = Some(model_instance::Optimizer::Adam);
+        mi.fastmath = false;
+        mi.ffm_k = 1;
+        mi.ffm_fields = vec![vec![], vec![]];
+
+        let re = get_regressor_with_weights(&mi);
+        assert_eq!(re.get_name(), "Regressor with optimizers \"AdagradFlex\" (LR) and \"Adam\" (FFM)");
+
+        // Without FFM only LR optimizer matters
+        mi.ffm_k = 0;
+        let re = get_regressor_with_weights(&mi);
+        assert_eq!(re.get_name(), "Regressor with optimizer \"AdagradFlex\"");
+    }
+
+    #[test]
+    fn test_adam() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();        
+        mi.optimizer = model_instance::Optimizer::Adam;
+        mi.learning_rate = 0.1;
+        
+        let mut re = get_regressor_with_weights(&mi);
+        let mut fb_instance = lr_vec(vec![HashAndValue{hash: 1, value: 1.0}]);
+        fb_instance.label = 1.0;
+        assert_eq!(re.learn(&fb_instance, true), 0.5);
+        // first Adam step moves the weight by learning rate
+        assert_eq!(re.learn(&fb_instance, true), 0.52497917);
+    }
+
+    #[test]
+    fn test_l2_regularization() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();        
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.loss_function = model_instance::LossFunction::Squared;
+        mi.link = model_instance::Link::Identity;
+        mi.l2 = 0.5;
+        
+        let mut re = Regressor::new::<optimizer::OptimizerSGD>(&mi);
+        let mut fb_instance = lr_vec(vec![HashAndValue{hash: 1, value: 1.0}]);
+        fb_instance.label = 2.0;
+        assert_eq!(re.learn(&fb_instance, true), 0.0);
+        // gradient: (2.0 - 0.2) - 0.5 * 0.2 = 1.7
+        assert_eq!(re.learn(&fb_instance, true), 0.2);
+        assert_eq!(re.predict(&fb_instance), 0.37);
+    }
+
+    #[test]
+    fn test_l1_regularization() {
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();        
+        mi.learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.loss_function = model_instance::LossFunction::Squared;
+        mi.link = model_instance::Link::Identity;
+        mi.l1 = 1.5;
+        
+        let mut re = Regressor::new::<optimizer::OptimizerSGD>(&mi);
+        let mut fb_instance = lr_vec(vec![HashAndValue{hash: 1, value: 1.0}]);
+        fb_instance.label = 2.0;
+        assert_eq!(re.learn(&fb_instance, true), 0.0);
+        assert_eq!(re.learn(&fb_instance, true), 0.05);
+        assert_eq!(re.predict(&fb_instance), 0.095000006);
+
+        // Gradient weaker than L1 keeps a zero weight at zero
+        mi.l1 = 3.0;
+        let mut re = Regressor::new::<optimizer::OptimizerSGD>(&mi);
+        assert_eq!(re.learn(&fb_instance, true), 0.0);
+        assert_eq!(re.learn(&fb_instance, true), 0.0);
+    }
+
+}
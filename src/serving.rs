@@ -1,12 +1,19 @@
 use std::error::Error;
 use std::net;
+use std::os::unix::net as unix_net;
+use std::os::unix::fs::FileTypeExt;
+use std::fs;
 use std::io::{BufReader, BufWriter};
 use std::io;
 use std::thread;
 use std::sync::mpsc;
 use std::sync::Arc;
 use std::sync::Mutex;
-use std::ops::DerefMut;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, AtomicUsize, Ordering};
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+use std::mem;
+use std::ptr;
 use std::str;
 use daemonize::Daemonize;
 
@@ -15,25 +22,190 @@ use crate::vwmap;
 use crate::regressor;
 use crate::feature_buffer;
 use crate::model_instance;
-use crate::optimizer;
 use crate::persistence;
-use crate::regressor::Regressor;
 use crate::multithread_helpers::{BoxedRegressorTrait};
+use crate::serving_http;
+use crate::serving_metrics;
+use crate::serving_metrics::WorkerMetrics;
+use serde_json::json;
 
 
 
 pub struct Serving {
-    listening_interface: String,
+    listening_interface: net::SocketAddr,
+    http_listening_interface: Option<net::SocketAddr>,
+    metrics_listening_interface: Option<net::SocketAddr>,
+    // When set, the line protocol is served on this unix domain socket instead of TCP
+    unix_socket: Option<String>,
     worker_threads: Vec<thread::JoinHandle<u32>>,
-    sender: mpsc::Sender<net::TcpStream>,
+    // Dropped on shutdown, workers exit when they see the channel closed
+    sender: Option<mpsc::Sender<Connection>>,
     foreground: bool,
+    state: Arc<ServingState>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    // Connections accepted, but not yet picked up by a worker. 0 means no limit.
+    max_pending_connections: usize,
+    shutdown_timeout: Duration,
+}
+
+// Shared by the accept loop and all the worker threads
+pub struct ServingState {
+    shutting_down: AtomicBool,
+    pending_connections: AtomicUsize,
+    // Indexed by worker id
+    metrics: Vec<WorkerMetrics>,
+    // Reloading puts a new model here and bumps the generation, workers pick it up before the next example.
+    // Old model is freed when the last worker stops using it.
+    model: Mutex<Arc<ServedModel>>,
+    model_generation: AtomicU64,
+    // Reloaded models are memory mapped too (--mmap)
+    mmap: bool,
+    // Clients can load models from paths of their choice (--allow_model_loading)
+    allow_model_loading: bool,
+}
+
+impl ServingState {
+    pub fn new(num_workers: usize, model: ServedModel) -> ServingState {
+        ServingState {
+            shutting_down: AtomicBool::new(false),
+            pending_connections: AtomicUsize::new(0),
+            metrics: (0..num_workers).map(|_| WorkerMetrics::default()).collect(),
+            model: Mutex::new(Arc::new(model)),
+            model_generation: AtomicU64::new(0),
+            mmap: false,
+            allow_model_loading: false,
+        }
+    }
+
+    // Loads the model into new memory and swaps it in. Unless allow_structure_change is set,
+    // the new model has to have the same structure and namespaces as the current one.
+    pub fn reload(&self, filename: &str, allow_structure_change: bool) -> Result<(), Box<dyn Error>> {
+        let (mi, vw, re) = if self.mmap {
+            persistence::new_regressor_from_filename_mmap(filename)?
+        } else {
+            persistence::new_regressor_from_filename(filename, true)?
+        };
+        let mut model = self.model.lock().unwrap();
+        if !allow_structure_change {
+            persistence::check_compatible(&model.mi, &model.vw, &mi, &vw)?;
+        }
+        *model = Arc::new(ServedModel::new(mi, vw, re));
+        self.model_generation.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+// Weights are never written to while serving, workers only predict with them
+pub struct ServedModel {
+    mi: model_instance::ModelInstance,
+    vw: vwmap::VwNamespaceMap,
+    re: BoxedRegressorTrait,
+}
+
+impl ServedModel {
+    pub fn new(mi: model_instance::ModelInstance, vw: vwmap::VwNamespaceMap, re: regressor::Regressor) -> ServedModel {
+        ServedModel {
+            mi: mi,
+            vw: vw,
+            re: BoxedRegressorTrait::new(Box::new(re)),
+        }
+    }
+}
+
+// Both protocols are served by the same pool of worker threads
+pub enum Connection {
+    Line(net::TcpStream),
+    LineUnix(unix_net::UnixStream),
+    Http(net::TcpStream),
+}
+
+impl Connection {
+    fn set_timeouts(&self, read_timeout: Option<Duration>, write_timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Connection::Line(stream) | Connection::Http(stream) => {
+                stream.set_nonblocking(false)?;
+                stream.set_read_timeout(read_timeout)?;
+                stream.set_write_timeout(write_timeout)
+            },
+            Connection::LineUnix(stream) => {
+                stream.set_nonblocking(false)?;
+                stream.set_read_timeout(read_timeout)?;
+                stream.set_write_timeout(write_timeout)
+            },
+        }
+    }
+
+    // Tells the client that the daemon is overloaded, the connection is closed when dropped
+    fn reject(self) {
+        let message = "Too many pending connections";
+        let _ = match self {
+            Connection::Line(mut stream) => io::Write::write_all(&mut stream, format!("ERR: {}\n", message).as_bytes()),
+            Connection::LineUnix(mut stream) => io::Write::write_all(&mut stream, format!("ERR: {}\n", message).as_bytes()),
+            Connection::Http(mut stream) => serving_http::write_response(&mut stream, 503, &serving_http::error_body(message), false)
+                                                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string())),
+        };
+    }
+}
+
+// The signal handler only writes to this pipe, the accept loop polls its read end next to the listening sockets
+static SHUTDOWN_PIPE_WRITE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn handle_shutdown_signal(_signal: libc::c_int) {
+    let fd = SHUTDOWN_PIPE_WRITE.load(Ordering::SeqCst);
+    if fd >= 0 {
+        unsafe { libc::write(fd, b"x".as_ptr() as *const libc::c_void, 1); }
+    }
+}
+
+// Installs SIGTERM and SIGINT handlers, returns the file descriptor that becomes readable on the signal
+fn install_shutdown_handler() -> Result<libc::c_int, Box<dyn Error>> {
+    let mut fds: [libc::c_int; 2] = [-1, -1];
+    unsafe {
+        if libc::pipe(fds.as_mut_ptr()) != 0 {
+            return Err(format!("Cannot create the shutdown pipe: {}", io::Error::last_os_error()))?;
+        }
+        // Signal handler must never block, even if signals keep coming
+        libc::fcntl(fds[1], libc::F_SETFL, libc::fcntl(fds[1], libc::F_GETFL) | libc::O_NONBLOCK);
+        SHUTDOWN_PIPE_WRITE.store(fds[1], Ordering::SeqCst);
+        for signal in &[libc::SIGTERM, libc::SIGINT] {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = handle_shutdown_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(*signal, &action, ptr::null_mut()) != 0 {
+                return Err(format!("Cannot install the signal handler: {}", io::Error::last_os_error()))?;
+            }
+        }
+    }
+    Ok(fds[0])
+}
+
+enum LineListener {
+    Tcp(net::TcpListener),
+    Unix(unix_net::UnixListener),
+}
+
+// Timeouts and broken connections, as opposed to parse errors that the parser also returns as io::Error
+fn is_stream_error(e: &Box<dyn Error>) -> bool {
+    match e.downcast_ref::<io::Error>() {
+        Some(e) => e.kind() != io::ErrorKind::Other && e.kind() != io::ErrorKind::InvalidData,
+        None => false,
+    }
 }
 
 pub struct WorkerThread {
     id: u32,
-    re_fixed: BoxedRegressorTrait,
+    // Translator and parser are made for this model, all three change together
+    model: Arc<ServedModel>,
+    model_generation: u64,
     fbt: feature_buffer::FeatureBufferTranslator,
     pa: parser::VowpalParser,
+    // Set by the "context" command, features of the context are shared by all the examples that follow
+    context_line: Vec<u8>,
+    context_record: Vec<u32>,
+    context: Option<regressor::ContextCache>,
+    state: Arc<ServingState>,
 }
 
 pub trait IsEmpty {
@@ -44,6 +216,11 @@ impl IsEmpty for io::BufReader<&net::TcpStream> {
         return self.buffer().is_empty();
     }
 }
+impl IsEmpty for io::BufReader<&unix_net::UnixStream> {
+    fn is_empty(&mut self) -> bool {
+        return self.buffer().is_empty();
+    }
+}
 
 // These are used only for unit-tests
 #[derive (Debug, PartialEq)]
@@ -51,23 +228,18 @@ pub enum ConnectionEnd {
     EndOfStream,
     StreamWriteError,
     StreamFlushError,
+    StreamReadError,
     ParseError,
+    Shutdown,
 }
 
 impl WorkerThread {
     pub fn new(
         id: u32, 
-        re_fixed: BoxedRegressorTrait, 
-        fbt: feature_buffer::FeatureBufferTranslator, 
-        pa: parser::VowpalParser,
-        receiver: Arc<Mutex<mpsc::Receiver<net::TcpStream>>>,
+        receiver: Arc<Mutex<mpsc::Receiver<Connection>>>,
+        state: Arc<ServingState>,
     ) -> Result<thread::JoinHandle<u32>, Box<dyn Error>> {
-        let mut wt = WorkerThread {
-            id: id,
-            re_fixed: re_fixed,
-            fbt: fbt,
-            pa: pa
-        };
+        let mut wt = WorkerThread::with_state(id, state);
         let thread = thread::spawn(move || {
             wt.start(receiver);
             1u32
@@ -75,20 +247,53 @@ impl WorkerThread {
         Ok(thread)
     }
 
+    pub fn with_state(id: u32, state: Arc<ServingState>) -> WorkerThread {
+        let model = Arc::clone(&state.model.lock().unwrap());
+        WorkerThread {
+            id: id,
+            model_generation: state.model_generation.load(Ordering::SeqCst),
+            fbt: feature_buffer::FeatureBufferTranslator::new(&model.mi),
+            pa: parser::VowpalParser::new(&model.vw),
+            model: model,
+            context_line: Vec::new(),
+            context_record: Vec::new(),
+            context: None,
+            state: state,
+        }
+    }
+
+    // Switches to the newest model if it was reloaded, the context is then computed again
+    fn refresh_model(&mut self) -> Result<(), Box<dyn Error>> {
+        let generation = self.state.model_generation.load(Ordering::SeqCst);
+        if generation == self.model_generation {
+            return Ok(());
+        }
+        let model = Arc::clone(&self.state.model.lock().unwrap());
+        self.fbt = feature_buffer::FeatureBufferTranslator::new(&model.mi);
+        self.pa = parser::VowpalParser::new(&model.vw);
+        self.model = model;
+        self.model_generation = generation;
+        if self.context.is_some() {
+            let context_line = self.context_line.clone();
+            if let Err(e) = self.set_context(&context_line) {
+                self.context = None;
+                return Err(format!("Context does not fit the reloaded model: {}", e))?;
+            }
+        }
+        Ok(())
+    }
+
     pub fn handle_connection(&mut self, 
                              reader: &mut (impl io::BufRead + IsEmpty),
                              writer: &mut impl io::Write,
                              ) -> ConnectionEnd
     {
         let mut i = 0u64;  // This is per-thread example number
+        self.context = None;
         loop {
-            let reading_result = self.pa.next_vowpal(reader);
-
-            match reading_result {
-                Ok([]) => return ConnectionEnd::EndOfStream, // EOF
-                Ok(buffer2) => {
-                    self.fbt.translate(buffer2, i);
-                    let p = self.re_fixed.predict(&(self.fbt.feature_buffer));
+            match self.predict_next(reader, i) {
+                Ok(None) => return ConnectionEnd::EndOfStream, // EOF
+                Ok(Some(p)) => {
                     let p_res = format!("{:.6}\n", p);
                     match writer.write_all(p_res.as_bytes()) {
                         Ok(_) => {},
@@ -97,8 +302,12 @@ impl WorkerThread {
                 },
                 Err(e) =>
                     {
-                        if e.is::<parser::FlushCommand>() {
+                        if is_stream_error(&e) {
+                            // Read timeout or a broken connection, there is no one to reply to
+                            return ConnectionEnd::StreamReadError;
+                        } else if e.is::<parser::FlushCommand>() {
                             // FlushCommand just causes us to flush, not to break
+                            WorkerMetrics::inc(&self.metrics().flushes);
                             match writer.flush() {
                                 Ok(_) => {},
                                 Err(_e) => { /*println!("Flushing the socket failed, dropping it");*/ return ConnectionEnd::StreamFlushError; }
@@ -106,15 +315,18 @@ impl WorkerThread {
                         } else if e.is::<parser::HogwildLoadCommand>() {
                             // FlushCommand just causes us to flush, not to break
                             let hogwild_command = e.downcast_ref::<parser::HogwildLoadCommand>().unwrap();
-                            match persistence::hogwild_load(self.re_fixed.deref_mut(), &hogwild_command.filename) {
+                            match self.reload(&hogwild_command.filename, false) {
                                 Ok(_) => {
+                                    WorkerMetrics::inc(&self.metrics().reloads);
                                     let p_res = format!("hogwild_load success\n");
                                     match writer.write_all(p_res.as_bytes()) {
                                         Ok(_) => {},
                                         Err(_e) => { /*println!("Write to socket failed, dropping it"); */ return ConnectionEnd::StreamWriteError; }
                                     };
                                 },
-                                Err(_e) => {
+                                Err(e) => {
+                                    println!("hogwild_load of {} failed: {}", hogwild_command.filename, e);
+                                    WorkerMetrics::inc(&self.metrics().reload_errors);
                                     // TODO This kind of error should fold the whole daemon...
                                     let p_res = format!("ERR: hogwild_load fail\n");
                                     match writer.write_all(p_res.as_bytes()) {
@@ -124,8 +336,45 @@ impl WorkerThread {
                                     return ConnectionEnd::StreamWriteError;
                                 }
                             }                   
+                        } else if e.is::<parser::LoadModelCommand>() {
+                            let load_command = e.downcast_ref::<parser::LoadModelCommand>().unwrap();
+                            let p_res = match self.check_model_loading_allowed().and_then(|_| self.reload(&load_command.filename, true)) {
+                                Ok(_) => {
+                                    WorkerMetrics::inc(&self.metrics().reloads);
+                                    format!("load_model success\n")
+                                },
+                                Err(e) => {
+                                    WorkerMetrics::inc(&self.metrics().reload_errors);
+                                    format!("ERR: load_model fail: {}\n", e)
+                                },
+                            };
+                            match writer.write_all(p_res.as_bytes()) {
+                                Ok(_) => {},
+                                Err(_e) => { return ConnectionEnd::StreamWriteError; }
+                            };
+                        } else if e.is::<parser::ContextCommand>() {
+                            let context_command = e.downcast_ref::<parser::ContextCommand>().unwrap();
+                            if let Err(e) = self.set_context(&context_command.line) {
+                                WorkerMetrics::inc(&self.metrics().parse_errors);
+                                let p_res = format!("ERR: {}\n", e.to_string());
+                                match writer.write_all(p_res.as_bytes()) {
+                                    Ok(_) => match writer.flush() {
+                                        Ok(_) => {},
+                                        Err(_e) => { return ConnectionEnd::StreamFlushError; }
+                                    },
+                                    Err(_e) => { return ConnectionEnd::StreamWriteError; }
+                                };
+                                return ConnectionEnd::ParseError;
+                            }
+                        } else if e.is::<parser::StatsCommand>() {
+                            let stats = format!("{}# EOF\n", serving_metrics::prometheus_text(&self.state.metrics));
+                            match writer.write_all(stats.as_bytes()) {
+                                Ok(_) => {},
+                                Err(_e) => { return ConnectionEnd::StreamWriteError; }
+                            };
                         } else
                         {
+                            WorkerMetrics::inc(&self.metrics().parse_errors);
                             let p_res = format!("ERR: {}\n", e.to_string());
                             match writer.write_all(p_res.as_bytes()) {
                                 Ok(_) => match writer.flush() {
@@ -145,25 +394,240 @@ impl WorkerThread {
                     Ok(_) => {},
                     Err(_e) => { /*println!("Flushing the socket failed, dropping it");*/ return ConnectionEnd::StreamFlushError; }
                 };
+                // Everything received so far is answered, good time to close when draining
+                if self.state.shutting_down.load(Ordering::SeqCst) {
+                    return ConnectionEnd::Shutdown;
+                }
             }
             i += 1;
         }
     }
     
-    pub fn start(&mut self, receiver: Arc<Mutex<mpsc::Receiver<net::TcpStream>>>) -> () {
+    // Reads and predicts the next example, None at the end of the stream. Commands are returned as errors, like from the parser.
+    fn predict_next(&mut self, reader: &mut impl io::BufRead, example_num: u64) -> Result<Option<f32>, Box<dyn Error>> {
+        self.refresh_model()?;
+        if !self.parse_next(reader)? {
+            return Ok(None);
+        }
+        if self.state.model_generation.load(Ordering::SeqCst) != self.model_generation {
+            // Model was reloaded while we were waiting for this example, so it is parsed again for the new model
+            let line = self.pa.last_line().to_vec();
+            self.refresh_model()?;
+            self.parse_next(&mut &line[..])?;
+        }
+        let buffer = &self.pa.output_buffer;
+        let start = Instant::now();
+        let p = match &mut self.context {
+            Some(context) => {
+                self.fbt.translate_with_context(buffer, example_num);
+                self.model.re.predict_with_context(&(self.fbt.feature_buffer), context)
+            },
+            None => {
+                self.fbt.translate(buffer, example_num);
+                self.model.re.predict(&(self.fbt.feature_buffer))
+            },
+        };
+        let metrics = &self.state.metrics[self.id as usize];
+        metrics.prediction_latency.observe(start.elapsed());
+        WorkerMetrics::inc(&metrics.lines_predicted);
+        Ok(Some(p))
+    }
+
+    // Parses the next example into the output buffer of the parser, false at the end of the stream
+    fn parse_next(&mut self, reader: &mut impl io::BufRead) -> Result<bool, Box<dyn Error>> {
+        let buffer = match self.context {
+            Some(_) => self.pa.next_vowpal_with_context(reader, &self.context_record)?,
+            None => self.pa.next_vowpal(reader)?,
+        };
+        Ok(!buffer.is_empty())
+    }
+
+    fn metrics(&self) -> &WorkerMetrics {
+        &self.state.metrics[self.id as usize]
+    }
+
+    // Serves HTTP requests of one connection:
+    // POST /predict with {"examples": [{"A": ["a1", "a2:0.5"]}, ...], "context": {"U": ["u1"]}} ("context" is optional),
+    // responds with {"predictions": [...]}
+    // POST /reload with {"filename": "model.fw"} does the same as the hogwild_load command,
+    // with "allow_structure_change": true the same as the load_model command
+    // GET /health
+    pub fn handle_http_connection(&mut self,
+                             reader: &mut impl io::BufRead,
+                             writer: &mut impl io::Write,
+                             ) -> ConnectionEnd
+    {
+        loop {
+            let request = match serving_http::read_request(reader) {
+                Ok(Some(request)) => request,
+                Ok(None) => return ConnectionEnd::EndOfStream,
+                Err(e) if is_stream_error(&e) => return ConnectionEnd::StreamReadError,
+                Err(e) => {
+                    // We can't tell where the next request starts, so the connection is dropped
+                    WorkerMetrics::inc(&self.metrics().parse_errors);
+                    let _ = serving_http::write_response(writer, 400, &serving_http::error_body(&e.to_string()), false);
+                    return ConnectionEnd::ParseError;
+                }
+            };
+            let start = Instant::now();
+            let (status, body) = match (request.method.as_str(), request.path.as_str()) {
+                ("POST", "/predict") => match self.http_predict(&request.body) {
+                    Ok(predictions) => (200, json!({"predictions": predictions})),
+                    Err(e) => {
+                        WorkerMetrics::inc(&self.metrics().parse_errors);
+                        (400, serving_http::error_body(&e.to_string()))
+                    },
+                },
+                ("POST", "/reload") => match self.http_reload(&request.body) {
+                    Ok(_) => {
+                        WorkerMetrics::inc(&self.metrics().reloads);
+                        (200, json!({"status": "ok"}))
+                    },
+                    Err(e) => {
+                        WorkerMetrics::inc(&self.metrics().reload_errors);
+                        (500, serving_http::error_body(&e.to_string()))
+                    },
+                },
+                ("GET", "/health") => (200, json!({"status": "ok"})),
+                (_, "/predict") | (_, "/reload") | (_, "/health") => (405, serving_http::error_body("Method not allowed")),
+                _ => (404, serving_http::error_body("Not found")),
+            };
+            let shutting_down = self.state.shutting_down.load(Ordering::SeqCst);
+            if serving_http::write_response(writer, status, &body, request.keep_alive && !shutting_down).is_err() {
+                return ConnectionEnd::StreamWriteError;
+            }
+            self.metrics().http_request_latency.observe(start.elapsed());
+            if shutting_down {
+                return ConnectionEnd::Shutdown;
+            }
+            if !request.keep_alive {
+                return ConnectionEnd::EndOfStream;
+            }
+        }
+    }
+
+    fn http_predict(&mut self, body: &[u8]) -> Result<Vec<f32>, Box<dyn Error>> {
+        let request: serde_json::Value = serde_json::from_slice(body)?;
+        let examples = match request.get("examples").and_then(|examples| examples.as_array()) {
+            Some(examples) => examples,
+            None => return Err("Request has to have a list of \"examples\"")?,
+        };
+        self.refresh_model()?;
+        match request.get("context") {
+            Some(context) => self.set_context(serving_http::json_to_line(context)?.as_bytes())?,
+            None => self.context = None,
+        }
+        let mut lines: Vec<u8> = Vec::new();
+        for example in examples {
+            lines.extend_from_slice(serving_http::json_to_line(example)?.as_bytes());
+        }
+        let mut reader = &lines[..];
+        let mut predictions: Vec<f32> = Vec::with_capacity(examples.len());
+        for i in 0..examples.len() {
+            match self.predict_next(&mut reader, i as u64)? {
+                Some(p) => predictions.push(p),
+                None => return Err("Empty example")?,
+            }
+        }
+        Ok(predictions)
+    }
+
+    fn http_reload(&mut self, body: &[u8]) -> Result<(), Box<dyn Error>> {
+        let request: serde_json::Value = serde_json::from_slice(body)?;
+        let filename = match request.get("filename").and_then(|filename| filename.as_str()) {
+            Some(filename) => filename,
+            None => return Err("Request has to have a \"filename\"")?,
+        };
+        let allow_structure_change = request.get("allow_structure_change").and_then(|allow| allow.as_bool()).unwrap_or(false);
+        self.check_model_loading_allowed()?;
+        self.reload(filename, allow_structure_change)
+    }
+
+    // Loading a file that the client names is only possible when the daemon was started with --allow_model_loading
+    fn check_model_loading_allowed(&self) -> Result<(), Box<dyn Error>> {
+        if !self.state.allow_model_loading {
+            return Err("Loading models is disabled, the daemon has to be started with --allow_model_loading")?;
+        }
+        Ok(())
+    }
+
+    fn reload(&mut self, filename: &str, allow_structure_change: bool) -> Result<(), Box<dyn Error>> {
+        self.state.reload(filename, allow_structure_change)?;
+        self.refresh_model()
+    }
+
+    // Context line holds features shared by the examples of the connection that follow it (until the next context line),
+    // examples then only have the rest of the features. Empty context line removes the context.
+    fn set_context(&mut self, line: &[u8]) -> Result<(), Box<dyn Error>> {
+        if line == b"\n" {
+            self.context = None;
+            return Ok(());
+        }
+        let mut input = line;
+        self.context_record = self.pa.next_vowpal(&mut input)?.to_vec();
+        self.context_line = line.to_vec();
+        self.fbt.translate_context(&self.context_record);
+        self.context = Some(self.model.re.prepare_context(&self.fbt.feature_buffer, &self.fbt.ffm_context_fields));
+        Ok(())
+    }
+
+    pub fn start(&mut self, receiver: Arc<Mutex<mpsc::Receiver<Connection>>>) -> () {
         // Simple endless serving loop: receive new connection and serve it
         // when handle_connection exits, the connection is dropped
         loop {
-            let tcp_stream = receiver.lock().unwrap().recv().unwrap();
-            let mut reader = BufReader::new(&tcp_stream);
-            let mut writer = BufWriter::new(&tcp_stream);
-            self.handle_connection(&mut reader, &mut writer);
+            let connection = match receiver.lock().unwrap().recv() {
+                Ok(connection) => connection,
+                // Channel is closed when the daemon shuts down and all the queued connections were taken
+                Err(_) => return,
+            };
+            self.state.pending_connections.fetch_sub(1, Ordering::SeqCst);
+            WorkerMetrics::inc(&self.metrics().connections);
+            match connection {
+                Connection::Line(tcp_stream) => {
+                    let mut reader = BufReader::new(&tcp_stream);
+                    let mut writer = BufWriter::new(&tcp_stream);
+                    self.handle_connection(&mut reader, &mut writer);
+                },
+                Connection::LineUnix(unix_stream) => {
+                    let mut reader = BufReader::new(&unix_stream);
+                    let mut writer = BufWriter::new(&unix_stream);
+                    self.handle_connection(&mut reader, &mut writer);
+                },
+                Connection::Http(tcp_stream) => {
+                    let mut reader = BufReader::new(&tcp_stream);
+                    let mut writer = BufWriter::new(&tcp_stream);
+                    self.handle_http_connection(&mut reader, &mut writer);
+                },
+            }
         }
     }
     
 }
 
 
+// Address to listen on, IPv6 addresses can be given with or without brackets
+pub fn listening_address(bind: &str, port: u16) -> Result<net::SocketAddr, Box<dyn Error>> {
+    let ip: net::IpAddr = match bind.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(ip) => ip,
+        Err(_) => return Err(format!("--bind should be an IPv4 or IPv6 address, got: {}", bind))?,
+    };
+    Ok(net::SocketAddr::new(ip, port))
+}
+
+// Answers GET /metrics with the Prometheus text of all the workers
+pub fn serve_metrics(reader: &mut impl io::BufRead, writer: &mut impl io::Write, state: &ServingState) {
+    while let Ok(Some(request)) = serving_http::read_request(reader) {
+        let written = match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/metrics") => serving_http::write_text_response(writer, 200, "text/plain; version=0.0.4",
+                                                                      &serving_metrics::prometheus_text(&state.metrics), request.keep_alive),
+            _ => serving_http::write_response(writer, 404, &serving_http::error_body("Not found"), request.keep_alive),
+        };
+        if written.is_err() || !request.keep_alive {
+            return;
+        }
+    }
+}
+
 impl Serving {
     pub fn new<'a>(cl: &clap::ArgMatches<'a>,
                    vw: &vwmap::VwNamespaceMap,
@@ -179,20 +643,70 @@ impl Serving {
 
 
 
-        let listening_interface = format!("127.0.0.1:{}", port);
-        println!("Starting to listen on {}", listening_interface);
-        let mut s = Serving {
-            listening_interface: listening_interface.to_string(),
-            worker_threads: Vec::new(),
-            sender: sender,
-            foreground: cl.is_present("foreground"),
+        let bind = cl.value_of("bind").unwrap_or("127.0.0.1");
+        let listening_interface = listening_address(bind, port)?;
+        // Daemonizing changes the working directory to /, so relative socket paths are resolved now
+        let unix_socket = match cl.value_of("unix_socket") {
+            Some(path) => Some(std::env::current_dir()?.join(path).to_string_lossy().to_string()),
+            None => None
+        };
+        match &unix_socket {
+            Some(path) => println!("Starting to listen on unix socket {}", path),
+            None => println!("Starting to listen on {}", listening_interface),
+        }
+        let http_listening_interface = match cl.value_of("http_port") {
+            Some(http_port) => {
+                let http_port: u16 = http_port.parse().expect("http_port should be integer");
+                let http_listening_interface = listening_address(bind, http_port)?;
+                println!("Starting to listen for HTTP on {}", http_listening_interface);
+                Some(http_listening_interface)
+            },
+            None => None
+        };
+        let metrics_listening_interface = match cl.value_of("metrics_port") {
+            Some(metrics_port) => {
+                let metrics_port: u16 = metrics_port.parse().expect("metrics_port should be integer");
+                let metrics_listening_interface = listening_address(bind, metrics_port)?;
+                println!("Serving metrics on {}", metrics_listening_interface);
+                Some(metrics_listening_interface)
+            },
+            None => None
         };
-
         let num_children = match cl.value_of("num_children") {
             Some(num_children) => num_children.parse().expect("num_children should be integer"),
             None => 10
         };
         println!("Number of threads {}", num_children);
+        let seconds = |name: &str| -> Option<Duration> {
+            match cl.value_of(name) {
+                Some(value) => {
+                    let value: f32 = value.parse().expect(&format!("{} should be a number of seconds", name));
+                    if value > 0.0 { Some(Duration::from_secs_f32(value)) } else { None }
+                },
+                None => None
+            }
+        };
+        let mut s = Serving {
+            listening_interface: listening_interface,
+            http_listening_interface: http_listening_interface,
+            metrics_listening_interface: metrics_listening_interface,
+            unix_socket: unix_socket,
+            worker_threads: Vec::new(),
+            sender: Some(sender),
+            foreground: cl.is_present("foreground"),
+            state: Arc::new(ServingState {
+                mmap: cl.is_present("mmap"),
+                allow_model_loading: cl.is_present("allow_model_loading"),
+                ..ServingState::new(num_children as usize, ServedModel::new(mi.clone(), vw.clone(), *re_fixed))
+            }),
+            read_timeout: seconds("read_timeout"),
+            write_timeout: seconds("write_timeout"),
+            max_pending_connections: match cl.value_of("max_pending_connections") {
+                Some(max_pending_connections) => max_pending_connections.parse().expect("max_pending_connections should be integer"),
+                None => 0
+            },
+            shutdown_timeout: seconds("shutdown_timeout").unwrap_or(Duration::from_secs(10)),
+        };
 
         if !s.foreground {
             //  let stdout = File::create("/tmp/daemon.out").unwrap();
@@ -206,15 +720,10 @@ impl Serving {
             }
         }
 
-        let re_fixed2 = BoxedRegressorTrait::new(re_fixed);
-        let fbt = feature_buffer::FeatureBufferTranslator::new(mi);
-        let pa = parser::VowpalParser::new(&vw);
         for i in 0..num_children {
             let newt = WorkerThread::new(i,
-                                         re_fixed2.clone(),
-                                         fbt.clone(),
-                                         pa.clone(),
                                          Arc::clone(&receiver),
+                                         Arc::clone(&s.state),
             )?;
             s.worker_threads.push(newt);
         }
@@ -222,13 +731,133 @@ impl Serving {
     }
 
     pub fn serve(&mut self) -> Result<(), Box<dyn Error>> {
-        let listener = net::TcpListener::bind(&self.listening_interface).expect("Cannot bind to the interface");
+        let shutdown_fd = install_shutdown_handler()?;
+        let listener = match &self.unix_socket {
+            Some(path) => {
+                // A socket file left behind by a previous run would make bind fail
+                if let Ok(metadata) = fs::symlink_metadata(path) {
+                    if metadata.file_type().is_socket() {
+                        fs::remove_file(path)?;
+                    }
+                }
+                let listener = unix_net::UnixListener::bind(path).expect("Cannot bind to the unix socket");
+                listener.set_nonblocking(true)?;
+                LineListener::Unix(listener)
+            },
+            None => {
+                let listener = net::TcpListener::bind(&self.listening_interface).expect("Cannot bind to the interface");
+                listener.set_nonblocking(true)?;
+                LineListener::Tcp(listener)
+            },
+        };
+        let http_listener = match &self.http_listening_interface {
+            Some(http_listening_interface) => {
+                let http_listener = net::TcpListener::bind(http_listening_interface).expect("Cannot bind to the HTTP interface");
+                http_listener.set_nonblocking(true)?;
+                Some(http_listener)
+            },
+            None => None
+        };
+        if let Some(metrics_listening_interface) = &self.metrics_listening_interface {
+            let metrics_listener = net::TcpListener::bind(metrics_listening_interface).expect("Cannot bind to the metrics interface");
+            let state = Arc::clone(&self.state);
+            // Scrapes are rare and quick, so one thread outside of the worker pool serves them
+            thread::spawn(move || {
+                for stream in metrics_listener.incoming() {
+                    if let Ok(stream) = stream {
+                        let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+                        let _ = stream.set_write_timeout(Some(Duration::from_secs(5)));
+                        serve_metrics(&mut BufReader::new(&stream), &mut BufWriter::new(&stream), &state);
+                    }
+                }
+            });
+        }
         println!("Bind done, deamonizing and calling accept");
-        for stream in listener.incoming() {
-            self.sender.send(stream?)?;
+
+        // Listeners are non-blocking, so we accept until there is nothing left and then wait in poll()
+        let mut pollfds = vec![libc::pollfd {fd: shutdown_fd, events: libc::POLLIN, revents: 0}];
+        pollfds.push(libc::pollfd {fd: match &listener {LineListener::Tcp(l) => l.as_raw_fd(), LineListener::Unix(l) => l.as_raw_fd()},
+                                   events: libc::POLLIN, revents: 0});
+        if let Some(http_listener) = &http_listener {
+            pollfds.push(libc::pollfd {fd: http_listener.as_raw_fd(), events: libc::POLLIN, revents: 0});
+        }
+        loop {
+            for pollfd in pollfds.iter_mut() {
+                pollfd.revents = 0;
+            }
+            if unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, -1) } < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e)?;
+            }
+            if pollfds[0].revents != 0 {
+                break;
+            }
+            loop {
+                let accepted = match &listener {
+                    LineListener::Tcp(l) => l.accept().map(|(stream, _)| Connection::Line(stream)),
+                    LineListener::Unix(l) => l.accept().map(|(stream, _)| Connection::LineUnix(stream)),
+                };
+                match accepted {
+                    Ok(connection) => self.dispatch(connection)?,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => { println!("Accept failed: {}", e); break; },
+                }
+            }
+            if let Some(http_listener) = &http_listener {
+                loop {
+                    match http_listener.accept() {
+                        Ok((stream, _)) => self.dispatch(Connection::Http(stream))?,
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => { println!("Accept failed: {}", e); break; },
+                    }
+                }
+            }
+        }
+        drop(listener);
+        drop(http_listener);
+        if let Some(path) = &self.unix_socket {
+            let _ = fs::remove_file(path);
+        }
+        self.drain();
+        Ok(())
+    }
+
+    fn dispatch(&self, connection: Connection) -> Result<(), Box<dyn Error>> {
+        if let Err(e) = connection.set_timeouts(self.read_timeout, self.write_timeout) {
+            println!("Cannot set timeouts on the connection, dropping it: {}", e);
+            return Ok(());
+        }
+        if self.max_pending_connections > 0 && self.state.pending_connections.load(Ordering::SeqCst) >= self.max_pending_connections {
+            connection.reject();
+            return Ok(());
         }
+        self.state.pending_connections.fetch_add(1, Ordering::SeqCst);
+        self.sender.as_ref().unwrap().send(connection)?;
         Ok(())
     }
+
+    // Lets the workers finish the connections they have (and the queued ones), but waits at most shutdown_timeout
+    fn drain(&mut self) {
+        println!("Shutting down, waiting for open connections to finish");
+        self.state.shutting_down.store(true, Ordering::SeqCst);
+        self.sender = None;
+        let deadline = Instant::now() + self.shutdown_timeout;
+        loop {
+            let running = self.worker_threads.iter().filter(|t| !t.is_finished()).count();
+            if running == 0 {
+                println!("All connections finished");
+                return;
+            }
+            if Instant::now() >= deadline {
+                println!("Shutdown timeout, exiting with {} connections still open", running);
+                return;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
 }
 
 
@@ -240,8 +869,7 @@ mod tests {
     use std::io::ErrorKind;
     use mockstream::{SharedMockStream, FailingMockStream};
     use crate::feature_buffer;
-    use crate::feature_buffer::HashAndValue;
-    use crate::feature_buffer::HashAndValueAndSeq;
+    use crate::optimizer;
     use tempfile::{tempdir};
     use std::str;
 
@@ -268,15 +896,8 @@ C,featureC
         let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
         let mi = model_instance::ModelInstance::new_empty().unwrap();        
         let mut re = regressor::Regressor::new::<optimizer::OptimizerAdagradLUT>(&mi);
-        let re_fixed = BoxedRegressorTrait::new(Box::new(re.immutable_regressor(&mi).unwrap()));
-        let fbt = feature_buffer::FeatureBufferTranslator::new(&mi);
-        let pa = parser::VowpalParser::new(&vw);
-
-        let mut newt = WorkerThread {id: 1,
-                                 fbt: fbt,
-                                 pa: pa,
-                                 re_fixed: re_fixed,
-                                 };
+        let model = ServedModel::new(mi.clone(), vw.clone(), re.immutable_regressor(&mi).unwrap());
+        let mut newt = WorkerThread::with_state(1, Arc::new(ServingState::new(2, model)));
 
         { // WORKING STREAM TEST
             let mut mocked_stream = SharedMockStream::new();
@@ -328,15 +949,402 @@ C,featureC
                                  
     }
 
-    fn lr_and_ffm_vec(v1:Vec<feature_buffer::HashAndValue>, v2:Vec<feature_buffer::HashAndValueAndSeq>, ffm_fields_count:u32) -> feature_buffer::FeatureBuffer {
-        feature_buffer::FeatureBuffer {
-                    label: 0.0,
-                    example_importance: 1.0,
-                    example_number: 0,
-                    lr_buffer: v1,
-                    ffm_buffer: v2,
-                    ffm_fields_count: ffm_fields_count,
+    // A worker with a small trained LR + FFM model over namespaces A, B and C
+    const TRAIN_LINES: &[u8] = b"1 |A a1 |B b1 |C c1\n-1 |A a1 |B b2\n1 |A a2 a3:2 |B b2 |C c2\n-1 |A a2 |B b1 |C c1 c2\n";
+
+    // A small LR + FFM model over namespaces A, B and C
+    fn trained_model(train_lines: &[u8], ffm_k: u32) -> (model_instance::ModelInstance, vwmap::VwNamespaceMap, regressor::Regressor) {
+        let vw_map_string = r#"
+A,featureA
+B,featureB
+C,featureC
+"#;
+        let vw = vwmap::VwNamespaceMap::new(vw_map_string).unwrap();
+        let ns = |name: &[u8]| vw.map_vwname_to_namespace_descriptor[&name.to_vec()];
+        let mut mi = model_instance::ModelInstance::new_empty().unwrap();
+        mi.learning_rate = 0.1;
+        mi.ffm_learning_rate = 0.1;
+        mi.power_t = 0.0;
+        mi.ffm_power_t = 0.0;
+        mi.ffm_k = ffm_k;
+        mi.ffm_bit_precision = 18;
+        mi.add_constant_feature = true;
+        for combo in vec![vec![ns(b"A")], vec![ns(b"B")], vec![ns(b"A"), ns(b"C")]] {
+            mi.feature_combo_descs.push(model_instance::FeatureComboDesc {namespace_descriptors: combo, weight: 1.0});
+        }
+        mi.ffm_fields = vec![vec![ns(b"A")], vec![ns(b"B")], vec![ns(b"B"), ns(b"C")]];
+        let mut re = regressor::get_regressor_with_weights(&mi);
+        let mut fbt = feature_buffer::FeatureBufferTranslator::new(&mi);
+        let mut pa = parser::VowpalParser::new(&vw);
+        let mut train = train_lines;
+        loop {
+            let buffer = pa.next_vowpal(&mut train).unwrap();
+            if buffer.is_empty() {
+                break;
+            }
+            fbt.translate(buffer, 0);
+            re.learn(&fbt.feature_buffer, true);
+        }
+        (mi, vw, re)
+    }
+
+    // A worker with id 1 of two workers, serving the model
+    fn worker_for(mi: &model_instance::ModelInstance, vw: &vwmap::VwNamespaceMap, re: &mut regressor::Regressor) -> WorkerThread {
+        let model = ServedModel::new(mi.clone(), vw.clone(), re.immutable_regressor(mi).unwrap());
+        WorkerThread::with_state(1, Arc::new(ServingState::new(2, model)))
+    }
+
+    fn trained_worker() -> WorkerThread {
+        let (mi, vw, mut re) = trained_model(TRAIN_LINES, 4);
+        worker_for(&mi, &vw, &mut re)
+    }
+
+    fn predict_lines(worker: &mut WorkerThread, lines: &[u8]) -> String {
+        let mut mocked_stream = SharedMockStream::new();
+        let mut reader = BufReader::new(mocked_stream.clone());
+        let mut writer = BufWriter::new(mocked_stream.clone());
+        mocked_stream.push_bytes_to_read(lines);
+        assert_eq!(ConnectionEnd::EndOfStream, worker.handle_connection(&mut reader, &mut writer));
+        String::from_utf8(mocked_stream.pop_bytes_written()).unwrap()
+    }
+
+    // Reloads the model in the middle of reading
+    struct ReloadingReader<'a> {
+        input: &'a [u8],
+        state: Arc<ServingState>,
+        filename: String,
+    }
+
+    impl io::Read for ReloadingReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl io::BufRead for ReloadingReader<'_> {
+        fn fill_buf(&mut self) -> io::Result<&[u8]> {
+            self.state.reload(&self.filename, true).unwrap();
+            self.input.fill_buf()
         }
+        fn consume(&mut self, amt: usize) {
+            self.input.consume(amt)
+        }
+    }
+
+    #[test]
+    fn test_reload() {
+        let dir = tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_owned();
+        let (mi_1, vw_1, mut re_1) = trained_model(TRAIN_LINES, 4);
+        persistence::save_regressor_to_filename(&path("1.fw"), &mi_1, &vw_1, &re_1).unwrap();
+        // Same structure, different weights
+        let (mi_2, vw_2, mut re_2) = trained_model(&TRAIN_LINES[..20], 4);
+        persistence::save_regressor_to_filename(&path("2.fw"), &mi_2, &vw_2, &re_2).unwrap();
+        // Different structure
+        let (mi_3, vw_3, mut re_3) = trained_model(TRAIN_LINES, 2);
+        persistence::save_regressor_to_filename(&path("3.fw"), &mi_3, &vw_3, &re_3).unwrap();
+
+        let examples = b"|A a2 a3:2 |B b1 |C c1\n|A a1 |B b2\n";
+        let expected_1 = predict_lines(&mut worker_for(&mi_1, &vw_1, &mut re_1), examples);
+        let expected_2 = predict_lines(&mut worker_for(&mi_2, &vw_2, &mut re_2), examples);
+        let expected_3 = predict_lines(&mut worker_for(&mi_3, &vw_3, &mut re_3), examples);
+        assert_ne!(expected_1, expected_2);
+        assert_ne!(expected_1, expected_3);
+
+        // Without --allow_model_loading clients cannot choose what to load
+        let mut not_allowed = worker_for(&mi_1, &vw_1, &mut re_1);
+        assert_eq!(predict_lines(&mut not_allowed, format!("load_model {}\n", path("2.fw")).as_bytes()),
+                   "ERR: load_model fail: Loading models is disabled, the daemon has to be started with --allow_model_loading\n");
+        let mut mocked_stream = SharedMockStream::new();
+        mocked_stream.push_bytes_to_read(http_post("/reload", &format!(r#"{{"filename": "{}"}}"#, path("2.fw"))).as_bytes());
+        not_allowed.handle_http_connection(&mut BufReader::new(mocked_stream.clone()), &mut BufWriter::new(mocked_stream.clone()));
+        assert!(String::from_utf8(mocked_stream.pop_bytes_written()).unwrap().contains("--allow_model_loading"));
+        assert_eq!(predict_lines(&mut not_allowed, examples), expected_1);
+
+        let model = ServedModel::new(mi_1.clone(), vw_1.clone(), re_1.immutable_regressor(&mi_1).unwrap());
+        let mut newt = WorkerThread::with_state(1, Arc::new(ServingState {allow_model_loading: true, ..ServingState::new(2, model)}));
+        let mut other = WorkerThread::with_state(0, Arc::clone(&newt.state));
+        // Model that other worker is using at the moment stays valid after the swap
+        let in_flight = Arc::clone(&other.model);
+        assert_eq!(predict_lines(&mut other, examples), expected_1);
+
+        // Context is computed again for the new model
+        let output = predict_lines(&mut newt, format!("context |A a2 a3:2\n|B b1 |C c1\nhogwild_load {}\n|B b1 |C c1\n", path("2.fw")).as_bytes());
+        let lines = |s: &str, i: usize| s.lines().nth(i).unwrap().to_string();
+        assert_eq!(lines(&output, 0), lines(&expected_1, 0));
+        assert_eq!(lines(&output, 1), "hogwild_load success");
+        assert_eq!(lines(&output, 2), lines(&expected_2, 0));
+        assert_eq!(predict_lines(&mut other, examples), expected_2);
+        assert!(!Arc::ptr_eq(&in_flight, &other.model));
+        assert_eq!(in_flight.mi.ffm_k, 4);
+
+        // Example that was read while another worker reloaded is parsed again and predicted with the new model
+        let mut reader = ReloadingReader {input: &examples[..], state: Arc::clone(&newt.state), filename: path("3.fw")};
+        assert_eq!(format!("{:.6}", other.predict_next(&mut reader, 0).unwrap().unwrap()), lines(&expected_3, 0));
+        newt.state.reload(&path("2.fw"), true).unwrap();
+
+        // hogwild_load refuses a different structure, load_model takes it
+        let mut mocked_stream = SharedMockStream::new();
+        let mut reader = BufReader::new(mocked_stream.clone());
+        let mut writer = BufWriter::new(mocked_stream.clone());
+        mocked_stream.push_bytes_to_read(format!("hogwild_load {}\n", path("3.fw")).as_bytes());
+        assert_eq!(ConnectionEnd::StreamWriteError, newt.handle_connection(&mut reader, &mut writer));
+        assert_eq!(predict_lines(&mut other, examples), expected_2);
+        assert_eq!(predict_lines(&mut newt, format!("load_model {}\n", path("3.fw")).as_bytes()), "load_model success\n");
+        assert_eq!(predict_lines(&mut other, examples), expected_3);
+        assert_eq!(predict_lines(&mut newt, b"load_model /nonexistent/model.fw\n"),
+                   "ERR: load_model fail: Cannot open regressor /nonexistent/model.fw: No such file or directory (os error 2)\n");
+        assert_eq!(predict_lines(&mut other, examples), expected_3);
+
+        // Over HTTP
+        mocked_stream.push_bytes_to_read(http_post("/reload", &format!(r#"{{"filename": "{}"}}"#, path("1.fw"))).as_bytes());
+        mocked_stream.push_bytes_to_read(http_post("/reload", &format!(r#"{{"filename": "{}", "allow_structure_change": true}}"#, path("1.fw"))).as_bytes());
+        assert_eq!(ConnectionEnd::EndOfStream, newt.handle_http_connection(&mut reader, &mut writer));
+        let written = String::from_utf8(mocked_stream.pop_bytes_written()).unwrap();
+        assert!(written.contains("Models differ in ffm_k: 2 vs 4"));
+        assert!(written.contains("{\"status\":\"ok\"}"));
+        assert_eq!(predict_lines(&mut other, examples), expected_1);
+    }
+
+    #[test]
+    fn test_context() {
+        let mut newt = trained_worker();
+        let mut mocked_stream = SharedMockStream::new();
+        let mut reader = BufReader::new(mocked_stream.clone());
+        let mut writer = BufWriter::new(mocked_stream.clone());
+        mocked_stream.push_bytes_to_read(b"|A a2 a3:2 |B b1 |C c1\n|A a2 a3:2 |B b2\n|A a2 a3:2 |C c2 c1\n|A a1 |B b1\n");
+        assert_eq!(ConnectionEnd::EndOfStream, newt.handle_connection(&mut reader, &mut writer));
+        let expected = mocked_stream.pop_bytes_written();
+        assert_ne!(&expected[0..9], &expected[9..18]);
+
+        // The same examples with namespace A sent once as the context, empty context line removes it
+        mocked_stream.push_bytes_to_read(b"context |A a2 a3:2\n|B b1 |C c1\n|B b2\n|C c2 c1\ncontext\n|A a1 |B b1\n");
+        assert_eq!(ConnectionEnd::EndOfStream, newt.handle_connection(&mut reader, &mut writer));
+        assert_eq!(str::from_utf8(&mocked_stream.pop_bytes_written()), str::from_utf8(&expected));
+
+        // Context doesn't outlive the connection
+        mocked_stream.push_bytes_to_read(b"context |A a2 a3:2\n");
+        assert_eq!(ConnectionEnd::EndOfStream, newt.handle_connection(&mut reader, &mut writer));
+        mocked_stream.push_bytes_to_read(b"|A a2 a3:2 |B b1 |C c1\n");
+        assert_eq!(ConnectionEnd::EndOfStream, newt.handle_connection(&mut reader, &mut writer));
+        assert_eq!(mocked_stream.pop_bytes_written(), &expected[0..9]);
+
+        mocked_stream.push_bytes_to_read(b"context |A a2\n|A a1 |B b1\n");
+        assert_eq!(ConnectionEnd::ParseError, newt.handle_connection(&mut reader, &mut writer));
+        assert_eq!(str::from_utf8(&mocked_stream.pop_bytes_written()).unwrap(), "ERR: Namespace is already in the context: A\n");
+
+        mocked_stream.push_bytes_to_read(b"context |D d\n");
+        assert_eq!(ConnectionEnd::ParseError, newt.handle_connection(&mut reader, &mut writer));
+        assert_eq!(str::from_utf8(&mocked_stream.pop_bytes_written()).unwrap(), "ERR: Feature name was not predeclared in vw_namespace_map.csv: D\n");
+    }
+
+    #[test]
+    fn test_listening_address() {
+        assert_eq!(listening_address("127.0.0.1", 26542).unwrap().to_string(), "127.0.0.1:26542");
+        assert_eq!(listening_address("0.0.0.0", 80).unwrap().to_string(), "0.0.0.0:80");
+        assert_eq!(listening_address("::1", 26542).unwrap().to_string(), "[::1]:26542");
+        assert_eq!(listening_address("[::]", 26542).unwrap().to_string(), "[::]:26542");
+        assert!(listening_address("localhost", 26542).is_err());
+        assert!(listening_address("127.0.0.1:80", 26542).is_err());
+    }
+
+    #[test]
+    fn test_unix_socket() {
+        use std::io::{Read, Write};
+        let mut newt = trained_worker();
+        let mut mocked_stream = SharedMockStream::new();
+        let mut reader = BufReader::new(mocked_stream.clone());
+        let mut writer = BufWriter::new(mocked_stream.clone());
+        mocked_stream.push_bytes_to_read(b"|A a2 a3:2 |B b1 |C c1\n|A a1 |B b1\n");
+        assert_eq!(ConnectionEnd::EndOfStream, newt.handle_connection(&mut reader, &mut writer));
+        let expected = mocked_stream.pop_bytes_written();
+
+        let (mut client, server) = unix_net::UnixStream::pair().unwrap();
+        client.write_all(b"|A a2 a3:2 |B b1 |C c1\n|A a1 |B b1\n").unwrap();
+        client.shutdown(net::Shutdown::Write).unwrap();
+        let mut reader = BufReader::new(&server);
+        let mut writer = BufWriter::new(&server);
+        assert_eq!(ConnectionEnd::EndOfStream, newt.handle_connection(&mut reader, &mut writer));
+        drop(writer);
+        drop(server);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        assert_eq!(response, expected);
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let mut newt = trained_worker();
+        let mut mocked_stream = SharedMockStream::new();
+        let mut reader = BufReader::new(mocked_stream.clone());
+        let mut writer = BufWriter::new(mocked_stream.clone());
+        mocked_stream.push_bytes_to_read(b"|A a1 |B b1\n|A a2 |B b2\n");
+        assert_eq!(ConnectionEnd::EndOfStream, newt.handle_connection(&mut reader, &mut writer));
+        let expected = mocked_stream.pop_bytes_written();
+
+        // When draining, what was received is still answered, then the connection is closed
+        newt.state.shutting_down.store(true, Ordering::SeqCst);
+        mocked_stream.push_bytes_to_read(b"|A a1 |B b1\n");
+        assert_eq!(ConnectionEnd::Shutdown, newt.handle_connection(&mut reader, &mut writer));
+        assert_eq!(mocked_stream.pop_bytes_written(), &expected[0..9]);
+
+        mocked_stream.push_bytes_to_read(b"GET /health HTTP/1.1\r\n\r\nGET /health HTTP/1.1\r\n\r\n");
+        assert_eq!(ConnectionEnd::Shutdown, newt.handle_http_connection(&mut reader, &mut writer));
+        assert_eq!(str::from_utf8(&mocked_stream.pop_bytes_written()).unwrap(),
+                   "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 15\r\nConnection: close\r\n\r\n{\"status\":\"ok\"}");
+    }
+
+    #[test]
+    fn test_read_timeout() {
+        use std::io::Read;
+        let mut newt = trained_worker();
+        let (mut client, server) = unix_net::UnixStream::pair().unwrap();
+        let connection = Connection::LineUnix(server);
+        connection.set_timeouts(Some(Duration::from_millis(50)), None).unwrap();
+        let server = match connection { Connection::LineUnix(server) => server, _ => unreachable!() };
+        io::Write::write_all(&mut client, b"|A a1 |B b1\n").unwrap();
+        let mut reader = BufReader::new(&server);
+        let mut writer = BufWriter::new(&server);
+        // Client never closes the connection, the worker gives up on it after the timeout
+        assert_eq!(ConnectionEnd::StreamReadError, newt.handle_connection(&mut reader, &mut writer));
+        drop(writer);
+        drop(server);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert_eq!(response.lines().count(), 1);
+
+        let (mut client, server) = unix_net::UnixStream::pair().unwrap();
+        io::Write::write_all(&mut client, b"GET /health HTTP/1.1\r\n").unwrap();
+        server.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        let mut reader = BufReader::new(&server);
+        let mut writer = BufWriter::new(&server);
+        assert_eq!(ConnectionEnd::StreamReadError, newt.handle_http_connection(&mut reader, &mut writer));
+    }
+
+    #[test]
+    fn test_max_pending_connections() {
+        use std::io::Read;
+        let (sender, receiver) = mpsc::channel();
+        let serving = Serving {
+            listening_interface: listening_address("127.0.0.1", 0).unwrap(),
+            http_listening_interface: None,
+            metrics_listening_interface: None,
+            unix_socket: None,
+            worker_threads: Vec::new(),
+            sender: Some(sender),
+            foreground: true,
+            state: Arc::clone(&trained_worker().state),
+            read_timeout: None,
+            write_timeout: None,
+            max_pending_connections: 1,
+            shutdown_timeout: Duration::from_secs(1),
+        };
+        let (_client_1, server_1) = unix_net::UnixStream::pair().unwrap();
+        let (mut client_2, server_2) = unix_net::UnixStream::pair().unwrap();
+        serving.dispatch(Connection::LineUnix(server_1)).unwrap();
+        serving.dispatch(Connection::LineUnix(server_2)).unwrap();
+        assert_eq!(serving.state.pending_connections.load(Ordering::SeqCst), 1);
+        let mut response = String::new();
+        client_2.read_to_string(&mut response).unwrap();
+        assert_eq!(response, "ERR: Too many pending connections\n");
+        assert!(receiver.try_recv().is_ok());
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut newt = trained_worker();
+        let mut mocked_stream = SharedMockStream::new();
+        let mut reader = BufReader::new(mocked_stream.clone());
+        let mut writer = BufWriter::new(mocked_stream.clone());
+        mocked_stream.push_bytes_to_read(b"|A a1 |B b1\nflush\n|A a2 |B b2\nhogwild_load /nonexistent/model.fw\n");
+        assert_eq!(ConnectionEnd::StreamWriteError, newt.handle_connection(&mut reader, &mut writer));
+        mocked_stream.push_bytes_to_read(b"|A a1\n|D d\n");
+        assert_eq!(ConnectionEnd::ParseError, newt.handle_connection(&mut reader, &mut writer));
+        mocked_stream.pop_bytes_written();
+
+        mocked_stream.push_bytes_to_read(b"stats\n");
+        assert_eq!(ConnectionEnd::EndOfStream, newt.handle_connection(&mut reader, &mut writer));
+        let stats = String::from_utf8(mocked_stream.pop_bytes_written()).unwrap();
+        assert!(stats.ends_with("\n# EOF\n"));
+        // Test worker has id 1
+        let lines: Vec<&str> = stats.lines().collect();
+        assert!(lines.contains(&"fw_lines_predicted_total{worker=\"1\"} 3"));
+        assert!(lines.contains(&"fw_lines_predicted_total{worker=\"0\"} 0"));
+        assert!(lines.contains(&"fw_flushes_total{worker=\"1\"} 1"));
+        assert!(lines.contains(&"fw_reload_errors_total{worker=\"1\"} 1"));
+        assert!(lines.contains(&"fw_parse_errors_total{worker=\"1\"} 1"));
+        assert!(lines.contains(&"fw_prediction_latency_seconds_count{worker=\"1\"} 3"));
+
+        // The same text over HTTP on the metrics port
+        mocked_stream.push_bytes_to_read(b"GET /metrics HTTP/1.1\r\n\r\nGET /other HTTP/1.1\r\nConnection: close\r\n\r\n");
+        serve_metrics(&mut reader, &mut writer, &newt.state);
+        let response = String::from_utf8(mocked_stream.pop_bytes_written()).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n"));
+        assert!(response.contains(&stats[..stats.len() - "# EOF\n".len()]));
+        assert!(response.contains("HTTP/1.1 404 Not Found"));
+    }
+
+    fn http_post(path: &str, body: &str) -> String {
+        format!("POST {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", path, body.len(), body)
+    }
+
+    fn http_response_body(response: &[u8]) -> serde_json::Value {
+        let response = str::from_utf8(response).unwrap();
+        serde_json::from_str(&response[response.find("\r\n\r\n").unwrap() + 4..]).unwrap()
+    }
+
+    #[test]
+    fn test_http() {
+        let mut newt = trained_worker();
+        let mut mocked_stream = SharedMockStream::new();
+        let mut reader = BufReader::new(mocked_stream.clone());
+        let mut writer = BufWriter::new(mocked_stream.clone());
+        mocked_stream.push_bytes_to_read(b"|A a2 a3:2 |B b1 |C c1\n|A a2 a3:2 |B b2\n");
+        assert_eq!(ConnectionEnd::EndOfStream, newt.handle_connection(&mut reader, &mut writer));
+        let expected: Vec<f32> = str::from_utf8(&mocked_stream.pop_bytes_written()).unwrap()
+                                    .lines().map(|p| p.parse().unwrap()).collect();
+
+        mocked_stream.push_bytes_to_read(b"GET /health HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert_eq!(ConnectionEnd::EndOfStream, newt.handle_http_connection(&mut reader, &mut writer));
+        assert_eq!(str::from_utf8(&mocked_stream.pop_bytes_written()).unwrap(),
+                   "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 15\r\nConnection: close\r\n\r\n{\"status\":\"ok\"}");
+
+        // Predictions are printed with 6 decimals in the line protocol
+        let check_predictions = |response: &[u8]| {
+            let predictions = http_response_body(response)["predictions"].as_array().unwrap().clone();
+            assert_eq!(predictions.len(), expected.len());
+            for (p, e) in predictions.iter().zip(expected.iter()) {
+                assert!((p.as_f64().unwrap() as f32 - e).abs() < 1e-6);
+            }
+        };
+        mocked_stream.push_bytes_to_read(http_post("/predict", r#"{"examples": [{"A": ["a2", "a3:2"], "B": ["b1"], "C": ["c1"]}, {"A": ["a2", "a3:2"], "B": ["b2"]}]}"#).as_bytes());
+        assert_eq!(ConnectionEnd::EndOfStream, newt.handle_http_connection(&mut reader, &mut writer));
+        check_predictions(&mocked_stream.pop_bytes_written());
+
+        // Context applies only to the request it was sent with
+        mocked_stream.push_bytes_to_read(http_post("/predict", r#"{"context": {"A": ["a2", "a3:2"]}, "examples": [{"B": ["b1"], "C": ["c1"]}, {"B": ["b2"]}]}"#).as_bytes());
+        mocked_stream.push_bytes_to_read(http_post("/predict", r#"{"examples": [{"A": ["a2", "a3:2"], "B": ["b1"], "C": ["c1"]}, {"A": ["a2", "a3:2"], "B": ["b2"]}]}"#).as_bytes());
+        assert_eq!(ConnectionEnd::EndOfStream, newt.handle_http_connection(&mut reader, &mut writer));
+        let written = mocked_stream.pop_bytes_written();
+        let second = str::from_utf8(&written).unwrap().rfind("HTTP/1.1").unwrap();
+        check_predictions(&written[..second]);
+        check_predictions(&written[second..]);
+
+        mocked_stream.push_bytes_to_read(http_post("/predict", r#"{"examples": [{"D": ["d"]}]}"#).as_bytes());
+        mocked_stream.push_bytes_to_read(http_post("/predict", r#"{"examples": "#).as_bytes());
+        mocked_stream.push_bytes_to_read(http_post("/reload", r#"{"filename": "/nonexistent/model.fw"}"#).as_bytes());
+        mocked_stream.push_bytes_to_read(b"GET /predict HTTP/1.1\r\n\r\nGET /other HTTP/1.1\r\n\r\n");
+        assert_eq!(ConnectionEnd::EndOfStream, newt.handle_http_connection(&mut reader, &mut writer));
+        let written = String::from_utf8(mocked_stream.pop_bytes_written()).unwrap();
+        let statuses: Vec<&str> = written.match_indices("HTTP/1.1 ").map(|(i, _)| &written[i + 9..i + 12]).collect();
+        assert_eq!(statuses, vec!["400", "400", "500", "405", "404"]);
+        assert!(written.contains("Feature name was not predeclared in vw_namespace_map.csv: D"));
+
+        // Malformed request drops the connection
+        mocked_stream.push_bytes_to_read(b"|A a1\n");
+        assert_eq!(ConnectionEnd::ParseError, newt.handle_http_connection(&mut reader, &mut writer));
+        assert!(str::from_utf8(&mocked_stream.pop_bytes_written()).unwrap().starts_with("HTTP/1.1 400 Bad Request"));
     }
 
 
@@ -359,27 +1367,20 @@ C,featureC
         mi.ffm_fields = vec![vec![],vec![]]; 
         mi.optimizer = model_instance::Optimizer::Adagrad;
         mi.fastmath = false;
-        let mut re_1 = regressor::Regressor::new::<optimizer::OptimizerAdagradLUT>(&mi);
-        let mut re_2 = regressor::Regressor::new::<optimizer::OptimizerSGD>(&mi);
-        let mut p: f32;
+        let re_1 = regressor::Regressor::new::<optimizer::OptimizerAdagradLUT>(&mi);
+        let re_2 = regressor::Regressor::new::<optimizer::OptimizerSGD>(&mi);
+        let _p: f32;
 
         let dir = tempdir().unwrap();
         let regressor_filepath_1 = dir.path().join("test_regressor1.fw").to_str().unwrap().to_owned();
-        persistence::save_regressor_to_filename(&regressor_filepath_1, &mi, &vw, re_1).unwrap();
+        persistence::save_regressor_to_filename(&regressor_filepath_1, &mi, &vw, &re_1).unwrap();
         let regressor_filepath_2 = dir.path().join("test_regressor2.fw").to_str().unwrap().to_owned();
-        persistence::save_regressor_to_filename(&regressor_filepath_2, &mi, &vw, re_2).unwrap();
+        persistence::save_regressor_to_filename(&regressor_filepath_2, &mi, &vw, &re_2).unwrap();
 
         // OK NOW EVERYTHING IS READY... Let's start
         let mut re = regressor::Regressor::new::<optimizer::OptimizerAdagradLUT>(&mi);
-        let re_fixed = BoxedRegressorTrait::new(Box::new(re.immutable_regressor(&mi).unwrap()));
-        let fbt = feature_buffer::FeatureBufferTranslator::new(&mi);
-        let pa = parser::VowpalParser::new(&vw);
-
-        let mut newt = WorkerThread {id: 1,
-                                 fbt: fbt,
-                                 pa: pa,
-                                 re_fixed: re_fixed,
-                                 };
+        let model = ServedModel::new(mi.clone(), vw.clone(), re.immutable_regressor(&mi).unwrap());
+        let mut newt = WorkerThread::with_state(1, Arc::new(ServingState::new(2, model)));
 
         { // WORKING STREAM TEST
             let mut mocked_stream = SharedMockStream::new();
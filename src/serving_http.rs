@@ -0,0 +1,186 @@
+// Minimal HTTP/1.1 support for the daemon: just enough to read requests with a body and write JSON responses.
+// Worker threads of serving.rs handle HTTP connections the same way as the line protocol ones.
+use std::error::Error;
+use std::io;
+use std::io::{BufRead, Read};
+use serde_json::{json, Value};
+
+const MAX_HEADERS: usize = 100;
+// Request line and each header
+const MAX_LINE_LEN: usize = 8 * 1024;
+const MAX_BODY_LEN: usize = 4 * 1024 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub body: Vec<u8>,
+    pub keep_alive: bool,
+}
+
+// Like read_line, but never reads more than MAX_LINE_LEN bytes, so clients cannot make us buffer without limit
+fn read_limited_line(reader: &mut impl BufRead, line: &mut String) -> Result<usize, Box<dyn Error>> {
+    let len = (&mut *reader).take(MAX_LINE_LEN as u64).read_line(line)?;
+    if len == MAX_LINE_LEN && !line.ends_with('\n') {
+        return Err(format!("HTTP request line or header is longer than {} bytes", MAX_LINE_LEN))?;
+    }
+    Ok(len)
+}
+
+// Returns None when the client closed the connection before sending a new request
+pub fn read_request(reader: &mut impl BufRead) -> Result<Option<HttpRequest>, Box<dyn Error>> {
+    let mut line = String::new();
+    if read_limited_line(reader, &mut line)? == 0 {
+        return Ok(None);
+    }
+    let parts: Vec<&str> = line.trim_end().split(' ').collect();
+    if parts.len() != 3 || !parts[2].starts_with("HTTP/1.") {
+        return Err(format!("Malformed HTTP request line: {}", line.trim_end()))?;
+    }
+    let mut request = HttpRequest {
+        method: parts[0].to_string(),
+        path: parts[1].to_string(),
+        body: Vec::new(),
+        keep_alive: parts[2] != "HTTP/1.0",
+    };
+    let mut content_length: usize = 0;
+    for _ in 0..MAX_HEADERS {
+        line.truncate(0);
+        if read_limited_line(reader, &mut line)? == 0 {
+            return Err("Connection closed in the middle of HTTP headers")?;
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            if content_length > MAX_BODY_LEN {
+                return Err(format!("HTTP request body is too large: {} bytes", content_length))?;
+            }
+            // The buffer grows as the body arrives, not up front to whatever the client claims
+            (&mut *reader).take(content_length as u64).read_to_end(&mut request.body)?;
+            if request.body.len() != content_length {
+                return Err("Connection closed in the middle of HTTP body")?;
+            }
+            return Ok(Some(request));
+        }
+        let (name, value) = match header.find(':') {
+            Some(i) => (header[..i].trim().to_ascii_lowercase(), header[i+1..].trim().to_ascii_lowercase()),
+            None => return Err(format!("Malformed HTTP header: {}", header))?,
+        };
+        if name == "content-length" {
+            content_length = match value.parse() {
+                Ok(l) => l,
+                Err(_) => return Err(format!("Malformed Content-Length: {}", value))?,
+            };
+        } else if name == "connection" {
+            request.keep_alive = value != "close";
+        } else if name == "transfer-encoding" {
+            return Err("Transfer-Encoding is not supported, use Content-Length")?;
+        }
+    }
+    Err("Too many HTTP headers")?
+}
+
+// Without keep_alive the client is told that the connection will be closed
+pub fn write_response(writer: &mut impl io::Write, status: u16, body: &Value, keep_alive: bool) -> Result<(), Box<dyn Error>> {
+    write_text_response(writer, status, "application/json", &body.to_string(), keep_alive)
+}
+
+pub fn write_text_response(writer: &mut impl io::Write, status: u16, content_type: &str, body: &str, keep_alive: bool) -> Result<(), Box<dyn Error>> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    };
+    let connection = if keep_alive { "" } else { "Connection: close\r\n" };
+    write!(writer, "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}\r\n{}", status, reason, content_type, body.len(), connection, body)?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn error_body(message: &str) -> Value {
+    json!({"error": message})
+}
+
+// Turns a JSON object of namespace to a list of features ("name" or "name:value") into a vowpal wabbit line without a label
+pub fn json_to_line(example: &Value) -> Result<String, Box<dyn Error>> {
+    let namespaces = match example.as_object() {
+        Some(namespaces) => namespaces,
+        None => return Err(format!("Example has to be an object of namespace to a list of features, got: {}", example))?,
+    };
+    let mut line = String::new();
+    for (namespace, features) in namespaces {
+        if namespace.is_empty() || namespace.contains(|c: char| c.is_whitespace() || c == '|' || c == ':') {
+            return Err(format!("Invalid namespace name: {:?}", namespace))?;
+        }
+        let features = match features.as_array() {
+            Some(features) => features,
+            None => return Err(format!("Features of namespace {} have to be a list", namespace))?,
+        };
+        line.push('|');
+        line.push_str(namespace);
+        for feature in features {
+            let feature = match feature.as_str() {
+                Some(feature) if !feature.is_empty() && !feature.contains(|c: char| c.is_whitespace() || c == '|') => feature,
+                _ => return Err(format!("Invalid feature in namespace {}: {}", namespace, feature))?,
+            };
+            line.push(' ');
+            line.push_str(feature);
+        }
+        line.push(' ');
+    }
+    line.push('\n');
+    Ok(line)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_read_request() {
+        let mut input = Cursor::new(b"POST /predict HTTP/1.1\r\nHost: x\r\nContent-Length: 4\r\n\r\nbody\
+                                      GET /health HTTP/1.0\r\n\r\n\
+                                      GET /health HTTP/1.1\r\nConnection: close\r\n\r\n".to_vec());
+        assert_eq!(read_request(&mut input).unwrap().unwrap(), HttpRequest {method: "POST".to_string(), path: "/predict".to_string(), body: b"body".to_vec(), keep_alive: true});
+        assert_eq!(read_request(&mut input).unwrap().unwrap().keep_alive, false);
+        assert_eq!(read_request(&mut input).unwrap().unwrap().keep_alive, false);
+        assert_eq!(read_request(&mut input).unwrap(), None);
+
+        assert!(read_request(&mut Cursor::new(b"|A a\n".to_vec())).is_err());
+        assert!(read_request(&mut Cursor::new(b"GET / HTTP/1.1\r\nContent-Length: x\r\n\r\n".to_vec())).is_err());
+        assert!(read_request(&mut Cursor::new(b"GET / HTTP/1.1\r\n".to_vec())).is_err());
+        // Limits on what a client can make us buffer
+        let long_header = format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n", "x".repeat(MAX_LINE_LEN));
+        assert!(read_request(&mut Cursor::new(long_header.into_bytes())).is_err());
+        let long_line = format!("GET /{} HTTP/1.1\r\n\r\n", "x".repeat(MAX_LINE_LEN));
+        assert!(read_request(&mut Cursor::new(long_line.into_bytes())).is_err());
+        let too_large = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY_LEN + 1);
+        assert!(read_request(&mut Cursor::new(too_large.into_bytes())).is_err());
+        assert!(read_request(&mut Cursor::new(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nbody".to_vec())).is_err());
+    }
+
+    #[test]
+    fn test_json_to_line() {
+        let example: Value = serde_json::from_str(r#"{"A": ["a1", "a2:0.5"], "B": []}"#).unwrap();
+        assert_eq!(json_to_line(&example).unwrap(), "|A a1 a2:0.5 |B \n");
+        for bad in vec![r#"["a"]"#, r#"{"A": "a"}"#, r#"{"A": ["a b"]}"#, r#"{"A": [1]}"#, r#"{"A|": ["a"]}"#,
+                       r#"{"A": ["a\tb"]}"#, r#"{"A": ["a\r"]}"#, r#"{"A\t": ["a"]}"#, r#"{"A\r": ["a"]}"#] {
+            assert!(json_to_line(&serde_json::from_str(bad).unwrap()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_write_response() {
+        let mut output: Vec<u8> = Vec::new();
+        write_response(&mut output, 404, &error_body("x"), true).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "HTTP/1.1 404 Not Found\r\nContent-Type: application/json\r\nContent-Length: 13\r\n\r\n{\"error\":\"x\"}");
+        let mut output: Vec<u8> = Vec::new();
+        write_response(&mut output, 503, &error_body("x"), false).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "HTTP/1.1 503 Service Unavailable\r\nContent-Type: application/json\r\nContent-Length: 13\r\nConnection: close\r\n\r\n{\"error\":\"x\"}");
+    }
+}
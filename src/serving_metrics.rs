@@ -0,0 +1,117 @@
+// Counters and latency histograms of the daemon worker threads, written out in Prometheus text format.
+// Each worker only updates its own WorkerMetrics, so relaxed atomics are enough.
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// Upper bounds of the latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 14] = [0.00001, 0.000025, 0.00005, 0.0001, 0.00025, 0.0005,
+                                    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 1.0];
+
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()], // not cumulative, observations above the last bound are only in count
+    count: AtomicU64,
+    sum_ns: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_ns.fetch_add(latency.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+pub struct WorkerMetrics {
+    pub connections: AtomicU64,
+    pub lines_predicted: AtomicU64,
+    pub parse_errors: AtomicU64,
+    pub reloads: AtomicU64,
+    pub reload_errors: AtomicU64,
+    pub flushes: AtomicU64,
+    // Translating and predicting one example, reading and parsing are not included
+    pub prediction_latency: Histogram,
+    // Whole HTTP request, from the end of reading it to the response written
+    pub http_request_latency: Histogram,
+}
+
+impl WorkerMetrics {
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn write_counter(out: &mut String, workers: &[WorkerMetrics], name: &str, help: &str, counter: fn(&WorkerMetrics) -> &AtomicU64) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} counter", name).unwrap();
+    for (i, worker) in workers.iter().enumerate() {
+        writeln!(out, "{}{{worker=\"{}\"}} {}", name, i, counter(worker).load(Ordering::Relaxed)).unwrap();
+    }
+}
+
+fn write_histogram(out: &mut String, workers: &[WorkerMetrics], name: &str, help: &str, histogram: fn(&WorkerMetrics) -> &Histogram) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} histogram", name).unwrap();
+    for (i, worker) in workers.iter().enumerate() {
+        let histogram = histogram(worker);
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            writeln!(out, "{}_bucket{{worker=\"{}\",le=\"{}\"}} {}", name, i, bound, cumulative).unwrap();
+        }
+        let count = histogram.count.load(Ordering::Relaxed);
+        writeln!(out, "{}_bucket{{worker=\"{}\",le=\"+Inf\"}} {}", name, i, count).unwrap();
+        writeln!(out, "{}_sum{{worker=\"{}\"}} {}", name, i, histogram.sum_ns.load(Ordering::Relaxed) as f64 / 1e9).unwrap();
+        writeln!(out, "{}_count{{worker=\"{}\"}} {}", name, i, count).unwrap();
+    }
+}
+
+pub fn prometheus_text(workers: &[WorkerMetrics]) -> String {
+    let mut out = String::new();
+    write_counter(&mut out, workers, "fw_connections_total", "Connections served", |w| &w.connections);
+    write_counter(&mut out, workers, "fw_lines_predicted_total", "Examples predicted", |w| &w.lines_predicted);
+    write_counter(&mut out, workers, "fw_parse_errors_total", "Requests rejected because of a parse error", |w| &w.parse_errors);
+    write_counter(&mut out, workers, "fw_reloads_total", "Successful hogwild_load commands and HTTP reloads", |w| &w.reloads);
+    write_counter(&mut out, workers, "fw_reload_errors_total", "Failed hogwild_load commands and HTTP reloads", |w| &w.reload_errors);
+    write_counter(&mut out, workers, "fw_flushes_total", "Flush commands", |w| &w.flushes);
+    write_histogram(&mut out, workers, "fw_prediction_latency_seconds", "Time to translate and predict one example", |w| &w.prediction_latency);
+    write_histogram(&mut out, workers, "fw_http_request_latency_seconds", "Time to handle one HTTP request after it was read", |w| &w.http_request_latency);
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prometheus_text() {
+        let workers: Vec<WorkerMetrics> = vec![WorkerMetrics::default(), WorkerMetrics::default()];
+        WorkerMetrics::inc(&workers[1].lines_predicted);
+        WorkerMetrics::inc(&workers[1].lines_predicted);
+        WorkerMetrics::inc(&workers[0].parse_errors);
+        workers[1].prediction_latency.observe(Duration::from_micros(20));
+        workers[1].prediction_latency.observe(Duration::from_micros(300));
+        workers[1].prediction_latency.observe(Duration::from_secs(2));
+        let text = prometheus_text(&workers);
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines.contains(&"# TYPE fw_lines_predicted_total counter"));
+        assert!(lines.contains(&"fw_lines_predicted_total{worker=\"0\"} 0"));
+        assert!(lines.contains(&"fw_lines_predicted_total{worker=\"1\"} 2"));
+        assert!(lines.contains(&"fw_parse_errors_total{worker=\"0\"} 1"));
+        assert!(lines.contains(&"# TYPE fw_prediction_latency_seconds histogram"));
+        assert!(lines.contains(&"fw_prediction_latency_seconds_bucket{worker=\"1\",le=\"0.00001\"} 0"));
+        assert!(lines.contains(&"fw_prediction_latency_seconds_bucket{worker=\"1\",le=\"0.000025\"} 1"));
+        assert!(lines.contains(&"fw_prediction_latency_seconds_bucket{worker=\"1\",le=\"0.0005\"} 2"));
+        assert!(lines.contains(&"fw_prediction_latency_seconds_bucket{worker=\"1\",le=\"1\"} 2"));
+        assert!(lines.contains(&"fw_prediction_latency_seconds_bucket{worker=\"1\",le=\"+Inf\"} 3"));
+        assert!(lines.contains(&"fw_prediction_latency_seconds_sum{worker=\"1\"} 2.00032"));
+        assert!(lines.contains(&"fw_prediction_latency_seconds_count{worker=\"1\"} 3"));
+        assert!(lines.contains(&"fw_http_request_latency_seconds_count{worker=\"0\"} 0"));
+    }
+}
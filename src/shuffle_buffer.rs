@@ -0,0 +1,66 @@
+use merand48::*;
+
+// Bounded in-memory window used to shuffle records when replaying the cache on additional passes.
+// Each record is kept together with its example number (its position in the input), so holdout
+// decisions stay tied to the example and not to where it ended up after shuffling.
+pub struct ShuffleBuffer {
+    records: Vec<(u64, Vec<u32>)>,
+    capacity: usize,
+    seed: u64,
+}
+
+impl ShuffleBuffer {
+    pub fn new(capacity: usize, seed: u64) -> ShuffleBuffer {
+        ShuffleBuffer {
+            records: Vec::with_capacity(capacity),
+            capacity: capacity,
+            seed: seed,
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.records.len() >= self.capacity
+    }
+
+    pub fn push(&mut self, example_num: u64, record: &[u32]) {
+        self.records.push((example_num, record.to_vec()));
+    }
+
+    // Removes a random record from the window, None when the window is empty
+    pub fn pop(&mut self) -> Option<(u64, Vec<u32>)> {
+        if self.records.is_empty() {
+            return None;
+        }
+        let index = ((merand48(self.seed) * self.records.len() as f32) as usize).min(self.records.len() - 1);
+        self.seed += 1;
+        Some(self.records.swap_remove(index))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_shuffle_buffer() {
+        let mut sb = ShuffleBuffer::new(3, 1);
+        assert_eq!(sb.pop(), None);
+        sb.push(1, &[4, 1, 0, 1]);
+        sb.push(2, &[4, 2, 0, 2]);
+        assert!(!sb.is_full());
+        sb.push(3, &[4, 3, 0, 3]);
+        assert!(sb.is_full());
+
+        let mut popped: Vec<(u64, Vec<u32>)> = Vec::new();
+        while let Some(r) = sb.pop() {
+            // record travels together with its example number
+            assert_eq!(r.1[3] as u64, r.0);
+            popped.push(r);
+        }
+        assert_eq!(popped.len(), 3);
+        popped.sort();
+        assert_eq!(popped, vec![(1, vec![4, 1, 0, 1]), (2, vec![4, 2, 0, 2]), (3, vec![4, 3, 0, 3])]);
+    }
+}
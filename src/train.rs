@@ -0,0 +1,473 @@
+use std::error::Error;
+use std::path::Path;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::io::BufRead;
+use std::f32;
+use std::collections::VecDeque;
+use std::time::Instant;
+use flate2::read::MultiGzDecoder;
+
+use crate::vwmap;
+use crate::parser;
+use crate::model_instance;
+use crate::feature_buffer;
+use crate::regressor;
+use crate::cache;
+use crate::persistence;
+use crate::block_loss_functions;
+use crate::multithread_helpers;
+use crate::shuffle_buffer;
+use crate::metrics;
+use crate::hogwild;
+use crate::parse_pipeline;
+use crate::invert_hash;
+
+
+// Training (or testing) over --data, driven by command line arguments, this is what the fw binary does
+// when not in daemon mode. Returns the final model, so library users can continue to use it in-process.
+pub fn train(cl: &clap::ArgMatches) -> Result<(model_instance::ModelInstance, vwmap::VwNamespaceMap, Box<regressor::Regressor>), Box<dyn Error>> {
+    // Where will we be putting perdictions (if at all)
+    let mut predictions_file = match cl.value_of("predictions") {
+        Some(filename) => Some(BufWriter::new(File::create(filename)?)),
+        None => None      
+    };
+
+    let testonly = cl.is_present("testonly");
+
+
+    let final_regressor_filename = cl.value_of("final_regressor");
+    match final_regressor_filename {
+        Some(filename) => {
+            if !cl.is_present("save_resume") {
+                return Err("You need to use --save_resume with --final_regressor, for vowpal wabbit compatibility")?;
+            }
+            println!("final_regressor = {}", filename);
+        },
+        None => {}
+    };
+
+    let serving_model_precision = match cl.value_of("serving_model_precision") {
+        None | Some("f32") => regressor::ServingPrecision::F32,
+        Some("f16") => regressor::ServingPrecision::F16,
+        Some("int8") => regressor::ServingPrecision::Int8,
+        Some(other) => return Err(format!("--serving_model_precision only supports 'f32', 'f16' and 'int8', passed: {}", other))?
+    };
+
+    let vw: vwmap::VwNamespaceMap;
+    let re: regressor::Regressor;
+    let mut mi: model_instance::ModelInstance;
+
+    if let Some(filename) = cl.value_of("initial_regressor") {
+        println!("initial_regressor = {}", filename);
+        println!("WARNING: Model parameters come from the initial regressor, command line learning rates, power_t and --noconstant replace the saved ones, structure arguments have to match the model");
+        let override_mi = |mi: &mut model_instance::ModelInstance, vw: &vwmap::VwNamespaceMap| mi.override_from_cmdline(cl, vw);
+        let (mi2, vw2, re2) = if testonly && cl.is_present("mmap") {
+            persistence::new_regressor_from_filename_mmap_with_overrides(filename, override_mi)?
+        } else {
+            persistence::new_regressor_from_filename_with_overrides(filename, testonly, override_mi)?
+        };
+        mi = mi2; vw = vw2; re = re2;
+    } else {
+        // We load vw_namespace_map.csv just so we know all the namespaces ahead of time
+        // This is one of the major differences from vowpal
+        let input_filename = cl.value_of("data").expect("--data expected");
+        let vw_namespace_map_filepath = Path::new(input_filename).parent().expect("Couldn't access path given by --data").join("vw_namespace_map.csv");
+        vw = vwmap::VwNamespaceMap::new_from_csv_filepath(vw_namespace_map_filepath)?;
+        mi = model_instance::ModelInstance::new_from_cmdline(&cl, &vw)?;
+        re = regressor::get_regressor_with_weights(&mi);
+    };
+
+    // Continuing from a checkpoint, the examples it was trained on are read again but not learned from
+    let skip_examples = if testonly {0} else {mi.checkpoint_examples};
+    if skip_examples > 0 {
+        println!("Resuming from checkpoint, skipping the first {} examples", skip_examples);
+    }
+    mi.checkpoint_examples = 0;
+    
+    let input_filename = cl.value_of("data").expect("--data expected");
+    let mut cache = cache::RecordCache::new(input_filename, cl.is_present("cache"), &vw);
+    let mut fbt = feature_buffer::FeatureBufferTranslator::new(&mi);
+
+    let predictions_after:u64 = match cl.value_of("predictions_after") {
+        Some(examples) => examples.parse()?,
+        None => 0
+    };
+
+    let holdout_after_option : Option<u64> = cl.value_of("holdout_after").map(|s| s.parse().unwrap());
+
+    let prediction_model_delay:u64 = match cl.value_of("prediction_model_delay") {
+        Some(delay) => delay.parse()?,
+        None => 0
+    };
+    
+    let mut delayed_learning_fbs: VecDeque<feature_buffer::FeatureBuffer> = VecDeque::with_capacity(prediction_model_delay as usize);
+
+    // Setup Parser, input is owned, so it can be handed over to the parsing pipeline
+    let input = File::open(input_filename)?;
+    let mut bufferred_input: Option<Box<dyn BufRead + Send>> = match input_filename.ends_with(".gz") {
+        true =>  Some(Box::new(io::BufReader::new(MultiGzDecoder::new(input)))),
+        false => Some(Box::new(io::BufReader::new(input)))
+    };
+
+    let mut pa = parser::VowpalParser::new(&vw);
+
+    // Parsing in separate threads only makes sense when we are actually reading text input
+    let parse_threads: u32 = match cl.value_of("parse_threads") {
+        Some(parse_threads) => parse_threads.parse()?,
+        None => 0
+    };
+    let mut parse_pipeline = match parse_threads > 0 && !cache.reading {
+        true => Some(parse_pipeline::ParsePipeline::new(bufferred_input.take().unwrap(), &pa, parse_threads)),
+        false => None
+    };
+
+    let passes: u64 = match cl.value_of("passes") {
+        Some(passes) => passes.parse()?,
+        None => 1
+    };
+    if passes == 0 {
+        return Err("--passes has to be at least 1")?;
+    }
+    if passes > 1 && !cl.is_present("cache") {
+        return Err("--passes greater than 1 requires --cache")?;
+    }
+
+    let shuffle_buffer_len: usize = match cl.value_of("shuffle_buffer") {
+        Some(len) => len.parse()?,
+        None => 0
+    };
+
+    let threads: u32 = match cl.value_of("threads") {
+        Some(threads) => threads.parse()?,
+        None => 1
+    };
+    if threads == 0 {
+        return Err("--threads has to be at least 1")?;
+    }
+    if threads > 1 && (predictions_file.is_some() || prediction_model_delay > 0) {
+        return Err("--threads greater than 1 cannot be used with --predictions or --prediction_model_delay, since example order is not preserved")?;
+    }
+
+    let checkpoint_every: u64 = match cl.value_of("checkpoint_every") {
+        Some(examples) => examples.parse()?,
+        None => 0
+    };
+    let checkpoint_filename = match (checkpoint_every > 0, cl.value_of("checkpoint_dir")) {
+        (false, _) => None,
+        (true, None) => return Err("--checkpoint_every requires --checkpoint_dir")?,
+        (true, Some(dir)) => {
+            if testonly || threads > 1 || prediction_model_delay > 0 {
+                return Err("--checkpoint_every cannot be used with --testonly, --threads greater than 1 or --prediction_model_delay")?;
+            }
+            std::fs::create_dir_all(dir)?;
+            Some(Path::new(dir).join("checkpoint.fw").to_str().unwrap().to_string())
+        }
+    };
+    if skip_examples > 0 && (predictions_file.is_some() || prediction_model_delay > 0) {
+        return Err("Resuming from a checkpoint cannot be used with --predictions or --prediction_model_delay, the skipped examples are not predicted")?;
+    }
+
+    // Like vowpal, with multiple passes we hold out every 10th example, unless told otherwise
+    let holdout_selection = metrics::HoldoutSelection {
+        holdout_after: holdout_after_option,
+        holdout_period: if passes > 1 && holdout_after_option.is_none() && !cl.is_present("holdout_off") {10} else {0},
+    };
+
+    let now = Instant::now();
+    let mut example_num: u64;
+    let mut total_examples = 0;
+    let mut progressive_validation = metrics::ProgressiveValidation::new();
+    let mut holdout_metrics = metrics::HoldoutMetrics::new();
+    eprintln!("{}", metrics::ProgressiveValidation::header());
+    // With --threads all the workers share this regressor (Hogwild), single threaded training uses it directly
+    let mut re = multithread_helpers::BoxedRegressorTrait::new(Box::new(re));
+    for pass in 0..passes {
+        if pass > 0 {
+            cache.rewind(&vw)?;
+        }
+        // Shuffling only happens when replaying the cache, the first pass goes in input order
+        let mut shuffle_buffer = match shuffle_buffer_len > 0 && pass > 0 {
+            true => Some(shuffle_buffer::ShuffleBuffer::new(shuffle_buffer_len, pass)),
+            false => None
+        };
+        let mut read_example_num = 0;
+        let mut eof = false;
+        // Holdout metrics are reported per pass, the final ones come from the last pass
+        holdout_metrics = metrics::HoldoutMetrics::new();
+        let mut hogwild_trainer = match threads > 1 {
+            true => Some(hogwild::HogwildTrainer::new(threads, &re, &fbt, &mi, holdout_selection, testonly)?),
+            false => None
+        };
+        loop {
+
+            let reading_result;
+            let buffer:&[u32];
+            let shuffled_record;
+            if let Some(sb) = shuffle_buffer.as_mut() {
+                while !eof && !sb.is_full() {
+                    match cache.get_next_record() {
+                        Ok([]) => eof = true,
+                        Ok(buffer) => { read_example_num += 1; sb.push(read_example_num, buffer) },
+                        Err(_e) => return Err(_e)
+                    };
+                }
+                shuffled_record = match sb.pop() {
+                    Some(record) => record,
+                    None => break // EOF and window drained
+                };
+                example_num = shuffled_record.0;
+                buffer = &shuffled_record.1;
+            } else if !cache.reading {
+                reading_result = match parse_pipeline.as_mut() {
+                    Some(pp) => pp.next_record(),
+                    None => pa.next_vowpal(bufferred_input.as_mut().unwrap())
+                };
+                buffer = match reading_result {
+                        Ok([]) => break, // EOF
+                        Ok(buffer2) => buffer2,
+                        Err(_e) => return Err(_e)
+                };
+                if cache.writing {
+                        cache.push_record(buffer)?;
+                }
+                read_example_num += 1;
+                example_num = read_example_num;
+            } else {
+                reading_result = cache.get_next_record();
+                buffer = match reading_result {
+                        Ok([]) => break, // EOF
+                        Ok(buffer) => buffer,
+                        Err(_e) => return Err(_e)
+                };
+                read_example_num += 1;
+                example_num = read_example_num;
+            }
+            total_examples += 1;
+            if total_examples <= skip_examples {
+                continue;
+            }
+            if let Some(trainer) = hogwild_trainer.as_mut() {
+                trainer.push_record(example_num, buffer)?;
+                continue;
+            }
+            fbt.translate(buffer, example_num);
+            let mut prediction: f32 = 0.0;
+            let mut predicted = true;
+
+            let holdout = holdout_selection.is_holdout(example_num);
+
+            if prediction_model_delay == 0 {
+                prediction = re.learn(&fbt.feature_buffer, !testonly && !holdout);
+            } else {
+                if example_num > predictions_after {
+                    prediction = re.learn(&fbt.feature_buffer, false);
+                } else {
+                    predicted = false;
+                }
+                delayed_learning_fbs.push_back(fbt.feature_buffer.clone());
+                if (prediction_model_delay as usize) < delayed_learning_fbs.len() {
+                    let delayed_buffer = delayed_learning_fbs.pop_front().unwrap();
+                    re.learn(&delayed_buffer, !testonly);
+                }
+            } 
+
+            let label = fbt.feature_buffer.label;
+            if predicted && !label.is_nan() {
+                let loss = block_loss_functions::example_loss(&mi, prediction, label);
+                if holdout {
+                    holdout_metrics.add(loss, prediction, label);
+                } else if pass == 0 {
+                    // Later passes predict examples that were already learned from, that loss is not progressive
+                    if let Some(line) = progressive_validation.add(loss, label, prediction) {
+                        eprintln!("{}", line);
+                    }
+                }
+            }
+            
+            // Predictions are only written in the first pass, when examples come in input order
+            if pass == 0 && example_num > predictions_after {
+                match predictions_file.as_mut() {
+                    Some(file) =>  write!(file, "{:.6}\n", prediction)?,
+                    None => {}
+                }
+            }
+
+            if let Some(filename) = checkpoint_filename.as_ref() {
+                if total_examples % checkpoint_every == 0 {
+                    let mut checkpoint_mi = mi.clone();
+                    checkpoint_mi.checkpoint_examples = total_examples;
+                    persistence::save_regressor_to_filename_atomic(filename, &checkpoint_mi, &vw, &re)?;
+                    println!("checkpoint = {} ({} examples)", filename, total_examples);
+                }
+            }
+        }
+        if let Some(trainer) = hogwild_trainer.take() {
+            let (pv, hm) = trainer.finish()?;
+            if pass == 0 {
+                progressive_validation.merge(&pv);
+            }
+            holdout_metrics = hm;
+        }
+        if pass == 0 {
+            cache.write_finish()?;
+        }
+        if passes > 1 && holdout_metrics.count > 0 {
+            eprintln!("pass {} holdout loss: {:.6} ({} examples)", pass + 1, holdout_metrics.average_loss(), holdout_metrics.count);
+        }
+    }
+
+    if progressive_validation.count() > 0 {
+        eprintln!("average loss = {:.6} ({} examples)", progressive_validation.average_loss(), progressive_validation.count());
+    }
+    if holdout_metrics.count > 0 {
+        eprintln!("holdout loss = {:.6} ({} examples)", holdout_metrics.average_loss(), holdout_metrics.count);
+        // AUC is only meaningful for binary classification
+        if mi.loss_function == model_instance::LossFunction::Logistic {
+            match holdout_metrics.auc.auc() {
+                Some(auc) => eprintln!("holdout AUC = {:.6}", auc),
+                None => eprintln!("holdout AUC = undefined, holdout needs both positive and negative examples")
+            }
+        }
+    }
+
+    let elapsed = now.elapsed();
+    println!("Elapsed: {:.2?} rows: {}", elapsed, total_examples);
+
+    let mut re = match re.try_into_inner() {
+        Ok(re) => re,
+        Err(_) => return Err("Regressor is still shared after training")?
+    };
+    if let Some(filename) = cl.value_of("invert_hash") {
+        invert_hash::write_invert_hash_to_filename(filename, input_filename, &mi, &vw, &mut re)?;
+    }
+    match final_regressor_filename {
+        Some(filename) => persistence::save_regressor_to_filename(filename, &mi, &vw, &re)?,
+        None => {}
+    }
+    if let Some(filename) = cl.value_of("export_serving_model") {
+        persistence::save_serving_model_to_filename(filename, &mi, &vw, &mut re, serving_model_precision)?;
+        println!("export_serving_model = {} ({:?})", filename, serving_model_precision);
+    }
+    Ok((mi, vw, re))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmdline;
+    use crate::model::Model;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_train() {
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join("vw_namespace_map.csv"), "A,featureA\nB,featureB\n").unwrap();
+        let mut data = String::new();
+        for i in 0..200 {
+            data.push_str(if i % 2 == 0 {"1 |A a1 |B b1\n"} else {"-1 |A a2 |B b1\n"});
+        }
+        let data_path = dir.path().join("train.vw");
+        std::fs::write(&data_path, data).unwrap();
+        let model_path = dir.path().join("model.fw");
+
+        let cl = cmdline::parse_from(vec!["fw", "--data", data_path.to_str().unwrap(), "--keep", "A", "--keep", "B",
+                                          "--adaptive", "-l", "0.1", "--save_resume", "-f", model_path.to_str().unwrap()]).unwrap();
+        let (mi, vw, re) = train(&cl).unwrap();
+        let mut model = Model::from_regressor(mi, vw, *re);
+        let positive = model.predict("|A a1 |B b1").unwrap();
+        let negative = model.predict("|A a2 |B b1").unwrap();
+        assert!(positive > 0.75);
+        assert!(negative < 0.25);
+        // The final regressor is the same as the returned one
+        let mut loaded = Model::load(model_path.to_str().unwrap()).unwrap();
+        assert_eq!(loaded.predict("|A a1 |B b1").unwrap(), positive);
+
+        // The learning rate on the command line replaces the saved one, with 0.0 continuing doesn't change the weights
+        let cl = cmdline::parse_from(vec!["fw", "--data", data_path.to_str().unwrap(), "-i", model_path.to_str().unwrap(), "-l", "0.0"]).unwrap();
+        let (mi, vw, re) = train(&cl).unwrap();
+        assert_eq!(mi.learning_rate, 0.0);
+        assert_eq!(Model::from_regressor(mi, vw, *re).predict("|A a1 |B b1").unwrap(), positive);
+        let cl = cmdline::parse_from(vec!["fw", "--data", data_path.to_str().unwrap(), "-i", model_path.to_str().unwrap(), "--keep", "A"]).unwrap();
+        assert!(train(&cl).is_err()); // structure differs from the saved model
+        let cl = cmdline::parse_from(vec!["fw", "--data", data_path.to_str().unwrap(), "-i", model_path.to_str().unwrap(), "-t", "--mmap",
+                                          "--keep", "A"]).unwrap();
+        assert!(train(&cl).is_err()); // also when memory mapped
+
+        // Serving model of an existing model
+        let serving_path = dir.path().join("serving.fw");
+        let cl = cmdline::parse_from(vec!["fw", "--data", data_path.to_str().unwrap(), "-i", model_path.to_str().unwrap(), "-t",
+                                          "--export_serving_model", serving_path.to_str().unwrap(), "--serving_model_precision", "f16"]).unwrap();
+        train(&cl).unwrap();
+        let mut serving = Model::load_immutable(serving_path.to_str().unwrap()).unwrap();
+        assert!((serving.predict("|A a1 |B b1").unwrap() - positive).abs() < 0.001);
+        assert!(Model::load(serving_path.to_str().unwrap()).is_err());
+        let cl = cmdline::parse_from(vec!["fw", "--data", data_path.to_str().unwrap(), "--export_serving_model", serving_path.to_str().unwrap(),
+                                          "--serving_model_precision", "f8"]).unwrap();
+        assert!(train(&cl).is_err());
+
+        let cl = cmdline::parse_from(vec!["fw", "--data", data_path.to_str().unwrap(), "-f", model_path.to_str().unwrap()]).unwrap();
+        assert!(train(&cl).is_err()); // -f requires --save_resume
+    }
+
+    #[test]
+    fn test_checkpoint_resume() {
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join("vw_namespace_map.csv"), "A,featureA\nB,featureB\n").unwrap();
+        let mut data = String::new();
+        for i in 0..200 {
+            data.push_str(if i % 3 == 0 {"1 |A a1 |B b1\n"} else {"-1 |A a2 |B b1\n"});
+        }
+        let data_path = dir.path().join("train.vw");
+        std::fs::write(&data_path, &data).unwrap();
+        // A run that "crashed" after 150 examples, the last checkpoint was written after 100
+        let crashed_path = dir.path().join("crashed").join("train.vw");
+        std::fs::create_dir(dir.path().join("crashed")).unwrap();
+        std::fs::copy(dir.path().join("vw_namespace_map.csv"), dir.path().join("crashed").join("vw_namespace_map.csv")).unwrap();
+        std::fs::write(&crashed_path, data.lines().take(150).map(|l| format!("{}\n", l)).collect::<String>()).unwrap();
+        let checkpoint_dir = dir.path().join("checkpoints");
+        let args = vec!["--keep", "A", "--keep", "B", "--adaptive", "-l", "0.1", "--interactions", "AB",
+                        "--checkpoint_every", "100", "--checkpoint_dir", checkpoint_dir.to_str().unwrap()];
+
+        let mut cl_args = vec!["fw", "--data", data_path.to_str().unwrap()];
+        cl_args.extend(args.iter());
+        let (mi, vw, re) = train(&cmdline::parse_from(cl_args).unwrap()).unwrap();
+        let mut uninterrupted = Model::from_regressor(mi, vw, *re);
+
+        let mut cl_args = vec!["fw", "--data", crashed_path.to_str().unwrap()];
+        cl_args.extend(args.iter());
+        train(&cmdline::parse_from(cl_args).unwrap()).unwrap();
+        let checkpoint_path = checkpoint_dir.join("checkpoint.fw");
+        assert_eq!(std::fs::read_dir(&checkpoint_dir).unwrap().count(), 1);
+        let checkpoint = Model::load(checkpoint_path.to_str().unwrap()).unwrap();
+        assert_eq!(checkpoint.model_instance().checkpoint_examples, 100);
+
+        // Resuming skips the 100 examples in the checkpoint and ends with the same model
+        let model_path = dir.path().join("model.fw");
+        let cl = cmdline::parse_from(vec!["fw", "--data", data_path.to_str().unwrap(), "-i", checkpoint_path.to_str().unwrap(),
+                                          "--save_resume", "-f", model_path.to_str().unwrap()]).unwrap();
+        let (mi, vw, re) = train(&cl).unwrap();
+        assert_eq!(mi.checkpoint_examples, 0);
+        let mut resumed = Model::from_regressor(mi, vw, *re);
+        for example in &["|A a1 |B b1", "|A a2 |B b1"] {
+            assert_eq!(resumed.predict(example).unwrap(), uninterrupted.predict(example).unwrap());
+        }
+        // The final model is not a checkpoint, continuing from it learns from all examples
+        let model = Model::load(model_path.to_str().unwrap()).unwrap();
+        assert_eq!(model.model_instance().checkpoint_examples, 0);
+        // Predictions would not line up with the input
+        let predictions_path = dir.path().join("predictions.txt");
+        let cl = cmdline::parse_from(vec!["fw", "--data", data_path.to_str().unwrap(), "-i", checkpoint_path.to_str().unwrap(),
+                                          "-p", predictions_path.to_str().unwrap()]).unwrap();
+        assert!(train(&cl).is_err());
+        let cl = cmdline::parse_from(vec!["fw", "--data", data_path.to_str().unwrap(), "-i", checkpoint_path.to_str().unwrap(),
+                                          "--prediction_model_delay", "10"]).unwrap();
+        assert!(train(&cl).is_err());
+
+        let cl = cmdline::parse_from(vec!["fw", "--data", data_path.to_str().unwrap(), "--checkpoint_every", "100"]).unwrap();
+        assert!(train(&cl).is_err()); // --checkpoint_every requires --checkpoint_dir
+    }
+}
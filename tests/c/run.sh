@@ -0,0 +1,34 @@
+#!/bin/bash
+# Builds libfw.so and the C harness, trains a small model with the fw binary
+# and checks that predictions through the C ABI match the ones of "fw -t -p"
+set -e
+
+ROOT=$(cd "$(dirname "$0")/../.." && pwd)
+TARGET=${TARGET:-release}
+TMP=$(mktemp -d)
+trap "rm -rf $TMP" EXIT
+
+if [ "$TARGET" = "release" ]; then
+    (cd "$ROOT" && cargo build --release)
+else
+    (cd "$ROOT" && cargo build)
+fi
+FW="$ROOT/target/$TARGET/fw"
+
+cat > "$TMP/vw_namespace_map.csv" <<EOF
+A,featureA
+B,featureB
+C,featureC
+EOF
+for i in $(seq 1 1000); do
+    a=$((RANDOM % 10)); b=$((RANDOM % 10)); c=$((RANDOM % 3))
+    if [ $(((a + b) % 3)) -eq 0 ]; then label=1; else label=-1; fi
+    echo "$label |A a$a |B b$b b$c:0.5 |C c$c"
+done > "$TMP/train.vw"
+
+"$FW" --data "$TMP/train.vw" --keep A --keep B --interactions AC --ffm_field A --ffm_field B --ffm_field C --ffm_k 4 --ffm_bit_precision 18 \
+      --adaptive -l 0.1 --save_resume -f "$TMP/model.fw" > /dev/null
+"$FW" --data "$TMP/train.vw" -i "$TMP/model.fw" -t -p "$TMP/predictions.txt" > /dev/null
+
+cc -O2 -Wall -I "$ROOT/include" "$ROOT/tests/c/test_ffi.c" -L "$ROOT/target/$TARGET" -lfw -lm -o "$TMP/test_ffi"
+LD_LIBRARY_PATH="$ROOT/target/$TARGET" "$TMP/test_ffi" "$TMP/model.fw" "$TMP/train.vw" "$TMP/predictions.txt"
@@ -0,0 +1,110 @@
+// Test harness for the C ABI (include/fw.h), run it with tests/c/run.sh
+//
+// Usage: test_ffi <model.fw> <examples.vw> <predictions.txt>
+// Predicts every example of examples.vw and checks that predictions match the ones
+// written by "fw -t -p", both through text lines and through pre-parsed features.
+
+#include <math.h>
+#include <stdio.h>
+#include <string.h>
+#include "fw.h"
+
+#define MAX_FEATURES 256
+
+static int failures = 0;
+
+#define CHECK(cond, ...) do { if (!(cond)) { fprintf(stderr, "FAIL %s:%d: ", __FILE__, __LINE__); fprintf(stderr, __VA_ARGS__); fprintf(stderr, "\n"); failures++; } } while (0)
+
+// Splits a vowpal line into features and hashes them, only plain "|N f1 f2:weight" syntax is supported
+static int hash_line(FwModel *model, char *line, uint16_t *namespace_indexes, uint32_t *hashes, float *values) {
+    int n = 0;
+    char namespace_name[64] = "";
+    for (char *token = strtok(line, " \n"); token != NULL; token = strtok(NULL, " \n")) {
+        if (token[0] == '|') {
+            snprintf(namespace_name, sizeof(namespace_name), "%s", token + 1);
+            continue;
+        }
+        if (namespace_name[0] == '\0' || n >= MAX_FEATURES) {
+            continue; // label
+        }
+        float value = 1.0f;
+        char *colon = strchr(token, ':');
+        if (colon != NULL) {
+            *colon = '\0';
+            value = strtof(colon + 1, NULL);
+        }
+        if (fw_feature_hash(model, namespace_name, token, &namespace_indexes[n], &hashes[n]) != FW_OK) {
+            return -1;
+        }
+        values[n++] = value;
+    }
+    return n;
+}
+
+int main(int argc, char **argv) {
+    if (argc != 4) {
+        fprintf(stderr, "Usage: %s <model.fw> <examples.vw> <predictions.txt>\n", argv[0]);
+        return 2;
+    }
+
+    CHECK(fw_load("/nonexistent/model.fw") == NULL, "loading a missing model should fail");
+    CHECK(fw_last_error() != NULL, "missing error message");
+
+    FwModel *model = fw_load(argv[1]);
+    if (model == NULL) {
+        fprintf(stderr, "fw_load failed: %s\n", fw_last_error());
+        return 1;
+    }
+    FwModel *clone = fw_clone(model);
+    CHECK(clone != NULL, "fw_clone failed");
+
+    FILE *examples = fopen(argv[2], "r");
+    FILE *predictions = fopen(argv[3], "r");
+    if (examples == NULL || predictions == NULL) {
+        fprintf(stderr, "Cannot open examples or predictions\n");
+        return 1;
+    }
+
+    char line[4096];
+    char line_copy[4096];
+    int count = 0;
+    float expected;
+    while (fgets(line, sizeof(line), examples) != NULL && fscanf(predictions, "%f", &expected) == 1) {
+        float prediction = -1.0f;
+        CHECK(fw_predict(count % 2 ? clone : model, line, &prediction) == FW_OK, "fw_predict failed: %s", fw_last_error());
+        // predictions file has 6 decimals
+        CHECK(fabsf(prediction - expected) < 1e-5f, "example %d: predicted %f, expected %f", count, prediction, expected);
+
+        uint16_t namespace_indexes[MAX_FEATURES];
+        uint32_t hashes[MAX_FEATURES];
+        float values[MAX_FEATURES];
+        strcpy(line_copy, line);
+        int n = hash_line(model, line_copy, namespace_indexes, hashes, values);
+        CHECK(n >= 0, "fw_feature_hash failed: %s", fw_last_error());
+        float prediction_features = -1.0f;
+        CHECK(fw_predict_features(model, namespace_indexes, hashes, values, n, &prediction_features) == FW_OK,
+              "fw_predict_features failed: %s", fw_last_error());
+        CHECK(prediction_features == prediction, "example %d: pre-parsed features predicted %f, text %f", count, prediction_features, prediction);
+        count++;
+    }
+    CHECK(count > 0, "no examples were read");
+
+    float prediction;
+    CHECK(fw_predict(model, "|namespace_that_does_not_exist x", &prediction) == FW_ERROR, "unknown namespace should fail");
+    CHECK(fw_last_error() != NULL && strlen(fw_last_error()) > 0, "missing error message");
+
+    // Swapping in the same weights does not change predictions
+    CHECK(fw_hogwild_load(clone, argv[1]) == FW_OK, "fw_hogwild_load failed: %s", fw_last_error());
+
+    fclose(examples);
+    fclose(predictions);
+    fw_free(clone);
+    fw_free(model);
+
+    if (failures > 0) {
+        fprintf(stderr, "%d checks failed\n", failures);
+        return 1;
+    }
+    printf("C ABI test passed, %d examples\n", count);
+    return 0;
+}